@@ -28,6 +28,18 @@ pub enum HashFunctionTextualName {
     /// IANA name for the Sha-512 algorithm
     #[serde(rename = "sha-512")]
     Sha512,
+    /// Name for the fixed-output Sha3-224 algorithm
+    #[serde(rename = "sha3-224")]
+    Sha3_224,
+    /// Name for the fixed-output Sha3-256 algorithm
+    #[serde(rename = "sha3-256")]
+    Sha3_256,
+    /// Name for the fixed-output Sha3-384 algorithm
+    #[serde(rename = "sha3-384")]
+    Sha3_384,
+    /// Name for the fixed-output Sha3-512 algorithm
+    #[serde(rename = "sha3-512")]
+    Sha3_512,
     /// IANA name for the Shake128 algorithm
     #[serde(rename = "shake128")]
     Shake128,
@@ -36,6 +48,39 @@ pub enum HashFunctionTextualName {
     Shake256,
 }
 
+impl HashFunctionTextualName {
+    /// Returns the length of the digest in bytes produced by the algorithm,
+    /// None for extendable-output functions without a fixed digest length
+    pub fn digest_length(&self) -> Option<usize> {
+        match self {
+            Self::Md2 => Some(16),
+            Self::Md5 => Some(16),
+            Self::Sha1 => Some(20),
+            Self::Sha224 => Some(28),
+            Self::Sha256 => Some(32),
+            Self::Sha384 => Some(48),
+            Self::Sha512 => Some(64),
+            Self::Sha3_224 => Some(28),
+            Self::Sha3_256 => Some(32),
+            Self::Sha3_384 => Some(48),
+            Self::Sha3_512 => Some(64),
+            Self::Shake128 => None,
+            Self::Shake256 => None,
+        }
+    }
+
+    /// Returns the collision resistance of the algorithm in bits, half of
+    /// its digest length. Use it to pick the strongest of several hashes,
+    /// the declaration order of the variants does not rank them.
+    pub fn strength(&self) -> usize {
+        match self {
+            Self::Shake128 => 128,
+            Self::Shake256 => 256,
+            _ => self.digest_length().unwrap_or(0) * 8 / 2,
+        }
+    }
+}
+
 impl std::str::FromStr for HashFunctionTextualName {
     type Err = IANARegistryError;
 
@@ -48,6 +93,10 @@ impl std::str::FromStr for HashFunctionTextualName {
             "sha-256" => Ok(Self::Sha256),
             "sha-384" => Ok(Self::Sha384),
             "sha-512" => Ok(Self::Sha512),
+            "sha3-224" => Ok(Self::Sha3_224),
+            "sha3-256" => Ok(Self::Sha3_256),
+            "sha3-384" => Ok(Self::Sha3_384),
+            "sha3-512" => Ok(Self::Sha3_512),
             "shake128" => Ok(Self::Shake128),
             "shake256" => Ok(Self::Shake256),
             _ => Err(Self::Err::HashParseError),
@@ -67,6 +116,10 @@ impl TryFrom<&str> for HashFunctionTextualName {
             "sha-256" => Ok(Self::Sha256),
             "sha-384" => Ok(Self::Sha384),
             "sha-512" => Ok(Self::Sha512),
+            "sha3-224" => Ok(Self::Sha3_224),
+            "sha3-256" => Ok(Self::Sha3_256),
+            "sha3-384" => Ok(Self::Sha3_384),
+            "sha3-512" => Ok(Self::Sha3_512),
             "shake128" => Ok(Self::Shake128),
             "shake256" => Ok(Self::Shake256),
             _ => Err(Self::Error::HashParseError),
@@ -84,6 +137,10 @@ impl From<HashFunctionTextualName> for &'static str {
             HashFunctionTextualName::Sha256 => "sha-256",
             HashFunctionTextualName::Sha384 => "sha-384",
             HashFunctionTextualName::Sha512 => "sha-512",
+            HashFunctionTextualName::Sha3_224 => "sha3-224",
+            HashFunctionTextualName::Sha3_256 => "sha3-256",
+            HashFunctionTextualName::Sha3_384 => "sha3-384",
+            HashFunctionTextualName::Sha3_512 => "sha3-512",
             HashFunctionTextualName::Shake128 => "shake128",
             HashFunctionTextualName::Shake256 => "shake256",
         }
@@ -110,6 +167,10 @@ mod tests {
             ("sha-256", HashFunctionTextualName::Sha256),
             ("sha-384", HashFunctionTextualName::Sha384),
             ("sha-512", HashFunctionTextualName::Sha512),
+            ("sha3-224", HashFunctionTextualName::Sha3_224),
+            ("sha3-256", HashFunctionTextualName::Sha3_256),
+            ("sha3-384", HashFunctionTextualName::Sha3_384),
+            ("sha3-512", HashFunctionTextualName::Sha3_512),
             ("shake128", HashFunctionTextualName::Shake128),
             ("shake256", HashFunctionTextualName::Shake256),
         ]
@@ -120,6 +181,8 @@ mod tests {
         let mut hashes = vec![
             HashFunctionTextualName::Shake256,
             HashFunctionTextualName::Shake128,
+            HashFunctionTextualName::Sha3_512,
+            HashFunctionTextualName::Sha3_256,
             HashFunctionTextualName::Sha512,
             HashFunctionTextualName::Sha384,
             HashFunctionTextualName::Sha256,
@@ -145,12 +208,50 @@ mod tests {
                 HashFunctionTextualName::Sha256,
                 HashFunctionTextualName::Sha384,
                 HashFunctionTextualName::Sha512,
+                HashFunctionTextualName::Sha3_256,
+                HashFunctionTextualName::Sha3_512,
                 HashFunctionTextualName::Shake128,
                 HashFunctionTextualName::Shake256,
             ]
         );
     }
 
+    #[test]
+    fn test_digest_length() {
+        assert_eq!(HashFunctionTextualName::Md5.digest_length(), Some(16));
+        assert_eq!(HashFunctionTextualName::Sha1.digest_length(), Some(20));
+        assert_eq!(HashFunctionTextualName::Sha256.digest_length(), Some(32));
+        assert_eq!(HashFunctionTextualName::Sha512.digest_length(), Some(64));
+        assert_eq!(HashFunctionTextualName::Sha3_256.digest_length(), Some(32));
+        assert_eq!(HashFunctionTextualName::Shake128.digest_length(), None);
+    }
+
+    #[test]
+    fn test_strength() {
+        assert!(
+            HashFunctionTextualName::Sha512.strength()
+                > HashFunctionTextualName::Sha3_224.strength()
+        );
+        assert!(
+            HashFunctionTextualName::Sha3_256.strength() > HashFunctionTextualName::Sha1.strength()
+        );
+        assert_eq!(
+            HashFunctionTextualName::Sha3_512.strength(),
+            HashFunctionTextualName::Sha512.strength()
+        );
+        assert_eq!(HashFunctionTextualName::Shake128.strength(), 128);
+        assert_eq!(
+            [
+                HashFunctionTextualName::Sha512,
+                HashFunctionTextualName::Sha3_224,
+                HashFunctionTextualName::Md5,
+            ]
+            .into_iter()
+            .max_by_key(HashFunctionTextualName::strength),
+            Some(HashFunctionTextualName::Sha512)
+        );
+    }
+
     #[test]
     fn test_from_str() {
         for (name_str, name_enum) in HASH_NAMES.iter() {
@@ -125,7 +125,7 @@ pub enum OperatingSystemName {
     #[serde(rename = "FORTH")]
     FORTH,
     /// IANA registry name for the FREEBSD operating system
-    #[serde(rename = "FREEBSD")]
+    #[serde(rename = "FREEBSD", alias = "FreeBSD")]
     FREEBSD,
     /// IANA registry name for the FUZZ operating system
     #[serde(rename = "FUZZ")]
@@ -170,7 +170,12 @@ pub enum OperatingSystemName {
     #[serde(rename = "KOSOS")]
     KOSOS,
     /// IANA registry name for the LINUX operating system
-    #[serde(rename = "LINUX")]
+    #[serde(
+        rename = "LINUX",
+        alias = "Linux",
+        alias = "linux",
+        alias = "GNU/Linux"
+    )]
     Linux,
     /// IANA registry name for the LINUX-1.0 operating system
     #[serde(rename = "LINUX-1.0")]
@@ -200,7 +205,13 @@ pub enum OperatingSystemName {
     #[serde(rename = "LOCUS")]
     LOCUS,
     /// IANA registry name for the MACOS operating system
-    #[serde(rename = "MACOS")]
+    #[serde(
+        rename = "MACOS",
+        alias = "macOS",
+        alias = "MacOS",
+        alias = "MacOSX",
+        alias = "Mac OS X"
+    )]
     MacOS,
     /// IANA registry name for the MINOS operating system
     #[serde(rename = "MINOS")]
@@ -278,7 +289,7 @@ pub enum OperatingSystemName {
     #[serde(rename = "NTOS")]
     NTOS,
     /// IANA registry name for the OPENBSD operating system
-    #[serde(rename = "OPENBSD")]
+    #[serde(rename = "OPENBSD", alias = "OpenBSD")]
     OpenBSD,
     /// IANA registry name for the OPENVME operating system
     #[serde(rename = "OPENVME")]
@@ -509,7 +520,13 @@ pub enum OperatingSystemName {
     #[serde(rename = "WANG")]
     Wang,
     /// IANA registry name for the WIN32 operating system
-    #[serde(rename = "WIN32")]
+    #[serde(
+        rename = "WIN32",
+        alias = "Windows",
+        alias = "windows",
+        alias = "Windows 10",
+        alias = "Windows 11"
+    )]
     Win32,
     /// IANA registry name for the WINDOWS-95 operating system
     #[serde(rename = "WINDOWS-95")]
@@ -573,6 +590,54 @@ pub enum OperatingSystemName {
     Xenix,
 }
 
+/// Common real-world spellings of operating system names which are not part
+/// of the registry. Keys are normalized as done by [OperatingSystemName::parse_lenient].
+const LENIENT_NAMES: &[(&str, OperatingSystemName)] = &[
+    ("GNU/LINUX", OperatingSystemName::Linux),
+    ("GNU-LINUX", OperatingSystemName::Linux),
+    ("MACOSX", OperatingSystemName::MacOS),
+    ("MAC-OS", OperatingSystemName::MacOS),
+    ("MAC-OS-X", OperatingSystemName::MacOS),
+    ("OSX", OperatingSystemName::MacOS),
+    ("OS-X", OperatingSystemName::MacOS),
+    ("DARWIN", OperatingSystemName::MacOS),
+    ("WINDOWS", OperatingSystemName::Win32),
+    ("WINDOWS-10", OperatingSystemName::Win32),
+    ("WINDOWS-11", OperatingSystemName::Win32),
+    ("WIN64", OperatingSystemName::Win32),
+];
+
+impl OperatingSystemName {
+    /// Best-effort parsing of operating system names as they are found in the wild.
+    ///
+    /// Registry names are matched first, afterwards the name is normalized (trimmed,
+    /// upper-cased, spaces and underscores replaced by dashes) and matched against the
+    /// registry and a table of common spellings like `GNU/Linux`, `MacOSX` or `Windows 10`.
+    pub fn parse_lenient(s: &str) -> Result<Self, IANARegistryError> {
+        if let Ok(name) = s.parse() {
+            return Ok(name);
+        }
+
+        let normalized: String = s
+            .trim()
+            .chars()
+            .map(|c| match c {
+                ' ' | '_' => '-',
+                c => c.to_ascii_uppercase(),
+            })
+            .collect();
+        if let Ok(name) = normalized.parse() {
+            return Ok(name);
+        }
+
+        LENIENT_NAMES
+            .iter()
+            .find(|(spelling, _)| *spelling == normalized)
+            .map(|(_, name)| *name)
+            .ok_or(IANARegistryError::OsNameParseError)
+    }
+}
+
 impl std::str::FromStr for OperatingSystemName {
     type Err = IANARegistryError;
 
@@ -1422,6 +1487,47 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_lenient() {
+        for (name_str, name_enum) in OS_NAMES.iter() {
+            assert_eq!(Ok(*name_enum), OperatingSystemName::parse_lenient(name_str));
+        }
+        for (name_str, name_enum) in [
+            ("Linux", OperatingSystemName::Linux),
+            ("linux", OperatingSystemName::Linux),
+            ("GNU/Linux", OperatingSystemName::Linux),
+            (" freebsd ", OperatingSystemName::FREEBSD),
+            ("MacOSX", OperatingSystemName::MacOS),
+            ("Mac OS X", OperatingSystemName::MacOS),
+            ("Windows 10", OperatingSystemName::Win32),
+            ("windows-nt_4", OperatingSystemName::WindowsNT4),
+        ] {
+            assert_eq!(Ok(name_enum), OperatingSystemName::parse_lenient(name_str));
+        }
+        assert_eq!(
+            Err(IANARegistryError::OsNameParseError),
+            OperatingSystemName::parse_lenient("xyz")
+        );
+    }
+
+    #[test]
+    fn test_deserialize_aliases() {
+        use serde::de::{value::StrDeserializer, IntoDeserializer};
+        for (name_str, name_enum) in [
+            ("LINUX", OperatingSystemName::Linux),
+            ("GNU/Linux", OperatingSystemName::Linux),
+            ("MacOSX", OperatingSystemName::MacOS),
+            ("Windows 10", OperatingSystemName::Win32),
+        ] {
+            let deserializer: StrDeserializer<serde::de::value::Error> =
+                name_str.into_deserializer();
+            assert_eq!(
+                OperatingSystemName::deserialize(deserializer),
+                Ok(name_enum)
+            );
+        }
+    }
+
     #[test]
     fn test_display() {
         for (name_str, name_enum) in OS_NAMES.iter() {
@@ -0,0 +1,28 @@
+use std::path::PathBuf;
+
+/// Generates the build time constants used by `BuildInfo`
+fn main() {
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    let target = std::env::var("TARGET").unwrap_or_default();
+    let profile = std::env::var("PROFILE").unwrap_or_default();
+
+    let out_file = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("build_info.rs");
+    std::fs::write(
+        out_file,
+        format!(
+            "pub(crate) const ENABLED_FEATURES: &[&str] = &{features:?};\n\
+             pub(crate) const TARGET: &str = {target:?};\n\
+             pub(crate) const PROFILE: &str = {profile:?};\n"
+        ),
+    )
+    .unwrap();
+
+    println!("cargo:rerun-if-changed=build.rs");
+}
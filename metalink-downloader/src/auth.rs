@@ -0,0 +1,235 @@
+//! Authentication of requests, by credentials given on the command line or
+//! per host from a `.netrc` file.
+
+use crate::Result;
+
+use anyhow::Context;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest_middleware::{Middleware, Next};
+use std::path::{Path, PathBuf};
+
+/// Credentials sent with a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Basic {
+        user: String,
+        password: Option<String>,
+    },
+    Bearer(String),
+}
+
+impl Credentials {
+    /// Parse `user:password` or `user` into basic auth credentials
+    pub(crate) fn parse_basic(s: &str) -> std::result::Result<Self, String> {
+        let (user, password) = match s.split_once(':') {
+            Some((user, password)) => (user, Some(password.to_owned())),
+            None => (s, None),
+        };
+        if user.is_empty() {
+            return Err("the user must not be empty".to_owned());
+        }
+        Ok(Self::Basic {
+            user: user.to_owned(),
+            password,
+        })
+    }
+
+    fn header_value(&self) -> Result<HeaderValue> {
+        let mut value = match self {
+            Self::Basic { user, password } => {
+                let credentials = format!("{user}:{}", password.as_deref().unwrap_or_default());
+                HeaderValue::from_str(&format!("Basic {}", BASE64_STANDARD.encode(credentials)))?
+            }
+            Self::Bearer(token) => HeaderValue::from_str(&format!("Bearer {token}"))?,
+        };
+        value.set_sensitive(true);
+        Ok(value)
+    }
+}
+
+/// Where the credentials of requests come from
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuthOptions {
+    /// Sent with the requests to all hosts
+    pub credentials: Option<Credentials>,
+    /// `.netrc` file with credentials per host
+    pub netrc: Option<PathBuf>,
+}
+
+impl AuthOptions {
+    /// Middleware adding the credentials to requests, None if there are no
+    /// credentials
+    pub(crate) fn middleware(&self) -> Result<Option<AuthMiddleware>> {
+        let default = self
+            .credentials
+            .as_ref()
+            .map(Credentials::header_value)
+            .transpose()?;
+        let hosts = match &self.netrc {
+            Some(path) => read_netrc(path)?
+                .into_iter()
+                .map(|(host, credentials)| Ok((host, credentials.header_value()?)))
+                .collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+        if default.is_none() && hosts.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(AuthMiddleware { default, hosts }))
+    }
+}
+
+/// Adds the authorization header to requests, credentials given on the
+/// command line take precedence over the ones of the `.netrc` file
+pub(crate) struct AuthMiddleware {
+    default: Option<HeaderValue>,
+    /// Per host, `None` is the `default` entry of the `.netrc` file
+    hosts: Vec<(Option<String>, HeaderValue)>,
+}
+
+impl AuthMiddleware {
+    fn header_for(&self, host: Option<&str>) -> Option<&HeaderValue> {
+        self.default.as_ref().or_else(|| {
+            let matching = |entry: &&(Option<String>, HeaderValue)| {
+                matches!((&entry.0, host), (Some(machine), Some(host)) if machine.eq_ignore_ascii_case(host))
+            };
+            self.hosts
+                .iter()
+                .find(matching)
+                .or_else(|| self.hosts.iter().find(|(machine, _)| machine.is_none()))
+                .map(|(_, value)| value)
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for AuthMiddleware {
+    async fn handle(
+        &self,
+        mut req: reqwest::Request,
+        extensions: &mut http::Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest::Response> {
+        if !req.headers().contains_key(AUTHORIZATION) {
+            if let Some(value) = self.header_for(req.url().host_str()) {
+                req.headers_mut().insert(AUTHORIZATION, value.clone());
+            }
+        }
+        next.run(req, extensions).await
+    }
+}
+
+/// The `.netrc` file in the home directory
+pub(crate) fn default_netrc() -> Option<PathBuf> {
+    std::env::var_os("NETRC")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".netrc")))
+}
+
+fn read_netrc(path: &Path) -> Result<Vec<(Option<String>, Credentials)>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read netrc file {path:?}"))?;
+    Ok(parse_netrc(&content))
+}
+
+/// Parse the `machine` and `default` entries of a `.netrc` file, `None` is
+/// the host of the `default` entry. Macro definitions are not supported and
+/// end the parsing.
+fn parse_netrc(content: &str) -> Vec<(Option<String>, Credentials)> {
+    let mut entries = Vec::new();
+    let mut entry: Option<(Option<String>, Option<String>, Option<String>)> = None;
+    let mut finish = |entry: Option<(Option<String>, Option<String>, Option<String>)>| {
+        if let Some((host, Some(user), password)) = entry {
+            entries.push((host, Credentials::Basic { user, password }));
+        }
+    };
+    let mut tokens = content.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => {
+                finish(entry.take());
+                entry = tokens
+                    .next()
+                    .map(|host| (Some(host.to_owned()), None, None));
+            }
+            "default" => {
+                finish(entry.take());
+                entry = Some((None, None, None));
+            }
+            "login" => {
+                if let (Some(entry), Some(user)) = (entry.as_mut(), tokens.next()) {
+                    entry.1 = Some(user.to_owned());
+                }
+            }
+            "password" => {
+                if let (Some(entry), Some(password)) = (entry.as_mut(), tokens.next()) {
+                    entry.2 = Some(password.to_owned());
+                }
+            }
+            "account" => {
+                tokens.next();
+            }
+            "macdef" => {
+                tracing::warn!("Macro definitions in netrc files are not supported, ignoring the rest of the file");
+                break;
+            }
+            _ => {}
+        }
+    }
+    finish(entry);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn netrc_credentials_are_looked_up_by_host() {
+        let entries = parse_netrc(
+            "machine artifacts.example.com login ci password s3cret\n\
+             machine other.example.com login bob\n\
+             default login anonymous password guest\n",
+        );
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[1],
+            (
+                Some("other.example.com".to_owned()),
+                Credentials::Basic {
+                    user: "bob".to_owned(),
+                    password: None
+                }
+            )
+        );
+
+        let middleware = AuthMiddleware {
+            default: None,
+            hosts: entries
+                .into_iter()
+                .map(|(host, credentials)| (host, credentials.header_value().unwrap()))
+                .collect(),
+        };
+        let header = |host| middleware.header_for(host).unwrap().to_str().unwrap();
+        assert_eq!(header(Some("ARTIFACTS.example.com")), "Basic Y2k6czNjcmV0");
+        assert_eq!(
+            header(Some("mirror.example.org")),
+            "Basic YW5vbnltb3VzOmd1ZXN0"
+        );
+
+        let bearer = AuthMiddleware {
+            default: Some(
+                Credentials::Bearer("token".to_owned())
+                    .header_value()
+                    .unwrap(),
+            ),
+            hosts: Vec::new(),
+        };
+        assert_eq!(
+            bearer.header_for(Some("any.example.com")).unwrap(),
+            "Bearer token"
+        );
+        assert!(Credentials::parse_basic(":pass").is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Number of downloaded chunks buffered for the file writer
+pub(crate) const WRITE_QUEUE_CAPACITY: usize = 16;
+
+static STALLS: AtomicU64 = AtomicU64::new(0);
+static STALL_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Record that a download task waited `duration` for room in the full writer queue
+pub(crate) fn record_stall(duration: Duration) {
+    STALLS.fetch_add(1, Ordering::Relaxed);
+    STALL_NANOS.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Time download tasks spent waiting on a full writer queue
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct WriterStalls {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl WriterStalls {
+    /// Stalls recorded since the start of the process
+    pub(crate) fn snapshot() -> Self {
+        Self {
+            count: STALLS.load(Ordering::Relaxed),
+            time: Duration::from_nanos(STALL_NANOS.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Stalls recorded between `earlier` and this snapshot
+    pub(crate) fn since(&self, earlier: &WriterStalls) -> Self {
+        Self {
+            count: self.count - earlier.count,
+            time: self.time.saturating_sub(earlier.time),
+        }
+    }
+
+    /// Whether `tasks` download tasks running for `elapsed` spent more than
+    /// half of their time waiting on the writer, i.e. the disk is slower than
+    /// the network
+    pub(crate) fn is_disk_bound(&self, tasks: usize, elapsed: Duration) -> bool {
+        !elapsed.is_zero() && self.time * 2 > elapsed * tasks as u32
+    }
+}
+
+impl std::fmt::Display for WriterStalls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "writer queue was full {} times, download tasks waited {:.1}s for the disk",
+            self.count,
+            self.time.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_bound_when_tasks_mostly_wait_on_writer() {
+        let stalls = WriterStalls {
+            count: 4,
+            time: Duration::from_secs(5),
+        };
+        assert!(stalls.is_disk_bound(2, Duration::from_secs(4)));
+        assert!(!stalls.is_disk_bound(4, Duration::from_secs(4)));
+        assert!(!WriterStalls::default().is_disk_bound(1, Duration::ZERO));
+    }
+}
@@ -0,0 +1,80 @@
+use iana_registry_enums::HashFunctionTextualName;
+
+use crate::types::SUPPORTED_HASH_TYPES;
+
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+}
+
+/// Information about how this build of the downloader was configured
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildInfo {
+    /// The crate version
+    pub version: &'static str,
+    /// Cargo features enabled at build time
+    pub features: &'static [&'static str],
+    /// Hash algorithms the downloader can verify
+    pub hash_algorithms: &'static [HashFunctionTextualName],
+    /// The TLS implementation used by the HTTP client
+    pub tls_backend: &'static str,
+    /// The SHA-2 implementation selected for the CPU at runtime
+    pub sha2_backend: &'static str,
+    /// The target triple the binary was built for
+    pub target: &'static str,
+    /// The cargo profile used for the build
+    pub profile: &'static str,
+}
+
+impl BuildInfo {
+    /// Returns the build information of the running binary
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            features: generated::ENABLED_FEATURES,
+            hash_algorithms: SUPPORTED_HASH_TYPES,
+            tls_backend: "native-tls",
+            sha2_backend: sha2_backend(),
+            target: generated::TARGET,
+            profile: generated::PROFILE,
+        }
+    }
+}
+
+/// The SHA-2 implementation the `sha2` crate picks on this CPU, it detects the
+/// SHA extensions at runtime and falls back to a portable implementation
+fn sha2_backend() -> &'static str {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if std::arch::is_x86_feature_detected!("sha")
+        && std::arch::is_x86_feature_detected!("sse2")
+        && std::arch::is_x86_feature_detected!("ssse3")
+        && std::arch::is_x86_feature_detected!("sse4.1")
+    {
+        return "sha-ni";
+    }
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("sha2") {
+        return "armv8 sha2";
+    }
+    "portable"
+}
+
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let features = if self.features.is_empty() {
+            String::from("none")
+        } else {
+            self.features.join(", ")
+        };
+        let hash_algorithms: Vec<String> = self
+            .hash_algorithms
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        writeln!(f, "features: {features}")?;
+        writeln!(f, "hash algorithms: {}", hash_algorithms.join(", "))?;
+        writeln!(f, "tls backend: {}", self.tls_backend)?;
+        writeln!(f, "sha-2 backend: {}", self.sha2_backend)?;
+        writeln!(f, "target: {}", self.target)?;
+        write!(f, "profile: {}", self.profile)
+    }
+}
@@ -1,13 +1,328 @@
-use clap::{Parser, Subcommand};
+use crate::auth::Credentials;
+use crate::commands::{HistoryFormat, LintDeny, PlanFormat};
+use crate::config::Config;
+use crate::cookies::parse_cookie;
+use crate::dns::{parse_resolve, ResolveOverride};
+use crate::http::{ChunkSize, HttpVersion, DEFAULT_MAX_REDIRECTS, DEFAULT_MAX_REQUEST_SIZE};
+use crate::lock::LockMode;
+use crate::selection::{Dedupe, FileFilter, MirrorRewrite, MirrorSelection, OnConflict};
+use crate::telemetry::TraceFormat;
+use crate::writeback::FsyncPolicy;
+use clap::{Args, Parser, Subcommand};
+use iana_registry_enums::{HashFunctionTextualName, OperatingSystemName};
+use reqwest::header::{HeaderName, HeaderValue};
+#[cfg(feature = "observability")]
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, disable_version_flag = true)]
 pub struct Cli {
+    /// Print version
+    #[arg(short = 'V', long)]
+    pub version: bool,
+
+    /// Together with `--version` print enabled features, supported hash
+    /// algorithms and the TLS backend
+    #[arg(long, requires = "version")]
+    pub build_info: bool,
+
+    /// Read defaults from this config file instead of
+    /// `~/.config/metalink-downloader/config.toml`
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Read existing files in blocks of this size when hashing them, e.g.
+    /// `4MiB`
+    #[arg(long, global = true, default_value = "1MiB", value_parser = parse_size)]
+    pub hash_buffer: u64,
+
+    /// Hash up to this many files or downloaded pieces at the same time, off
+    /// the threads doing the network transfers [default: one per core]
+    #[arg(long, global = true, value_name = "N")]
+    pub hash_threads: Option<NonZeroUsize>,
+
+    /// Collect adjacent downloaded chunks of a plain file in a buffer of this
+    /// size before writing them, 0 writes every chunk at once
+    #[arg(long, global = true, value_name = "SIZE", default_value = "4MiB", value_parser = parse_size)]
+    pub write_buffer: u64,
+
+    /// Write buffered chunks after this long at the latest, e.g. `500ms`
+    #[arg(long, global = true, value_name = "DURATION", default_value = "1s", value_parser = parse_duration)]
+    pub flush_interval: Duration,
+
+    /// When downloaded files are synced to disk, trading durability after a
+    /// crash or power loss for throughput
+    #[arg(long, global = true, value_enum, default_value = "per-file")]
+    pub fsync: FsyncPolicy,
+
+    /// Write the chunks of plain files with O_DIRECT, bypassing the page
+    /// cache, on Linux. Meant for fast disks and networks where copying the
+    /// data through the cache is the bottleneck. The writes are still
+    /// synchronous pwrite calls, io_uring is not used.
+    #[cfg(feature = "direct-io")]
+    #[arg(long, global = true)]
+    pub direct_io: bool,
+
+    /// Format of the lines written to `log/output.log`
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    pub trace_format: TraceFormat,
+
+    /// Export the tracing spans to this OTLP/HTTP collector, e.g.
+    /// `http://localhost:4318/v1/traces`
+    #[cfg(feature = "otlp")]
+    #[arg(long, global = true, value_name = "URL")]
+    pub otlp_endpoint: Option<url::Url>,
+
     #[command(subcommand)]
-    pub command: Commands,
+    pub command: Option<Commands>,
+}
+
+/// Selects the files of a metalink to work on
+#[derive(Debug, Args)]
+pub struct FilterArgs {
+    /// Only include files whose name matches this glob, can be given multiple times
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Skip files whose name matches this glob, can be given multiple times
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Only include the file with this name, can be given multiple times
+    #[arg(long = "file", value_name = "NAME")]
+    pub files: Vec<String>,
+
+    /// Only include files for this operating system, can be given multiple
+    /// times. Files without an os are always included.
+    #[arg(long, value_parser = parse_os)]
+    pub os: Vec<OperatingSystemName>,
+
+    /// Only include files in this language, can be given multiple times.
+    /// Files without a language are always included.
+    #[arg(long)]
+    pub language: Vec<String>,
+}
+
+impl FilterArgs {
+    pub(crate) fn into_filter(self) -> crate::Result<FileFilter> {
+        Ok(FileFilter::new(&self.include, &self.exclude, &self.files)?
+            .with_oses(self.os)
+            .with_languages(self.language))
+    }
+}
+
+/// Selects the mirrors files are downloaded from
+#[derive(Debug, Args)]
+pub struct MirrorArgs {
+    /// Prefer mirrors in this location, an ISO3166-1 alpha-2 country code.
+    /// Can be given multiple times, earlier locations are preferred.
+    #[arg(long, value_name = "COUNTRY")]
+    pub prefer_location: Vec<String>,
+
+    /// Skip mirrors whose url or host matches this glob, can be given
+    /// multiple times
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude_mirror: Vec<String>,
+
+    /// Only use mirrors whose url or host matches this glob, can be given
+    /// multiple times
+    #[arg(long, value_name = "PATTERN")]
+    pub only_mirror: Vec<String>,
+
+    /// Replace the url prefix of mirrors, e.g.
+    /// 'https://old.example=>https://mirror.local'. Applied before
+    /// --exclude-mirror and --only-mirror, can be given multiple times
+    #[arg(long, value_name = "FROM=>TO", value_parser = MirrorRewrite::parse)]
+    pub rewrite_mirror: Vec<MirrorRewrite>,
+}
+
+impl MirrorArgs {
+    pub(crate) fn into_selection(
+        self,
+        config: &Config,
+        allow_http: bool,
+    ) -> crate::Result<MirrorSelection> {
+        MirrorSelection::new(config.preferred_locations(self.prefer_location))
+            .with_http(allow_http)
+            .with_rewrites(self.rewrite_mirror)
+            .with_filters(&self.exclude_mirror, &self.only_mirror)
+    }
+}
+
+/// Settings of the http client, defaults are taken from the config file
+#[derive(Debug, Default, Args)]
+pub struct HttpArgs {
+    /// overwrite user agent
+    #[arg(long)]
+    pub user_agent: Option<String>,
+
+    /// Send all requests through this proxy
+    #[arg(long)]
+    pub proxy: Option<url::Url>,
+
+    /// Limit the download rate to this many bytes per second, e.g. `512KiB`
+    #[arg(long, value_parser = parse_size)]
+    pub limit_rate: Option<u64>,
+
+    /// Trust the PEM encoded CA certificates in this file in addition to the
+    /// system ones, can be given multiple times
+    #[arg(long, value_name = "PATH")]
+    pub ca_cert: Vec<PathBuf>,
+
+    /// Authenticate with the PEM encoded client certificate in this file
+    #[arg(long, value_name = "PATH", requires = "client_key")]
+    pub client_cert: Option<PathBuf>,
+
+    /// PEM encoded PKCS#8 private key of `--client-cert`
+    #[arg(long, value_name = "PATH", requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+
+    /// Give up connecting to a mirror after this long, e.g. `10s` [default: 20s]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub connect_timeout: Option<Duration>,
+
+    /// Abort a request after receiving no data for this long, e.g. `1m`
+    /// [default: 30s]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub read_timeout: Option<Duration>,
+
+    /// Abort fetching a chunk which takes longer than this, slow transfers
+    /// are not aborted by default
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub chunk_timeout: Option<Duration>,
+
+    /// Follow at most this many redirects per request
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_REDIRECTS)]
+    pub max_redirects: usize,
+
+    /// Do not follow redirects of a mirror to another host
+    #[arg(long)]
+    pub no_cross_host_redirects: bool,
+
+    /// Start at most this many requests per second to the same host
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_requests_per_second_per_host: Option<u32>,
+
+    /// Wait at least this long between the starts of two requests to the
+    /// same host, e.g. `500ms`
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub delay_between_requests: Option<Duration>,
+
+    /// Close connections which were idle for this long, e.g. `30s`
+    /// [default: 90s]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub pool_idle_timeout: Option<Duration>,
+
+    /// Keep at most this many idle connections per host for reuse
+    #[arg(long, value_name = "N")]
+    pub pool_max_idle_per_host: Option<usize>,
+
+    /// Send TCP keep-alive probes at this interval, e.g. `60s`, so idle
+    /// connections survive middleboxes
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub tcp_keepalive: Option<Duration>,
+
+    /// Connect to ADDR when requesting HOST instead of looking it up, e.g.
+    /// `mirror.example:443:192.0.2.7` to test a staging mirror, can be given
+    /// multiple times. The override applies to HOST on all ports.
+    #[arg(long, value_name = "HOST:PORT:ADDR", value_parser = parse_resolve)]
+    pub resolve: Vec<ResolveOverride>,
+
+    /// Fetch adjacent pieces missing on disk with one request of at most
+    /// this size, e.g. `64MiB`, each piece is still verified on its own.
+    /// 0 requests every piece on its own.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value_t = DEFAULT_MAX_REQUEST_SIZE)]
+    pub max_request_size: u64,
+
+    /// HTTP version to speak with the mirrors
+    #[arg(long, value_enum, default_value = "auto")]
+    pub http_version: HttpVersion,
+
+    /// Also download from mirrors serving files over plain http, the data is
+    /// only protected by the checksums of the metalink
+    #[arg(long)]
+    pub allow_http: bool,
+
+    /// Accept invalid TLS certificates, dangerous as it allows anyone in
+    /// between to tamper with the download
+    #[arg(long)]
+    pub insecure: bool,
+
+    /// Basic auth credentials sent to all mirrors
+    #[arg(long, value_name = "USER[:PASSWORD]", value_parser = Credentials::parse_basic)]
+    pub user: Option<Credentials>,
+
+    /// Bearer token sent to all mirrors
+    #[arg(long, value_name = "TOKEN", conflicts_with = "user")]
+    pub bearer_token: Option<String>,
+
+    /// Look up credentials per host in `~/.netrc`, or the file in `$NETRC`
+    #[arg(long)]
+    pub netrc: bool,
+
+    /// Look up credentials per host in this netrc file
+    #[arg(long, value_name = "PATH", conflicts_with = "netrc")]
+    pub netrc_file: Option<PathBuf>,
+
+    /// Send this header with all requests, can be given multiple times
+    #[arg(long, value_name = "NAME: VALUE", value_parser = parse_header)]
+    pub header: Vec<(HeaderName, HeaderValue)>,
+
+    /// Send this cookie to all hosts, can be given multiple times
+    #[arg(long, value_name = "NAME=VALUE", value_parser = parse_cookie)]
+    pub cookie: Vec<String>,
+
+    /// Load cookies from this file in the Netscape format, e.g. exported
+    /// from a browser or written by curl
+    #[arg(long, value_name = "PATH")]
+    pub cookie_jar: Option<PathBuf>,
+}
+
+/// Exports Prometheus metrics of the download
+#[cfg(feature = "observability")]
+#[derive(Debug, Default, Args)]
+pub struct MetricsArgs {
+    /// Serve metrics in the Prometheus text format on this address, e.g.
+    /// `127.0.0.1:9890`
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Write metrics in the Prometheus text format to this file every 15
+    /// seconds and once the download finished, e.g. for the textfile
+    /// collector of the node exporter
+    #[arg(long, value_name = "PATH")]
+    pub metrics_textfile: Option<PathBuf>,
+}
+
+fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME: VALUE, got {s:?}"))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|e| format!("invalid header name {name:?}: {e}"))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|e| format!("invalid header value {value:?}: {e}"))?;
+    Ok((name, value))
+}
+
+/// `Some` if one of the flags enabling or disabling a setting was given
+pub(crate) fn flag(enable: bool, disable: bool) -> Option<bool> {
+    match (enable, disable) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_os(s: &str) -> Result<OperatingSystemName, String> {
+    OperatingSystemName::parse_lenient(s).map_err(|e| format!("{e}: {s}"))
 }
 
+// Parsed once at startup, the size of the variants does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// Download file
@@ -20,13 +335,52 @@ pub enum Commands {
         #[arg(short, long)]
         target_dir: PathBuf,
 
-        /// overwrite user agent
-        #[arg(long, default_value=concat!("metalink-downloader/", env!("CARGO_PKG_VERSION")))]
-        user_agent: String,
+        /// Name of the downloaded file inside `target_dir`. Defaults to the
+        /// name the server suggests in its Content-Disposition header, then to
+        /// the last segment of the url path.
+        #[arg(short, long, value_name = "NAME")]
+        output: Option<PathBuf>,
+
+        #[command(flatten)]
+        http: HttpArgs,
+
+        /// Max number of chunks downloaded at the same time, 0 picks one per
+        /// core limited by the number of chunks [default: 0]
+        #[arg(long)]
+        max_threads: Option<u16>,
+
+        /// Ask the server for a metalink describing `url` and download with it
+        /// if the server offers one
+        #[arg(long)]
+        negotiate_metalink: bool,
+
+        /// Size of the segments the file is downloaded in, e.g. `8MiB`. `auto`
+        /// measures the throughput of the first segment and picks a size
+        /// which takes about two seconds to fetch.
+        #[arg(long, default_value = "1MiB", value_parser = parse_chunk_size)]
+        chunk_size: ChunkSize,
+
+        /// Download files up to this size in a single request
+        #[arg(long, default_value = "1MiB", value_parser = parse_size)]
+        min_split_size: u64,
+
+        /// Halve the number of concurrent downloads while the disk is slower
+        /// than the network
+        #[arg(long)]
+        reduce_on_slow_disk: bool,
 
-        /// Max number of download threads to use
-        #[arg(long, default_value_t=2, value_parser = clap::value_parser!(u16).range(2..))]
-        max_threads: u16,
+        /// Only print errors, no progress and no summary
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Write directly into the target file instead of `<name>.part`
+        /// which is renamed to the target once the download is complete
+        #[arg(long)]
+        no_atomic: bool,
+
+        /// Record scheduler decisions and transport outcomes to this replay log
+        #[arg(long)]
+        replay_log: Option<PathBuf>,
     },
 
     /// Dryrun the planning phase
@@ -38,23 +392,597 @@ pub enum Commands {
         /// The target or download directory
         #[arg(short, long)]
         target_dir: PathBuf,
+
+        /// Only check whether the target directory satisfies the metalink,
+        /// print the files that would be downloaded as JSON and fail if there
+        /// are any
+        #[arg(long)]
+        check: bool,
+
+        /// Only list the files of the metalink with their os and language and
+        /// whether they are selected
+        #[arg(long, conflicts_with = "check")]
+        list: bool,
+
+        /// Print the minimized plan in this format, `json` follows a stable
+        /// schema meant for scripting
+        #[arg(long, value_enum, conflicts_with_all = ["check", "list"])]
+        format: Option<PlanFormat>,
+
+        #[command(flatten)]
+        filter: FilterArgs,
     },
 
     /// Download Metalink
     DownloadMetalink {
-        /// the metalink to plan the download for
-        #[arg(short, long)]
-        metalink_file: PathBuf,
+        /// the metalink to plan the download for, can be given multiple times
+        #[arg(short, long, required_unless_present_any = ["metalink_url", "metalink_dir", "watch"])]
+        metalink_file: Vec<PathBuf>,
+
+        /// fetch the metalink to plan the download for from this url
+        #[arg(long, conflicts_with_all = ["metalink_file", "metalink_dir"])]
+        metalink_url: Option<url::Url>,
+
+        /// Download the files of all `*.meta4` and `*.metalink` files in this
+        /// directory, can be given multiple times. Files described by several
+        /// metalinks are downloaded once.
+        #[arg(long, value_name = "DIR")]
+        metalink_dir: Vec<PathBuf>,
+
+        /// Download the `*.meta4` and `*.metalink` files dropped into this
+        /// directory until interrupted. Processed metalinks are moved into
+        /// its `done` or `failed` subdirectory.
+        #[arg(
+            long,
+            value_name = "DIR",
+            conflicts_with_all = ["metalink_file", "metalink_url", "metalink_dir", "dry_run"]
+        )]
+        watch: Option<PathBuf>,
+
+        /// Fetch the metalink url again after this long, e.g. `6h`, and
+        /// download the files which changed, until interrupted
+        #[arg(
+            long,
+            value_name = "DURATION",
+            value_parser = parse_duration,
+            requires = "metalink_url",
+            conflicts_with_all = ["metalink_file", "metalink_dir", "watch", "dry_run"]
+        )]
+        sync_interval: Option<Duration>,
+
+        #[command(flatten)]
+        filter: FilterArgs,
 
         /// The target or download directory
         #[arg(short, long)]
         target_dir: PathBuf,
 
-        /// overwrite user agent
-        #[arg(long, default_value=concat!("metalink-downloader/", env!("CARGO_PKG_VERSION")))]
-        user_agent: String,
+        #[command(flatten)]
+        http: HttpArgs,
 
         #[arg(short, long)]
         verify_chunk_checksums: bool,
+
+        /// Do not verify chunks while downloading, even if the config file
+        /// enables it
+        #[arg(long, conflicts_with = "verify_chunk_checksums")]
+        no_verify_chunk_checksums: bool,
+
+        /// Verify downloaded files against their file checksum, even if the
+        /// config file disables it
+        #[arg(long, conflicts_with = "no_verify")]
+        verify: bool,
+
+        /// Skip the verification of downloaded files against their file checksum
+        #[arg(long)]
+        no_verify: bool,
+
+        #[command(flatten)]
+        mirrors: MirrorArgs,
+
+        /// Move files failing verification into this directory, next to a
+        /// `.json` sidecar with the expected and actual checksums, instead of
+        /// leaving them to be overwritten by the next download
+        #[arg(long, conflicts_with = "no_verify")]
+        quarantine_dir: Option<PathBuf>,
+
+        /// Fully re-hash files matching this glob even if all their pieces are
+        /// valid, can be given multiple times
+        #[arg(long, value_name = "GLOB")]
+        refresh: Vec<String>,
+
+        /// Re-download files matching this glob regardless of their state on
+        /// disk, can be given multiple times
+        #[arg(long, value_name = "GLOB")]
+        force: Vec<String>,
+
+        /// Hash all existing files, even the ones which did not change since
+        /// they were last verified
+        #[arg(long)]
+        revalidate: bool,
+
+        /// Whether to wait for or fail on other runs holding the lock on the
+        /// target directory
+        #[arg(long, value_enum, default_value = "fail", conflicts_with = "no_lock")]
+        lock: LockMode,
+
+        /// Do not lock the target directory against concurrent runs
+        #[arg(long)]
+        no_lock: bool,
+
+        /// Only print the minimized plan without downloading anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Output format of `--dry-run`
+        #[arg(long, value_enum, default_value = "table", requires = "dry_run")]
+        format: PlanFormat,
+
+        /// Continue downloading the remaining files after a file failed
+        /// instead of cancelling them
+        #[arg(long)]
+        keep_going: bool,
+
+        /// Only print errors, no progress and no summary
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// What to do with existing files which have no checksums to validate
+        /// them
+        #[arg(long, value_enum, default_value = "overwrite")]
+        on_conflict: OnConflict,
+
+        /// Download all files directly into the target directory instead of
+        /// reproducing the directories of their names
+        #[arg(long, conflicts_with = "strip_components")]
+        flatten: bool,
+
+        /// Remove this many leading directories from the file names
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        strip_components: usize,
+
+        /// How files with the same checksum as another file of the metalink
+        /// are created, they are downloaded once unless this is off
+        #[arg(long, value_enum, default_value = "copy")]
+        dedupe: Dedupe,
+
+        /// Write directly into the target files instead of `<name>.part` files
+        /// which are renamed to the target after verification
+        #[arg(long)]
+        no_atomic: bool,
+
+        /// Copy files from this directory, e.g. a local mirror or a previous
+        /// download, instead of downloading them. Only valid pieces are
+        /// copied, can be given multiple times.
+        #[arg(long, value_name = "DIR")]
+        local_source: Vec<PathBuf>,
+
+        /// Hard link whole files found in a local source or `file://` mirror
+        /// instead of copying them. The files share their content with the
+        /// source afterwards.
+        #[arg(long)]
+        hardlink_local: bool,
+
+        /// Set the modification time of downloaded files to the updated or
+        /// published time of the metalink, or the Last-Modified time sent by
+        /// the mirror
+        #[arg(long)]
+        preserve_timestamps: bool,
+
+        /// After a successful run write a checksum file like `SHA256SUMS`
+        /// covering the files into the target directory, signed into
+        /// `SHA256SUMS.asc` if the config file sets a `signing-key`
+        #[arg(long, value_name = "HASH", conflicts_with = "dry_run")]
+        write_checksums: Option<HashFunctionTextualName>,
+
+        /// Write warnings as JSON events to this file
+        #[arg(long)]
+        warnings_log: Option<PathBuf>,
+
+        /// Record scheduler decisions and transport outcomes to this replay log
+        #[arg(long)]
+        replay_log: Option<PathBuf>,
+
+        #[cfg(feature = "observability")]
+        #[command(flatten)]
+        metrics: MetricsArgs,
     },
+
+    /// Re-download only the corrupt pieces of files already on disk
+    Repair {
+        /// the metalink describing the files to repair
+        #[arg(short, long)]
+        metalink_file: PathBuf,
+
+        /// The directory containing the downloaded files
+        #[arg(short, long)]
+        target_dir: PathBuf,
+
+        #[command(flatten)]
+        http: HttpArgs,
+
+        /// Only report corrupt ranges without downloading them
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Whether to wait for or fail on other runs holding the lock on the
+        /// target directory
+        #[arg(long, value_enum, default_value = "fail", conflicts_with = "no_lock")]
+        lock: LockMode,
+
+        /// Do not lock the target directory against concurrent runs
+        #[arg(long)]
+        no_lock: bool,
+
+        /// Continue with the remaining files after a repair failed
+        #[arg(long)]
+        keep_going: bool,
+
+        #[command(flatten)]
+        mirrors: MirrorArgs,
+    },
+
+    /// Generate a metalink for all files of a local directory or the files
+    /// listed in a checksum file
+    Generate {
+        /// The directory containing the files to publish, with `--from-sums`
+        /// the sizes are read from the files in it [default with
+        /// `--from-sums`: the directory of the checksum file]
+        #[arg(short, long, required_unless_present = "from_sums")]
+        dir: Option<PathBuf>,
+
+        /// Take the files and their checksums from this checksum file like
+        /// `SHA256SUMS` instead of hashing them. Sizes of files missing in
+        /// the directory are requested from the base url.
+        #[arg(long, value_name = "FILE")]
+        from_sums: Option<PathBuf>,
+
+        /// The url the directory is published under
+        #[arg(short, long)]
+        base_url: url::Url,
+
+        /// List the files on the mirrors of this mirror list as well, one
+        /// base url per line, optionally followed by the country code and
+        /// priority of the mirror, e.g. `https://mirror.example/pub/ de 10`
+        #[arg(long, value_name = "FILE")]
+        mirrors: Option<PathBuf>,
+
+        /// Length of the pieces, e.g. `262144`, `256KiB` or `1MiB`
+        #[arg(long, default_value = "1MiB", value_parser = parse_size, conflicts_with = "from_sums")]
+        piece_length: u64,
+
+        /// Hash function used for file and piece hashes [default: sha-256,
+        /// with `--from-sums` the one the name of the checksum file suggests]
+        #[arg(long)]
+        hash: Option<HashFunctionTextualName>,
+
+        /// Write the metalink to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        #[command(flatten)]
+        http: HttpArgs,
+    },
+
+    /// Embed detached OpenPGP signatures of the published files into a metalink
+    Sign {
+        /// The metalink to sign
+        #[arg(short, long)]
+        metalink_file: PathBuf,
+
+        /// The directory containing the files listed in the metalink
+        #[arg(short, long)]
+        dir: PathBuf,
+
+        /// Key id or user id of the private key in the gpg keyring to sign with
+        #[arg(short, long)]
+        key: String,
+
+        /// The gpg executable used for signing
+        #[arg(long, default_value = "gpg")]
+        gpg: PathBuf,
+
+        /// Write the signed metalink to this file instead of updating it in place
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Check a metalink for spec violations and problems like weak hashes,
+    /// plain http mirrors, duplicate urls and urls which can not be fetched
+    Lint {
+        /// The metalink to check
+        metalink_file: PathBuf,
+
+        /// Also fail on warnings, e.g. `--deny warnings`
+        #[arg(long, value_enum)]
+        deny: Option<LintDeny>,
+    },
+
+    /// Check the health of the mirrors of a metalink by requesting every url
+    Probe {
+        /// The metalink whose urls are probed
+        metalink_file: PathBuf,
+
+        /// Send at most this many requests at the same time
+        #[arg(long, value_name = "N", default_value_t = 16)]
+        max_requests: usize,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[command(flatten)]
+        http: HttpArgs,
+    },
+
+    /// Report which files changed between two metalinks and which files and
+    /// bytes syncing a tree to the new one would transfer
+    PlanDiff {
+        /// The metalink the tree was synced to before
+        #[arg(long)]
+        old: PathBuf,
+
+        /// The metalink the tree would be synced to
+        #[arg(long)]
+        new: PathBuf,
+
+        /// The synced tree
+        #[arg(short, long)]
+        target_dir: PathBuf,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+
+    /// Verify a downloaded tree against two metalinks and report where they
+    /// disagree, without modifying the tree
+    CrossCheck {
+        /// The metalink the tree was downloaded with
+        #[arg(short, long)]
+        metalink_file: PathBuf,
+
+        /// The metalink to cross-check against, e.g. an internally re-generated one
+        #[arg(short, long)]
+        against: PathBuf,
+
+        /// The downloaded tree
+        #[arg(short, long)]
+        target_dir: PathBuf,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+
+    /// Verify a downloaded tree against a checksum file like `SHA256SUMS`
+    Verify {
+        /// The checksum file listing the files and their checksums
+        #[arg(long, value_name = "FILE")]
+        sums: PathBuf,
+
+        /// The directory the paths in the checksum file are relative to
+        /// [default: the directory of the checksum file]
+        #[arg(short, long)]
+        target_dir: Option<PathBuf>,
+
+        /// Hash function of the checksums [default: from the name of the
+        /// checksum file or the length of the checksums]
+        #[arg(long)]
+        hash: Option<HashFunctionTextualName>,
+
+        /// Also compare the checksums to the ones in this metalink
+        #[arg(short, long)]
+        metalink_file: Option<PathBuf>,
+    },
+
+    /// Query the downloads recorded in the history of a target directory
+    History {
+        /// The target directory whose history is printed
+        #[arg(short, long)]
+        target_dir: PathBuf,
+
+        /// Only show files whose path matches this glob, can be given
+        /// multiple times
+        #[arg(long = "file", value_name = "GLOB")]
+        files: Vec<String>,
+
+        /// Only show files downloaded from mirrors whose url or host matches
+        /// this glob, can be given multiple times
+        #[arg(long = "mirror", value_name = "PATTERN")]
+        mirrors: Vec<String>,
+
+        /// Only show downloads completed on or after this day, e.g.
+        /// `2024-05-01`
+        #[arg(long, value_name = "DATE")]
+        since: Option<chrono::NaiveDate>,
+
+        /// Summarize the files, bytes and throughput of each mirror instead
+        /// of listing the downloads
+        #[arg(long)]
+        by_mirror: bool,
+
+        #[arg(long, value_enum, default_value = "table")]
+        format: HistoryFormat,
+    },
+
+    /// Run download jobs queued by `job add` until interrupted
+    Daemon {
+        /// Socket to accept jobs on, e.g. `unix:/run/mld.sock`
+        #[arg(long, value_name = "unix:PATH", value_parser = parse_socket)]
+        listen: PathBuf,
+
+        /// File the job queue is kept in [default:
+        /// `~/.local/state/metalink-downloader/jobs.json`]
+        #[arg(long, value_name = "PATH")]
+        state_file: Option<PathBuf>,
+
+        /// Run at most this many jobs at the same time
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        max_jobs: usize,
+
+        #[command(flatten)]
+        http: HttpArgs,
+    },
+
+    /// Control the jobs of a running daemon
+    Job {
+        /// Socket the daemon listens on, e.g. `unix:/run/mld.sock`
+        #[arg(long, value_name = "unix:PATH", value_parser = parse_socket)]
+        socket: PathBuf,
+
+        #[command(subcommand)]
+        verb: JobVerb,
+    },
+
+    /// Replay a recorded replay log through the download engine and check
+    /// its invariants
+    #[command(hide = true)]
+    Replay {
+        /// The replay log recorded with `--replay-log`
+        #[arg(short, long)]
+        log: PathBuf,
+    },
+}
+
+/// Requests to the daemon
+#[derive(Debug, Subcommand)]
+pub enum JobVerb {
+    /// Queue the download of a metalink
+    Add {
+        /// Path or http(s) url of the metalink
+        #[arg(short, long)]
+        metalink: String,
+
+        /// The target or download directory
+        #[arg(short, long)]
+        target_dir: PathBuf,
+    },
+    /// Print the state of all jobs, or of the files of a single job
+    Status { id: Option<u64> },
+    /// Stop the requests of a job until it is resumed, downloaded chunks are
+    /// kept
+    Pause { id: u64 },
+    /// Continue a paused job, or queue it again if it is no longer running
+    Resume { id: u64 },
+    /// Give up on a queued, paused or running job
+    Cancel { id: u64 },
+}
+
+/// Parse the address of the daemon socket, only unix sockets are supported
+fn parse_socket(s: &str) -> Result<PathBuf, String> {
+    match s.strip_prefix("unix:") {
+        Some(path) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => Err(format!("expected unix:PATH, got {s:?}")),
+    }
+}
+
+/// Parse a byte size with an optional binary unit suffix (`KiB`, `MiB`, `GiB`)
+pub(crate) fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("invalid size: {s}"))?;
+    let factor = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        unit => return Err(format!("unknown size unit: {unit}")),
+    };
+    number
+        .checked_mul(factor)
+        .ok_or_else(|| format!("size too large: {s}"))
+}
+
+/// Parse a segment size or `auto`
+pub(crate) fn parse_chunk_size(s: &str) -> Result<ChunkSize, String> {
+    if s.trim().eq_ignore_ascii_case("auto") {
+        return Ok(ChunkSize::Auto);
+    }
+    match parse_size(s)? {
+        0 => Err("the chunk size must not be zero".to_owned()),
+        size => Ok(ChunkSize::Fixed(size)),
+    }
+}
+
+/// Parse a duration in seconds with an optional unit suffix (`ms`, `s`, `m`,
+/// `h`)
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {s}"))?;
+    let duration = match unit.trim() {
+        "ms" => Duration::from_millis(number),
+        "" | "s" => Duration::from_secs(number),
+        "m" => Duration::from_secs(number.saturating_mul(60)),
+        "h" => Duration::from_secs(number.saturating_mul(60 * 60)),
+        unit => return Err(format!("unknown duration unit: {unit}")),
+    };
+    if duration.is_zero() {
+        return Err(format!("duration must not be zero: {s}"));
+    }
+    Ok(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_handles_units() {
+        assert_eq!(parse_size("262144"), Ok(262144));
+        assert_eq!(parse_size("256KiB"), Ok(256 * 1024));
+        assert_eq!(parse_size("1MiB"), Ok(1024 * 1024));
+        assert_eq!(parse_size("2 G"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("1MB").is_err());
+        assert!(parse_size("MiB").is_err());
+        assert_eq!(parse_chunk_size("Auto"), Ok(ChunkSize::Auto));
+        assert_eq!(parse_chunk_size("8MiB"), Ok(ChunkSize::Fixed(8 << 20)));
+        assert!(parse_chunk_size("0").is_err());
+    }
+
+    #[test]
+    fn parse_duration_handles_units() {
+        assert_eq!(parse_duration("20"), Ok(Duration::from_secs(20)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1 h"), Ok(Duration::from_secs(3600)));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("1d").is_err());
+    }
+
+    #[test]
+    fn arguments_are_parsed_without_argv() {
+        let cli = crate::App::from_args([
+            "metalink-downloader",
+            "download-file",
+            "--url",
+            "https://example.com/file.iso",
+            "--target-dir",
+            "downloads",
+        ])
+        .unwrap();
+        let Some(Commands::DownloadFile {
+            url, target_dir, ..
+        }) = cli.command
+        else {
+            panic!("expected download-file, got {:?}", cli.command);
+        };
+        assert_eq!(url.as_str(), "https://example.com/file.iso");
+        assert_eq!(target_dir, PathBuf::from("downloads"));
+        assert!(crate::App::from_args(["metalink-downloader", "download-file"]).is_err());
+
+        let sync = |source: &[&str]| {
+            let args = ["metalink-downloader", "download-metalink", "-t", "mirror"];
+            crate::App::from_args(
+                args.iter()
+                    .chain(source)
+                    .chain(&["--sync-interval", "6h"])
+                    .copied(),
+            )
+        };
+        assert!(sync(&["--metalink-url", "https://example.com/repo.meta4"]).is_ok());
+        assert!(sync(&["-m", "repo.meta4"]).is_err());
+    }
 }
@@ -0,0 +1,259 @@
+use crate::commands::plan::{drift, DriftReason};
+use crate::selection::{FileFilter, Layout, MirrorSelection};
+use crate::types::Plan;
+use crate::Result;
+
+use anyhow::anyhow;
+use metalink::Metalink;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use tracing::info;
+
+/// A difference between how two metalinks describe the same file
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub(crate) enum MetadataDiscrepancy {
+    /// The file is only listed in the metalink
+    OnlyInMetalink,
+    /// The file is only listed in the metalink it is checked against
+    OnlyInAgainst,
+    SizeMismatch {
+        metalink: Option<u64>,
+        against: Option<u64>,
+    },
+    /// Both metalinks list a file checksum of the same type but disagree
+    ChecksumMismatch {
+        hash_type: String,
+        metalink: String,
+        against: String,
+    },
+    /// Both metalinks list pieces of the same type and length but disagree
+    PiecesMismatch { hash_type: String, length: u64 },
+}
+
+/// Discrepancies found for a single file
+#[derive(Debug, Serialize)]
+struct FileReport {
+    name: String,
+    metadata: Vec<MetadataDiscrepancy>,
+    /// Why the file on disk does not satisfy the metalink, if it does not
+    metalink_drift: Option<DriftReason>,
+    /// Why the file on disk does not satisfy the other metalink, if it does not
+    against_drift: Option<DriftReason>,
+}
+
+/// Verify the tree in `target_dir` against both `metalink_file` and `against`
+/// without modifying it. Prints every file with discrepancies as JSON and
+/// fails if there are any.
+pub(crate) async fn cross_check(
+    metalink_file: PathBuf,
+    against: PathBuf,
+    target_dir: PathBuf,
+    filter: FileFilter,
+) -> Result<()> {
+    info!("File: {metalink_file:?}, Against: {against:?}, Target: {target_dir:?}");
+    let mut metadata = compare_metalinks(
+        &Metalink::load_from_file_lenient(metalink_file.clone())?,
+        &Metalink::load_from_file_lenient(against.clone())?,
+        &filter,
+    );
+    // Nothing is downloaded, plain http mirrors do not need to be skipped
+    let mirrors = MirrorSelection::default().with_http(true);
+    let mut metalink_drift: BTreeMap<String, DriftReason> = drift(Plan::new(
+        metalink_file,
+        &target_dir,
+        &filter,
+        &mirrors,
+        &Layout::default(),
+    )?)?
+    .into_iter()
+    .map(|drift| (drift.name, drift.reason))
+    .collect();
+    let mut against_drift: BTreeMap<String, DriftReason> = drift(Plan::new(
+        against,
+        &target_dir,
+        &filter,
+        &mirrors,
+        &Layout::default(),
+    )?)?
+    .into_iter()
+    .map(|drift| (drift.name, drift.reason))
+    .collect();
+
+    let names: BTreeSet<String> = metadata
+        .keys()
+        .chain(metalink_drift.keys())
+        .chain(against_drift.keys())
+        .cloned()
+        .collect();
+    let reports: Vec<FileReport> = names
+        .into_iter()
+        .map(|name| FileReport {
+            metadata: metadata.remove(&name).unwrap_or_default(),
+            metalink_drift: metalink_drift.remove(&name),
+            against_drift: against_drift.remove(&name),
+            name,
+        })
+        .collect();
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&reports).map_err(|e| anyhow!(e))?
+    );
+    if !reports.is_empty() {
+        return Err(anyhow!("{} files differ between the metalinks", reports.len()).into());
+    }
+    Ok(())
+}
+
+/// Compare the file descriptions of two metalinks by name
+pub(crate) fn compare_metalinks(
+    metalink: &Metalink,
+    against: &Metalink,
+    filter: &FileFilter,
+) -> BTreeMap<String, Vec<MetadataDiscrepancy>> {
+    let files = |metalink: &Metalink| -> BTreeMap<String, metalink::File> {
+        metalink
+            .files()
+            .iter()
+            .filter(|file| filter.matches_file(file))
+            .map(|file| (file.name().clone(), file.clone()))
+            .collect()
+    };
+    let metalink_files = files(metalink);
+    let against_files = files(against);
+
+    let mut discrepancies = BTreeMap::new();
+    for (name, file) in &metalink_files {
+        let found = match against_files.get(name) {
+            Some(other) => compare_files(file, other),
+            None => vec![MetadataDiscrepancy::OnlyInMetalink],
+        };
+        if !found.is_empty() {
+            discrepancies.insert(name.clone(), found);
+        }
+    }
+    for name in against_files.keys() {
+        if !metalink_files.contains_key(name) {
+            discrepancies.insert(name.clone(), vec![MetadataDiscrepancy::OnlyInAgainst]);
+        }
+    }
+    discrepancies
+}
+
+fn compare_files(file: &metalink::File, other: &metalink::File) -> Vec<MetadataDiscrepancy> {
+    let mut discrepancies = Vec::new();
+
+    let size = file.size().map(|size| size.size());
+    let other_size = other.size().map(|size| size.size());
+    if size != other_size {
+        discrepancies.push(MetadataDiscrepancy::SizeMismatch {
+            metalink: size,
+            against: other_size,
+        });
+    }
+
+    for hash in file.hashes().into_iter().flatten() {
+        let Some(hash_type) = hash.hash_type() else {
+            continue;
+        };
+        let other_hash = other
+            .hashes()
+            .into_iter()
+            .flatten()
+            .find(|other| other.hash_type() == Some(hash_type));
+        if let Some(other_hash) = other_hash {
+            if !hash.value().eq_ignore_ascii_case(other_hash.value()) {
+                discrepancies.push(MetadataDiscrepancy::ChecksumMismatch {
+                    hash_type: hash_type.to_string(),
+                    metalink: hash.value().to_owned(),
+                    against: other_hash.value().to_owned(),
+                });
+            }
+        }
+    }
+
+    if let (Some(pieces), Some(other_pieces)) = (file.pieces(), other.pieces()) {
+        let comparable = pieces.hash_type() == other_pieces.hash_type()
+            && pieces.length() == other_pieces.length();
+        if comparable
+            && !pieces
+                .hashes()
+                .iter()
+                .map(|hash| hash.value().to_ascii_lowercase())
+                .eq(other_pieces
+                    .hashes()
+                    .iter()
+                    .map(|hash| hash.value().to_ascii_lowercase()))
+        {
+            discrepancies.push(MetadataDiscrepancy::PiecesMismatch {
+                hash_type: pieces.hash_type().to_string(),
+                length: pieces.length(),
+            });
+        }
+    }
+
+    discrepancies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iana_registry_enums::HashFunctionTextualName;
+    use metalink::{FileBuilder, FileUrl, Hash, MetalinkBuilder, Size};
+
+    fn file(name: &str, size: u64, sha256: &str) -> metalink::File {
+        FileBuilder::new()
+            .with_name(name)
+            .with_size(Size::new(size))
+            .with_hashes(vec![Hash::new(
+                Some(HashFunctionTextualName::Sha256),
+                sha256,
+            )])
+            .with_urls(vec![FileUrl::new(
+                url::Url::parse("https://example.com/file").unwrap(),
+                None,
+                None,
+            )])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn compare_metalinks_reports_differences_by_name() {
+        let metalink = MetalinkBuilder::new()
+            .with_files(vec![
+                file("a", 1, "aa"),
+                file("b", 2, "bb"),
+                file("c", 3, "cc"),
+            ])
+            .build()
+            .unwrap();
+        let against = MetalinkBuilder::new()
+            .with_files(vec![
+                file("a", 1, "AA"),
+                file("b", 2, "ff"),
+                file("d", 4, "dd"),
+            ])
+            .build()
+            .unwrap();
+
+        let discrepancies = compare_metalinks(&metalink, &against, &FileFilter::default());
+
+        assert!(!discrepancies.contains_key("a"));
+        assert_eq!(
+            discrepancies["b"],
+            vec![MetadataDiscrepancy::ChecksumMismatch {
+                hash_type: "sha-256".to_owned(),
+                metalink: "bb".to_owned(),
+                against: "ff".to_owned(),
+            }]
+        );
+        assert_eq!(
+            discrepancies["c"],
+            vec![MetadataDiscrepancy::OnlyInMetalink]
+        );
+        assert_eq!(discrepancies["d"], vec![MetadataDiscrepancy::OnlyInAgainst]);
+    }
+}
@@ -0,0 +1,278 @@
+//! The daemon running download jobs and the client talking to it.
+//!
+//! Clients send one JSON request per line over a unix socket, the daemon
+//! answers each with one JSON response line.
+
+use crate::cli::JobVerb;
+use crate::jobs::{JobManager, JobState, JobStatus};
+use crate::shutdown;
+use crate::{MetalinkDownloadError, MetalinkDownloaderBuilder, Result};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+/// What a client asks the daemon for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "verb", rename_all = "kebab-case")]
+pub(crate) enum Request {
+    /// Queue the download of a metalink, paths must be absolute
+    Add {
+        metalink: String,
+        target_dir: PathBuf,
+    },
+    /// All jobs or a single one
+    Status {
+        id: Option<u64>,
+    },
+    Pause {
+        id: u64,
+    },
+    Resume {
+        id: u64,
+    },
+    Cancel {
+        id: u64,
+    },
+}
+
+/// The answer of the daemon
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Response {
+    /// The jobs affected by or asked for in the request
+    Jobs(Vec<JobStatus>),
+    Error(String),
+}
+
+impl From<JobVerb> for Request {
+    fn from(verb: JobVerb) -> Self {
+        match verb {
+            JobVerb::Add {
+                metalink,
+                target_dir,
+            } => Self::Add {
+                metalink,
+                target_dir,
+            },
+            JobVerb::Status { id } => Self::Status { id },
+            JobVerb::Pause { id } => Self::Pause { id },
+            JobVerb::Resume { id } => Self::Resume { id },
+            JobVerb::Cancel { id } => Self::Cancel { id },
+        }
+    }
+}
+
+/// `$XDG_STATE_HOME/metalink-downloader/jobs.json`, falling back to
+/// `~/.local/state` if `XDG_STATE_HOME` is not set
+pub(crate) fn default_state_file() -> Result<PathBuf> {
+    let state_dir = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })
+        .ok_or_else(|| anyhow!("No home directory to keep the job queue in, use --state-file"))?;
+    Ok(state_dir.join("metalink-downloader").join("jobs.json"))
+}
+
+/// Run the jobs of the queue saved in `state_file` and answer the clients
+/// connecting to `socket` until SIGINT or SIGTERM. The running jobs stop
+/// after their chunks in flight and are queued again in the saved queue.
+#[cfg(unix)]
+pub(crate) async fn daemon(
+    socket: PathBuf,
+    state_file: PathBuf,
+    max_jobs: usize,
+    downloader: MetalinkDownloaderBuilder,
+) -> Result<()> {
+    let manager = JobManager::load(state_file, downloader, max_jobs)?;
+    let listener = listen(&socket).await?;
+    shutdown::install_handler();
+    tracing::info!("Listening for jobs on {socket:?}");
+    let scheduler = tokio::spawn({
+        let manager = manager.clone();
+        async move { manager.run().await }
+    });
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let manager = manager.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, &manager).await {
+                            tracing::warn!("Failed to answer client: {e:#}");
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!("Failed to accept client: {e}"),
+            },
+            _ = shutdown::requested() => break,
+        }
+    }
+    // No new clients are accepted while the running jobs stop
+    drop(listener);
+    let _ = std::fs::remove_file(&socket);
+    scheduler.await.with_context(|| "Job scheduler failed")?;
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn listen(socket: &Path) -> Result<tokio::net::UnixListener> {
+    if tokio::net::UnixStream::connect(socket).await.is_ok() {
+        return Err(anyhow!("A daemon is already listening on {socket:?}").into());
+    }
+    // Left behind by a daemon which did not stop cleanly
+    if socket.exists() {
+        std::fs::remove_file(socket)
+            .with_context(|| format!("Failed to remove stale socket {socket:?}"))?;
+    }
+    Ok(tokio::net::UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {socket:?}"))?)
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn daemon(
+    _: PathBuf,
+    _: PathBuf,
+    _: usize,
+    _: MetalinkDownloaderBuilder,
+) -> Result<()> {
+    Err(anyhow!("The daemon needs unix sockets, which this platform does not have").into())
+}
+
+#[cfg(unix)]
+async fn serve(stream: tokio::net::UnixStream, manager: &JobManager) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => answer(manager, request),
+            Err(e) => Response::Error(format!("Invalid request: {e}")),
+        };
+        let mut response = serde_json::to_vec(&response)?;
+        response.push(b'\n');
+        writer.write_all(&response).await?;
+    }
+    Ok(())
+}
+
+fn answer(manager: &JobManager, request: Request) -> Response {
+    let jobs = match request {
+        Request::Add {
+            metalink,
+            target_dir,
+        } => manager.add(metalink, target_dir).map(|job| vec![job]),
+        Request::Status { id } => manager.status(id),
+        Request::Pause { id } => manager.pause(id).map(|job| vec![job]),
+        Request::Resume { id } => manager.resume(id).map(|job| vec![job]),
+        Request::Cancel { id } => manager.cancel(id).map(|job| vec![job]),
+    };
+    match jobs {
+        Ok(jobs) => Response::Jobs(jobs),
+        Err(e) => Response::Error(format!("{e:#}")),
+    }
+}
+
+/// Send `request` to the daemon listening on `socket` and print the jobs it
+/// answers with
+pub(crate) async fn job(socket: PathBuf, verb: JobVerb) -> Result<()> {
+    let request = absolute_paths(verb.into())?;
+    let jobs = match send(&socket, &request).await? {
+        Response::Jobs(jobs) => jobs,
+        Response::Error(e) => return Err(anyhow!("The daemon refused: {e}").into()),
+    };
+    let details = matches!(request, Request::Status { id: Some(_) });
+    for job in jobs {
+        let state = match &job.state {
+            JobState::Failed(e) => format!("failed: {e}"),
+            state => format!("{state:?}").to_lowercase(),
+        };
+        println!(
+            "{:>4}  {state:<10}  {}  -> {:?}",
+            job.id, job.metalink, job.target_dir
+        );
+        if details {
+            for (name, state) in &job.files {
+                println!("      {name}: {state}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The daemon runs in another directory, relative paths are resolved here
+fn absolute_paths(request: Request) -> Result<Request> {
+    let Request::Add {
+        metalink,
+        target_dir,
+    } = request
+    else {
+        return Ok(request);
+    };
+    let metalink = match url::Url::parse(&metalink) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => metalink,
+        _ => std::path::absolute(&metalink)
+            .with_context(|| format!("Invalid metalink path {metalink:?}"))?
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let target_dir = std::path::absolute(&target_dir)
+        .with_context(|| format!("Invalid target directory {target_dir:?}"))?;
+    Ok(Request::Add {
+        metalink,
+        target_dir,
+    })
+}
+
+#[cfg(unix)]
+async fn send(socket: &Path, request: &Request) -> Result<Response> {
+    let stream = tokio::net::UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("No daemon is listening on {socket:?}"))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut line = serde_json::to_vec(request).map_err(anyhow::Error::from)?;
+    line.push(b'\n');
+    writer
+        .write_all(&line)
+        .await
+        .with_context(|| "Failed to send the request to the daemon")?;
+    let response = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await
+        .with_context(|| "Failed to read the answer of the daemon")?
+        .ok_or_else(|| anyhow!("The daemon closed the connection"))?;
+    serde_json::from_str(&response)
+        .map_err(|e| MetalinkDownloadError::Other(anyhow!("Invalid answer of the daemon: {e}")))
+}
+
+#[cfg(not(unix))]
+async fn send(_: &Path, _: &Request) -> Result<Response> {
+    Err(anyhow!("The daemon needs unix sockets, which this platform does not have").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_tagged_with_their_verb() {
+        let request: Request = serde_json::from_str(r#"{"verb":"pause","id":3}"#).unwrap();
+        assert_eq!(request, Request::Pause { id: 3 });
+        assert_eq!(
+            serde_json::to_string(&Request::Status { id: None }).unwrap(),
+            r#"{"verb":"status","id":null}"#
+        );
+
+        let Request::Add { metalink, .. } = absolute_paths(Request::Add {
+            metalink: "https://example.com/release.meta4".to_owned(),
+            target_dir: PathBuf::from("mirror"),
+        })
+        .unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(metalink, "https://example.com/release.meta4");
+    }
+}
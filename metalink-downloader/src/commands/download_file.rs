@@ -1,44 +1,182 @@
-use crate::http::{get_file_size, make_http_client, segregrated_download, simple_download};
-use crate::types::ChunkMetaData;
-use crate::Result;
+use crate::backpressure::WriterStalls;
+use crate::http::{
+    attachment_file_name, make_http_client, measure_chunk_size, segregrated_download,
+    simple_download, verify_file_size, ChunkSize, Client, Concurrency, HttpOptions, HttpTransport,
+    Segmentation,
+};
+use crate::progress::ProgressMode;
+use crate::remote::{negotiate_metalink, MetalinkSource, CACHE_DIR};
+use crate::selection::MirrorSelection;
+use crate::transport::{Transport, Transports};
+use crate::types::{part_file, ChunkMetaData};
+use crate::warnings::{self, Warning};
+use crate::writeback;
+use crate::{MetalinkDownloadError, Result};
 
-use anyhow::anyhow;
-use std::path::PathBuf;
+use super::DownloadMetalinkOptions;
 
-const ONE_MB: u64 = 1_048_576;
+use anyhow::{anyhow, Context};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 
+/// Options of downloading a single file
+#[derive(Clone)]
+pub struct DownloadFileOptions {
+    /// Name of the file inside the target directory, taken from the
+    /// server's Content-Disposition header or the url if not given
+    pub output: Option<PathBuf>,
+    pub http: HttpOptions,
+    pub concurrency: Concurrency,
+    pub segmentation: Segmentation,
+    /// Download with a metalink if the server offers one for the url
+    pub negotiate: bool,
+    /// Transports for urls the built-in http transport does not fetch
+    pub transports: Transports,
+    pub progress: ProgressMode,
+    /// Write directly into the target file instead of a part file which is
+    /// renamed to the target once the download is complete
+    pub in_place: bool,
+}
+
+#[tracing::instrument(name = "download_file", skip_all, fields(url = %url))]
 pub async fn download_file(
     url: url::Url,
     target_dir: PathBuf,
-    user_agent: String,
-    max_threads: u16,
+    options: DownloadFileOptions,
 ) -> Result<()> {
-    let client = make_http_client(user_agent)?;
+    let DownloadFileOptions {
+        output,
+        http,
+        concurrency,
+        segmentation,
+        negotiate,
+        transports,
+        progress,
+        in_place,
+    } = options;
+    let client = make_http_client(&http)?;
     let url = reqwest::Url::parse(url.as_str())?;
-    let path = PathBuf::from(url.path());
-    let file_name = path
-        .file_name()
-        .ok_or(anyhow!("Unable to extract file path from url"))?;
+    let registered = transports.find(&url);
+    if registered.is_none() && !matches!(url.scheme(), "http" | "https") {
+        return Err(MetalinkDownloadError::UnsupportedScheme { url });
+    }
+    // Only http mirrors can offer a metalink for the url
+    if negotiate && registered.is_none() {
+        if let Some(metalink_file) =
+            negotiate_metalink(&client, &url, &target_dir.join(CACHE_DIR)).await?
+        {
+            tracing::info!("{url} is described by a metalink, downloading with metalink");
+            let report = super::download_metalink(
+                vec![MetalinkSource::File(metalink_file)],
+                target_dir,
+                DownloadMetalinkOptions {
+                    mirrors: MirrorSelection::default().with_http(http.allow_http),
+                    http,
+                    verify_files: true,
+                    progress,
+                    in_place,
+                    transports,
+                    ..Default::default()
+                },
+            )
+            .await?;
+            return report.failure().map_or(Ok(()), Err);
+        }
+    }
+
+    let suggested_name = match (&output, &registered) {
+        (None, None) => attachment_file_name(&client, &url).await,
+        _ => None,
+    };
+    let file_name = match output {
+        Some(output) => output,
+        None => match suggested_name {
+            Some(name) => PathBuf::from(name),
+            None => PathBuf::from(url.path())
+                .file_name()
+                .map(PathBuf::from)
+                .ok_or(anyhow!(
+                    "Unable to extract a file name from {url}, name the file with --output"
+                ))?,
+        },
+    };
     let target_file = target_dir.join(file_name);
+    // The target is only replaced once the download is complete
+    let path = if in_place {
+        target_file.clone()
+    } else {
+        part_file(&target_file)
+    };
 
-    match get_file_size(&client, url.clone()).await? {
+    let transport: Arc<dyn Transport> = match &registered {
+        Some(transport) => transport.clone(),
+        None => Arc::new(HttpTransport::new(client.clone())),
+    };
+    let probe = transport.probe(&url).await?;
+    match probe.size {
         Some(size) => {
-            if size <= ONE_MB {
-                simple_download(&client, url.clone(), target_file).await
+            if size <= segmentation.min_split_size || !probe.accepts_ranges {
+                fetch_whole(&client, registered.as_deref(), &url, &path).await?;
             } else {
-                let ranges = ChunkMetaData::calculate_ranges(size, ONE_MB, &target_file);
+                let chunk_size = match segmentation.chunk_size {
+                    ChunkSize::Fixed(chunk_size) => chunk_size,
+                    ChunkSize::Auto => {
+                        measure_chunk_size(transport.as_ref(), &url, &path, size).await?
+                    }
+                };
+                let ranges = ChunkMetaData::calculate_ranges(size, chunk_size, &path);
+                let stalls_before = WriterStalls::snapshot();
+                let started = Instant::now();
                 segregrated_download(
-                    &client,
+                    &transport,
                     url.clone(),
-                    target_file,
+                    path.clone(),
                     size,
                     &ranges,
                     None,
-                    max_threads,
+                    concurrency,
                 )
-                .await
+                .await?;
+                let stalls = WriterStalls::snapshot().since(&stalls_before);
+                let tasks = concurrency.parallelism(ranges.len());
+                if stalls.is_disk_bound(tasks, started.elapsed()) {
+                    warnings::warn(Warning::SlowDisk {
+                        file: target_file.clone(),
+                        stalls: stalls.count,
+                        waited_ms: stalls.time.as_millis() as u64,
+                    });
+                }
+                verify_file_size(&path, size)?;
             }
         }
-        None => simple_download(&client, url.clone(), target_file).await,
+        None => fetch_whole(&client, registered.as_deref(), &url, &path).await?,
+    }
+    writeback::file_complete(&path)?;
+    if path != target_file {
+        std::fs::rename(&path, &target_file)
+            .with_context(|| format!("Failed to rename {path:?} to {target_file:?}"))?;
+    }
+    writeback::download_complete(&target_dir)?;
+    Ok(())
+}
+
+/// Fetch the whole file in a single request, with a registered transport if
+/// one supports the url
+async fn fetch_whole(
+    client: &Client,
+    registered: Option<&dyn Transport>,
+    url: &url::Url,
+    path: &Path,
+) -> Result<()> {
+    match registered {
+        Some(transport) => {
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            transport.fetch_whole(url, path).await?;
+        }
+        None => {
+            simple_download(client, url.clone(), path.to_path_buf(), None, None).await?;
+        }
     }
+    Ok(())
 }
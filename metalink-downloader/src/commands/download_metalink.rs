@@ -1,105 +1,1232 @@
-use crate::http::{download, make_http_client, simple_download, Client};
-use crate::types::{FilePlan, Plan};
-use crate::Result;
-use anyhow::Context;
+use crate::commands::{print_plan, PlanFormat};
+use crate::connections::ConnectionReuse;
+use crate::control::{FileState, JobControl, Pause};
+use crate::delta::{self, DeltaPlan};
+use crate::disk;
+use crate::events::{self, DownloadEvent};
+use crate::history::History;
+use crate::http::{
+    copy_local_file, download, local_path, make_http_client, simple_download, verify_file_size,
+    Client, HttpOptions, HttpTransport, PieceFetch,
+};
+use crate::keys::Keybindings;
+use crate::latency::LatencyBreakdown;
+use crate::local_source::{LocalCopy, LocalSources};
+use crate::lock::{lock_target_dir, LockMode};
+use crate::metaurl::{MetaUrlHandler, MetaUrlHandlers};
+use crate::quarantine::{quarantine, QuarantineRecord};
+use crate::remote::MetalinkSource;
+use crate::report::{DownloadReport, DownloadSummary, FileOutcome, Verification};
+use crate::run;
+use crate::selection::{
+    ConflictDecision, Dedupe, FileFilter, Layout, MirrorSelection, OnConflict, RefreshSelection,
+};
+use crate::shutdown;
+use crate::sums::{self, Signing};
+use crate::transport::{Transport, Transports};
+use crate::types::{hash_threads, Duplicate, FilePlan, Plan};
+use crate::validators::ValidatorStore;
+use crate::verification_cache::VerificationCache;
+use crate::warnings::{self, Warning};
+use crate::writeback;
+use crate::{MetalinkDownloadError, Result};
+use anyhow::{anyhow, Context};
+use futures::StreamExt;
+use iana_registry_enums::HashFunctionTextualName;
+use std::collections::HashMap;
 use std::fmt::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tokio::task::JoinHandle;
+use tracing::Instrument;
 
-use crate::types::ProgressUpdate;
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use crate::progress::{
+    progress_channel, ProgressMode, ProgressReceiver, ProgressSender, ProgressUpdate,
+    PROGRESS_CHANNEL_CAPACITY, PROGRESS_LINE_INTERVAL,
+};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
+
+/// Options of a metalink download
+#[derive(Clone, Default)]
+pub struct DownloadMetalinkOptions {
+    pub http: HttpOptions,
+    pub verify_chunk_checksums: bool,
+    pub verify_files: bool,
+    /// Move files failing verification into this directory
+    pub quarantine_dir: Option<PathBuf>,
+    /// Files of the metalink to download
+    pub filter: FileFilter,
+    /// Order in which the urls of a file are tried
+    pub mirrors: MirrorSelection,
+    /// Directories files are copied from before downloading them
+    pub local_sources: LocalSources,
+    /// Where the files are placed inside the target directory
+    pub layout: Layout,
+    /// How files with the same content as another file are created
+    pub dedupe: Dedupe,
+    pub selection: RefreshSelection,
+    pub metaurl_handlers: MetaUrlHandlers,
+    /// Transports for urls the built-in http transport does not fetch
+    pub transports: Transports,
+    pub lock: LockMode,
+    /// Cancel or retry individual files while the download runs
+    pub control: JobControl,
+    /// Only print the minimized plan in this format instead of downloading
+    pub dry_run: Option<PlanFormat>,
+    /// Continue with the remaining files after a file failed instead of
+    /// cancelling them
+    pub keep_going: bool,
+    /// How progress and the summary are displayed
+    pub progress: ProgressMode,
+    /// Set the modification time of downloaded files from the metalink or
+    /// the mirror
+    pub preserve_timestamps: bool,
+    /// Write directly into the target files instead of a part file which is
+    /// renamed to the target after verification
+    pub in_place: bool,
+    /// Write a checksum file of this hash type covering the files after a
+    /// successful download
+    pub write_checksums: Option<HashFunctionTextualName>,
+    /// Sign the checksum file with this key
+    pub signing: Option<Signing>,
+}
 
 pub async fn download_metalink(
-    metalink_file: PathBuf,
+    sources: Vec<MetalinkSource>,
     target_dir: PathBuf,
-    user_agent: String,
-    verify_chunk_checksums: bool,
-) -> Result<()> {
-    log::info!("==========Start Metalink Download==========");
-    let plan = Plan::new(metalink_file, &target_dir)?.minimize_plan()?;
+    options: DownloadMetalinkOptions,
+) -> Result<DownloadReport> {
+    // The summary only covers what this download recorded, even if others
+    // run in the same process
+    run::scope(download_metalink_run(sources, target_dir, options)).await
+}
+
+async fn download_metalink_run(
+    sources: Vec<MetalinkSource>,
+    target_dir: PathBuf,
+    options: DownloadMetalinkOptions,
+) -> Result<DownloadReport> {
+    let DownloadMetalinkOptions {
+        http,
+        verify_chunk_checksums,
+        verify_files,
+        quarantine_dir,
+        filter,
+        mirrors,
+        local_sources,
+        layout,
+        dedupe,
+        selection,
+        metaurl_handlers,
+        transports,
+        lock,
+        control,
+        dry_run,
+        keep_going,
+        progress,
+        preserve_timestamps,
+        in_place,
+        write_checksums,
+        signing,
+    } = options;
+    if let Some(hash_type) = write_checksums {
+        sums::file_name(hash_type)?;
+    }
+    // A dry run only reads the target directory and does not need the lock
+    let _lock = match dry_run {
+        Some(_) => None,
+        None => Some(lock_target_dir(&target_dir, lock).await?),
+    };
+    tracing::info!("==========Start Metalink Download==========");
+    let client = make_http_client(&http)?;
+    let mut metalink_files = Vec::new();
+    for source in &sources {
+        metalink_files.extend(source.resolve(&client, &target_dir).await?);
+    }
+    let plan = Plan::from_metalinks(&metalink_files, &target_dir, &filter, &mirrors, &layout)?;
+    let planned: Vec<(String, PathBuf)> = plan
+        .files
+        .iter()
+        .map(|file| (file.name.clone(), file.target_file.clone()))
+        .collect();
+    let cache = Arc::new(VerificationCache::load(&target_dir));
+    // Validating existing files hashes them, which must not block the runtime
+    let show_progress = progress != ProgressMode::Quiet;
+    let plan = tokio::task::spawn_blocking({
+        let cache = cache.clone();
+        let selection = selection.clone();
+        move || plan.minimize_plan(&selection, &cache, show_progress)
+    })
+    .await
+    .with_context(|| "Plan minimization task failed")??;
+    let mut plan = match dedupe {
+        Dedupe::Off => plan,
+        Dedupe::Hardlink | Dedupe::Copy => plan.dedupe(),
+    };
+    let duplicates = std::mem::take(&mut plan.duplicates);
+    if let Some(format) = dry_run {
+        plan.duplicates = duplicates;
+        print_plan(plan, format)?;
+        return Ok(DownloadReport::default());
+    }
+    cache.save()?;
+    let copied: u64 = match dedupe {
+        Dedupe::Copy => duplicates.iter().filter_map(|d| d.file.file_size).sum(),
+        Dedupe::Hardlink | Dedupe::Off => 0,
+    };
+    disk::ensure_free_space(&target_dir, plan.total_size + copied)?;
+    events::emit(|| DownloadEvent::DownloadPlanned {
+        files: plan.files.len(),
+        bytes: plan.total_size,
+    });
+
+    shutdown::install_handler();
 
-    let client = make_http_client(user_agent)?;
     let total_size = plan.total_size;
-    let (prog_tx, prog_rx) = tokio::sync::mpsc::unbounded_channel::<ProgressUpdate>();
+    let (prog_tx, prog_rx) = progress_channel(PROGRESS_CHANNEL_CAPACITY);
+    let pause = control.pause_switch();
     let progress_reporter: JoinHandle<Result<()>> =
-        tokio::spawn(async move { progress_reporter_task(prog_rx, total_size).await });
+        tokio::spawn(
+            async move { progress_reporter_task(prog_rx, total_size, progress, pause).await },
+        );
+    let keys = match progress {
+        ProgressMode::Bars => Keybindings::listen(control.clone()),
+        ProgressMode::Lines | ProgressMode::Quiet => None,
+    };
 
+    let download_started = Instant::now();
+    let history = Arc::new(History::new(&target_dir));
     let tracker = tokio_util::task::TaskTracker::new();
-    for file in plan.files {
-        let cloned_file = file.clone();
-        let cloned_tx = prog_tx.clone();
-        let cloned_client = client.clone();
-        tracker.spawn(async move {
-            let _ = download_file_task(
-                &cloned_client,
-                &cloned_file,
-                &cloned_tx,
-                verify_chunk_checksums,
-            )
-            .await;
-        });
+    let context = FileTaskContext {
+        client: client.clone(),
+        tx: prog_tx.clone(),
+        pieces: PieceFetch {
+            verify_checksums: verify_chunk_checksums,
+            max_request_size: http.max_request_size,
+        },
+        metaurl_handlers,
+        transports,
+        local_sources,
+        control: control.clone(),
+        keep_going,
+        validators: Arc::new(ValidatorStore::load(&target_dir)),
+        selection,
+        history: history.clone(),
+        preserve_timestamps,
+        in_place,
+    };
+    let mut retries = control.start();
+    let mut tasks = Vec::new();
+    for file in &plan.files {
+        tasks.push((
+            file.clone(),
+            spawn_file_task(&tracker, &context, file.clone()),
+        ));
     }
     tracker.close();
-    tracker.wait().await;
+    loop {
+        tokio::select! {
+            biased;
+            Some(name) = retries.recv() => {
+                if let Some(file) = plan.files.iter().find(|file| file.name == name) {
+                    tracing::info!("Retrying {:?}", file.target_file);
+                    tasks.push((file.clone(), spawn_file_task(&tracker, &context, file.clone())));
+                }
+            }
+            _ = tracker.wait() => {
+                // Retries requested while the last task finished are still honoured
+                control.finish();
+                match retries.try_recv() {
+                    Ok(name) => {
+                        if let Some(file) = plan.files.iter().find(|file| file.name == name) {
+                            tasks.push((file.clone(), spawn_file_task(&tracker, &context, file.clone())));
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+    drop(context);
+
+    // Only the last attempt of a retried file counts
+    let mut results: Vec<(FilePlan, Result<()>)> = Vec::new();
+    for (file, task) in tasks {
+        let result = match task.await {
+            Ok(result) => result,
+            Err(e) => Err(MetalinkDownloadError::Other(e.into())),
+        };
+        match results.iter_mut().find(|(f, _)| f.name == file.name) {
+            Some(previous) => *previous = (file, result),
+            None => results.push((file, result)),
+        }
+    }
+    let mut summary = DownloadSummary::default();
+    for (file, decision) in &plan.conflicts {
+        if *decision == ConflictDecision::Failed {
+            summary.add(
+                file.clone(),
+                FileOutcome::Failed(MetalinkDownloadError::Conflict { file: file.clone() }),
+            );
+        }
+    }
+    summary.set_conflicts(plan.conflicts.clone());
+    summary.set_skipped(skipped_files(planned, &plan, &duplicates));
+    let mut downloaded = Vec::new();
+    for (file, result) in results {
+        match result {
+            Ok(()) => downloaded.push(file),
+            Err(e) if e.is_cancelled() => summary.add(file.target_file, FileOutcome::Cancelled),
+            Err(e) => summary.add(file.target_file, FileOutcome::Failed(e)),
+        }
+    }
+
+    summary.set_download_time(download_started.elapsed());
+    summary.set_latency(LatencyBreakdown::collect());
+    summary.set_connections(ConnectionReuse::collect());
 
-    prog_tx
-        .send(ProgressUpdate::Finished)
-        .with_context(|| "Failed to send finish progress command")?;
+    if verify_files {
+        let verification_started = Instant::now();
+        for (target_file, outcome) in verification_stage(
+            downloaded,
+            &target_dir,
+            quarantine_dir.as_deref(),
+            &cache,
+            &prog_tx,
+        )
+        .await
+        {
+            summary.add(target_file, outcome);
+        }
+        summary.set_verification_time(verification_started.elapsed());
+    } else {
+        for file in downloaded {
+            let outcome = match file.finish() {
+                Ok(()) => FileOutcome::Downloaded(Verification::Disabled),
+                Err(e) => FileOutcome::Failed(e),
+            };
+            summary.add(file.target_file, outcome);
+        }
+    }
+    create_duplicates(
+        duplicates,
+        dedupe,
+        preserve_timestamps,
+        &cache,
+        &mut summary,
+    );
+    writeback::download_complete(&target_dir)?;
+    cache.save()?;
+    // The history is only informational, it must not fail the download
+    let completed = plan
+        .files
+        .iter()
+        .filter_map(|file| match summary.outcome(&file.target_file) {
+            Some(FileOutcome::Downloaded(verification)) => Some((file, *verification)),
+            _ => None,
+        });
+    if let Err(e) = history.record(completed) {
+        tracing::warn!("{e:#}");
+    }
+
+    // All download tasks are done and dropped their senders, dropping the last
+    // one closes the channel so the reporter drains the remaining updates and exits
+    drop(prog_tx);
     progress_reporter
         .await
         .with_context(|| "Progress Reporter failed")??;
+    drop(keys);
+
+    summary.set_warnings(warnings::take());
+    if progress != ProgressMode::Quiet {
+        print!("{summary}");
+    }
+    if shutdown::is_requested() {
+        return Err(MetalinkDownloadError::Interrupted {
+            command: shutdown::resume_command(),
+        });
+    }
+    let report = summary.into_report();
+    if let (Some(hash_type), true) = (write_checksums, report.failed.is_empty()) {
+        let files = report
+            .succeeded
+            .iter()
+            .chain(&report.skipped)
+            .cloned()
+            .collect();
+        let path = sums::write(&target_dir, hash_type, files, signing.as_ref()).await?;
+        tracing::info!("Wrote checksum file {path:?}");
+    }
+    Ok(report)
+}
+
+/// Target files of the files of the metalink which the minimized plan left
+/// out, besides conflicts which failed
+fn skipped_files(
+    planned: Vec<(String, PathBuf)>,
+    plan: &Plan,
+    duplicates: &[Duplicate],
+) -> Vec<PathBuf> {
+    planned
+        .into_iter()
+        .filter(|(name, target_file)| {
+            !plan.files.iter().any(|file| file.name == *name)
+                && !duplicates
+                    .iter()
+                    .any(|duplicate| duplicate.file.name == *name)
+                && !plan.conflicts.iter().any(|(file, decision)| {
+                    file == target_file && *decision == ConflictDecision::Failed
+                })
+        })
+        .map(|(_, target_file)| target_file)
+        .collect()
+}
+
+/// Everything a file download task needs besides the file itself
+#[derive(Clone)]
+struct FileTaskContext {
+    client: Client,
+    tx: ProgressSender,
+    pieces: PieceFetch,
+    metaurl_handlers: MetaUrlHandlers,
+    transports: Transports,
+    local_sources: LocalSources,
+    control: JobControl,
+    keep_going: bool,
+    validators: Arc<ValidatorStore>,
+    selection: RefreshSelection,
+    history: Arc<History>,
+    preserve_timestamps: bool,
+    in_place: bool,
+}
+
+/// Download `file` on `tracker`, the download can be cancelled through the
+/// job control and its state is tracked there
+fn spawn_file_task(
+    tracker: &tokio_util::task::TaskTracker,
+    context: &FileTaskContext,
+    file: FilePlan,
+) -> JoinHandle<Result<()>> {
+    let cancel = context.control.register(&file.name);
+    let context = context.clone();
+    let span = tracing::info_span!("download_file", file = %file.name);
+    tracker.spawn(run::bind(
+        async move {
+            let FileTaskContext {
+                tx,
+                control,
+                keep_going,
+                ..
+            } = &context;
+            if shutdown::is_requested() {
+                control.set_state(&file.name, FileState::Cancelled);
+                return Err(MetalinkDownloadError::Cancelled {
+                    file: file.target_file.clone(),
+                });
+            }
+            control.set_state(&file.name, FileState::Downloading);
+            let key: Arc<Path> = Arc::from(file.target_file.as_path());
+            let size = file
+                .chunks
+                .as_ref()
+                .map(|chunks| chunks.total_bytes())
+                .or(file.file_size)
+                .unwrap_or(0);
+            events::emit(|| DownloadEvent::FileStarted {
+                file: file.target_file.clone(),
+                size,
+            });
+            // Progress is only displayed, a stopped reporter must not fail the download
+            let _ = tx.send(ProgressUpdate::Started {
+                file: key.clone(),
+                size,
+            });
+            let result = tokio::select! {
+                _ = cancel.cancelled() => Err(MetalinkDownloadError::Cancelled {
+                    file: file.target_file.clone(),
+                }),
+                result = download_file_task(&context, &file) => result,
+            };
+            let (state, update) = match &result {
+                Ok(()) => (
+                    FileState::Downloaded,
+                    ProgressUpdate::Finished { file: key },
+                ),
+                Err(e) if e.is_cancelled() => (
+                    FileState::Cancelled,
+                    ProgressUpdate::Cancelled { file: key },
+                ),
+                Err(e) => (
+                    FileState::Failed(format!("{e:#}")),
+                    ProgressUpdate::Failed { file: key },
+                ),
+            };
+            events::emit(|| match &result {
+                Ok(()) => DownloadEvent::FileDownloaded {
+                    file: file.target_file.clone(),
+                },
+                Err(e) if e.is_cancelled() => DownloadEvent::FileCancelled {
+                    file: file.target_file.clone(),
+                },
+                Err(e) => DownloadEvent::FileFailed {
+                    file: file.target_file.clone(),
+                    reason: format!("{e:#}"),
+                },
+            });
+            let _ = tx.send(update);
+            control.set_state(&file.name, state);
+            if !keep_going && matches!(control.state(&file.name), Some(FileState::Failed(_))) {
+                tracing::info!(
+                    "Cancelling remaining files after {:?} failed",
+                    file.target_file
+                );
+                control.cancel_all();
+            }
+            result
+        }
+        .instrument(span),
+    ))
+}
+
+async fn download_file_task(context: &FileTaskContext, file: &FilePlan) -> Result<()> {
+    tracing::info!("Start downloading: {:?}", file.target_file);
+    let started = Instant::now();
+    let path = prepare_download(file, context.in_place)?;
+    let mut mirror = file.url.clone();
+    let server_modified = match context.local_sources.copy(file, &path, &context.tx).await? {
+        LocalCopy::Complete => {
+            mirror = None;
+            None
+        }
+        LocalCopy::Missing(chunks) => {
+            let remaining = FilePlan {
+                chunks: Some(chunks),
+                ..file.clone()
+            };
+            fetch_file(context, &remaining, &path).await?
+        }
+        LocalCopy::Unavailable => match DeltaPlan::new(file, &path) {
+            Some(delta) => delta_update(context, delta, file, &path).await?,
+            None => fetch_file(context, file, &path).await?,
+        },
+    };
+    // An unchanged file is kept in place instead of downloaded to the part file
+    let downloaded = file.downloaded_file();
+    if let Some(file_size) = file.file_size {
+        verify_file_size(&downloaded, file_size)?;
+    }
+    if context.preserve_timestamps {
+        let modified = file.modified.map(SystemTime::from).or(server_modified);
+        if let Some(modified) = modified {
+            set_modified(&downloaded, modified)?;
+        }
+    }
+    context
+        .history
+        .downloaded(&file.target_file, mirror, started.elapsed());
+    tracing::info!("Finish downloading: {:?}", file.target_file);
+    Ok(())
+}
+
+/// Update the outdated `file` into `path` with a delta, a failed update
+/// falls back to downloading the whole file
+async fn delta_update(
+    context: &FileTaskContext,
+    delta: DeltaPlan,
+    file: &FilePlan,
+    path: &Path,
+) -> Result<Option<SystemTime>> {
+    let Some(url) = &file.url else {
+        return fetch_file(context, file, path).await;
+    };
+    let registered = context.transports.find(url);
+    let http;
+    let transport: &dyn Transport = match &registered {
+        Some(transport) => transport.as_ref(),
+        None => {
+            http = HttpTransport::new(context.client.clone());
+            &http
+        }
+    };
+    match delta::update(
+        &context.client,
+        transport,
+        delta,
+        url,
+        file,
+        path,
+        &context.tx,
+    )
+    .await
+    {
+        Ok(()) => Ok(None),
+        Err(e) if e.is_cancelled() => Err(e),
+        Err(e) => {
+            tracing::warn!(
+                "Delta update of {:?} failed, downloading it: {e:#}",
+                file.target_file
+            );
+            fetch_file(context, file, path).await
+        }
+    }
+}
+
+/// Fetch `file` into `path` from its mirrors in order of preference, the
+/// next mirror is tried when one fails, then a metaurl. Returns the
+/// modification time the mirror reported, if any.
+async fn fetch_file(
+    context: &FileTaskContext,
+    file: &FilePlan,
+    path: &Path,
+) -> Result<Option<SystemTime>> {
+    let metaurl = context.metaurl_handlers.find(&file.metaurls);
+    let mut urls: Vec<&url::Url> = file.url.iter().collect();
+    urls.extend(
+        file.urls
+            .iter()
+            .filter(|url| file.url.as_ref() != Some(*url)),
+    );
+    let mut urls = urls.into_iter().peekable();
+    let mut errors = Vec::new();
+    while let Some(url) = urls.next() {
+        let e = match fetch_from_mirror(context, url, file, path).await {
+            Ok(server_modified) => return Ok(server_modified),
+            Err(e) if e.is_cancelled() => return Err(e),
+            Err(e) => e,
+        };
+        let next = urls
+            .peek()
+            .copied()
+            .or_else(|| metaurl.as_ref().map(|(_, metaurl)| metaurl.url()));
+        if let Some(next) = next {
+            events::emit(|| DownloadEvent::MirrorSwitched {
+                file: file.target_file.clone(),
+                from: url.clone(),
+                to: next.clone(),
+                reason: format!("{e:#}"),
+            });
+            warnings::warn(Warning::SkippedMirror {
+                file: file.target_file.clone(),
+                url: url.clone(),
+                reason: format!("{e:#}, falling back to {next}"),
+            });
+        }
+        errors.push(e);
+    }
+
+    match metaurl {
+        Some((handler, metaurl)) => {
+            match fetch_metaurl(handler.as_ref(), metaurl, file, path).await {
+                Ok(()) => return Ok(None),
+                Err(e) => errors.push(e),
+            }
+        }
+        None if errors.is_empty() => {
+            let media_types: Vec<String> = file
+                .metaurls
+                .iter()
+                .map(|metaurl| metaurl.mediatype().to_string())
+                .collect();
+            return Err(anyhow!(
+                "{:?} is only available through metaurls of type {}, but no handler supports them",
+                file.target_file,
+                media_types.join(", ")
+            )
+            .into());
+        }
+        None => {}
+    }
+    if errors.len() == 1 {
+        return Err(errors.remove(0));
+    }
+    Err(MetalinkDownloadError::AllMirrorsFailed {
+        file: file.target_file.clone(),
+        errors,
+    })
+}
+
+/// Fetch `file` from the mirror `url` into `path`
+async fn fetch_from_mirror(
+    context: &FileTaskContext,
+    url: &url::Url,
+    file: &FilePlan,
+    path: &Path,
+) -> Result<Option<SystemTime>> {
+    match (context.transports.find(url), local_path(url)) {
+        (Some(transport), _) => transport_download(context, transport.as_ref(), url, file, path)
+            .await
+            .map(|()| None),
+        (None, Some(source)) => context
+            .local_sources
+            .copy_mirror(source, file, path, &context.tx)
+            .await
+            .map(|()| None),
+        (None, None) if !matches!(url.scheme(), "http" | "https") => {
+            Err(MetalinkDownloadError::UnsupportedScheme { url: url.clone() })
+        }
+        (None, None) => http_download(context, url, file, path).await,
+    }
+}
+
+/// Choose the file a download is written to. Unless downloading in place
+/// this is the part file, which starts with the valid pieces of an existing
+/// target file. In place downloads continue an existing part file.
+fn prepare_download(file: &FilePlan, in_place: bool) -> Result<PathBuf> {
+    let part = file.part_file();
+    let pieces_planned = file.chunks.is_some();
+    if in_place {
+        if part.exists() {
+            // The plan was made against the part file
+            if pieces_planned {
+                std::fs::rename(&part, &file.target_file)
+                    .with_context(|| format!("Failed to rename {part:?}"))?;
+            } else {
+                std::fs::remove_file(&part)
+                    .with_context(|| format!("Failed to remove {part:?}"))?;
+            }
+        }
+        return Ok(file.target_file.clone());
+    }
+    if part.exists() && !pieces_planned {
+        // A whole file download can not be continued, a stale part file must
+        // not be mistaken for the download
+        std::fs::remove_file(&part).with_context(|| format!("Failed to remove {part:?}"))?;
+    } else if !part.exists() && pieces_planned && file.target_file.exists() {
+        std::fs::copy(&file.target_file, &part)
+            .with_context(|| format!("Failed to copy {:?} to {part:?}", file.target_file))?;
+    }
+    Ok(part)
+}
 
+/// Download the pieces of `file` which are not valid on disk yet, pausing
+/// while `pause` is set
+async fn piece_download(
+    transport: &dyn Transport,
+    url: &url::Url,
+    file: &FilePlan,
+    path: &Path,
+    tx: &ProgressSender,
+    fetch: PieceFetch,
+    pause: &Pause,
+) -> Result<()> {
+    let Some(chunks) = file.chunks.as_ref().filter(|chunks| !chunks.is_empty()) else {
+        tracing::info!("{path:?} is complete, nothing to download");
+        return Ok(());
+    };
+    download(
+        transport,
+        url.clone(),
+        path.to_path_buf(),
+        &chunks.to_vec(),
+        Some(tx.clone()),
+        fetch,
+        pause,
+    )
+    .await
+    .with_context(|| format!("Parallel download of {:?} failed", file.target_file))?;
     Ok(())
 }
 
-async fn download_file_task(
-    client: &Client,
+/// Download `file` with a registered transport. Files without pieces are
+/// fetched whole as there are no validators to make the request conditional.
+async fn transport_download(
+    context: &FileTaskContext,
+    transport: &dyn Transport,
+    url: &url::Url,
     file: &FilePlan,
-    tx: &tokio::sync::mpsc::UnboundedSender<ProgressUpdate>,
-    verify_chunk_checksums: bool,
+    path: &Path,
 ) -> Result<()> {
-    log::info!("Start downloading: {:?}", file.target_file);
-    if let Some(chunks) = file.chunks.as_ref() {
-        download(
+    if file.chunks.is_some() {
+        return piece_download(
+            transport,
+            url,
+            file,
+            path,
+            &context.tx,
+            context.pieces,
+            &context.control.pause_switch(),
+        )
+        .await;
+    }
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow!("{path:?} has no parent directory"))?;
+    std::fs::create_dir_all(parent)?;
+    transport
+        .fetch_whole(url, path)
+        .await
+        .with_context(|| format!("Download of {:?} from {url} failed", file.target_file))?;
+    Ok(())
+}
+
+async fn http_download(
+    context: &FileTaskContext,
+    url: &url::Url,
+    file: &FilePlan,
+    path: &Path,
+) -> Result<Option<SystemTime>> {
+    let FileTaskContext {
+        client,
+        validators,
+        selection,
+        tx,
+        ..
+    } = context;
+    let progress = (tx, Arc::from(file.target_file.as_path()));
+    let downloaded = if file.chunks.is_some() {
+        let transport = HttpTransport::new(client.clone());
+        piece_download(
+            &transport,
+            url,
+            file,
+            path,
+            &context.tx,
+            context.pieces,
+            &context.control.pause_switch(),
+        )
+        .await?;
+        None
+    } else if file.file_checksums.is_some() {
+        simple_download(
             client,
-            file.url.clone(),
-            file.target_file.clone(),
-            chunks,
-            Some(tx.clone()),
-            verify_chunk_checksums,
+            url.clone(),
+            path.to_path_buf(),
+            None,
+            Some(progress),
         )
         .await
-        .with_context(|| format!("Parallel download of {:?} failed", file.target_file))?;
+        .with_context(|| format!("Simple download of {:?} failed", file.target_file))?
     } else {
-        simple_download(client, file.url.clone(), file.target_file.clone())
-            .await
-            .with_context(|| format!("Simple download of {:?} failed", file.target_file))?;
+        // Without a checksum the file on disk can not be checked, only
+        // download it again if it changed on the server unless it is
+        // requested unconditionally
+        let unconditional =
+            selection.is_forced(&file.name) || selection.on_conflict() == OnConflict::Overwrite;
+        let known = if unconditional {
+            None
+        } else {
+            validators.get(&file.target_file)
+        };
+        let downloaded = simple_download(
+            client,
+            url.clone(),
+            path.to_path_buf(),
+            known.as_ref(),
+            Some(progress),
+        )
+        .await
+        .with_context(|| format!("Simple download of {:?} failed", file.target_file))?;
+        validators.set(&file.target_file, downloaded.clone())?;
+        downloaded
+    };
+    // The Last-Modified time of the mirror, if it sent one
+    Ok(downloaded
+        .and_then(|validators| validators.last_modified)
+        .and_then(|last_modified| httpdate::parse_http_date(&last_modified).ok()))
+}
+
+/// Create the duplicates of downloaded files from them, a duplicate of a
+/// file which was not downloaded is not created either
+fn create_duplicates(
+    duplicates: Vec<Duplicate>,
+    dedupe: Dedupe,
+    preserve_timestamps: bool,
+    cache: &VerificationCache,
+    summary: &mut DownloadSummary,
+) {
+    for Duplicate { file, original } in duplicates {
+        let outcome = match summary.outcome(&original) {
+            Some(FileOutcome::Downloaded(verification)) if !shutdown::is_requested() => {
+                let verification = *verification;
+                match create_duplicate(&original, &file, dedupe, preserve_timestamps) {
+                    Ok(()) => {
+                        if let (Verification::Verified, Some(checksum)) =
+                            (verification, &file.file_checksums)
+                        {
+                            cache.record(&file.target_file, checksum);
+                        }
+                        FileOutcome::Downloaded(verification)
+                    }
+                    Err(e) => FileOutcome::Failed(e),
+                }
+            }
+            Some(FileOutcome::Failed(_)) => FileOutcome::Failed(
+                anyhow!("{original:?} with the same content failed to download").into(),
+            ),
+            _ => FileOutcome::Cancelled,
+        };
+        summary.add(file.target_file, outcome);
+    }
+}
+
+fn create_duplicate(
+    original: &Path,
+    file: &FilePlan,
+    dedupe: Dedupe,
+    preserve_timestamps: bool,
+) -> Result<()> {
+    tracing::info!("Creating {:?} from {original:?}", file.target_file);
+    let part = file.part_file();
+    if part.exists() {
+        std::fs::remove_file(&part).with_context(|| format!("Failed to remove {part:?}"))?;
+    }
+    copy_local_file(original, &file.target_file, dedupe == Dedupe::Hardlink)?;
+    if preserve_timestamps && dedupe == Dedupe::Copy {
+        let modified = std::fs::metadata(original)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to read the modification time of {original:?}"))?;
+        set_modified(&file.target_file, modified)?;
     }
-    log::info!("Finish downloading: {:?}", file.target_file);
     Ok(())
 }
 
+/// Set the modification time of a downloaded file
+fn set_modified(target_file: &Path, modified: SystemTime) -> Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(target_file)
+        .and_then(|file| file.set_modified(modified))
+        .with_context(|| format!("Failed to set the modification time of {target_file:?}"))?;
+    Ok(())
+}
+
+async fn fetch_metaurl(
+    handler: &dyn MetaUrlHandler,
+    metaurl: &metalink::MetaUrl,
+    file: &FilePlan,
+    path: &Path,
+) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    handler.fetch(metaurl, path).await.with_context(|| {
+        format!(
+            "Fetching {:?} from {} failed",
+            file.target_file,
+            metaurl.url()
+        )
+    })?;
+    Ok(())
+}
+
+/// Verify all downloaded files against their file-level checksums.
+///
+/// Runs after all downloads finished so hashing never competes with the
+/// downloads, files are hashed in parallel up to `--hash-threads`.
+///
+/// Files failing verification are moved into `quarantine_dir` if given,
+/// verified files are remembered in `cache`.
+async fn verification_stage(
+    files: Vec<FilePlan>,
+    target_dir: &Path,
+    quarantine_dir: Option<&Path>,
+    cache: &VerificationCache,
+    tx: &ProgressSender,
+) -> Vec<(PathBuf, FileOutcome)> {
+    let parallelism = hash_threads();
+    futures::stream::iter(files)
+        .map(|file| async move {
+            let key: Arc<Path> = Arc::from(file.target_file.as_path());
+            let verified = match verify_file_checksum(&file).await {
+                Ok(verification) => file.finish().map(|()| verification),
+                Err(e) => Err(e),
+            };
+            let outcome = match verified {
+                Ok(verification) => {
+                    if verification == Verification::Verified {
+                        if let Some(checksum) = &file.file_checksums {
+                            cache.record(&file.target_file, checksum);
+                        }
+                        events::emit(|| DownloadEvent::VerificationPassed {
+                            file: file.target_file.clone(),
+                        });
+                        let _ = tx.send(ProgressUpdate::Verified { file: key });
+                    }
+                    FileOutcome::Downloaded(verification)
+                }
+                Err(e) => {
+                    cache.forget(&file.target_file);
+                    if let Some(quarantine_dir) = quarantine_dir {
+                        quarantine_file(&file, &e, target_dir, quarantine_dir);
+                    }
+                    events::emit(|| DownloadEvent::VerificationFailed {
+                        file: file.target_file.clone(),
+                        reason: format!("{e:#}"),
+                    });
+                    let _ = tx.send(ProgressUpdate::Failed { file: key });
+                    FileOutcome::Failed(e)
+                }
+            };
+            (file.target_file, outcome)
+        })
+        .buffer_unordered(parallelism)
+        .collect()
+        .await
+}
+
+/// Verify the downloaded file against the strongest file-level checksum
+#[tracing::instrument(name = "verify", skip_all, fields(file = %file.name))]
+async fn verify_file_checksum(file: &FilePlan) -> Result<Verification> {
+    let Some(checksum) = file.file_checksums.clone() else {
+        return Ok(Verification::NoChecksum);
+    };
+    let downloaded = file.downloaded_file();
+    tracing::info!("Verifying: {downloaded:?}");
+    let actual = tokio::task::spawn_blocking({
+        let checksum = checksum.clone();
+        move || checksum.calculate_file_checksum(&downloaded)
+    })
+    .await
+    .with_context(|| "File verification task failed")??;
+
+    if actual != checksum.expected() {
+        return Err(MetalinkDownloadError::FileChecksumMismatch {
+            file: file.target_file.clone(),
+            expected: checksum.expected().to_owned(),
+            actual,
+        });
+    }
+    Ok(Verification::Verified)
+}
+
+/// Move a file that failed its checksum verification into the quarantine directory
+fn quarantine_file(
+    file: &FilePlan,
+    error: &MetalinkDownloadError,
+    target_dir: &Path,
+    quarantine_dir: &Path,
+) {
+    let (
+        MetalinkDownloadError::FileChecksumMismatch {
+            expected, actual, ..
+        },
+        Some(checksum),
+    ) = (error, file.file_checksums.as_ref())
+    else {
+        return;
+    };
+    let relative = file
+        .target_file
+        .strip_prefix(target_dir)
+        .unwrap_or(&file.target_file);
+    let record = QuarantineRecord {
+        file: file.downloaded_file(),
+        url: file.url.clone(),
+        hash_type: checksum.hash_type().to_string(),
+        expected: expected.clone(),
+        actual: actual.clone(),
+        quarantined_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs()),
+    };
+    match quarantine(quarantine_dir, relative, &record) {
+        Ok(destination) => tracing::warn!(
+            "Quarantined corrupt file {:?} to {destination:?}",
+            file.target_file
+        ),
+        Err(e) => tracing::error!("Failed to quarantine {:?}: {e:#}", file.target_file),
+    }
+}
+
+/// Counts of the files shown next to the overall progress
+#[derive(Debug, Default)]
+struct FileCounts {
+    downloaded: usize,
+    verified: usize,
+    failed: usize,
+}
+
+impl std::fmt::Display for FileCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} downloaded, {} verified, {} failed",
+            self.downloaded, self.verified, self.failed
+        )
+    }
+}
+
+/// Show the overall progress and a bar for every file in flight. Without a
+/// terminal the bars are hidden and the overall progress is printed as
+/// plain lines instead.
 async fn progress_reporter_task(
-    mut prog_rx: tokio::sync::mpsc::UnboundedReceiver<ProgressUpdate>,
+    prog_rx: ProgressReceiver,
     total_size: u64,
+    mode: ProgressMode,
+    pause: Pause,
 ) -> Result<()> {
-    let pb = ProgressBar::new(total_size);
-    pb.set_style(
-            ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+    let multi = match mode {
+        ProgressMode::Bars => MultiProgress::new(),
+        ProgressMode::Lines | ProgressMode::Quiet => {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        }
+    };
+    let overall = multi.add(ProgressBar::new(total_size));
+    overall.set_style(
+            ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta}) {msg}")
                 .unwrap()
                 .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
                 .progress_chars("#>-"));
-    let mut bytes_downloaded = 0;
-    while let Some(cmd) = prog_rx.recv().await {
-        match cmd {
-            ProgressUpdate::Progressed(bytes) => {
-                bytes_downloaded += bytes;
-                pb.set_position(bytes_downloaded);
+    let file_style = ProgressStyle::with_template(
+        "  {msg:30!} [{bar:30.cyan/blue}] {bytes}/{total_bytes} {bytes_per_sec} ({eta})",
+    )
+    .unwrap()
+    .progress_chars("#>-");
+
+    let mut files: HashMap<Arc<Path>, ProgressBar> = HashMap::new();
+    let mut counts = FileCounts::default();
+    let mut last_line = Instant::now();
+    let mut paused = pause.subscribe();
+    overall.set_message(counts.to_string());
+    loop {
+        let update = tokio::select! {
+            update = prog_rx.recv() => match update {
+                Some(update) => update,
+                None => break,
+            },
+            Ok(()) = paused.changed() => {
+                overall.set_message(overall_message(&counts, *paused.borrow()));
+                continue;
+            }
+        };
+        match update {
+            ProgressUpdate::Started { file, size } => {
+                let pb = multi.add(ProgressBar::new(size));
+                pb.set_style(file_style.clone());
+                pb.set_message(file_label(&file));
+                files.insert(file, pb);
+            }
+            ProgressUpdate::Progressed { file, bytes } => {
+                overall.inc(bytes + prog_rx.take_dropped_bytes());
+                if let Some(pb) = files.get(&file) {
+                    pb.inc(bytes);
+                    // Files of unknown size grow the total as they arrive
+                    let beyond = pb.position().saturating_sub(pb.length().unwrap_or(0));
+                    if beyond > 0 {
+                        pb.inc_length(beyond);
+                        overall.inc_length(beyond);
+                    }
+                }
+            }
+            ProgressUpdate::Finished { file } => {
+                counts.downloaded += 1;
+                if let Some(pb) = files.remove(&file) {
+                    pb.finish_and_clear();
+                }
+            }
+            ProgressUpdate::Verified { file } => {
+                tracing::debug!("Verified {file:?}");
+                counts.verified += 1;
+            }
+            ProgressUpdate::Failed { file } => {
+                counts.failed += 1;
+                // The bar of a failed file stays visible
+                if let Some(pb) = files.remove(&file) {
+                    pb.abandon_with_message(format!("{} failed", file_label(&file)));
+                }
+            }
+            ProgressUpdate::Cancelled { file } => {
+                if let Some(pb) = files.remove(&file) {
+                    pb.finish_and_clear();
+                }
             }
-            ProgressUpdate::Finished => break,
         }
+        overall.set_message(overall_message(&counts, *paused.borrow()));
+        if mode == ProgressMode::Lines && last_line.elapsed() >= PROGRESS_LINE_INTERVAL {
+            print_progress_line(&overall, &counts);
+            last_line = Instant::now();
+        }
+    }
+    overall.inc(prog_rx.take_dropped_bytes());
+    if mode == ProgressMode::Lines {
+        print_progress_line(&overall, &counts);
+    }
+    for pb in files.into_values() {
+        pb.finish_and_clear();
     }
 
-    pb.finish_with_message("Download Finished");
+    overall.finish();
     Ok(())
 }
+
+fn overall_message(counts: &FileCounts, paused: bool) -> String {
+    if paused {
+        format!("{counts}, paused, press p to resume")
+    } else {
+        counts.to_string()
+    }
+}
+
+fn print_progress_line(overall: &ProgressBar, counts: &FileCounts) {
+    let downloaded = overall.position();
+    let total_size = overall.length().unwrap_or(0);
+    let percent = if total_size == 0 {
+        100.0
+    } else {
+        downloaded as f64 / total_size as f64 * 100.0
+    };
+    eprintln!(
+        "Progress: {downloaded}/{total_size} bytes ({percent:.1}%), {counts}, elapsed {}s",
+        overall.elapsed().as_secs()
+    );
+}
+
+fn file_label(file: &Path) -> String {
+    file.file_name()
+        .unwrap_or(file.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+
+    #[tokio::test]
+    async fn a_failed_mirror_falls_back_to_the_next_one() {
+        let temp = tempfile::tempdir().unwrap();
+        let metalink = temp.path().join("test.meta4");
+        std::fs::write(
+            &metalink,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="file.txt">
+    <size>6</size>
+    <pieces length="3" type="sha-256">
+      <hash>ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad</hash>
+      <hash>cb8379ac2098aa165029e3938a51da0bcecfc008fd6795f401178647f96c5b34</hash>
+    </pieces>
+    <url>mock://first/file.txt</url>
+    <url>mock://second/file.txt</url>
+  </file>
+</metalink>"#,
+        )
+        .unwrap();
+        let target_dir = temp.path().join("target");
+        // The first request, the one to the first mirror, fails
+        let mut transports = Transports::default();
+        transports.push(Arc::new(
+            MockTransport::new(b"abcdef").with_failing_range(0, 1),
+        ));
+
+        let report = download_metalink(
+            vec![MetalinkSource::File(metalink)],
+            target_dir.clone(),
+            DownloadMetalinkOptions {
+                verify_chunk_checksums: true,
+                transports,
+                progress: ProgressMode::Quiet,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(report.failure().is_none());
+        assert_eq!(
+            std::fs::read(target_dir.join("file.txt")).unwrap(),
+            b"abcdef"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use crate::http::{make_http_client, HttpOptions, HttpTransport};
+use crate::mirrorlist::{self, Mirror};
+use crate::remote::CACHE_DIR;
+use crate::sums;
+use crate::transport::Transport;
+use crate::types::{CheckSum, SUPPORTED_HASH_TYPES};
+use crate::{MetalinkDownloadError, Result};
+
+use anyhow::{anyhow, Context};
+use iana_registry_enums::HashFunctionTextualName;
+use metalink::{FileBuilder, Hash, Metalink, MetalinkBuilder, Pieces, Size};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+pub async fn generate(
+    dir: PathBuf,
+    base_url: url::Url,
+    mut mirrors: Vec<Mirror>,
+    piece_length: u64,
+    hash_type: HashFunctionTextualName,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    if !SUPPORTED_HASH_TYPES.contains(&hash_type) {
+        return Err(anyhow!("Unsupported hash type: {hash_type}").into());
+    }
+    if piece_length == 0 {
+        return Err(anyhow!("Piece length must be greater than 0").into());
+    }
+
+    mirrors.insert(0, Mirror::new(base_url));
+
+    let metalink = tokio::task::spawn_blocking(move || {
+        let mut files = Vec::new();
+        for path in walk_dir(&dir)? {
+            files.push(generate_file(
+                &dir,
+                &path,
+                &mirrors,
+                piece_length,
+                hash_type,
+            )?);
+        }
+        if files.is_empty() {
+            return Err(MetalinkDownloadError::Other(anyhow!(
+                "No files found in {dir:?}"
+            )));
+        }
+        Ok(MetalinkBuilder::new()
+            .with_generator(concat!("metalink-downloader/", env!("CARGO_PKG_VERSION")))
+            .with_files(files)
+            .build()?)
+    })
+    .await
+    .with_context(|| "Metalink generation task failed")??;
+
+    write(metalink, output)
+}
+
+/// Generate a metalink for the files listed in the checksum file `sums`
+/// without hashing them. The size of a file is taken from the copy in `dir`,
+/// by default the directory of the checksum file, or requested from
+/// `base_url` if there is none. Untagged checksums are of `hash_type`, or
+/// of the type the name of the checksum file suggests.
+pub(crate) async fn generate_from_sums(
+    sums: PathBuf,
+    dir: Option<PathBuf>,
+    base_url: url::Url,
+    mut mirrors: Vec<Mirror>,
+    hash_type: Option<HashFunctionTextualName>,
+    http_options: HttpOptions,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    mirrors.insert(0, Mirror::new(base_url));
+    let content = std::fs::read_to_string(&sums)
+        .with_context(|| format!("Failed to read checksum file {sums:?}"))?;
+    let entries = sums::parse(&content, hash_type.or_else(|| sums::hash_type_of(&sums)))?;
+    if entries.is_empty() {
+        return Err(anyhow!("No files listed in {sums:?}").into());
+    }
+    let dir = dir.unwrap_or_else(|| match sums.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    });
+
+    let transport = HttpTransport::new(make_http_client(&http_options)?);
+    let mut files = Vec::with_capacity(entries.len());
+    for sum in entries {
+        let name = sum.name.trim_start_matches("./");
+        let urls = mirrorlist::file_urls(&mirrors, name)?;
+        let url = urls[0].url();
+        let size = match std::fs::metadata(dir.join(name)) {
+            Ok(metadata) if metadata.is_file() => Some(metadata.len()),
+            _ => {
+                tracing::info!("Requesting the size of {url}");
+                match transport.probe(&url).await {
+                    Ok(probe) => probe.size,
+                    Err(e) => {
+                        tracing::warn!("Failed to request the size of {url}: {e}");
+                        None
+                    }
+                }
+            }
+        };
+        if size.is_none() {
+            tracing::warn!("{name}: size unknown, it is left out of the metalink");
+        }
+        let checksum = sum.checksum;
+        let mut file = FileBuilder::new()
+            .with_name(name)
+            .with_hashes(vec![Hash::new(
+                Some(checksum.hash_type()),
+                checksum.expected(),
+            )])
+            .with_urls(urls);
+        if let Some(size) = size {
+            file = file.with_size(Size::new(size));
+        }
+        files.push(file.build()?);
+    }
+
+    let metalink = MetalinkBuilder::new()
+        .with_generator(concat!("metalink-downloader/", env!("CARGO_PKG_VERSION")))
+        .with_files(files)
+        .build()?;
+    write(metalink, output)
+}
+
+fn write(metalink: Metalink, output: Option<PathBuf>) -> Result<()> {
+    match output {
+        Some(output) => metalink.save_to_file(output)?,
+        None => print!("{}", metalink.to_xml_string()?),
+    }
+    Ok(())
+}
+
+/// Prefix of the state files the downloader keeps in a target directory
+const STATE_FILE_PREFIX: &str = ".metalink-downloader.";
+
+/// Whether `path` is bookkeeping of the downloader rather than a download:
+/// its state files, the cache of fetched metalinks or an unfinished `.part`
+/// file
+fn is_bookkeeping(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    if path.is_dir() {
+        return name == CACHE_DIR;
+    }
+    name.starts_with(STATE_FILE_PREFIX) || name.ends_with(".part")
+}
+
+/// Collect all regular files below `dir` which are not bookkeeping of the
+/// downloader, sorted by path
+fn walk_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in std::fs::read_dir(&current)
+            .with_context(|| format!("Failed to read directory: {current:?}"))?
+        {
+            let path = entry?.path();
+            if is_bookkeeping(&path) {
+                continue;
+            }
+            if path.is_dir() {
+                pending.push(path);
+            } else if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn generate_file(
+    dir: &Path,
+    path: &Path,
+    mirrors: &[Mirror],
+    piece_length: u64,
+    hash_type: HashFunctionTextualName,
+) -> Result<metalink::File> {
+    tracing::info!("Hashing: {path:?}");
+    let name = path
+        .strip_prefix(dir)
+        .map_err(|e| MetalinkDownloadError::Other(e.into()))?
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    let urls = mirrorlist::file_urls(mirrors, &name)?;
+
+    let size = std::fs::metadata(path)?.len();
+    let file_hash = CheckSum::new(hash_type, String::new()).calculate_file_checksum(path)?;
+    let piece_hashes = piece_hashes(path, piece_length, hash_type)?;
+
+    Ok(FileBuilder::new()
+        .with_name(&name)
+        .with_size(Size::new(size))
+        .with_hashes(vec![Hash::new(Some(hash_type), &file_hash)])
+        .with_pieces(Pieces::new(hash_type, piece_length, piece_hashes))
+        .with_urls(urls)
+        .build()?)
+}
+
+fn piece_hashes(
+    path: &Path,
+    piece_length: u64,
+    hash_type: HashFunctionTextualName,
+) -> Result<Vec<Hash>> {
+    let file = std::fs::File::open(path)?;
+    let mut hashes = Vec::new();
+    loop {
+        let mut piece = Vec::new();
+        (&file).take(piece_length).read_to_end(&mut piece)?;
+        if piece.is_empty() {
+            break;
+        }
+        let checksum = CheckSum::calculate(hash_type, &bytes::Bytes::from(piece));
+        hashes.push(Hash::new(None, &checksum));
+    }
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walk_dir_skips_bookkeeping_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::create_dir_all(dir.join(CACHE_DIR)).unwrap();
+        for name in [
+            "a.iso",
+            "sub/b.iso",
+            "sub/c.iso.part",
+            ".metalink-downloader.lock",
+            ".metalink-downloader.verified.json",
+            ".metalink-downloader.verified.json.tmp",
+            ".metalink-cache/remote.meta4",
+        ] {
+            std::fs::write(dir.join(name), b"data").unwrap();
+        }
+
+        assert_eq!(
+            walk_dir(dir).unwrap(),
+            [dir.join("a.iso"), dir.join("sub/b.iso")]
+        );
+    }
+}
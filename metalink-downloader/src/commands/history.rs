@@ -0,0 +1,195 @@
+use crate::history::{self, HistoryEntry};
+use crate::{MetalinkDownloadError, Result};
+
+use chrono::NaiveDate;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Output format of the history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum HistoryFormat {
+    /// One JSON object per line, as recorded
+    Json,
+    Table,
+}
+
+/// Selects the entries of the history to print
+#[derive(Debug, Default)]
+pub(crate) struct HistoryQuery {
+    /// Globs matching the path of the file relative to the target directory
+    pub files: Vec<String>,
+    /// Globs matching the url or host of the mirror
+    pub mirrors: Vec<String>,
+    /// Only downloads completed on or after this day
+    pub since: Option<NaiveDate>,
+}
+
+/// What a mirror delivered according to the history
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct MirrorStats {
+    files: usize,
+    bytes: u64,
+    /// Seconds spent downloading
+    duration: f64,
+    /// Files which were not verified against a checksum
+    unverified: usize,
+}
+
+impl MirrorStats {
+    fn throughput(&self) -> f64 {
+        if self.duration > 0.0 {
+            self.bytes as f64 / self.duration
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Print the downloads recorded in the history of `target_dir` which match
+/// `query`, or what each mirror delivered with `by_mirror`
+pub(crate) async fn history(
+    target_dir: PathBuf,
+    query: HistoryQuery,
+    by_mirror: bool,
+    format: HistoryFormat,
+) -> Result<()> {
+    let files = glob_set(&query.files)?;
+    let mirrors = glob_set(&query.mirrors)?;
+    let since = query.since.map(|day| day.to_string());
+    let entries: Vec<HistoryEntry> = history::read(&target_dir)?
+        .into_iter()
+        .filter(|entry| query.files.is_empty() || files.is_match(&entry.file))
+        .filter(|entry| {
+            query.mirrors.is_empty()
+                || entry.mirror.as_ref().is_some_and(|url| {
+                    mirrors.is_match(url.as_str())
+                        || url.host_str().is_some_and(|host| mirrors.is_match(host))
+                })
+        })
+        // RFC 3339 timestamps in UTC sort like the points in time
+        .filter(|entry| since.as_ref().is_none_or(|since| entry.timestamp >= *since))
+        .collect();
+
+    if by_mirror {
+        let stats = mirror_stats(&entries);
+        match format {
+            HistoryFormat::Json => {
+                for (mirror, stats) in stats {
+                    let line = serde_json::json!({ "mirror": mirror, "stats": stats });
+                    println!("{line}");
+                }
+            }
+            HistoryFormat::Table => {
+                println!(
+                    "{:<40} {:>6} {:>14} {:>12} {:>10}",
+                    "MIRROR", "FILES", "BYTES", "BYTES/S", "UNVERIFIED"
+                );
+                for (mirror, stats) in stats {
+                    println!(
+                        "{mirror:<40} {:>6} {:>14} {:>12.0} {:>10}",
+                        stats.files,
+                        stats.bytes,
+                        stats.throughput(),
+                        stats.unverified
+                    );
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    match format {
+        HistoryFormat::Json => {
+            for entry in &entries {
+                println!(
+                    "{}",
+                    serde_json::to_string(entry).map_err(anyhow::Error::from)?
+                );
+            }
+        }
+        HistoryFormat::Table => {
+            for entry in &entries {
+                println!(
+                    "{}  {:>12}  {:>7.1}s  {:<10}  {}  {}",
+                    entry.timestamp,
+                    entry.size,
+                    entry.duration,
+                    entry
+                        .verified_checksum
+                        .as_deref()
+                        .and_then(|checksum| checksum.split_once(':'))
+                        .map_or("unverified", |(hash_type, _)| hash_type),
+                    entry.file.display(),
+                    entry.mirror.as_ref().map_or("local", |url| url.as_str()),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What each mirror host delivered, files copied from local sources are
+/// counted as `local`
+fn mirror_stats(entries: &[HistoryEntry]) -> BTreeMap<String, MirrorStats> {
+    let mut stats: BTreeMap<String, MirrorStats> = BTreeMap::new();
+    for entry in entries {
+        let mirror = entry
+            .mirror
+            .as_ref()
+            .and_then(|url| url.host_str())
+            .unwrap_or("local");
+        let stats = stats.entry(mirror.to_owned()).or_default();
+        stats.files += 1;
+        stats.bytes += entry.size;
+        stats.duration += entry.duration;
+        if entry.verified_checksum.is_none() {
+            stats.unverified += 1;
+        }
+    }
+    stats
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).map_err(|e| MetalinkDownloadError::Other(e.into()))?);
+    }
+    builder
+        .build()
+        .map_err(|e| MetalinkDownloadError::Other(e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirrors_are_summarized_by_host() {
+        let entry = |mirror: Option<&str>, size, verified: bool| HistoryEntry {
+            timestamp: "2026-10-16T12:00:00Z".to_owned(),
+            file: PathBuf::from("file.iso"),
+            size,
+            verified_checksum: verified.then(|| "sha-256:00".to_owned()),
+            mirror: mirror.map(|url| url::Url::parse(url).unwrap()),
+            duration: 2.0,
+        };
+        let stats = mirror_stats(&[
+            entry(Some("https://a.example/x.iso"), 100, true),
+            entry(Some("https://a.example/y.iso"), 300, false),
+            entry(None, 50, true),
+        ]);
+        assert_eq!(
+            stats["a.example"],
+            MirrorStats {
+                files: 2,
+                bytes: 400,
+                duration: 4.0,
+                unverified: 1,
+            }
+        );
+        assert_eq!(stats["a.example"].throughput(), 100.0);
+        assert_eq!(stats["local"].files, 1);
+    }
+}
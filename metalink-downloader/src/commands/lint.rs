@@ -0,0 +1,250 @@
+use crate::selection::is_insecure;
+use crate::transport::Transports;
+use crate::types::{SUPPORTED_HASH_TYPES, WEAK_HASH_TYPES};
+use crate::Result;
+
+use anyhow::anyhow;
+use iana_registry_enums::HashFunctionTextualName;
+use metalink::Metalink;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+/// Findings which fail the lint in addition to errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LintDeny {
+    Warnings,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Warning => write!(f, "warning"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem of a metalink found by the lint
+#[derive(Debug, PartialEq)]
+struct Finding {
+    severity: Severity,
+    message: String,
+}
+
+impl Finding {
+    fn error(message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            message,
+        }
+    }
+
+    fn warning(message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message,
+        }
+    }
+}
+
+/// Check `metalink_file` for violations of the specification and for what
+/// makes its files hard to download or verify. Urls of schemes none of the
+/// built-in and registered `transports` fetches are reported. Prints every
+/// finding and fails if there are errors, or warnings with `deny`.
+pub(crate) async fn lint(
+    metalink_file: PathBuf,
+    deny: Option<LintDeny>,
+    transports: &Transports,
+) -> Result<()> {
+    let mut found = Vec::new();
+    let metalink = match Metalink::load_from_file(&metalink_file) {
+        Ok(metalink) => metalink,
+        Err(strict) => {
+            let metalink = Metalink::load_from_file_lenient(&metalink_file)?;
+            found.push(Finding::error(format!(
+                "only parses in lenient mode: {:#}",
+                anyhow::Error::from(strict)
+            )));
+            metalink
+        }
+    };
+    found.extend(findings(&metalink, transports));
+
+    let count = |severity| {
+        found
+            .iter()
+            .filter(|finding| finding.severity == severity)
+            .count()
+    };
+    let (errors, warnings) = (count(Severity::Error), count(Severity::Warning));
+    for Finding { severity, message } in &found {
+        println!("{severity}: {message}");
+    }
+    if errors > 0 || (deny == Some(LintDeny::Warnings) && warnings > 0) {
+        return Err(anyhow!("{metalink_file:?}: {errors} errors, {warnings} warnings").into());
+    }
+    println!("{metalink_file:?}: {errors} errors, {warnings} warnings");
+    Ok(())
+}
+
+fn findings(metalink: &Metalink, transports: &Transports) -> Vec<Finding> {
+    let mut found: Vec<Finding> = metalink
+        .violations()
+        .into_iter()
+        .map(Finding::error)
+        .collect();
+
+    for file in metalink.files() {
+        let name = file.name();
+        if file.urls().is_none_or(Vec::is_empty) && file.meta_urls().is_none_or(Vec::is_empty) {
+            found.push(Finding::error(format!("file {name}: no urls or metaurls")));
+        }
+
+        let hash_types: Vec<HashFunctionTextualName> = file
+            .hashes()
+            .into_iter()
+            .flatten()
+            .filter_map(|hash| hash.hash_type())
+            .chain(file.pieces().map(|pieces| pieces.hash_type()))
+            .filter(|hash_type| SUPPORTED_HASH_TYPES.contains(hash_type))
+            .collect();
+        if hash_types.is_empty() {
+            found.push(Finding::warning(format!(
+                "file {name}: no supported hashes, it can not be verified"
+            )));
+        } else if hash_types
+            .iter()
+            .all(|hash_type| WEAK_HASH_TYPES.contains(hash_type))
+        {
+            found.push(Finding::warning(format!(
+                "file {name}: only weak hashes ({})",
+                hash_types
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+
+        if file.pieces().is_some() && file.size().is_none() {
+            found.push(Finding::warning(format!(
+                "file {name}: pieces without a size, the last piece can not be located"
+            )));
+        }
+
+        let mut seen = HashSet::new();
+        for file_url in file.urls().into_iter().flatten() {
+            let url = file_url.url();
+            if !seen.insert(url.clone()) {
+                found.push(Finding::warning(format!(
+                    "file {name}: url {url} is listed more than once"
+                )));
+            } else if !matches!(url.scheme(), "http" | "https" | "file")
+                && transports.find(&url).is_none()
+            {
+                found.push(Finding::warning(format!(
+                    "file {name}: url {url} can not be fetched, no transport supports {}",
+                    url.scheme()
+                )));
+            } else if is_insecure(&url) {
+                found.push(Finding::warning(format!(
+                    "file {name}: url {url} is not https"
+                )));
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn findings_cover_hashes_sizes_and_urls() {
+        const METALINK: &str = r#"
+            <metalink>
+                <file name="weak">
+                    <hash type="md5">d41d8cd98f00b204e9800998ecf8427e</hash>
+                    <size>0</size>
+                    <url>https://a.example/weak</url>
+                    <url>http://b.example/weak</url>
+                    <url>https://a.example/weak</url>
+                </file>
+                <file name="pieces">
+                    <pieces type="sha-256" length="1024">
+                        <hash>e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b85</hash>
+                    </pieces>
+                    <url>ftp://c.example/pieces</url>
+                </file>
+                <file name="ok">
+                    <hash type="sha-256">e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855</hash>
+                    <size>0</size>
+                    <url>https://a.example/ok</url>
+                </file>
+            </metalink>
+        "#;
+        let metalink = Metalink::try_from(METALINK).unwrap();
+        let messages: Vec<(Severity, String)> = findings(&metalink, &Transports::default())
+            .into_iter()
+            .map(|finding| (finding.severity, finding.message))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (
+                    Severity::Error,
+                    "file pieces: sha-256 hash of piece 0 has an invalid length".to_owned()
+                ),
+                (
+                    Severity::Warning,
+                    "file weak: only weak hashes (md5)".to_owned()
+                ),
+                (
+                    Severity::Warning,
+                    "file weak: url http://b.example/weak is not https".to_owned()
+                ),
+                (
+                    Severity::Warning,
+                    "file weak: url https://a.example/weak is listed more than once".to_owned()
+                ),
+                (
+                    Severity::Warning,
+                    "file pieces: pieces without a size, the last piece can not be located"
+                        .to_owned()
+                ),
+                (
+                    Severity::Warning,
+                    "file pieces: url ftp://c.example/pieces can not be fetched, no transport supports ftp"
+                        .to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn names_leaving_the_directory_are_errors() {
+        const METALINK: &str = r#"
+            <metalink>
+                <file name="../../.bashrc">
+                    <hash type="sha-256">e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855</hash>
+                    <url>https://a.example/bashrc</url>
+                </file>
+            </metalink>
+        "#;
+        let metalink = Metalink::try_from(METALINK).unwrap();
+        assert_eq!(
+            findings(&metalink, &Transports::default()),
+            [Finding::error(
+                "file ../../.bashrc: name is not a relative path inside the download directory"
+                    .to_owned()
+            )]
+        );
+    }
+}
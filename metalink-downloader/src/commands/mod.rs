@@ -1,7 +1,34 @@
+mod cross_check;
+mod daemon;
 mod download_file;
 mod download_metalink;
+mod generate;
+mod history;
+mod lint;
 mod plan;
+mod plan_diff;
+mod probe;
+mod repair;
+mod replay;
+mod sign;
+mod verify;
 
-pub use download_file::download_file;
-pub use download_metalink::download_metalink;
-pub use plan::plan;
+pub(crate) use cross_check::cross_check;
+pub(crate) use daemon::{daemon, default_state_file, job};
+pub use download_file::{download_file, DownloadFileOptions};
+pub use download_metalink::{download_metalink, DownloadMetalinkOptions};
+pub use generate::generate;
+pub(crate) use generate::generate_from_sums;
+pub use history::HistoryFormat;
+pub(crate) use history::{history, HistoryQuery};
+pub(crate) use lint::lint;
+pub use lint::LintDeny;
+pub use plan::PlanFormat;
+pub(crate) use plan::{plan, print_plan, PlanMode};
+pub(crate) use plan_diff::plan_diff;
+pub(crate) use probe::probe;
+pub use repair::repair;
+pub use replay::replay;
+pub(crate) use sign::detached_signature;
+pub use sign::sign;
+pub(crate) use verify::verify;
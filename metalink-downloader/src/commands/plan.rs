@@ -1,15 +1,326 @@
-use crate::types::Plan;
+use crate::selection::{FileFilter, Layout, MirrorSelection, RefreshSelection};
+use crate::types::{FilePlan, Plan};
+use crate::verification_cache::VerificationCache;
 use crate::Result;
 
-use log::info;
+use anyhow::anyhow;
+use metalink::Metalink;
+use serde::Serialize;
 use std::path::PathBuf;
+use tracing::info;
 
-pub async fn plan(metalink_file: PathBuf, target_dir: PathBuf) -> Result<()> {
+/// What the plan command prints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PlanMode {
+    /// The full and the minimized plan
+    Print,
+    /// The files that would be downloaded as JSON
+    Check,
+    /// The files of the metalink and whether they are selected
+    List,
+    /// The minimized plan in the given format
+    Report(PlanFormat),
+}
+
+impl PlanMode {
+    pub(crate) fn from_flags(check: bool, list: bool, format: Option<PlanFormat>) -> Self {
+        match (check, list, format) {
+            (true, _, _) => Self::Check,
+            (_, true, _) => Self::List,
+            (_, _, Some(format)) => Self::Report(format),
+            _ => Self::Print,
+        }
+    }
+}
+
+pub(crate) async fn plan(
+    metalink_file: PathBuf,
+    target_dir: PathBuf,
+    mode: PlanMode,
+    filter: FileFilter,
+) -> Result<()> {
     info!("File: {metalink_file:?}, Target: {target_dir:?}");
-    let plan = Plan::new(metalink_file, &target_dir)?;
+    if mode == PlanMode::List {
+        return list_files(metalink_file, &filter);
+    }
+    let plan = Plan::new(
+        metalink_file,
+        &target_dir,
+        &filter,
+        &MirrorSelection::default(),
+        &Layout::default(),
+    )?;
+    match mode {
+        PlanMode::Check => return check_plan(plan),
+        PlanMode::Report(format) => {
+            return print_plan(
+                plan.minimize_plan(
+                    &RefreshSelection::default(),
+                    &VerificationCache::load(&target_dir),
+                    true,
+                )?,
+                format,
+            )
+        }
+        _ => {}
+    }
     println!("{plan:#?}");
 
-    let minimized_plan = plan.minimize_plan()?;
+    let minimized_plan = plan.minimize_plan(
+        &RefreshSelection::default(),
+        &VerificationCache::load(&target_dir),
+        true,
+    )?;
     println!("{minimized_plan:#?}");
     Ok(())
 }
+
+/// Print every file of the metalink with its size, os and language, selected
+/// files are marked with `*`
+fn list_files(metalink_file: PathBuf, filter: &FileFilter) -> Result<()> {
+    let metalink = Metalink::load_from_file_lenient(metalink_file)?;
+    for file in metalink.files() {
+        let oses: Vec<String> = file
+            .oses()
+            .into_iter()
+            .flatten()
+            .map(|os| os.name().to_string())
+            .collect();
+        let languages: Vec<&str> = file
+            .languages()
+            .into_iter()
+            .flatten()
+            .map(|language| language.language())
+            .collect();
+        println!(
+            "{} {}\t{}\tos: {}\tlanguage: {}",
+            if filter.matches_file(file) { '*' } else { ' ' },
+            file.name(),
+            file.size()
+                .map_or_else(|| "unknown size".to_owned(), |size| size.size().to_string()),
+            if oses.is_empty() {
+                "any".to_owned()
+            } else {
+                oses.join(",")
+            },
+            if languages.is_empty() {
+                "any".to_owned()
+            } else {
+                languages.join(",")
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Output format of a plan
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PlanFormat {
+    Json,
+    Yaml,
+    Table,
+}
+
+/// Version of the schema of `plan --format json` and `--dry-run --format json`,
+/// bumped on incompatible changes
+const PLAN_SCHEMA_VERSION: u32 = 1;
+
+/// A minimized plan as printed for scripting
+#[derive(Debug, Serialize)]
+struct PlanReport {
+    schema_version: u32,
+    /// Bytes that would be downloaded
+    total_bytes: u64,
+    files: Vec<Drift>,
+    /// Files created from another file with the same content
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    duplicates: Vec<DuplicateReport>,
+}
+
+/// A file created from another file of the plan instead of downloading it
+#[derive(Debug, Serialize)]
+struct DuplicateReport {
+    name: String,
+    target_file: PathBuf,
+    original: PathBuf,
+}
+
+/// Print the files of a minimized plan in the given format
+pub(crate) fn print_plan(plan: Plan, format: PlanFormat) -> Result<()> {
+    let report = PlanReport {
+        schema_version: PLAN_SCHEMA_VERSION,
+        total_bytes: plan.total_size,
+        files: plan.files.into_iter().map(Drift::new).collect(),
+        duplicates: plan
+            .duplicates
+            .into_iter()
+            .map(|duplicate| DuplicateReport {
+                name: duplicate.file.name,
+                target_file: duplicate.file.target_file,
+                original: duplicate.original,
+            })
+            .collect(),
+    };
+    match format {
+        PlanFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report).map_err(|e| anyhow!(e))?
+        ),
+        PlanFormat::Yaml => print!(
+            "{}",
+            serde_yaml::to_string(&report).map_err(|e| anyhow!(e))?
+        ),
+        PlanFormat::Table => {
+            println!(
+                "{:<40} {:<17} {:>8} {:>14}  URL",
+                "NAME", "REASON", "CHUNKS", "BYTES"
+            );
+            for file in &report.files {
+                println!(
+                    "{:<40} {:<17} {:>8} {:>14}  {}",
+                    file.name,
+                    file.reason.to_string(),
+                    file.chunks,
+                    file.bytes,
+                    file.urls
+                        .first()
+                        .or(file.metaurls.first())
+                        .map_or("-", url::Url::as_str),
+                );
+            }
+            for duplicate in &report.duplicates {
+                println!(
+                    "{:<40} {:<17} {:>8} {:>14}  {}",
+                    duplicate.name,
+                    "duplicate",
+                    0,
+                    0,
+                    duplicate.original.display()
+                );
+            }
+            println!(
+                "{} files, {} bytes to fetch",
+                report.files.len(),
+                report.total_bytes
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Why a file of the metalink would be downloaded
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DriftReason {
+    /// The file does not exist
+    Missing,
+    /// Some pieces of the file do not match their checksums
+    InvalidPieces,
+    /// The file does not match its file checksum
+    ChecksumMismatch,
+    /// The file has no checksums and can not be verified
+    Unverifiable,
+}
+
+impl std::fmt::Display for DriftReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            Self::Missing => "missing",
+            Self::InvalidPieces => "invalid_pieces",
+            Self::ChecksumMismatch => "checksum_mismatch",
+            Self::Unverifiable => "unverifiable",
+        };
+        write!(f, "{reason}")
+    }
+}
+
+/// A file that does not satisfy the metalink
+#[derive(Debug, Serialize)]
+pub(crate) struct Drift {
+    pub name: String,
+    target_file: PathBuf,
+    pub reason: DriftReason,
+    /// Urls the file can be downloaded from, the first one is used
+    urls: Vec<url::Url>,
+    /// Metaurls the file can be fetched from if downloading from the urls fails
+    metaurls: Vec<url::Url>,
+    /// Size of the file if known
+    size: Option<u64>,
+    /// Number of chunks that would be downloaded, 1 for files downloaded as a whole
+    chunks: usize,
+    /// Byte ranges that would be downloaded, inclusive
+    ranges: Vec<(u64, u64)>,
+    pub bytes: u64,
+}
+
+impl Drift {
+    fn new(file: FilePlan) -> Self {
+        let reason = if !file.target_file.exists() {
+            DriftReason::Missing
+        } else if file.chunks.is_some() {
+            DriftReason::InvalidPieces
+        } else if file.file_checksums.is_some() {
+            DriftReason::ChecksumMismatch
+        } else {
+            DriftReason::Unverifiable
+        };
+        let ranges: Vec<(u64, u64)> = match (&reason, &file.chunks) {
+            (DriftReason::Missing, _) | (_, None) => file
+                .file_size
+                .filter(|size| *size > 0)
+                .map(|size| vec![(0, size - 1)])
+                .unwrap_or_default(),
+            (_, Some(chunks)) => chunks
+                .iter()
+                .map(|chunk| (chunk.start, chunk.end))
+                .collect(),
+        };
+        let chunks = match (&reason, &file.chunks) {
+            (DriftReason::Missing, _) | (_, None) => 1,
+            (_, Some(chunks)) => chunks.len(),
+        };
+        Self {
+            name: file.name,
+            target_file: file.target_file,
+            reason,
+            urls: file.urls,
+            metaurls: file
+                .metaurls
+                .iter()
+                .map(|metaurl| metaurl.url().clone())
+                .collect(),
+            size: file.file_size,
+            chunks,
+            bytes: ranges.iter().map(|(start, end)| end - start + 1).sum(),
+            ranges,
+        }
+    }
+}
+
+/// Files of `plan` that do not satisfy their metalink, every file is hashed
+/// regardless of earlier verifications
+pub(crate) fn drift(plan: Plan) -> Result<Vec<Drift>> {
+    Ok(plan
+        .minimize_plan(
+            &RefreshSelection::default(),
+            &VerificationCache::in_memory(),
+            true,
+        )?
+        .files
+        .into_iter()
+        .map(Drift::new)
+        .collect())
+}
+
+/// Print the files which would need downloading as JSON, fails if there are any
+fn check_plan(plan: Plan) -> Result<()> {
+    let drift = drift(plan)?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&drift).map_err(|e| anyhow!(e))?
+    );
+    if !drift.is_empty() {
+        return Err(anyhow!("{} files do not satisfy the metalink", drift.len()).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use crate::commands::cross_check::{compare_metalinks, MetadataDiscrepancy};
+use crate::commands::plan::{drift, Drift};
+use crate::selection::{FileFilter, Layout, MirrorSelection};
+use crate::types::Plan;
+use crate::Result;
+
+use anyhow::anyhow;
+use metalink::Metalink;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use tracing::info;
+
+/// How a file changed from the old to the new metalink
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Change {
+    /// The file is only listed in the new metalink
+    Added,
+    /// The file is only listed in the old metalink, a sync keeps it on disk
+    Removed,
+    /// The metalinks describe the file differently
+    Changed,
+    Unchanged,
+}
+
+/// A file which changed between the metalinks or would be transferred
+#[derive(Debug, Serialize)]
+struct FileDiff {
+    name: String,
+    change: Change,
+    /// How the descriptions of a changed file differ, `metalink` is the old
+    /// and `against` the new description
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    metadata: Vec<MetadataDiscrepancy>,
+    /// What a sync to the new metalink would download of the file
+    transfer: Option<Drift>,
+}
+
+#[derive(Debug, Serialize)]
+struct PlanDiff {
+    files: Vec<FileDiff>,
+    files_to_transfer: usize,
+    bytes_to_transfer: u64,
+}
+
+/// Report which files changed from `old` to `new` and which files and bytes
+/// a sync of `target_dir` to `new` would transfer, without modifying the
+/// tree. Files which are unchanged and satisfy `new` on disk are left out.
+pub(crate) async fn plan_diff(
+    old: PathBuf,
+    new: PathBuf,
+    target_dir: PathBuf,
+    filter: FileFilter,
+) -> Result<()> {
+    info!("Old: {old:?}, New: {new:?}, Target: {target_dir:?}");
+    let mut metadata = compare_metalinks(
+        &Metalink::load_from_file_lenient(old)?,
+        &Metalink::load_from_file_lenient(new.clone())?,
+        &filter,
+    );
+    // Nothing is downloaded, plain http mirrors do not need to be skipped
+    let mirrors = MirrorSelection::default().with_http(true);
+    let mut transfers: BTreeMap<String, Drift> = drift(Plan::new(
+        new,
+        &target_dir,
+        &filter,
+        &mirrors,
+        &Layout::default(),
+    )?)?
+    .into_iter()
+    .map(|drift| (drift.name.clone(), drift))
+    .collect();
+
+    let names: BTreeSet<String> = metadata.keys().chain(transfers.keys()).cloned().collect();
+    let files: Vec<FileDiff> = names
+        .into_iter()
+        .map(|name| {
+            let metadata = metadata.remove(&name).unwrap_or_default();
+            let change = match metadata.first() {
+                None => Change::Unchanged,
+                Some(MetadataDiscrepancy::OnlyInMetalink) => Change::Removed,
+                Some(MetadataDiscrepancy::OnlyInAgainst) => Change::Added,
+                Some(_) => Change::Changed,
+            };
+            FileDiff {
+                transfer: transfers.remove(&name),
+                // Whether a file was added or removed says it all
+                metadata: match change {
+                    Change::Changed => metadata,
+                    _ => Vec::new(),
+                },
+                change,
+                name,
+            }
+        })
+        .collect();
+
+    let transferred = files.iter().filter_map(|file| file.transfer.as_ref());
+    let diff = PlanDiff {
+        files_to_transfer: transferred.clone().count(),
+        bytes_to_transfer: transferred.map(|drift| drift.bytes).sum(),
+        files,
+    };
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&diff).map_err(|e| anyhow!(e))?
+    );
+    Ok(())
+}
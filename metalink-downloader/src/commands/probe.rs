@@ -0,0 +1,257 @@
+use crate::http::{
+    content_length, local_path, make_http_client, request_range, Client, HttpOptions,
+};
+use crate::selection::FileFilter;
+use crate::transport::Transports;
+use crate::Result;
+
+use anyhow::anyhow;
+use futures::StreamExt;
+use metalink::Metalink;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// What probing a url of the metalink found out
+#[derive(Debug)]
+struct UrlProbe {
+    file: String,
+    url: url::Url,
+    /// Status of the HEAD request, None for urls not fetched over http
+    status: Option<reqwest::StatusCode>,
+    /// Time the requests took
+    latency: Duration,
+    /// Size reported by the mirror
+    size: Option<u64>,
+    /// Size declared in the metalink
+    declared_size: Option<u64>,
+    /// None if the mirror does not tell
+    accepts_ranges: Option<bool>,
+    error: Option<String>,
+}
+
+impl UrlProbe {
+    fn new(file: &metalink::File, url: url::Url) -> Self {
+        Self {
+            file: file.name().clone(),
+            url,
+            status: None,
+            latency: Duration::ZERO,
+            size: None,
+            declared_size: file.size().map(|size| size.size()),
+            accepts_ranges: None,
+            error: None,
+        }
+    }
+
+    /// Why the url can not be downloaded from, None if it can
+    fn problem(&self) -> Option<String> {
+        if let Some(error) = &self.error {
+            return Some(error.clone());
+        }
+        if let Some(status) = self.status.filter(|status| !status.is_success()) {
+            return Some(format!("status {status}"));
+        }
+        match (self.size, self.declared_size) {
+            (Some(size), Some(declared)) if size != declared => Some(format!(
+                "size {size} differs from the declared size {declared}"
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// How the urls of a mirror host fared
+#[derive(Debug, Default)]
+struct MirrorHealth {
+    urls: usize,
+    healthy: usize,
+    latency: Duration,
+    /// Urls which do not accept range requests
+    no_ranges: usize,
+}
+
+/// Request every url of the files of `metalink_file` selected by `filter`,
+/// at most `max_requests` at the same time. Prints the status, latency,
+/// size and range support of every url and a summary per mirror host, and
+/// fails if any url can not be downloaded from.
+pub(crate) async fn probe(
+    metalink_file: PathBuf,
+    filter: FileFilter,
+    http_options: HttpOptions,
+    max_requests: usize,
+    transports: &Transports,
+) -> Result<()> {
+    info!("File: {metalink_file:?}");
+    let metalink = Metalink::load_from_file_lenient(metalink_file)?;
+    // Nothing is downloaded, plain http mirrors are probed as well
+    let client = make_http_client(&HttpOptions {
+        allow_http: true,
+        ..http_options
+    })?;
+
+    let urls: Vec<UrlProbe> = metalink
+        .files()
+        .iter()
+        .filter(|file| filter.matches_file(file))
+        .flat_map(|file| {
+            file.urls()
+                .into_iter()
+                .flatten()
+                .map(|file_url| UrlProbe::new(file, file_url.url()))
+        })
+        .collect();
+    let probes: Vec<UrlProbe> = futures::stream::iter(urls)
+        .map(|url| probe_url(&client, transports, url))
+        .buffered(max_requests.max(1))
+        .collect()
+        .await;
+
+    let mut mirrors: BTreeMap<String, MirrorHealth> = BTreeMap::new();
+    println!(
+        "{:<6} {:>9} {:>14} {:<6} URL",
+        "STATUS", "LATENCY", "SIZE", "RANGES"
+    );
+    for probe in &probes {
+        let problem = probe.problem();
+        println!(
+            "{:<6} {:>8.0}ms {:>14} {:<6} {}{}",
+            probe
+                .status
+                .map_or_else(|| "-".to_owned(), |status| status.as_u16().to_string()),
+            probe.latency.as_secs_f64() * 1000.0,
+            probe
+                .size
+                .map_or_else(|| "unknown".to_owned(), |size| size.to_string()),
+            match probe.accepts_ranges {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => "?",
+            },
+            probe.url,
+            problem.as_ref().map_or_else(String::new, |problem| format!(
+                "  ({}: {problem})",
+                probe.file
+            )),
+        );
+        let mirror = mirrors
+            .entry(probe.url.host_str().unwrap_or("local").to_owned())
+            .or_default();
+        mirror.urls += 1;
+        mirror.latency += probe.latency;
+        if problem.is_none() {
+            mirror.healthy += 1;
+        }
+        if probe.accepts_ranges == Some(false) {
+            mirror.no_ranges += 1;
+        }
+    }
+
+    println!();
+    println!(
+        "{:<40} {:>6} {:>8} {:>12} {:>9}",
+        "MIRROR", "URLS", "HEALTHY", "AVG LATENCY", "NO RANGES"
+    );
+    for (host, mirror) in &mirrors {
+        println!(
+            "{host:<40} {:>6} {:>8} {:>10.0}ms {:>9}",
+            mirror.urls,
+            mirror.healthy,
+            mirror.latency.as_secs_f64() * 1000.0 / mirror.urls as f64,
+            mirror.no_ranges,
+        );
+    }
+
+    let unhealthy = probes
+        .iter()
+        .filter(|probe| probe.problem().is_some())
+        .count();
+    if unhealthy > 0 {
+        return Err(anyhow!("{unhealthy} of {} urls are unhealthy", probes.len()).into());
+    }
+    Ok(())
+}
+
+async fn probe_url(client: &Client, transports: &Transports, mut probe: UrlProbe) -> UrlProbe {
+    let started = Instant::now();
+    if let Some(transport) = transports.find(&probe.url) {
+        match transport.probe(&probe.url).await {
+            Ok(found) => {
+                probe.size = found.size;
+                probe.accepts_ranges = Some(found.accepts_ranges);
+            }
+            Err(e) => probe.error = Some(e.to_string()),
+        }
+    } else if let Some(path) = local_path(&probe.url) {
+        match std::fs::metadata(&path) {
+            Ok(metadata) => {
+                probe.size = Some(metadata.len());
+                probe.accepts_ranges = Some(true);
+            }
+            Err(e) => probe.error = Some(format!("{path:?}: {e}")),
+        }
+    } else if matches!(probe.url.scheme(), "http" | "https") {
+        if let Err(e) = head(client, &mut probe).await {
+            probe.error = Some(e.to_string());
+        }
+    } else {
+        probe.error = Some(format!("no transport supports {}", probe.url.scheme()));
+    }
+    probe.latency = started.elapsed();
+    probe
+}
+
+/// Fill in `probe` from a HEAD request, falling back to requesting the first
+/// byte if the mirror does not advertise whether it accepts ranges
+async fn head(client: &Client, probe: &mut UrlProbe) -> Result<()> {
+    let response = client.head(probe.url.clone()).send().await?;
+    probe.status = Some(response.status());
+    if !response.status().is_success() {
+        return Ok(());
+    }
+    probe.size = content_length(response.headers())?;
+    probe.accepts_ranges = match response.headers().get(reqwest::header::ACCEPT_RANGES) {
+        Some(value) if value.as_bytes().eq_ignore_ascii_case(b"bytes") => Some(true),
+        Some(value) if value.as_bytes().eq_ignore_ascii_case(b"none") => Some(false),
+        _ => None,
+    };
+    if probe.accepts_ranges.is_none() {
+        let response = request_range(client, &probe.url, 0, 0).await?;
+        probe.accepts_ranges = match response.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT => Some(true),
+            reqwest::StatusCode::OK => Some(false),
+            _ => None,
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_with_errors_or_size_mismatches_are_unhealthy() {
+        let probe = |status: u16, size, declared_size| UrlProbe {
+            file: "file".to_owned(),
+            url: url::Url::parse("https://a.example/file").unwrap(),
+            status: Some(reqwest::StatusCode::from_u16(status).unwrap()),
+            latency: Duration::from_millis(20),
+            size,
+            declared_size,
+            accepts_ranges: Some(true),
+            error: None,
+        };
+        assert_eq!(probe(200, Some(10), Some(10)).problem(), None);
+        assert_eq!(probe(200, None, Some(10)).problem(), None);
+        assert_eq!(
+            probe(404, None, Some(10)).problem().as_deref(),
+            Some("status 404 Not Found")
+        );
+        assert_eq!(
+            probe(200, Some(9), Some(10)).problem().as_deref(),
+            Some("size 9 differs from the declared size 10")
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use crate::control::Pause;
+use crate::http::{download, make_http_client, HttpOptions, HttpTransport, PieceFetch};
+use crate::lock::{lock_target_dir, LockMode};
+use crate::selection::{FileFilter, Layout, MirrorSelection};
+use crate::types::{invalid_chunks_on_disk, ChunkMetaData, Plan};
+use crate::{MetalinkDownloadError, Result};
+
+use anyhow::Context;
+use std::path::PathBuf;
+
+pub async fn repair(
+    metalink_file: PathBuf,
+    target_dir: PathBuf,
+    http: HttpOptions,
+    mirrors: MirrorSelection,
+    dry_run: bool,
+    lock: LockMode,
+    keep_going: bool,
+) -> Result<()> {
+    tracing::info!("==========Start Metalink Repair==========");
+    let _lock = lock_target_dir(&target_dir, lock).await?;
+    let plan = Plan::new(
+        metalink_file,
+        &target_dir,
+        &FileFilter::default(),
+        &mirrors,
+        &Layout::default(),
+    )?;
+    let transport = HttpTransport::new(make_http_client(&http)?);
+
+    let total = plan.files.len();
+    let mut failures: Vec<MetalinkDownloadError> = Vec::new();
+    for file in plan.files {
+        let Some(chunks) = file.chunks else {
+            println!("{:?}: no pieces in metalink, skipped", file.target_file);
+            continue;
+        };
+        if !file.target_file.exists() {
+            println!("{:?}: missing, skipped", file.target_file);
+            continue;
+        }
+
+        let bad_chunks = invalid_chunks_on_disk(chunks, &file.target_file)?.to_vec();
+        if bad_chunks.is_empty() {
+            println!("{:?}: ok", file.target_file);
+            continue;
+        }
+
+        println!(
+            "{:?}: {} corrupt pieces in ranges {}",
+            file.target_file,
+            bad_chunks.len(),
+            format_ranges(&bad_chunks)
+        );
+        if dry_run {
+            continue;
+        }
+
+        let Some(url) = file.url.clone() else {
+            println!("{:?}: no url to repair from, skipped", file.target_file);
+            continue;
+        };
+        let repaired = download(
+            &transport,
+            url,
+            file.target_file.clone(),
+            &bad_chunks,
+            None,
+            PieceFetch {
+                verify_checksums: true,
+                max_request_size: http.max_request_size,
+            },
+            &Pause::default(),
+        )
+        .await
+        .with_context(|| format!("Repair of {:?} failed", file.target_file));
+        match repaired {
+            Ok(()) => println!("{:?}: repaired", file.target_file),
+            Err(e) if keep_going => {
+                println!("{:?}: {e:#}", file.target_file);
+                failures.push(e.into());
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if failures.is_empty() {
+        return Ok(());
+    }
+    Err(MetalinkDownloadError::FilesFailed {
+        failed: failures.len(),
+        total,
+        verification_failed: failures.iter().any(|e| e.is_verification_failure()),
+        network_failed: failures.iter().all(|e| e.is_network_failure()),
+    })
+}
+
+/// Format chunks as a list of byte ranges, merging adjacent chunks
+fn format_ranges(chunks: &[ChunkMetaData]) -> String {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for chunk in chunks {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == chunk.start => *end = chunk.end,
+            _ => ranges.push((chunk.start, chunk.end)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| format!("{start}-{end}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,22 @@
+use crate::replay::{load, replay as replay_events, reproduce};
+use crate::Result;
+
+use anyhow::anyhow;
+use std::path::PathBuf;
+
+pub async fn replay(log: PathBuf) -> Result<()> {
+    let events = load(&log)?;
+    let mut anomalies = replay_events(&events);
+    anomalies.extend(reproduce(&events).await?);
+    println!("Replayed {} events from {log:?}", events.len());
+
+    if anomalies.is_empty() {
+        println!("No anomalies detected");
+        return Ok(());
+    }
+
+    for anomaly in &anomalies {
+        println!("  {anomaly}");
+    }
+    Err(anyhow!("{} anomalies detected in replay log", anomalies.len()).into())
+}
@@ -0,0 +1,64 @@
+use crate::selection::Layout;
+use crate::{MetalinkDownloadError, Result};
+
+use anyhow::{anyhow, Context};
+use metalink::{Metalink, Signature};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Media type of ASCII armored detached OpenPGP signatures
+const PGP_SIGNATURE: &str = "application/pgp-signature";
+
+pub async fn sign(
+    metalink_file: PathBuf,
+    dir: PathBuf,
+    key: String,
+    gpg: PathBuf,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let mut metalink = Metalink::load_from_file(&metalink_file)?;
+    let media_type: mime::Mime = PGP_SIGNATURE
+        .parse()
+        .map_err(|e: mime::FromStrError| MetalinkDownloadError::Other(e.into()))?;
+
+    for file in metalink.files_mut() {
+        let path = dir.join(Layout::default().relative_path(file.name())?);
+        tracing::info!("Signing: {path:?}");
+        let signature = tokio::task::spawn_blocking({
+            let gpg = gpg.clone();
+            let key = key.clone();
+            move || detached_signature(&gpg, &key, &path)
+        })
+        .await
+        .with_context(|| "Signing task failed")??;
+        file.set_signature(Signature::new(media_type.clone(), &signature));
+    }
+
+    metalink.save_to_file(output.unwrap_or(metalink_file))?;
+    Ok(())
+}
+
+/// Create an ASCII armored detached signature of `path` with gpg
+pub(crate) fn detached_signature(gpg: &Path, key: &str, path: &Path) -> Result<String> {
+    let output = Command::new(gpg)
+        .args([
+            "--batch",
+            "--armor",
+            "--detach-sign",
+            "--local-user",
+            key,
+            "--output",
+            "-",
+        ])
+        .arg(path)
+        .output()
+        .with_context(|| format!("Failed to run {gpg:?}"))?;
+    if !output.status.success() {
+        return Err(MetalinkDownloadError::Other(anyhow!(
+            "Signing {path:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    String::from_utf8(output.stdout)
+        .map_err(|_| MetalinkDownloadError::Other(anyhow!("gpg returned an invalid signature")))
+}
@@ -0,0 +1,222 @@
+use crate::sums::{self, Sum};
+use crate::types::hash_threads;
+use crate::{MetalinkDownloadError, Result};
+
+use anyhow::Context;
+use futures::StreamExt;
+use iana_registry_enums::HashFunctionTextualName;
+use metalink::Metalink;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Outcome of checking a file listed in a checksum file
+#[derive(Debug, PartialEq)]
+enum SumCheck {
+    Ok,
+    Failed,
+    Missing,
+}
+
+/// Verify the files listed in the checksum file `sums` against their
+/// checksums. Paths are relative to `target_dir`, by default the directory
+/// of the checksum file. With `metalink_file` the checksums are also
+/// compared to the file checksums of the same type in the metalink. Prints
+/// the outcome of every file and fails if any file does not match.
+pub(crate) async fn verify(
+    sums: PathBuf,
+    target_dir: Option<PathBuf>,
+    hash: Option<HashFunctionTextualName>,
+    metalink_file: Option<PathBuf>,
+) -> Result<()> {
+    info!("Sums: {sums:?}, Target: {target_dir:?}, Metalink: {metalink_file:?}");
+    let content = std::fs::read_to_string(&sums)
+        .with_context(|| format!("Failed to read checksum file {sums:?}"))?;
+    let entries = sums::parse(&content, hash.or_else(|| sums::hash_type_of(&sums)))?;
+    let target_dir = target_dir.unwrap_or_else(|| match sums.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    });
+
+    let checks: Vec<Result<SumCheck>> = futures::stream::iter(&entries)
+        .map(|sum| {
+            let path = target_dir.join(&sum.name);
+            let checksum = sum.checksum.clone();
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    if !path.is_file() {
+                        return Ok(SumCheck::Missing);
+                    }
+                    let actual = checksum.calculate_file_checksum(&path)?;
+                    Ok(if actual.eq_ignore_ascii_case(checksum.expected()) {
+                        SumCheck::Ok
+                    } else {
+                        SumCheck::Failed
+                    })
+                })
+                .await
+                .with_context(|| "Hashing task failed")?
+            }
+        })
+        .buffered(hash_threads())
+        .collect()
+        .await;
+
+    let mut failed = 0;
+    for (sum, check) in entries.iter().zip(checks) {
+        match check? {
+            SumCheck::Ok => println!("{}: ok", sum.name),
+            SumCheck::Failed => {
+                println!("{}: FAILED", sum.name);
+                failed += 1;
+            }
+            SumCheck::Missing => {
+                println!("{}: missing", sum.name);
+                failed += 1;
+            }
+        }
+    }
+
+    let mut total = entries.len();
+    if let Some(metalink_file) = metalink_file {
+        let metalink = Metalink::load_from_file_lenient(metalink_file)?;
+        let disagreements = compare_with_metalink(&entries, &metalink);
+        for (name, disagreement) in &disagreements {
+            println!("{name}: {disagreement}");
+        }
+        failed += disagreements.len();
+        total += disagreements.len();
+    }
+
+    if failed == 0 {
+        return Ok(());
+    }
+    Err(MetalinkDownloadError::FilesFailed {
+        failed,
+        total,
+        verification_failed: true,
+        network_failed: false,
+    })
+}
+
+/// Where the checksum file and the metalink disagree, by file name. Files
+/// without a checksum of the same type in the metalink are not compared.
+fn compare_with_metalink(entries: &[Sum], metalink: &Metalink) -> BTreeMap<String, String> {
+    let files: BTreeMap<&str, &metalink::File> = metalink
+        .files()
+        .iter()
+        .map(|file| (file.name().as_str(), file))
+        .collect();
+    let listed: BTreeMap<&str, &Sum> = entries
+        .iter()
+        .map(|sum| (normalize(&sum.name), sum))
+        .collect();
+
+    let mut disagreements = BTreeMap::new();
+    for (name, sum) in &listed {
+        let Some(file) = files.get(name) else {
+            disagreements.insert(sum.name.clone(), "not in the metalink".to_owned());
+            continue;
+        };
+        let hash_type = sum.checksum.hash_type();
+        let expected = file
+            .hashes()
+            .into_iter()
+            .flatten()
+            .find(|hash| hash.hash_type() == Some(hash_type));
+        if let Some(expected) = expected {
+            if !expected
+                .value()
+                .eq_ignore_ascii_case(sum.checksum.expected())
+            {
+                disagreements.insert(
+                    sum.name.clone(),
+                    format!("{hash_type} differs from the metalink"),
+                );
+            }
+        }
+    }
+    for name in files.keys() {
+        if !listed.contains_key(name) {
+            disagreements.insert(
+                (*name).to_owned(),
+                "only in the metalink, not in the checksum file".to_owned(),
+            );
+        }
+    }
+    disagreements
+}
+
+/// Checksum files list paths like `./file.iso`, metalinks `file.iso`
+fn normalize(name: &str) -> &str {
+    let mut name = name;
+    while let Some(stripped) = name.strip_prefix("./") {
+        name = stripped;
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::CheckSum;
+    use metalink::{FileBuilder, FileUrl, Hash, MetalinkBuilder};
+
+    #[test]
+    fn checksum_files_are_compared_with_the_metalink_by_name() {
+        let file = |name: &str, hashes: Vec<Hash>| {
+            FileBuilder::new()
+                .with_name(name)
+                .with_hashes(hashes)
+                .with_urls(vec![FileUrl::new(
+                    url::Url::parse("https://example.com/file").unwrap(),
+                    None,
+                    None,
+                )])
+                .build()
+                .unwrap()
+        };
+        let sha256 = |value: &str| Hash::new(Some(HashFunctionTextualName::Sha256), value);
+        let metalink = MetalinkBuilder::new()
+            .with_files(vec![
+                file("a", vec![sha256("aa")]),
+                file("b", vec![sha256("bb")]),
+                file(
+                    "c",
+                    vec![Hash::new(Some(HashFunctionTextualName::Md5), "cc")],
+                ),
+                file("d", vec![sha256("dd")]),
+            ])
+            .build()
+            .unwrap();
+        let sum = |name: &str, value: &str| Sum {
+            name: name.to_owned(),
+            checksum: CheckSum::new(HashFunctionTextualName::Sha256, value.to_owned()),
+        };
+
+        let disagreements = compare_with_metalink(
+            &[
+                sum("./a", "AA"),
+                sum("b", "ff"),
+                sum("c", "cc"),
+                sum("e", "ee"),
+            ],
+            &metalink,
+        );
+
+        assert_eq!(
+            disagreements.into_iter().collect::<Vec<_>>(),
+            [
+                (
+                    "b".to_owned(),
+                    "sha-256 differs from the metalink".to_owned()
+                ),
+                (
+                    "d".to_owned(),
+                    "only in the metalink, not in the checksum file".to_owned()
+                ),
+                ("e".to_owned(), "not in the metalink".to_owned()),
+            ]
+        );
+    }
+}
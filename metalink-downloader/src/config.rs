@@ -0,0 +1,457 @@
+//! Defaults read from a TOML config file, flags given on the command line
+//! override them.
+//!
+//! ```toml
+//! user-agent = "mirror-bot/1.0"
+//! max-threads = 8
+//! preferred-locations = ["de", "fr"]
+//! proxy = "http://proxy.internal:3128"
+//! rate-limit = "4MiB"
+//! connect-timeout = "10s"
+//! read-timeout = "1m"
+//! chunk-timeout = "5m"
+//! verify-chunk-checksums = true
+//! verify-files = true
+//!
+//! # Sign the checksum files of `--write-checksums` with this gpg key
+//! signing-key = "releases@example.com"
+//!
+//! # Full speed at night, the window has no rate limit
+//! [[rate-schedule]]
+//! start = "01:00"
+//! end = "07:00"
+//!
+//! # Notify a chat once a download finished, see `hooks` for the payload
+//! [[on-success]]
+//! url = "https://chat.example.com/hooks/downloads"
+//! ```
+
+use crate::auth::{default_netrc, AuthOptions, Credentials};
+use crate::cli::{parse_duration, parse_size, HttpArgs};
+use crate::connections::PoolOptions;
+use crate::cookies::CookieOptions;
+use crate::dns::DnsOptions;
+use crate::hooks::{Hook, Hooks};
+use crate::http::{
+    HttpOptions, RedirectOptions, Timeouts, TlsOptions, DEFAULT_CONNECT_TIMEOUT,
+    DEFAULT_READ_TIMEOUT,
+};
+use crate::politeness::PolitenessOptions;
+use crate::rate_limit::{RateWindow, Schedule};
+use crate::sums::Signing;
+use crate::Result;
+
+use anyhow::{anyhow, Context};
+use chrono::NaiveTime;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// User agent sent if neither the command line nor the config file set one
+pub(crate) const DEFAULT_USER_AGENT: &str =
+    concat!("metalink-downloader/", env!("CARGO_PKG_VERSION"));
+
+/// Number of download threads used if neither the command line nor the
+/// config file set it, picked from the cores and the number of chunks
+const DEFAULT_MAX_THREADS: u16 = 0;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Config {
+    user_agent: Option<String>,
+    max_threads: Option<u16>,
+    /// ISO3166-1 alpha-2 codes of the locations whose mirrors are preferred
+    preferred_locations: Vec<String>,
+    proxy: Option<url::Url>,
+    /// Bytes per second, e.g. `1048576` or `"1MiB"`
+    rate_limit: Option<Size>,
+    /// Rate limits for windows of the local time of day, outside of all
+    /// windows `rate_limit` applies
+    rate_schedule: Vec<RateWindowConfig>,
+    verify_chunk_checksums: Option<bool>,
+    verify_files: Option<bool>,
+    connect_timeout: Option<Time>,
+    read_timeout: Option<Time>,
+    chunk_timeout: Option<Time>,
+    /// Notified after a download succeeded
+    on_success: Vec<Hook>,
+    /// Notified after files failed or the download was aborted
+    on_failure: Vec<Hook>,
+    /// Notified about every file once it is complete
+    on_file_complete: Vec<Hook>,
+    /// Key id or user id of the gpg key checksum files are signed with
+    signing_key: Option<String>,
+    /// The gpg executable used for signing, `gpg` by default
+    gpg: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct RateWindowConfig {
+    /// Local time of day as `HH:MM`
+    start: String,
+    end: String,
+    /// Unlimited if not set
+    rate_limit: Option<Size>,
+}
+
+/// A byte size given as number or with a unit suffix
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    WithUnit(String),
+}
+
+impl Size {
+    fn bytes(&self) -> std::result::Result<u64, String> {
+        match self {
+            Self::Bytes(bytes) => Ok(*bytes),
+            Self::WithUnit(size) => parse_size(size),
+        }
+    }
+}
+
+/// A duration given as seconds or with a unit suffix
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Time {
+    Seconds(u64),
+    WithUnit(String),
+}
+
+impl Time {
+    fn duration(&self) -> std::result::Result<Duration, String> {
+        match self {
+            Self::Seconds(seconds) => parse_duration(&seconds.to_string()),
+            Self::WithUnit(duration) => parse_duration(duration),
+        }
+    }
+}
+
+fn parse_time(time: &str) -> anyhow::Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .with_context(|| format!("rate-schedule: invalid time {time:?}, expected HH:MM"))
+}
+
+impl Config {
+    /// Load the config file at `path`, or at the default location if no path
+    /// is given. A missing config file at the default location is not an
+    /// error.
+    pub(crate) fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+        if !required && !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {path:?}"))?;
+        tracing::info!("Loaded config file {path:?}");
+        Ok(Self::parse(&content).with_context(|| format!("Invalid config file {path:?}"))?)
+    }
+
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        config.schedule()?;
+        config.timeouts(&HttpArgs::default())?;
+        Ok(config)
+    }
+
+    pub(crate) fn http_options(&self, args: HttpArgs) -> Result<HttpOptions> {
+        let timeouts = self.timeouts(&args)?;
+        Ok(HttpOptions {
+            user_agent: args
+                .user_agent
+                .or_else(|| self.user_agent.clone())
+                .unwrap_or_else(|| DEFAULT_USER_AGENT.to_owned()),
+            proxy: args.proxy.or_else(|| self.proxy.clone()),
+            allow_http: args.allow_http,
+            version: args.http_version,
+            tls: TlsOptions {
+                ca_certs: args.ca_cert,
+                client_identity: args.client_cert.zip(args.client_key),
+                insecure: args.insecure,
+            },
+            auth: AuthOptions {
+                credentials: args.user.or(args.bearer_token.map(Credentials::Bearer)),
+                netrc: args
+                    .netrc_file
+                    .or_else(|| args.netrc.then(default_netrc).flatten()),
+            },
+            headers: args.header.into_iter().collect(),
+            cookies: CookieOptions {
+                cookies: args.cookie,
+                jar: args.cookie_jar,
+            },
+            timeouts,
+            redirects: RedirectOptions {
+                max: args.max_redirects,
+                cross_host: !args.no_cross_host_redirects,
+            },
+            politeness: PolitenessOptions {
+                max_per_second: args.max_requests_per_second_per_host,
+                delay: args.delay_between_requests,
+            },
+            pool: PoolOptions {
+                idle_timeout: args.pool_idle_timeout,
+                max_idle_per_host: args.pool_max_idle_per_host,
+                tcp_keepalive: args.tcp_keepalive,
+            },
+            dns: DnsOptions {
+                overrides: args.resolve,
+                resolver: None,
+            },
+            max_request_size: args.max_request_size,
+        })
+    }
+
+    fn timeouts(&self, args: &HttpArgs) -> anyhow::Result<Timeouts> {
+        let timeout = |name: &str, time: &Option<Time>| {
+            time.as_ref()
+                .map(Time::duration)
+                .transpose()
+                .map_err(|e| anyhow!("{name}: {e}"))
+        };
+        let connect = timeout("connect-timeout", &self.connect_timeout)?;
+        let read = timeout("read-timeout", &self.read_timeout)?;
+        let chunk = timeout("chunk-timeout", &self.chunk_timeout)?;
+        Ok(Timeouts {
+            connect: args
+                .connect_timeout
+                .or(connect)
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            read: args.read_timeout.or(read).unwrap_or(DEFAULT_READ_TIMEOUT),
+            chunk: args.chunk_timeout.or(chunk),
+        })
+    }
+
+    pub(crate) fn max_threads(&self, max_threads: Option<u16>) -> u16 {
+        max_threads
+            .or(self.max_threads)
+            .unwrap_or(DEFAULT_MAX_THREADS)
+    }
+
+    /// The preferred locations given on the command line replace the ones
+    /// of the config file
+    pub(crate) fn preferred_locations(&self, locations: Vec<String>) -> Vec<String> {
+        if locations.is_empty() {
+            self.preferred_locations.clone()
+        } else {
+            locations
+        }
+    }
+
+    /// A rate limit given on the command line replaces the schedule of the
+    /// config file
+    pub(crate) fn rate_schedule(&self, rate_limit: Option<u64>) -> Result<Schedule> {
+        match rate_limit {
+            Some(rate_limit) => Ok(Schedule::fixed(Some(rate_limit))),
+            None => Ok(self.schedule()?),
+        }
+    }
+
+    fn schedule(&self) -> anyhow::Result<Schedule> {
+        let limit = |size: &Option<Size>| {
+            size.as_ref()
+                .map(Size::bytes)
+                .transpose()
+                .map_err(|e| anyhow!("rate-limit: {e}"))
+        };
+        let windows = self
+            .rate_schedule
+            .iter()
+            .map(|window| {
+                Ok(RateWindow {
+                    start: parse_time(&window.start)?,
+                    end: parse_time(&window.end)?,
+                    limit: limit(&window.rate_limit)?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Schedule {
+            default: limit(&self.rate_limit)?,
+            windows,
+        })
+    }
+
+    pub(crate) fn hooks(&self) -> Hooks {
+        Hooks {
+            on_success: self.on_success.clone(),
+            on_failure: self.on_failure.clone(),
+            on_file_complete: self.on_file_complete.clone(),
+        }
+    }
+
+    /// How checksum files are signed, None if no signing key is set
+    pub(crate) fn signing(&self) -> Option<Signing> {
+        Some(Signing {
+            key: self.signing_key.clone()?,
+            gpg: self.gpg.clone().unwrap_or_else(|| PathBuf::from("gpg")),
+        })
+    }
+
+    /// `force` is `Some` if a flag on the command line enables or disables
+    /// checking the pieces while downloading
+    pub(crate) fn verify_chunk_checksums(&self, force: Option<bool>) -> bool {
+        force.or(self.verify_chunk_checksums).unwrap_or(false)
+    }
+
+    /// `force` is `Some` if a flag on the command line enables or disables
+    /// verifying the downloaded files
+    pub(crate) fn verify_files(&self, force: Option<bool>) -> bool {
+        force.or(self.verify_files).unwrap_or(true)
+    }
+}
+
+/// `$XDG_CONFIG_HOME/metalink-downloader/config.toml`, falling back to
+/// `~/.config` if `XDG_CONFIG_HOME` is not set
+fn default_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("metalink-downloader").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let config = Config::parse(
+            r#"
+            user-agent = "mirror-bot/1.0"
+            max-threads = 8
+            preferred-locations = ["de"]
+            rate-limit = "1MiB"
+            verify-files = false
+            "#,
+        )
+        .unwrap();
+        let http_args = |user_agent: Option<&str>| HttpArgs {
+            user_agent: user_agent.map(str::to_owned),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.http_options(http_args(None)).unwrap().user_agent,
+            "mirror-bot/1.0"
+        );
+        assert_eq!(
+            config
+                .http_options(http_args(Some("cli")))
+                .unwrap()
+                .user_agent,
+            "cli"
+        );
+        assert_eq!(config.max_threads(None), 8);
+        assert_eq!(config.max_threads(Some(4)), 4);
+        assert_eq!(config.preferred_locations(Vec::new()), ["de"]);
+        assert_eq!(config.preferred_locations(vec!["fr".to_owned()]), ["fr"]);
+        assert_eq!(
+            config.rate_schedule(None).unwrap(),
+            Schedule::fixed(Some(1024 * 1024))
+        );
+        assert_eq!(
+            config.rate_schedule(Some(10)).unwrap(),
+            Schedule::fixed(Some(10))
+        );
+        assert!(!config.verify_files(None));
+        assert!(config.verify_files(Some(true)));
+        assert!(!config.verify_chunk_checksums(None));
+
+        let defaults = Config::default();
+        assert_eq!(
+            defaults.http_options(http_args(None)).unwrap().user_agent,
+            DEFAULT_USER_AGENT
+        );
+        assert_eq!(defaults.max_threads(None), DEFAULT_MAX_THREADS);
+        assert!(defaults.verify_files(None));
+
+        let timeouts = Config::parse("read-timeout = \"2m\"\nchunk-timeout = 300")
+            .unwrap()
+            .timeouts(&HttpArgs {
+                connect_timeout: Some(Duration::from_secs(5)),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(timeouts.connect, Duration::from_secs(5));
+        assert_eq!(timeouts.read, Duration::from_secs(120));
+        assert_eq!(timeouts.chunk, Some(Duration::from_secs(300)));
+
+        assert_eq!(
+            Config::parse("max-threads = 1").unwrap().max_threads(None),
+            1
+        );
+        assert!(Config::parse("read-timeout = \"1d\"").is_err());
+        assert!(Config::parse("rate-limit = \"1MB\"").is_err());
+        assert!(Config::parse("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn rate_schedule_is_read_from_config_file() {
+        let config = Config::parse(
+            r#"
+            rate-limit = "1MiB"
+
+            [[rate-schedule]]
+            start = "01:00"
+            end = "07:00"
+
+            [[rate-schedule]]
+            start = "18:00"
+            end = "23:30"
+            rate-limit = 1024
+            "#,
+        )
+        .unwrap();
+        let schedule = config.rate_schedule(None).unwrap();
+        assert_eq!(schedule.default, Some(1024 * 1024));
+        assert_eq!(schedule.windows.len(), 2);
+        assert_eq!(schedule.windows[0].limit, None);
+        assert_eq!(
+            schedule.windows[1].end,
+            NaiveTime::from_hms_opt(23, 30, 0).unwrap()
+        );
+        assert_eq!(
+            config.rate_schedule(Some(1)).unwrap(),
+            Schedule::fixed(Some(1))
+        );
+
+        assert!(Config::parse("[[rate-schedule]]\nstart = \"1am\"\nend = \"07:00\"").is_err());
+    }
+
+    #[test]
+    fn hooks_are_read_from_config_file() {
+        let hooks = Config::parse(
+            r#"
+            on-success = [{ url = "https://chat.example.com/hook" }]
+
+            [[on-file-complete]]
+            command = ["publish", "--quiet"]
+            "#,
+        )
+        .unwrap()
+        .hooks();
+        assert_eq!(
+            hooks.on_success,
+            [Hook::Url(
+                url::Url::parse("https://chat.example.com/hook").unwrap()
+            )]
+        );
+        assert!(hooks.on_failure.is_empty());
+        assert_eq!(
+            hooks.on_file_complete,
+            [Hook::Command(vec![
+                "publish".to_owned(),
+                "--quiet".to_owned()
+            ])]
+        );
+        assert!(Config::parse("[[on-failure]]\nscript = \"notify\"").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! Keeping connections to the mirrors warm and reporting how often they were
+//! reused. A file with small pieces is fetched with thousands of range
+//! requests, each new connection costs a TCP and TLS handshake.
+
+use crate::run;
+
+use hyper_util::client::legacy::connect::HttpInfo;
+use reqwest_middleware::{Middleware, Next};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// How idle connections are kept, reqwest's defaults apply to unset options
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct PoolOptions {
+    /// Close connections idle for longer than this
+    pub idle_timeout: Option<Duration>,
+    /// Most idle connections kept per host
+    pub max_idle_per_host: Option<usize>,
+    /// Interval of TCP keep-alive probes, none are sent by default
+    pub tcp_keepalive: Option<Duration>,
+}
+
+impl PoolOptions {
+    pub(crate) fn apply(&self, mut builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        if let Some(idle_timeout) = self.idle_timeout {
+            builder = builder.pool_idle_timeout(idle_timeout);
+        }
+        if let Some(max_idle_per_host) = self.max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle_per_host);
+        }
+        builder.tcp_keepalive(self.tcp_keepalive)
+    }
+}
+
+/// Requests of a run whose connection is known and the distinct connections
+/// they were sent over, identified by their local and remote address
+#[derive(Default)]
+pub(crate) struct Connections {
+    requests: usize,
+    seen: HashSet<(SocketAddr, SocketAddr)>,
+}
+
+fn record(connection: (SocketAddr, SocketAddr)) {
+    run::with(|run| {
+        let mut connections = run.connections.lock().unwrap_or_else(|e| e.into_inner());
+        connections.requests += 1;
+        connections.seen.insert(connection);
+    });
+}
+
+/// Records the connection every response arrived on, placed after the retry
+/// middleware so every attempt is counted
+pub(crate) struct ConnectionStatsMiddleware;
+
+#[async_trait::async_trait]
+impl Middleware for ConnectionStatsMiddleware {
+    async fn handle(
+        &self,
+        req: reqwest::Request,
+        extensions: &mut http::Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest::Response> {
+        let response = next.run(req, extensions).await?;
+        if let Some(info) = response.extensions().get::<HttpInfo>() {
+            record((info.local_addr(), info.remote_addr()));
+        }
+        Ok(response)
+    }
+}
+
+/// How many requests of a run were sent over a connection opened before
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ConnectionReuse {
+    pub requests: usize,
+    pub connections: usize,
+}
+
+impl ConnectionReuse {
+    /// Aggregate the requests the current run recorded so far
+    pub(crate) fn collect() -> Self {
+        run::with(|run| {
+            let connections = run.connections.lock().unwrap_or_else(|e| e.into_inner());
+            Self {
+                requests: connections.requests,
+                connections: connections.seen.len(),
+            }
+        })
+        .unwrap_or_default()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.requests == 0
+    }
+
+    /// Share of the requests which reused a connection
+    fn reused(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        (self.requests - self.connections) as f64 / self.requests as f64
+    }
+}
+
+impl std::fmt::Display for ConnectionReuse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} requests over {} connections, {:.1}% reused a connection",
+            self.requests,
+            self.connections,
+            self.reused() * 100.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_is_the_share_of_requests_without_a_new_connection() {
+        let reuse = ConnectionReuse {
+            requests: 40,
+            connections: 4,
+        };
+        assert_eq!(reuse.reused(), 0.9);
+        assert_eq!(
+            reuse.to_string(),
+            "40 requests over 4 connections, 90.0% reused a connection\n"
+        );
+        assert_eq!(ConnectionReuse::default().reused(), 0.0);
+    }
+}
@@ -0,0 +1,302 @@
+//! Control over the individual files of a running metalink download.
+//!
+//! A [`JobControl`] is handed to the download engine which registers every
+//! file of the job with it. Callers holding a clone of the handle can inspect
+//! the state of each file and cancel or retry it without affecting the other
+//! files of the job. Pausing the job stops the chunk requests of all its
+//! files until it is resumed.
+
+use crate::{shutdown, Result};
+
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+
+/// State of a single file of a download job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileState {
+    /// Waiting to be downloaded
+    Queued,
+    Downloading,
+    Downloaded,
+    /// The download failed with the given error
+    Failed(String),
+    Cancelled,
+}
+
+#[derive(Debug)]
+struct FileEntry {
+    state: FileState,
+    cancel: CancellationToken,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    files: BTreeMap<String, FileEntry>,
+    /// Retries are handed to the engine through this channel while the job runs
+    retry_tx: Option<mpsc::UnboundedSender<String>>,
+    /// Set by `cancel_all`, files registered afterwards start out cancelled
+    cancelled: bool,
+}
+
+/// Handle to control the files of a download job, cloning it yields a handle
+/// to the same job
+#[derive(Debug, Clone, Default)]
+pub struct JobControl {
+    inner: Arc<Mutex<Inner>>,
+    pause: Pause,
+}
+
+impl JobControl {
+    /// All files of the job and their current state
+    pub fn files(&self) -> Vec<(String, FileState)> {
+        self.lock()
+            .files
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.state.clone()))
+            .collect()
+    }
+
+    /// Current state of the file `name`
+    pub fn state(&self, name: &str) -> Option<FileState> {
+        self.lock().files.get(name).map(|entry| entry.state.clone())
+    }
+
+    /// Cancel the queued or running download of the file `name`
+    pub fn cancel(&self, name: &str) -> Result<()> {
+        let inner = self.lock();
+        let entry = inner
+            .files
+            .get(name)
+            .ok_or_else(|| anyhow!("{name} is not part of the job"))?;
+        match entry.state {
+            FileState::Queued | FileState::Downloading => {
+                entry.cancel.cancel();
+                Ok(())
+            }
+            _ => Err(anyhow!("{name} is not being downloaded").into()),
+        }
+    }
+
+    /// Cancel all queued and running downloads of the job
+    pub fn cancel_all(&self) {
+        let mut inner = self.lock();
+        inner.cancelled = true;
+        for entry in inner.files.values() {
+            if matches!(entry.state, FileState::Queued | FileState::Downloading) {
+                entry.cancel.cancel();
+            }
+        }
+    }
+
+    /// Stop issuing chunk requests until the job is resumed. The requests in
+    /// flight are dropped, which releases their connections, the chunks
+    /// written so far are kept. Files downloaded without pieces continue.
+    pub fn pause(&self) {
+        self.pause.set(true);
+    }
+
+    /// Continue the paused downloads of the job
+    pub fn resume(&self) {
+        self.pause.set(false);
+    }
+
+    /// Whether the downloads of the job are paused
+    pub fn is_paused(&self) -> bool {
+        self.pause.is_paused()
+    }
+
+    /// Download the failed or cancelled file `name` again, only possible
+    /// while the job is still running
+    pub fn retry(&self, name: &str) -> Result<()> {
+        let mut inner = self.lock();
+        let retry_tx = inner
+            .retry_tx
+            .clone()
+            .ok_or_else(|| anyhow!("The job has finished"))?;
+        let entry = inner
+            .files
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("{name} is not part of the job"))?;
+        if !matches!(entry.state, FileState::Failed(_) | FileState::Cancelled) {
+            return Err(anyhow!("Only failed or cancelled files can be retried").into());
+        }
+        retry_tx
+            .send(name.to_owned())
+            .map_err(|_| anyhow!("The job has finished"))?;
+        entry.state = FileState::Queued;
+        entry.cancel = CancellationToken::new();
+        Ok(())
+    }
+
+    /// Start accepting retries, the engine receives the names of the files to
+    /// retry from the returned channel
+    pub(crate) fn start(&self) -> mpsc::UnboundedReceiver<String> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.lock().retry_tx = Some(tx);
+        rx
+    }
+
+    /// Stop accepting retries
+    pub(crate) fn finish(&self) {
+        self.lock().retry_tx = None;
+    }
+
+    /// Register the file `name` as queued, returns the token cancelling it
+    pub(crate) fn register(&self, name: &str) -> CancellationToken {
+        let mut inner = self.lock();
+        let cancelled = inner.cancelled;
+        let entry = inner.files.entry(name.to_owned()).or_insert_with(|| {
+            let cancel = CancellationToken::new();
+            if cancelled {
+                cancel.cancel();
+            }
+            FileEntry {
+                state: FileState::Queued,
+                cancel,
+            }
+        });
+        entry.state = FileState::Queued;
+        entry.cancel.clone()
+    }
+
+    pub(crate) fn set_state(&self, name: &str, state: FileState) {
+        if let Some(entry) = self.lock().files.get_mut(name) {
+            entry.state = state;
+        }
+    }
+
+    /// The switch the engine waits on before issuing chunk requests
+    pub(crate) fn pause_switch(&self) -> Pause {
+        self.pause.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Whether the downloads of a job are paused, cloning it yields a handle to
+/// the same switch
+#[derive(Debug, Clone)]
+pub(crate) struct Pause(Arc<watch::Sender<bool>>);
+
+impl Default for Pause {
+    fn default() -> Self {
+        Self(Arc::new(watch::Sender::new(false)))
+    }
+}
+
+impl Pause {
+    fn set(&self, paused: bool) {
+        self.0
+            .send_if_modified(|current| std::mem::replace(current, paused) != paused);
+    }
+
+    fn is_paused(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait until the downloads are not paused, false if a shutdown was
+    /// requested while waiting
+    pub(crate) async fn resumed(&self) -> bool {
+        let mut paused = self.0.subscribe();
+        tokio::select! {
+            biased;
+            _ = paused.wait_for(|paused| !paused) => true,
+            _ = shutdown::requested() => false,
+        }
+    }
+
+    /// Receiver of the changes of the switch, to show them
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.0.subscribe()
+    }
+
+    async fn paused(&self) {
+        let _ = self.0.subscribe().wait_for(|paused| *paused).await;
+    }
+
+    /// Run the request `fetch` while the downloads are not paused. Pausing
+    /// drops the request in flight, it is started again once the downloads
+    /// are resumed. None if a shutdown was requested while paused.
+    pub(crate) async fn run<T, F>(&self, fetch: impl Fn() -> F) -> Option<T>
+    where
+        F: Future<Output = T>,
+    {
+        loop {
+            if !self.resumed().await {
+                return None;
+            }
+            tokio::select! {
+                result = fetch() => return Some(result),
+                _ = self.paused() => tracing::debug!("Paused, dropping the request in flight"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_can_be_cancelled_and_retried_individually() {
+        let control = JobControl::default();
+        let mut retries = control.start();
+        let a = control.register("a");
+        let b = control.register("b");
+
+        control.cancel("a").unwrap();
+        assert!(a.is_cancelled());
+        assert!(!b.is_cancelled());
+        assert!(control.retry("a").is_err());
+
+        control.set_state("a", FileState::Cancelled);
+        control.retry("a").unwrap();
+        assert_eq!(retries.try_recv().unwrap(), "a");
+        assert_eq!(control.state("a"), Some(FileState::Queued));
+        assert!(!control.register("a").is_cancelled());
+
+        control.set_state("b", FileState::Downloaded);
+        assert!(control.cancel("b").is_err());
+        assert!(control.cancel("c").is_err());
+
+        control.cancel_all();
+        assert!(control.register("c").is_cancelled());
+
+        control.set_state("b", FileState::Failed("boom".into()));
+        control.finish();
+        assert!(control.retry("b").is_err());
+    }
+
+    #[tokio::test]
+    async fn paused_requests_are_started_again_once_resumed() {
+        let control = JobControl::default();
+        let pause = control.pause_switch();
+        let started = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let fetch = {
+            let started = started.clone();
+            move || {
+                let started = started.clone();
+                async move {
+                    started.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    "chunk"
+                }
+            }
+        };
+        let request = tokio::spawn(async move { pause.run(fetch).await });
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        control.pause();
+        assert!(control.is_paused());
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!request.is_finished());
+        control.resume();
+        assert_eq!(request.await.unwrap(), Some("chunk"));
+        assert_eq!(started.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,155 @@
+//! Cookies sent with requests, given on the command line or loaded from a
+//! cookie file. Cookies set by the mirrors are kept for the rest of the run,
+//! e.g. the signed cookies a CDN sets before redirecting to the file.
+
+use crate::Result;
+
+use anyhow::{anyhow, Context};
+use reqwest::cookie::Jar;
+use reqwest_middleware::{Middleware, Next};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the cookies of requests come from
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CookieOptions {
+    /// `name=value` pairs sent to all hosts
+    pub cookies: Vec<String>,
+    /// Cookie file in the Netscape format written by curl and browsers
+    pub jar: Option<PathBuf>,
+}
+
+impl CookieOptions {
+    /// The cookie store of the client and the middleware adding the cookies
+    /// given on the command line to every request
+    pub(crate) fn store(&self) -> Result<(Arc<Jar>, Option<CookieMiddleware>)> {
+        let jar = Arc::new(Jar::default());
+        if let Some(path) = &self.jar {
+            for (cookie, url) in read_cookie_file(path)? {
+                jar.add_cookie_str(&cookie, &url);
+            }
+        }
+        let middleware = (!self.cookies.is_empty()).then(|| CookieMiddleware {
+            jar: jar.clone(),
+            cookies: self.cookies.clone(),
+        });
+        Ok((jar, middleware))
+    }
+}
+
+/// Adds the cookies given on the command line for the host of each request
+pub(crate) struct CookieMiddleware {
+    jar: Arc<Jar>,
+    cookies: Vec<String>,
+}
+
+#[async_trait::async_trait]
+impl Middleware for CookieMiddleware {
+    async fn handle(
+        &self,
+        req: reqwest::Request,
+        extensions: &mut http::Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest::Response> {
+        for cookie in &self.cookies {
+            self.jar.add_cookie_str(cookie, req.url());
+        }
+        next.run(req, extensions).await
+    }
+}
+
+/// Check that `s` is a `name=value` cookie
+pub(crate) fn parse_cookie(s: &str) -> std::result::Result<String, String> {
+    match s.split_once('=') {
+        Some((name, _)) if !name.trim().is_empty() && !s.contains(';') => Ok(s.to_owned()),
+        _ => Err(format!("expected name=value, got {s:?}")),
+    }
+}
+
+fn read_cookie_file(path: &Path) -> Result<Vec<(String, url::Url)>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read cookie file {path:?}"))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    Ok(
+        parse_cookie_file(&content, now)
+            .with_context(|| format!("Invalid cookie file {path:?}"))?,
+    )
+}
+
+/// Parse the cookies of a Netscape cookie file which did not expire at `now`
+/// into cookie strings and the url they are set for
+fn parse_cookie_file(content: &str, now: u64) -> anyhow::Result<Vec<(String, url::Url)>> {
+    let mut cookies = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [domain, include_subdomains, path, secure, expires, name, value] = fields[..] else {
+            return Err(anyhow!(
+                "line {}: expected 7 tab separated fields",
+                number + 1
+            ));
+        };
+        let expires: u64 = expires
+            .parse()
+            .with_context(|| format!("line {}: invalid expiry {expires:?}", number + 1))?;
+        if expires != 0 && expires <= now {
+            continue;
+        }
+        let host = domain.trim_start_matches('.');
+        let url = url::Url::parse(&format!("https://{host}{path}"))
+            .with_context(|| format!("line {}: invalid domain {domain:?}", number + 1))?;
+        let mut cookie = format!("{name}={value}; Path={path}");
+        if include_subdomains == "TRUE" {
+            cookie.push_str(&format!("; Domain={host}"));
+        }
+        if secure == "TRUE" {
+            cookie.push_str("; Secure");
+        }
+        cookies.push((cookie, url));
+    }
+    Ok(cookies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::cookie::CookieStore;
+
+    #[test]
+    fn cookie_file_cookies_are_sent_to_their_domain() {
+        let content = "# Netscape HTTP Cookie File\n\
+            .cdn.example.com\tTRUE\t/\tTRUE\t0\tsigned\tabc\n\
+            #HttpOnly_mirror.example.org\tFALSE\t/files\tFALSE\t2000000000\tsession\txyz\n\
+            old.example.org\tFALSE\t/\tFALSE\t1\texpired\t1\n";
+        let cookies = parse_cookie_file(content, 1_700_000_000).unwrap();
+        assert_eq!(cookies.len(), 2);
+
+        let jar = Jar::default();
+        for (cookie, url) in &cookies {
+            jar.add_cookie_str(cookie, url);
+        }
+        let sent = |url: &str| {
+            jar.cookies(&url::Url::parse(url).unwrap())
+                .map(|value| value.to_str().unwrap().to_owned())
+        };
+        assert_eq!(
+            sent("https://eu.cdn.example.com/file.iso").as_deref(),
+            Some("signed=abc")
+        );
+        assert_eq!(
+            sent("https://mirror.example.org/files/a").as_deref(),
+            Some("session=xyz")
+        );
+        assert_eq!(sent("https://mirror.example.org/other"), None);
+
+        assert!(parse_cookie_file("example.com\tFALSE\t/\n", 0).is_err());
+        assert!(parse_cookie("name=value").is_ok());
+        assert!(parse_cookie("value").is_err());
+    }
+}
@@ -0,0 +1,527 @@
+//! Delta updates of outdated files with zsync.
+//!
+//! A file on disk which fails its checksum and has no pieces is normally
+//! downloaded again as a whole. If a zsync control file is advertised for
+//! it, by a metaurl of type `application/x-zsync` or a `Link` header of the
+//! mirror, the blocks the old file shares with the new one are copied and
+//! only the changed blocks are fetched. The result is checked against the
+//! SHA-1 of the control file, a failed delta update falls back to a full
+//! download.
+
+use crate::http::{fetch_range, Client, DEFAULT_CHUNK_SIZE};
+use crate::progress::{ProgressSender, ProgressUpdate};
+use crate::transport::Transport;
+use crate::types::{CheckSum, ChunkMetaData, FilePlan};
+use crate::{MetalinkDownloadError, Result};
+
+use anyhow::{anyhow, Context};
+use digest::Digest;
+use iana_registry_enums::HashFunctionTextualName;
+use metalink::{MetaUrl, TorrentOrMime};
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Media type of zsync control files
+const ZSYNC_MEDIA_TYPE: &str = "application/x-zsync";
+/// Largest block size accepted from a control file, a block is held in
+/// memory while matching
+const MAX_BLOCK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Decides whether a file is updated with a delta instead of downloading it
+/// as a whole
+#[derive(Debug, Clone)]
+pub(crate) struct DeltaPlan {
+    /// The outdated file the unchanged blocks are copied from
+    pub old_file: PathBuf,
+    /// The control file advertised by the metalink, None if the mirror has
+    /// to be asked for one
+    pub control_url: Option<url::Url>,
+}
+
+impl DeltaPlan {
+    /// The delta update of `file` into `path`, None if the file is not
+    /// outdated, has pieces to repair it with instead or can not be checked
+    /// afterwards
+    pub(crate) fn new(file: &FilePlan, path: &Path) -> Option<Self> {
+        let candidate = file.chunks.is_none()
+            && file.file_checksums.is_some()
+            && file.url.is_some()
+            && file.target_file.is_file()
+            && path != file.target_file;
+        candidate.then(|| Self {
+            old_file: file.target_file.clone(),
+            control_url: control_url(&file.metaurls),
+        })
+    }
+}
+
+/// The url of the zsync control file among `metaurls`
+fn control_url(metaurls: &[MetaUrl]) -> Option<url::Url> {
+    metaurls
+        .iter()
+        .find(|metaurl| {
+            matches!(metaurl.mediatype(), TorrentOrMime::Mime(mime) if mime.essence_str() == ZSYNC_MEDIA_TYPE)
+        })
+        .map(|metaurl| metaurl.url().clone())
+}
+
+/// The zsync control file a mirror advertises for `url` in a `Link` header
+/// of a HEAD response
+pub(crate) async fn advertised_control_url(client: &Client, url: &url::Url) -> Option<url::Url> {
+    let response = client.head(url.clone()).send().await.ok()?;
+    response
+        .headers()
+        .get_all(reqwest::header::LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .find_map(|value| zsync_link(value, url))
+}
+
+/// Find a zsync control file in a `Link` header value, relative links are
+/// resolved against `base`
+fn zsync_link(value: &str, base: &url::Url) -> Option<url::Url> {
+    value.split(',').find_map(|link| {
+        let mut parts = link.split(';');
+        let target = parts.next()?.trim().strip_prefix('<')?.strip_suffix('>')?;
+        let zsync = parts.any(|parameter| {
+            parameter.split_once('=').is_some_and(|(name, value)| {
+                name.trim().eq_ignore_ascii_case("type")
+                    && value
+                        .trim()
+                        .trim_matches('"')
+                        .eq_ignore_ascii_case(ZSYNC_MEDIA_TYPE)
+            })
+        });
+        zsync.then(|| base.join(target).ok()).flatten()
+    })
+}
+
+/// Checksums of one block of the new file
+#[derive(Debug, Clone, PartialEq)]
+struct BlockSum {
+    /// Rolling checksum, truncated to the rsum bytes of the control file
+    rsum: u32,
+    /// Leading bytes of the MD4 digest of the block
+    checksum: Vec<u8>,
+}
+
+/// A parsed zsync control file
+#[derive(Debug)]
+struct ControlFile {
+    block_size: usize,
+    length: u64,
+    /// SHA-1 of the whole new file
+    sha1: Option<String>,
+    rsum_bytes: usize,
+    checksum_bytes: usize,
+    blocks: Vec<BlockSum>,
+}
+
+impl ControlFile {
+    /// Parse a control file received from a mirror, `expected_length` is the
+    /// size of the file according to the metalink if known
+    fn parse(data: &[u8], expected_length: Option<u64>) -> Result<Self> {
+        let invalid = |reason: &str| {
+            MetalinkDownloadError::Other(anyhow!("Invalid zsync control file: {reason}"))
+        };
+        let header_end = data
+            .windows(2)
+            .position(|window| window == b"\n\n")
+            .ok_or_else(|| invalid("the header is not terminated"))?;
+        let header = std::str::from_utf8(&data[..header_end])
+            .map_err(|_| invalid("the header is not valid UTF-8"))?;
+        let fields: HashMap<String, &str> = header
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+            .collect();
+        let number = |name: &str| -> Result<u64> {
+            fields
+                .get(name)
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| invalid(&format!("{name} is missing or not a number")))
+        };
+        let block_size = usize::try_from(number("blocksize")?)
+            .ok()
+            .filter(|block_size| *block_size <= MAX_BLOCK_SIZE)
+            .ok_or_else(|| invalid("the block size is too large"))?;
+        let length = number("length")?;
+        if expected_length.is_some_and(|expected| expected != length) {
+            return Err(invalid("it describes a file of a different size"));
+        }
+        // zsync defaults to a single block match, 4 rsum and 16 checksum bytes
+        let (rsum_bytes, checksum_bytes) = match fields.get("hash-lengths") {
+            Some(lengths) => {
+                let lengths: Vec<usize> = lengths
+                    .split(',')
+                    .map(|length| length.trim().parse())
+                    .collect::<std::result::Result<_, _>>()
+                    .map_err(|_| invalid("Hash-Lengths is not a list of numbers"))?;
+                match lengths[..] {
+                    [_, rsum_bytes, checksum_bytes] => (rsum_bytes, checksum_bytes),
+                    _ => return Err(invalid("Hash-Lengths needs three values")),
+                }
+            }
+            None => (4, 16),
+        };
+        if block_size == 0 || !(1..=4).contains(&rsum_bytes) || !(1..=16).contains(&checksum_bytes)
+        {
+            return Err(invalid("unsupported block size or hash lengths"));
+        }
+
+        let count = usize::try_from(length.div_ceil(block_size as u64))
+            .map_err(|_| invalid("the file is too large"))?;
+        let entry = rsum_bytes + checksum_bytes;
+        let sums = &data[header_end + 2..];
+        if count
+            .checked_mul(entry)
+            .is_none_or(|needed| sums.len() < needed)
+        {
+            return Err(invalid("the block checksums are truncated"));
+        }
+        let blocks = sums
+            .chunks_exact(entry)
+            .take(count)
+            .map(|entry| BlockSum {
+                rsum: entry[..rsum_bytes]
+                    .iter()
+                    .fold(0, |rsum, byte| rsum << 8 | u32::from(*byte)),
+                checksum: entry[rsum_bytes..].to_vec(),
+            })
+            .collect();
+        Ok(Self {
+            block_size,
+            length,
+            sha1: fields.get("sha-1").map(|sha1| (*sha1).to_owned()),
+            rsum_bytes,
+            checksum_bytes,
+            blocks,
+        })
+    }
+
+    fn rsum_mask(&self) -> u32 {
+        match self.rsum_bytes {
+            4 => u32::MAX,
+            bytes => (1 << (8 * bytes)) - 1,
+        }
+    }
+
+    /// Byte range of block `index` in the new file, inclusive
+    fn block_range(&self, index: usize) -> (u64, u64) {
+        let start = index as u64 * self.block_size as u64;
+        let end = (start + self.block_size as u64).min(self.length) - 1;
+        (start, end)
+    }
+
+    /// Find the blocks of the new file in `old`. Returns for every block its
+    /// offset in `old`, None if it has to be fetched. The last block is
+    /// zero padded in the control file and is always fetched if it is
+    /// partial.
+    fn match_blocks(&self, old: impl Read) -> Result<Vec<Option<u64>>> {
+        let block_size = self.block_size;
+        let mask = self.rsum_mask();
+        let full_blocks = (self.length / block_size as u64) as usize;
+        let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, block) in self.blocks.iter().enumerate().take(full_blocks) {
+            index.entry(block.rsum).or_default().push(i);
+        }
+        let mut found = vec![None; self.blocks.len()];
+        let mut missing = full_blocks;
+
+        let mut reader = BufReader::new(old);
+        let mut window = vec![0; block_size];
+        let mut head = 0;
+        let mut offset = 0u64;
+        if !fill(&mut reader, &mut window)? {
+            return Ok(found);
+        }
+        let (mut a, mut b) = rsum(&window);
+        while missing > 0 {
+            let key = (u32::from(a) << 16 | u32::from(b)) & mask;
+            let mut matched = false;
+            if let Some(candidates) = index.get(&key) {
+                let mut md4 = md4::Md4::new();
+                md4.update(&window[head..]);
+                md4.update(&window[..head]);
+                let digest = md4.finalize();
+                for &i in candidates {
+                    if found[i].is_none()
+                        && digest[..self.checksum_bytes] == self.blocks[i].checksum[..]
+                    {
+                        found[i] = Some(offset);
+                        missing -= 1;
+                        matched = true;
+                    }
+                }
+            }
+            if matched {
+                // Blocks do not overlap, continue after the matched one
+                if !fill(&mut reader, &mut window)? {
+                    break;
+                }
+                head = 0;
+                offset += block_size as u64;
+                (a, b) = rsum(&window);
+                continue;
+            }
+            let mut byte = [0];
+            if !fill(&mut reader, &mut byte)? {
+                break;
+            }
+            let (old, new) = (u16::from(window[head]), u16::from(byte[0]));
+            window[head] = byte[0];
+            head = (head + 1) % block_size;
+            a = a.wrapping_sub(old).wrapping_add(new);
+            b = b
+                .wrapping_sub((block_size as u16).wrapping_mul(old))
+                .wrapping_add(a);
+            offset += 1;
+        }
+        Ok(found)
+    }
+}
+
+/// Fill `buffer` from `reader`, false at the end of the input
+fn fill(reader: &mut impl Read, buffer: &mut [u8]) -> Result<bool> {
+    match reader.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The rolling checksum of zsync as its `a` and `b` halves
+fn rsum(block: &[u8]) -> (u16, u16) {
+    let len = block.len() as u16;
+    block
+        .iter()
+        .enumerate()
+        .fold((0u16, 0u16), |(a, b), (i, byte)| {
+            let byte = u16::from(*byte);
+            (
+                a.wrapping_add(byte),
+                b.wrapping_add(len.wrapping_sub(i as u16).wrapping_mul(byte)),
+            )
+        })
+}
+
+/// Update `file` into `path` from the outdated file of `delta`, fetching the
+/// changed blocks from `url` with `transport`
+pub(crate) async fn update(
+    client: &Client,
+    transport: &dyn Transport,
+    delta: DeltaPlan,
+    url: &url::Url,
+    file: &FilePlan,
+    path: &Path,
+    tx: &ProgressSender,
+) -> Result<()> {
+    if !transport.probe(url).await?.accepts_ranges {
+        return Err(MetalinkDownloadError::RangeNotSupported { url: url.clone() });
+    }
+    let control_url = match delta.control_url {
+        Some(control_url) => control_url,
+        None => advertised_control_url(client, url)
+            .await
+            .ok_or_else(|| anyhow!("{url} advertises no zsync control file"))?,
+    };
+    tracing::info!(
+        "Updating {:?} with zsync control file {control_url}",
+        file.target_file
+    );
+    let data = client
+        .get(control_url.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let control = Arc::new(
+        ControlFile::parse(&data, file.file_size)
+            .with_context(|| format!("Failed to parse {control_url}"))?,
+    );
+
+    // Hashing the old file must not block the runtime
+    let found = tokio::task::spawn_blocking({
+        let control = control.clone();
+        let old_file = delta.old_file.clone();
+        let path = path.to_path_buf();
+        move || copy_matching_blocks(&control, &old_file, &path)
+    })
+    .await
+    .with_context(|| "Delta matching task failed")??;
+
+    let copied: u64 = found
+        .iter()
+        .enumerate()
+        .filter(|(_, offset)| offset.is_some())
+        .map(|(i, _)| {
+            let (start, end) = control.block_range(i);
+            end - start + 1
+        })
+        .sum();
+    tracing::info!(
+        "Reusing {copied} of {} bytes of {:?}",
+        control.length,
+        delta.old_file
+    );
+
+    let mut output = std::fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open file {path:?}"))?;
+    for (start, end) in missing_ranges(&control, &found) {
+        let chunk = ChunkMetaData::new(start, end, Arc::from(file.target_file.as_path()));
+        let (bytes, _) = fetch_range(transport, url, &chunk).await?;
+        output
+            .seek(std::io::SeekFrom::Start(start))
+            .with_context(|| format!("Failed to seek file {path:?}"))?;
+        output
+            .write_all(&bytes)
+            .with_context(|| format!("Failed to write file {path:?}"))?;
+    }
+    output
+        .flush()
+        .with_context(|| format!("Failed to flush file {path:?}"))?;
+
+    if let Some(sha1) = &control.sha1 {
+        if !CheckSum::new(HashFunctionTextualName::Sha1, sha1.clone()).validate_file_checksum(path)
+        {
+            return Err(anyhow!(
+                "The delta update of {:?} does not match the SHA-1 of {control_url}",
+                file.target_file
+            )
+            .into());
+        }
+    }
+    // Progress is reported once the update succeeded, a failed update is
+    // downloaded again as a whole
+    tx.send(ProgressUpdate::Progressed {
+        file: Arc::from(file.target_file.as_path()),
+        bytes: control.length,
+    })?;
+    Ok(())
+}
+
+/// Create `path` with the length of the new file and copy the blocks found
+/// in `old_file` into it
+fn copy_matching_blocks(
+    control: &ControlFile,
+    old_file: &Path,
+    path: &Path,
+) -> Result<Vec<Option<u64>>> {
+    let found = control.match_blocks(
+        std::fs::File::open(old_file).with_context(|| format!("Failed to open {old_file:?}"))?,
+    )?;
+    let mut old = std::fs::File::open(old_file)?;
+    let mut output =
+        std::fs::File::create(path).with_context(|| format!("Failed to create file {path:?}"))?;
+    output.set_len(control.length)?;
+    let mut buffer = vec![0; control.block_size];
+    for (i, offset) in found.iter().enumerate() {
+        let Some(offset) = offset else {
+            continue;
+        };
+        let (start, _) = control.block_range(i);
+        old.seek(std::io::SeekFrom::Start(*offset))?;
+        old.read_exact(&mut buffer)?;
+        output.seek(std::io::SeekFrom::Start(start))?;
+        output.write_all(&buffer)?;
+    }
+    output.flush()?;
+    Ok(found)
+}
+
+/// Byte ranges of the blocks which were not found, consecutive blocks are
+/// fetched together up to the default chunk size
+fn missing_ranges(control: &ControlFile, found: &[Option<u64>]) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for (i, _) in found
+        .iter()
+        .enumerate()
+        .filter(|(_, offset)| offset.is_none())
+    {
+        let (start, end) = control.block_range(i);
+        match ranges.last_mut() {
+            Some(last) if last.1 + 1 == start && end - last.0 < DEFAULT_CHUNK_SIZE => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A control file for `data` in blocks of `block_size` bytes
+    fn control_file(data: &[u8], block_size: usize) -> Vec<u8> {
+        let mut control = format!(
+            "zsync: 0.6.2\nBlocksize: {block_size}\nLength: {}\nHash-Lengths: 1,3,8\n\n",
+            data.len()
+        )
+        .into_bytes();
+        for block in data.chunks(block_size) {
+            let mut padded = block.to_vec();
+            padded.resize(block_size, 0);
+            let (a, b) = rsum(&padded);
+            let rsum = (u32::from(a) << 16 | u32::from(b)).to_be_bytes();
+            control.extend_from_slice(&rsum[1..]);
+            control.extend_from_slice(&md4::Md4::digest(&padded)[..8]);
+        }
+        control
+    }
+
+    #[test]
+    fn unchanged_blocks_are_found_in_the_old_file() {
+        let new = b"aaaabbbbccccddddee";
+        let old = b"xxaaaabbbbXXXXddddyy";
+        let control = ControlFile::parse(&control_file(new, 4), Some(new.len() as u64)).unwrap();
+        assert_eq!(control.blocks.len(), 5);
+
+        let found = control.match_blocks(&old[..]).unwrap();
+        assert_eq!(found, [Some(2), Some(6), None, Some(14), None]);
+        assert_eq!(missing_ranges(&control, &found), [(8, 11), (16, 17)]);
+    }
+
+    #[test]
+    fn untrusted_sizes_are_rejected() {
+        let control = |header: &str| format!("zsync: 0.6.2\n{header}\n\n").into_bytes();
+        // The number of block checksums overflows
+        assert!(ControlFile::parse(
+            &control(&format!("Blocksize: 1\nLength: {}", u64::MAX)),
+            None
+        )
+        .is_err());
+        assert!(ControlFile::parse(
+            &control(&format!("Blocksize: {}\nLength: 1", u64::MAX)),
+            None
+        )
+        .is_err());
+        assert!(ControlFile::parse(
+            &control(&format!("Blocksize: {}\nLength: 0", MAX_BLOCK_SIZE + 1)),
+            None
+        )
+        .is_err());
+        assert!(ControlFile::parse(&control("Blocksize: 4\nLength: 0"), Some(0)).is_ok());
+        assert!(ControlFile::parse(&control("Blocksize: 4\nLength: 0"), Some(8)).is_err());
+    }
+
+    #[test]
+    fn zsync_links_are_resolved() {
+        let base = url::Url::parse("https://example.com/pub/file.iso").unwrap();
+        assert_eq!(
+            zsync_link(
+                r#"<https://example.com/meta4>; rel=describedby; type="application/metalink4+xml", <file.iso.zsync>; rel=describedby; type="application/x-zsync""#,
+                &base
+            ),
+            Some(url::Url::parse("https://example.com/pub/file.iso.zsync").unwrap())
+        );
+        assert_eq!(
+            zsync_link("<file.iso.torrent>; rel=describedby", &base),
+            None
+        );
+    }
+}
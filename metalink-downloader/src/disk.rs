@@ -0,0 +1,59 @@
+//! Free space of the file system the files are downloaded to.
+
+use crate::{MetalinkDownloadError, Result};
+
+use std::path::Path;
+
+/// Fail before downloading `needed` bytes into `dir` if its file system has
+/// less space left. File systems whose free space is unknown are not checked.
+pub(crate) fn ensure_free_space(dir: &Path, needed: u64) -> Result<()> {
+    let Some(available) = available_space(dir) else {
+        return Ok(());
+    };
+    if needed > available {
+        return Err(MetalinkDownloadError::InsufficientDiskSpace {
+            dir: dir.to_path_buf(),
+            needed,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// Bytes available to unprivileged users on the file system of `dir`, or of
+/// its closest existing ancestor if it is not created yet
+#[cfg(unix)]
+fn available_space(dir: &Path) -> Option<u64> {
+    let existing = dir
+        .ancestors()
+        .map(|dir| {
+            if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            }
+        })
+        .find(|dir| dir.exists())?;
+    let stat = rustix::fs::statvfs(existing).ok()?;
+    Some(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+#[cfg(not(unix))]
+fn available_space(_: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downloads_larger_than_the_free_space_are_refused() {
+        let dir = std::env::temp_dir().join("not-created-yet").join("target");
+        assert!(ensure_free_space(&dir, 0).is_ok());
+        assert!(matches!(
+            ensure_free_space(&dir, u64::MAX),
+            Err(MetalinkDownloadError::InsufficientDiskSpace { .. })
+        ));
+    }
+}
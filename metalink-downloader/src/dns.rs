@@ -0,0 +1,120 @@
+//! Resolving the host names of mirrors. Single hosts can be pinned to
+//! addresses like with curl's `--resolve`, e.g. to test a staging mirror
+//! under its public name, and applications can plug in their own resolver,
+//! e.g. DNS over HTTPS to bypass broken split-horizon DNS.
+
+use reqwest::dns::{Name, Resolve, Resolving};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Addresses `host` is connected to instead of the ones DNS returns
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolveOverride {
+    pub host: String,
+    pub port: u16,
+    pub addrs: Vec<IpAddr>,
+}
+
+/// A resolver registered by the application, shared by all clients
+#[derive(Clone)]
+pub(crate) struct Resolver(pub Arc<dyn Resolve>);
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Resolver")
+    }
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        self.0.resolve(name)
+    }
+}
+
+/// How host names are resolved, by the system resolver unless configured
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DnsOptions {
+    pub overrides: Vec<ResolveOverride>,
+    /// Resolves the hosts without an override
+    pub resolver: Option<Resolver>,
+}
+
+impl DnsOptions {
+    pub(crate) fn apply(&self, mut builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        if let Some(resolver) = &self.resolver {
+            builder = builder.dns_resolver(Arc::new(resolver.clone()));
+        }
+        for ResolveOverride { host, port, addrs } in &self.overrides {
+            // The client looks up addresses by host only and connects to the
+            // port of the url, an override pins the host on all ports
+            let addrs: Vec<SocketAddr> = addrs
+                .iter()
+                .map(|addr| SocketAddr::new(*addr, *port))
+                .collect();
+            builder = builder.resolve_to_addrs(host, &addrs);
+        }
+        builder
+    }
+}
+
+/// Parse a curl style `host:port:addr[,addr]` override, IPv6 addresses may
+/// be enclosed in brackets
+pub(crate) fn parse_resolve(s: &str) -> std::result::Result<ResolveOverride, String> {
+    let invalid = || format!("expected HOST:PORT:ADDR[,ADDR], got {s:?}");
+    let mut parts = s.splitn(3, ':');
+    let (Some(host), Some(port), Some(addrs)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    if host.is_empty() || host == "*" {
+        return Err(format!("{s:?} does not name a host"));
+    }
+    let port = port
+        .parse()
+        .map_err(|_| format!("invalid port {port:?} in {s:?}"))?;
+    let addrs = addrs
+        .split(',')
+        .map(|addr| {
+            let addr = addr.trim();
+            addr.strip_prefix('[')
+                .and_then(|addr| addr.strip_suffix(']'))
+                .unwrap_or(addr)
+                .parse()
+                .map_err(|_| format!("invalid address {addr:?} in {s:?}"))
+        })
+        .collect::<std::result::Result<_, _>>()?;
+    Ok(ResolveOverride {
+        host: host.to_ascii_lowercase(),
+        port,
+        addrs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_overrides_are_parsed() {
+        assert_eq!(
+            parse_resolve("Mirror.example:443:127.0.0.1"),
+            Ok(ResolveOverride {
+                host: "mirror.example".to_owned(),
+                port: 443,
+                addrs: vec!["127.0.0.1".parse().unwrap()],
+            })
+        );
+        assert_eq!(
+            parse_resolve("mirror.example:80:[::1],10.0.0.2")
+                .unwrap()
+                .addrs,
+            [
+                "::1".parse::<IpAddr>().unwrap(),
+                "10.0.0.2".parse().unwrap()
+            ]
+        );
+        assert!(parse_resolve("mirror.example:443").is_err());
+        assert!(parse_resolve("mirror.example:https:127.0.0.1").is_err());
+        assert!(parse_resolve("mirror.example:443:localhost").is_err());
+        assert!(parse_resolve("*:443:127.0.0.1").is_err());
+    }
+}
@@ -0,0 +1,350 @@
+//! Library interface for embedding the downloader into other applications.
+//!
+//! The command line parses its arguments into the same options, the library
+//! starts from the defaults of the command line and changes them through
+//! [`MetalinkDownloaderBuilder`].
+
+use crate::commands::{self, DownloadFileOptions, DownloadMetalinkOptions};
+use crate::config::DEFAULT_USER_AGENT;
+use crate::http::{
+    make_http_client, ChunkSize, Concurrency, HttpOptions, Segmentation, DEFAULT_MAX_REQUEST_SIZE,
+};
+use crate::metaurl::MetaUrlHandlers;
+use crate::progress::ProgressMode;
+use crate::remote::MetalinkSource;
+use crate::selection::{FileFilter, Layout, MirrorSelection, RefreshSelection};
+use crate::transport::Transports;
+use crate::types::Plan;
+use crate::verification_cache::VerificationCache;
+use crate::{DownloadReport, JobControl, LockMode, MetaUrlHandler, Result, Transport};
+
+use anyhow::Context;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Size of the segments of a plain file, the default of `--chunk-size`
+const DEFAULT_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+/// Downloads metalinks and plain files with fixed settings.
+///
+/// ```no_run
+/// # async fn example() -> metalink_downloader::Result<()> {
+/// let downloader = metalink_downloader::MetalinkDownloader::builder()
+///     .user_agent("my-app/1.0")
+///     .target_dir("downloads")
+///     .build()?;
+/// downloader
+///     .download_metalink("https://example.com/release.meta4")
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MetalinkDownloader {
+    target_dir: PathBuf,
+    http: HttpOptions,
+    max_threads: u16,
+    verify_chunk_checksums: bool,
+    verify_files: bool,
+    keep_going: bool,
+    lock: LockMode,
+    progress: ProgressMode,
+    metaurl_handlers: MetaUrlHandlers,
+    transports: Transports,
+}
+
+impl MetalinkDownloader {
+    pub fn builder() -> MetalinkDownloaderBuilder {
+        MetalinkDownloaderBuilder::default()
+    }
+
+    /// Download the files of the metalink at `path_or_url`, a local file, a
+    /// directory of metalinks or an http(s) url. Files failing to download
+    /// are listed in the report instead of failing the whole download.
+    pub async fn download_metalink(&self, path_or_url: &str) -> Result<DownloadReport> {
+        self.download_job(path_or_url, JobControl::default()).await
+    }
+
+    /// Like [`download_metalink`](Self::download_metalink), the files of
+    /// the download are registered with `control` to follow and cancel them
+    pub async fn download_job(
+        &self,
+        path_or_url: &str,
+        control: JobControl,
+    ) -> Result<DownloadReport> {
+        commands::download_metalink(
+            vec![metalink_source(path_or_url)],
+            self.target_dir.clone(),
+            DownloadMetalinkOptions {
+                http: self.http.clone(),
+                verify_chunk_checksums: self.verify_chunk_checksums,
+                verify_files: self.verify_files,
+                mirrors: self.mirrors(),
+                metaurl_handlers: self.metaurl_handlers.clone(),
+                transports: self.transports.clone(),
+                control,
+                keep_going: self.keep_going,
+                lock: self.lock,
+                progress: self.progress,
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Download the plain file at `url` into the target directory, named
+    /// after the server's Content-Disposition header or the url
+    pub async fn download_file(&self, url: &url::Url) -> Result<()> {
+        commands::download_file(
+            url.clone(),
+            self.target_dir.clone(),
+            DownloadFileOptions {
+                output: None,
+                http: self.http.clone(),
+                concurrency: Concurrency {
+                    max_threads: self.max_threads,
+                    reduce_on_slow_disk: false,
+                },
+                segmentation: Segmentation {
+                    chunk_size: ChunkSize::Fixed(DEFAULT_SEGMENT_SIZE),
+                    min_split_size: DEFAULT_SEGMENT_SIZE,
+                },
+                negotiate: false,
+                transports: self.transports.clone(),
+                progress: self.progress,
+                in_place: false,
+            },
+        )
+        .await
+    }
+
+    /// The files of the metalink at `path_or_url` which a download would
+    /// fetch, files already complete in the target directory are left out
+    pub async fn plan(&self, path_or_url: &str) -> Result<Plan> {
+        let client = make_http_client(&self.http)?;
+        let metalink_files = metalink_source(path_or_url)
+            .resolve(&client, &self.target_dir)
+            .await?;
+        let mirrors = self.mirrors();
+        let plan = Plan::from_metalinks(
+            &metalink_files,
+            &self.target_dir,
+            &FileFilter::default(),
+            &mirrors,
+            &Layout::default(),
+        )?;
+        let cache = VerificationCache::load(&self.target_dir);
+        // Validating existing files hashes them, which must not block the runtime
+        tokio::task::spawn_blocking(move || {
+            plan.minimize_plan(&RefreshSelection::default(), &cache, false)
+        })
+        .await
+        .with_context(|| "Plan minimization task failed")?
+    }
+
+    fn mirrors(&self) -> MirrorSelection {
+        MirrorSelection::default().with_http(self.http.allow_http)
+    }
+}
+
+/// Urls with a scheme are fetched, anything else is a path
+fn metalink_source(path_or_url: &str) -> MetalinkSource {
+    let path = PathBuf::from(path_or_url);
+    match url::Url::parse(path_or_url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => MetalinkSource::Url(url),
+        _ if path.is_dir() => MetalinkSource::Dir(path),
+        _ => MetalinkSource::File(path),
+    }
+}
+
+/// Settings of a [`MetalinkDownloader`], unset settings take the defaults of
+/// the command line
+#[derive(Clone)]
+pub struct MetalinkDownloaderBuilder {
+    target_dir: PathBuf,
+    http: HttpOptions,
+    max_threads: u16,
+    verify_chunk_checksums: bool,
+    verify_files: bool,
+    keep_going: bool,
+    lock: LockMode,
+    show_progress: bool,
+    metaurl_handlers: MetaUrlHandlers,
+    transports: Transports,
+}
+
+impl Default for MetalinkDownloaderBuilder {
+    fn default() -> Self {
+        Self {
+            target_dir: PathBuf::from("."),
+            http: HttpOptions {
+                user_agent: DEFAULT_USER_AGENT.to_owned(),
+                max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+                ..Default::default()
+            },
+            max_threads: 0,
+            verify_chunk_checksums: true,
+            verify_files: true,
+            keep_going: false,
+            lock: LockMode::Fail,
+            show_progress: false,
+            metaurl_handlers: MetaUrlHandlers::default(),
+            transports: Transports::default(),
+        }
+    }
+}
+
+impl MetalinkDownloaderBuilder {
+    /// Directory the files are downloaded into, the working directory by
+    /// default
+    pub fn target_dir(mut self, target_dir: impl Into<PathBuf>) -> Self {
+        self.target_dir = target_dir.into();
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.http.user_agent = user_agent.into();
+        self
+    }
+
+    /// Max number of concurrent chunk downloads of
+    /// [`download_file`](MetalinkDownloader::download_file), 0 picks one per
+    /// core. Metalinks are not affected, their pieces are downloaded one
+    /// after the other.
+    pub fn max_threads(mut self, max_threads: u16) -> Self {
+        self.max_threads = max_threads;
+        self
+    }
+
+    /// Fetch adjacent pieces with one request of at most this many bytes, 0
+    /// requests every piece on its own
+    pub fn max_request_size(mut self, max_request_size: u64) -> Self {
+        self.http.max_request_size = max_request_size;
+        self
+    }
+
+    /// Send all requests through this proxy
+    pub fn proxy(mut self, proxy: url::Url) -> Self {
+        self.http.proxy = Some(proxy);
+        self
+    }
+
+    /// Allow mirrors and metalinks served over plain http
+    pub fn allow_http(mut self, allow_http: bool) -> Self {
+        self.http.allow_http = allow_http;
+        self
+    }
+
+    /// Verify pieces while downloading, enabled by default
+    pub fn verify_chunk_checksums(mut self, verify: bool) -> Self {
+        self.verify_chunk_checksums = verify;
+        self
+    }
+
+    /// Verify downloaded files against their checksums, enabled by default
+    pub fn verify_files(mut self, verify: bool) -> Self {
+        self.verify_files = verify;
+        self
+    }
+
+    /// Continue with the remaining files after a file failed
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Whether to wait for or fail on other runs holding the lock on the
+    /// target directory, fails by default
+    pub fn lock(mut self, lock: LockMode) -> Self {
+        self.lock = lock;
+        self
+    }
+
+    /// Display progress and a summary on stdout like the command line,
+    /// downloads are silent by default
+    pub fn show_progress(mut self, show_progress: bool) -> Self {
+        self.show_progress = show_progress;
+        self
+    }
+
+    /// Register a handler for files published through metaurls, e.g. BitTorrent
+    pub fn metaurl_handler(mut self, handler: impl MetaUrlHandler + 'static) -> Self {
+        self.metaurl_handlers.push(Arc::new(handler));
+        self
+    }
+
+    /// Register a transport for urls the built-in http transport does not
+    /// fetch, registered transports take precedence over the built-in one
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transports.push(Arc::new(transport));
+        self
+    }
+
+    /// Resolve the hosts of mirrors with this resolver instead of the
+    /// system one
+    pub fn dns_resolver(mut self, resolver: impl reqwest::dns::Resolve + 'static) -> Self {
+        self.http.dns.resolver = Some(crate::dns::Resolver(Arc::new(resolver)));
+        self
+    }
+
+    /// Replace all http settings, e.g. with the ones of the command line
+    pub(crate) fn http_options(mut self, http: HttpOptions) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Replace the registered handlers and transports, e.g. with the ones
+    /// registered with the [`App`](crate::App)
+    pub(crate) fn extensions(
+        mut self,
+        metaurl_handlers: MetaUrlHandlers,
+        transports: Transports,
+    ) -> Self {
+        self.metaurl_handlers = metaurl_handlers;
+        self.transports = transports;
+        self
+    }
+
+    /// Fails if the http settings are invalid, e.g. a user agent which is
+    /// not a valid header value
+    pub fn build(self) -> Result<MetalinkDownloader> {
+        make_http_client(&self.http)?;
+        Ok(MetalinkDownloader {
+            target_dir: self.target_dir,
+            http: self.http,
+            max_threads: self.max_threads,
+            verify_chunk_checksums: self.verify_chunk_checksums,
+            verify_files: self.verify_files,
+            keep_going: self.keep_going,
+            lock: self.lock,
+            progress: ProgressMode::detect(!self.show_progress),
+            metaurl_handlers: self.metaurl_handlers,
+            transports: self.transports,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metalink_sources_are_told_apart() {
+        assert!(matches!(
+            metalink_source("https://example.com/file.meta4"),
+            MetalinkSource::Url(_)
+        ));
+        assert!(matches!(
+            metalink_source(std::env::temp_dir().to_str().unwrap()),
+            MetalinkSource::Dir(_)
+        ));
+        assert!(matches!(
+            metalink_source("release.meta4"),
+            MetalinkSource::File(_)
+        ));
+        assert!(MetalinkDownloader::builder()
+            .user_agent("invalid\nagent")
+            .build()
+            .is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use miette::Diagnostic;
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Diagnostic, Debug, Error)]
@@ -21,8 +22,198 @@ pub enum MetalinkDownloadError {
     #[error(transparent)]
     IoError(#[from] std::io::Error),
 
+    #[error("Checksum mismatch for {file:?} in range {start}-{end}: expected {expected}, found {actual}")]
+    #[diagnostic(
+        code(metalink_downloader::checksum_mismatch),
+        help("the mirror served corrupt data, exclude it with --exclude-mirror if this persists")
+    )]
+    ChecksumMismatch {
+        file: PathBuf,
+        start: u64,
+        end: u64,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Checksum mismatch for {file:?}: expected {expected}, found {actual}")]
+    #[diagnostic(
+        code(metalink_downloader::file_checksum_mismatch),
+        help("download the file again, --quarantine-dir keeps failing files for inspection")
+    )]
+    FileChecksumMismatch {
+        file: PathBuf,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Size mismatch for {file:?}: expected {expected} bytes, found {actual} bytes")]
+    #[diagnostic(code(metalink_downloader::size_mismatch))]
+    SizeMismatch {
+        file: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("{dir:?} is locked by another run ({holder}), use --lock wait to wait for it")]
+    #[diagnostic(code(metalink_downloader::locked))]
+    Locked { dir: PathBuf, holder: String },
+
+    #[error("{file:?} already exists without checksums to validate it, use --on-conflict to overwrite, skip or rename it")]
+    #[diagnostic(code(metalink_downloader::conflict))]
+    Conflict { file: PathBuf },
+
+    #[error("The file name {name:?} is not a relative path inside the target directory")]
+    #[diagnostic(
+        code(metalink_downloader::unsafe_file_name),
+        help(
+            "the metalink is malformed or malicious, names must not be absolute or contain \"..\""
+        )
+    )]
+    UnsafeFileName { name: String },
+
+    #[error("{url} does not serve byte ranges correctly: {reason}")]
+    #[diagnostic(code(metalink_downloader::invalid_range_response))]
+    InvalidRangeResponse { url: url::Url, reason: String },
+
+    #[error("{url} does not support range requests")]
+    #[diagnostic(code(metalink_downloader::range_not_supported))]
+    RangeNotSupported { url: url::Url },
+
+    #[error("{url} uses the unsupported scheme {}", url.scheme())]
+    #[diagnostic(
+        code(metalink_downloader::unsupported_scheme),
+        help(
+            "only http and https urls are downloaded unless an application embedding the downloader registers a transport for the scheme"
+        )
+    )]
+    UnsupportedScheme { url: url::Url },
+
+    #[error("All sources of {file:?} failed: {}", join_errors(errors))]
+    #[diagnostic(code(metalink_downloader::all_mirrors_failed))]
+    AllMirrorsFailed {
+        file: PathBuf,
+        /// The error of every source tried, in order
+        errors: Vec<MetalinkDownloadError>,
+    },
+
+    #[error("{needed} bytes are needed in {dir:?} but only {available} bytes are free")]
+    #[diagnostic(
+        code(metalink_downloader::insufficient_disk_space),
+        help("free up space, download fewer files with --include or --exclude, or download to another directory")
+    )]
+    InsufficientDiskSpace {
+        dir: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+
+    #[error("Download of {file:?} was cancelled")]
+    #[diagnostic(code(metalink_downloader::cancelled))]
+    Cancelled { file: PathBuf },
+
+    #[error("Download interrupted, the data on disk is kept, resume with: {command}")]
+    #[diagnostic(code(metalink_downloader::interrupted))]
+    Interrupted { command: String },
+
+    #[error("{failed} of {total} files failed")]
+    #[diagnostic(
+        code(metalink_downloader::files_failed),
+        help("run the same command again to retry only the failed files")
+    )]
+    FilesFailed {
+        failed: usize,
+        total: usize,
+        /// Whether any of the failed files failed verification
+        verification_failed: bool,
+        /// Whether all failed files failed because of network errors
+        network_failed: bool,
+    },
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+/// Exit code of a run where some files failed verification
+pub const EXIT_VERIFICATION_FAILED: i32 = 3;
+/// Exit code of a run where all files failed because of network errors
+pub const EXIT_NETWORK_FAILED: i32 = 4;
+/// Exit code of a run where some files were downloaded and others failed
+pub const EXIT_PARTIAL_SUCCESS: i32 = 5;
+/// Exit code of a run stopped by SIGINT or SIGTERM
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+impl MetalinkDownloadError {
+    /// Exit code of the process if the run fails with this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::FilesFailed {
+                verification_failed: true,
+                ..
+            } => EXIT_VERIFICATION_FAILED,
+            Self::FilesFailed { failed, total, .. } if failed < total => EXIT_PARTIAL_SUCCESS,
+            Self::FilesFailed {
+                network_failed: true,
+                ..
+            } => EXIT_NETWORK_FAILED,
+            Self::Interrupted { .. } => EXIT_INTERRUPTED,
+            _ => 1,
+        }
+    }
+
+    /// Whether the downloaded data did not match the metalink
+    pub(crate) fn is_verification_failure(&self) -> bool {
+        self.any_cause(&|e| {
+            matches!(
+                e,
+                Self::ChecksumMismatch { .. }
+                    | Self::FileChecksumMismatch { .. }
+                    | Self::SizeMismatch { .. }
+            )
+        })
+    }
+
+    /// Whether the download was cancelled instead of failing
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.any_cause(&|e| matches!(e, Self::Cancelled { .. }))
+    }
+
+    /// Whether a mirror answered a range request with something else than
+    /// the requested range
+    pub(crate) fn is_invalid_range_response(&self) -> bool {
+        self.any_cause(&|e| matches!(e, Self::InvalidRangeResponse { .. }))
+    }
+
+    /// Whether the error was caused by a failed request
+    pub(crate) fn is_network_failure(&self) -> bool {
+        self.any_cause(&|e| {
+            matches!(e, Self::RequestError(_) | Self::RequestMiddlewareError(_))
+                || matches!(e, Self::Other(e) if e.chain().any(|cause| {
+                    cause.is::<reqwest::Error>() || cause.is::<reqwest_middleware::Error>()
+                }))
+        })
+    }
+
+    /// Whether `predicate` holds for this error or an error it was caused by,
+    /// looking through context added with anyhow and the errors of all
+    /// sources of a file
+    fn any_cause(&self, predicate: &dyn Fn(&Self) -> bool) -> bool {
+        predicate(self)
+            || matches!(self, Self::Other(e) if e
+                .chain()
+                .filter_map(|cause| cause.downcast_ref::<Self>())
+                .any(|cause| cause.any_cause(predicate)))
+            || matches!(self, Self::AllMirrorsFailed { errors, .. } if errors
+                .iter()
+                .any(|error| error.any_cause(predicate)))
+    }
+}
+
+fn join_errors(errors: &[MetalinkDownloadError]) -> String {
+    errors
+        .iter()
+        .map(|error| format!("{error:#}"))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
 pub type Result<T> = std::result::Result<T, MetalinkDownloadError>;
@@ -0,0 +1,117 @@
+//! Events of running downloads for applications embedding the downloader.
+//!
+//! Events are broadcast process wide to every receiver returned by
+//! [`subscribe`], nothing is recorded before the first subscription. A
+//! receiver which falls behind by more than [`EVENT_CHANNEL_CAPACITY`]
+//! events skips the oldest ones and is told how many it missed with
+//! [`RecvError::Lagged`](tokio::sync::broadcast::error::RecvError::Lagged).
+//! Files are identified by their target path.
+
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+
+/// Number of events buffered for each receiver
+pub const EVENT_CHANNEL_CAPACITY: usize = 4096;
+
+/// Something that happened during a download
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DownloadEvent {
+    /// The files of a download were planned, `bytes` of them are fetched
+    DownloadPlanned { files: usize, bytes: u64 },
+    /// The download of a file of `size` bytes started
+    FileStarted { file: PathBuf, size: u64 },
+    /// `bytes` more bytes of a file were fetched or copied
+    Progressed { file: PathBuf, bytes: u64 },
+    /// A chunk of a file was written to disk
+    ChunkCompleted {
+        file: PathBuf,
+        offset: u64,
+        bytes: u64,
+    },
+    /// All bytes of a file were downloaded, it is verified next
+    FileDownloaded { file: PathBuf },
+    /// The file matched its checksum
+    VerificationPassed { file: PathBuf },
+    /// The file is in place at its target path, verified unless it has no
+    /// checksum or verification is disabled
+    FileCompleted { file: PathBuf },
+    /// The file did not match the metalink, it is not kept
+    VerificationFailed { file: PathBuf, reason: String },
+    /// Downloading the file failed
+    FileFailed { file: PathBuf, reason: String },
+    /// The download of the file was cancelled
+    FileCancelled { file: PathBuf },
+    /// A request is sent again after the previous attempt failed
+    Retry {
+        url: url::Url,
+        /// Number of the attempt about to be made, starting at 2
+        attempt: u32,
+        /// Why the previous attempt failed
+        reason: String,
+    },
+    /// A mirror of a file was given up on in favour of another source
+    MirrorSwitched {
+        file: PathBuf,
+        from: url::Url,
+        to: url::Url,
+        reason: String,
+    },
+}
+
+static EVENTS: OnceLock<broadcast::Sender<DownloadEvent>> = OnceLock::new();
+
+/// Receive the events of all downloads of the process from now on
+pub fn subscribe() -> broadcast::Receiver<DownloadEvent> {
+    EVENTS
+        .get_or_init(|| broadcast::channel(EVENT_CHANNEL_CAPACITY).0)
+        .subscribe()
+}
+
+/// Broadcast the event built by `event`, which is only built if somebody
+/// is subscribed
+pub(crate) fn emit(event: impl FnOnce() -> DownloadEvent) {
+    if let Some(events) = EVENTS.get() {
+        if events.receiver_count() > 0 {
+            // Receivers may be dropped concurrently, nobody is left to tell
+            let _ = events.send(event());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn subscribers_receive_events() {
+        let mut events = subscribe();
+        let file = PathBuf::from("subscribers_receive_events");
+        emit(|| DownloadEvent::FileStarted {
+            file: file.clone(),
+            size: 3,
+        });
+        emit(|| DownloadEvent::FileDownloaded { file: file.clone() });
+
+        // Downloads of other tests run in the same process
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let event = events.recv().await.unwrap();
+            if matches!(&event, DownloadEvent::FileStarted { file: f, .. } | DownloadEvent::FileDownloaded { file: f } if *f == file)
+            {
+                received.push(event);
+            }
+        }
+        assert_eq!(
+            received,
+            [
+                DownloadEvent::FileStarted {
+                    file: file.clone(),
+                    size: 3
+                },
+                DownloadEvent::FileDownloaded { file }
+            ]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! History of the downloads into a target directory.
+//!
+//! Every file a download completed is appended as one JSON line to a history
+//! file inside the target directory: its size, the checksum it was verified
+//! against, the mirror it came from and how long the download took. Lines
+//! are never rewritten, so the file doubles as an audit log and `history`
+//! queries it.
+
+use crate::report::Verification;
+use crate::types::FilePlan;
+use crate::Result;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Name of the file inside the target directory the history is kept in
+const HISTORY_FILE: &str = ".metalink-downloader.history.jsonl";
+
+/// A completed download as recorded in the history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct HistoryEntry {
+    /// When the download completed, RFC 3339 in UTC
+    pub timestamp: String,
+    /// Path of the file relative to the target directory
+    pub file: PathBuf,
+    pub size: u64,
+    /// The checksum the file was verified against, e.g. `sha-256:…`. None if
+    /// the file was not verified.
+    pub verified_checksum: Option<String>,
+    /// The mirror the file was downloaded from, None if it was copied from a
+    /// local source
+    pub mirror: Option<url::Url>,
+    /// Seconds the download took
+    pub duration: f64,
+}
+
+/// Where the download of a file came from and how long it took, until its
+/// outcome is known
+#[derive(Debug)]
+struct Downloaded {
+    mirror: Option<url::Url>,
+    duration: Duration,
+}
+
+/// The history of a target directory
+#[derive(Debug)]
+pub(crate) struct History {
+    target_dir: PathBuf,
+    downloaded: Mutex<HashMap<PathBuf, Downloaded>>,
+}
+
+impl History {
+    pub(crate) fn new(target_dir: &Path) -> Self {
+        Self {
+            target_dir: target_dir.to_path_buf(),
+            downloaded: Mutex::default(),
+        }
+    }
+
+    /// Remember that `target_file` was downloaded from `mirror` in `duration`,
+    /// it is recorded once it is verified
+    pub(crate) fn downloaded(
+        &self,
+        target_file: &Path,
+        mirror: Option<url::Url>,
+        duration: Duration,
+    ) {
+        self.lock()
+            .insert(target_file.to_path_buf(), Downloaded { mirror, duration });
+    }
+
+    /// Append an entry for each of the completed `files` which was downloaded
+    /// in this run
+    pub(crate) fn record<'a>(
+        &self,
+        files: impl IntoIterator<Item = (&'a FilePlan, Verification)>,
+    ) -> Result<()> {
+        let timestamp = now();
+        let mut lines = Vec::new();
+        let downloaded = self.lock();
+        for (file, verification) in files {
+            let Some(Downloaded { mirror, duration }) = downloaded.get(&file.target_file) else {
+                continue;
+            };
+            let size = match file.file_size {
+                Some(size) => size,
+                None => std::fs::metadata(&file.target_file)
+                    .with_context(|| format!("Failed to read metadata of {:?}", file.target_file))?
+                    .len(),
+            };
+            let verified_checksum = match (verification, &file.file_checksums) {
+                (Verification::Verified, Some(checksum)) => {
+                    Some(format!("{}:{}", checksum.hash_type(), checksum.expected()))
+                }
+                _ => None,
+            };
+            let entry = HistoryEntry {
+                timestamp: timestamp.clone(),
+                file: file
+                    .target_file
+                    .strip_prefix(&self.target_dir)
+                    .unwrap_or(&file.target_file)
+                    .to_path_buf(),
+                size,
+                verified_checksum,
+                mirror: mirror.clone(),
+                duration: duration.as_secs_f64(),
+            };
+            serde_json::to_writer(&mut lines, &entry)
+                .with_context(|| "Failed to serialize history entry")?;
+            lines.push(b'\n');
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let path = self.target_dir.join(HISTORY_FILE);
+        // A single write keeps the lines of concurrent runs apart
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut history| history.write_all(&lines))
+            .with_context(|| format!("Failed to append to history {path:?}"))?;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Downloaded>> {
+        self.downloaded.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The entries of the history of `target_dir`, oldest first. Lines which
+/// can not be parsed are skipped.
+pub(crate) fn read(target_dir: &Path) -> Result<Vec<HistoryEntry>> {
+    let path = target_dir.join(HISTORY_FILE);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(anyhow::Error::new(e)
+                .context(format!("Failed to read history {path:?}"))
+                .into())
+        }
+    };
+    Ok(content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(|(i, line)| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                tracing::warn!("Ignoring invalid line {} of {path:?}: {e}", i + 1);
+                None
+            }
+        })
+        .collect())
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completed_downloads_are_appended() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let file = |name: &str| FilePlan {
+            name: name.to_owned(),
+            target_file: dir.join(name),
+            url: None,
+            urls: Vec::new(),
+            metaurls: Vec::new(),
+            file_checksums: None,
+            chunks: None,
+            file_size: Some(6),
+            modified: None,
+        };
+        let (a, b) = (file("a.txt"), file("b.txt"));
+        let mirror = url::Url::parse("https://mirror.example/a.txt").unwrap();
+
+        let history = History::new(dir);
+        history.downloaded(&a.target_file, Some(mirror.clone()), Duration::from_secs(2));
+        // b was not downloaded in this run
+        history
+            .record([
+                (&a, Verification::NoChecksum),
+                (&b, Verification::NoChecksum),
+            ])
+            .unwrap();
+        History::new(dir).record([]).unwrap();
+
+        let entries = read(dir).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].file, PathBuf::from("a.txt"));
+        assert_eq!(entries[0].mirror, Some(mirror));
+        assert_eq!(entries[0].duration, 2.0);
+        assert_eq!(entries[0].verified_checksum, None);
+
+        history.record([(&a, Verification::Disabled)]).unwrap();
+        assert_eq!(read(dir).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,305 @@
+//! Notifications about finished downloads configured in the config file.
+//!
+//! ```toml
+//! # POST a JSON payload describing the download
+//! on-success = [{ url = "https://chat.example.com/hooks/downloads" }]
+//!
+//! # Run a command, the result is passed in `METALINK_*` variables
+//! [[on-failure]]
+//! command = ["/usr/local/bin/page-oncall", "metalink download failed"]
+//!
+//! [[on-file-complete]]
+//! command = ["/usr/local/bin/publish"]
+//! ```
+//!
+//! Commands get `METALINK_EVENT` (`success`, `failure` or `file-complete`)
+//! and `METALINK_PAYLOAD`, the JSON payload posted to urls. Success and
+//! failure hooks also get `METALINK_SUCCEEDED`, `METALINK_SKIPPED` and
+//! `METALINK_FAILED` with the number of files, failure hooks the error in
+//! `METALINK_ERROR`, file hooks the completed file in `METALINK_FILE`.
+//!
+//! A failing hook is logged, it never fails the download.
+
+use crate::config::DEFAULT_USER_AGENT;
+use crate::events::{self, DownloadEvent};
+use crate::{DownloadReport, Result};
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Give up on a hook url which does not answer within this time
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where a notification is sent
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) enum Hook {
+    /// POST the payload as JSON to this url
+    Url(url::Url),
+    /// Run this program with these arguments
+    Command(Vec<String>),
+}
+
+/// The hooks of the config file
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Hooks {
+    pub on_success: Vec<Hook>,
+    pub on_failure: Vec<Hook>,
+    pub on_file_complete: Vec<Hook>,
+}
+
+/// A file which failed to download
+#[derive(Debug, Serialize)]
+struct FailedFile {
+    file: PathBuf,
+    error: String,
+}
+
+/// What the hooks are told
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum Payload {
+    Success {
+        succeeded: Vec<PathBuf>,
+        skipped: Vec<PathBuf>,
+    },
+    Failure {
+        error: String,
+        succeeded: Vec<PathBuf>,
+        skipped: Vec<PathBuf>,
+        failed: Vec<FailedFile>,
+    },
+    FileComplete {
+        file: PathBuf,
+    },
+}
+
+impl Payload {
+    /// The payload of the outcome of a whole download
+    fn finished(result: &Result<DownloadReport>) -> Self {
+        let report = match result {
+            Ok(report) => report,
+            Err(e) => {
+                return Self::Failure {
+                    error: format!("{e:#}"),
+                    succeeded: Vec::new(),
+                    skipped: Vec::new(),
+                    failed: Vec::new(),
+                }
+            }
+        };
+        match report.failure() {
+            None => Self::Success {
+                succeeded: report.succeeded.clone(),
+                skipped: report.skipped.clone(),
+            },
+            Some(e) => Self::Failure {
+                error: format!("{e:#}"),
+                succeeded: report.succeeded.clone(),
+                skipped: report.skipped.clone(),
+                failed: report
+                    .failed
+                    .iter()
+                    .map(|(file, e)| FailedFile {
+                        file: file.clone(),
+                        error: format!("{e:#}"),
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    fn event(&self) -> &'static str {
+        match self {
+            Self::Success { .. } => "success",
+            Self::Failure { .. } => "failure",
+            Self::FileComplete { .. } => "file-complete",
+        }
+    }
+
+    /// The `METALINK_*` variables describing the payload to commands
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("METALINK_EVENT", self.event().to_owned()),
+            (
+                "METALINK_PAYLOAD",
+                serde_json::to_string(self).unwrap_or_default(),
+            ),
+        ];
+        let counts = |succeeded: &[PathBuf], skipped: &[PathBuf], failed: usize| {
+            [
+                ("METALINK_SUCCEEDED", succeeded.len().to_string()),
+                ("METALINK_SKIPPED", skipped.len().to_string()),
+                ("METALINK_FAILED", failed.to_string()),
+            ]
+        };
+        match self {
+            Self::Success { succeeded, skipped } => env.extend(counts(succeeded, skipped, 0)),
+            Self::Failure {
+                error,
+                succeeded,
+                skipped,
+                failed,
+            } => {
+                env.extend(counts(succeeded, skipped, failed.len()));
+                env.push(("METALINK_ERROR", error.clone()));
+            }
+            Self::FileComplete { file } => {
+                env.push(("METALINK_FILE", file.display().to_string()));
+            }
+        }
+        env
+    }
+}
+
+impl Hooks {
+    /// Notify the success or failure hooks about the outcome of a download
+    pub(crate) async fn finished(&self, result: &Result<DownloadReport>) {
+        let payload = Payload::finished(result);
+        let hooks = match payload {
+            Payload::Success { .. } => &self.on_success,
+            _ => &self.on_failure,
+        };
+        run_all(hooks, &payload).await;
+    }
+
+    /// Notify the file hooks about every file completed until the returned
+    /// watcher is finished, None if there are no file hooks
+    pub(crate) fn watch_files(&self) -> Option<FileHooks> {
+        if self.on_file_complete.is_empty() {
+            return None;
+        }
+        let stop = CancellationToken::new();
+        let task = tokio::spawn(notify_files(
+            events::subscribe(),
+            self.on_file_complete.clone(),
+            stop.clone(),
+        ));
+        Some(FileHooks { stop, task })
+    }
+}
+
+/// Runs the file hooks of completed files
+pub(crate) struct FileHooks {
+    stop: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl FileHooks {
+    /// Wait for the hooks of the files completed so far
+    pub(crate) async fn finish(self) {
+        self.stop.cancel();
+        if let Err(e) = self.task.await {
+            tracing::warn!("File hook task failed: {e}");
+        }
+    }
+}
+
+async fn notify_files(
+    mut events: tokio::sync::broadcast::Receiver<DownloadEvent>,
+    hooks: Vec<Hook>,
+    stop: CancellationToken,
+) {
+    let notify = |event: DownloadEvent| {
+        let hooks = &hooks;
+        async move {
+            if let DownloadEvent::FileCompleted { file } = event {
+                run_all(hooks, &Payload::FileComplete { file }).await;
+            }
+        }
+    };
+    loop {
+        tokio::select! {
+            biased;
+            event = events.recv() => match event {
+                Ok(event) => notify(event).await,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("File hooks missed {missed} download events");
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = stop.cancelled() => break,
+        }
+    }
+    loop {
+        match events.try_recv() {
+            Ok(event) => notify(event).await,
+            Err(TryRecvError::Lagged(_)) => {}
+            Err(TryRecvError::Empty | TryRecvError::Closed) => return,
+        }
+    }
+}
+
+async fn run_all(hooks: &[Hook], payload: &Payload) {
+    for hook in hooks {
+        if let Err(e) = run(hook, payload).await {
+            tracing::warn!("{} hook {hook:?} failed: {e:#}", payload.event());
+        }
+    }
+}
+
+async fn run(hook: &Hook, payload: &Payload) -> anyhow::Result<()> {
+    match hook {
+        Hook::Url(url) => {
+            reqwest::Client::builder()
+                .user_agent(DEFAULT_USER_AGENT)
+                .timeout(HOOK_TIMEOUT)
+                .build()?
+                .post(url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(payload)?)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        Hook::Command(command) => {
+            let Some((program, args)) = command.split_first() else {
+                anyhow::bail!("the command is empty");
+            };
+            let status = tokio::process::Command::new(program)
+                .args(args)
+                .envs(payload.env())
+                .status()
+                .await?;
+            if !status.success() {
+                anyhow::bail!("{program} exited with {status}");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_describe_the_outcome() {
+        let report = DownloadReport {
+            succeeded: vec![PathBuf::from("a.iso")],
+            skipped: Vec::new(),
+            failed: vec![(
+                PathBuf::from("b.iso"),
+                crate::MetalinkDownloadError::Other(anyhow::anyhow!("connection reset")),
+            )],
+        };
+        let payload = Payload::finished(&Ok(report));
+        let json: serde_json::Value = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["event"], "failure");
+        assert_eq!(json["succeeded"][0], "a.iso");
+        assert_eq!(json["failed"][0]["file"], "b.iso");
+        assert_eq!(json["failed"][0]["error"], "connection reset");
+
+        let env = payload.env();
+        assert!(env.contains(&("METALINK_EVENT", "failure".to_owned())));
+        assert!(env.contains(&("METALINK_SUCCEEDED", "1".to_owned())));
+        assert!(env.contains(&("METALINK_FAILED", "1".to_owned())));
+
+        let payload = Payload::finished(&Ok(DownloadReport::default()));
+        assert_eq!(payload.event(), "success");
+    }
+}
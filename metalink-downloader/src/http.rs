@@ -1,42 +1,428 @@
-use crate::types::{ChunkMetaData, Command, ProgressUpdate};
-use crate::Result;
-use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
+use crate::auth::AuthOptions;
+use crate::backpressure::{record_stall, WriterStalls, WRITE_QUEUE_CAPACITY};
+use crate::connections::{ConnectionStatsMiddleware, PoolOptions};
+use crate::control::Pause;
+use crate::cookies::CookieOptions;
+use crate::dns::DnsOptions;
+use crate::events::{self, DownloadEvent};
+use crate::latency::{self, timed, Stage};
+use crate::politeness::PolitenessOptions;
+use crate::progress::{ProgressSender, ProgressUpdate};
+use crate::rate_limit;
+use crate::replay::{self, FetchOutcome, ReplayEvent};
+use crate::run;
+use crate::shutdown;
+use crate::transport::{Fetched, Probe, Transport};
+use crate::types::{hash_buffer_size, hash_threads, ChunkMetaData, Command};
+use crate::validators::Validators;
+use crate::warnings::{self, Warning};
+use crate::writeback::{FileSink, WriteBuffer};
+use crate::{MetalinkDownloadError, Result};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, Middleware, Next};
 use reqwest_retry::{policies::ExponentialBackoff, Jitter, RetryTransientMiddleware};
 
-use anyhow::{anyhow, Context};
-use log::info;
-use std::io::{Seek, Write};
-use std::path::PathBuf;
-use std::time::Duration;
+use anyhow::Context;
+use std::collections::VecDeque;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, Instrument};
 
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tokio::task::JoinHandle;
 
 pub(crate) type Client = ClientWithMiddleware;
 
+/// Most concurrent downloads of a file picked automatically
+const MAX_AUTO_THREADS: usize = 16;
+
+/// How many chunks of a file are downloaded concurrently
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Concurrency {
+    /// Max number of concurrent chunk downloads, 0 picks one per core
+    pub max_threads: u16,
+    /// Halve the number of concurrent downloads while the disk can not keep up
+    pub reduce_on_slow_disk: bool,
+}
+
+impl Concurrency {
+    /// Number of chunks downloaded at the same time out of `chunks`, there
+    /// are no more downloads than chunks
+    pub(crate) fn parallelism(&self, chunks: usize) -> usize {
+        let threads = match self.max_threads {
+            0 => std::thread::available_parallelism()
+                .map_or(2, |n| n.get())
+                .clamp(2, MAX_AUTO_THREADS),
+            threads => threads as usize,
+        };
+        threads.min(chunks).max(1)
+    }
+}
+
+/// Size of the segments a plain file is downloaded in if not configured
+pub(crate) const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Largest segment size picked by `--chunk-size auto`
+const MAX_AUTO_CHUNK_SIZE: u64 = 64 * DEFAULT_CHUNK_SIZE;
+
+/// How long fetching one segment should take with `--chunk-size auto`, long
+/// enough that the request overhead does not matter on high latency links
+const AUTO_CHUNK_DURATION: Duration = Duration::from_secs(2);
+
+/// Size of the segments a plain file is downloaded in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkSize {
+    Fixed(u64),
+    /// Derived from the throughput measured while fetching the first segment
+    Auto,
+}
+
+/// How a plain file is split into segments
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Segmentation {
+    pub chunk_size: ChunkSize,
+    /// Files up to this size are downloaded in a single request
+    pub min_split_size: u64,
+}
+
+/// Settings of the http client
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HttpOptions {
+    pub user_agent: String,
+    /// Send all requests through this proxy
+    pub proxy: Option<url::Url>,
+    /// Allow requests over plain http
+    pub allow_http: bool,
+    pub tls: TlsOptions,
+    pub auth: AuthOptions,
+    /// Sent with all requests
+    pub headers: reqwest::header::HeaderMap,
+    pub cookies: CookieOptions,
+    pub version: HttpVersion,
+    pub timeouts: Timeouts,
+    pub redirects: RedirectOptions,
+    pub politeness: PolitenessOptions,
+    pub pool: PoolOptions,
+    pub dns: DnsOptions,
+    /// Adjacent pieces are fetched with one request of at most this many
+    /// bytes, 0 fetches every piece with its own request
+    pub max_request_size: u64,
+}
+
+/// Size of the requests adjacent pieces are coalesced into if not configured
+pub(crate) const DEFAULT_MAX_REQUEST_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How the pieces of a file are requested
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PieceFetch {
+    /// Verify every piece against its hash before writing it
+    pub verify_checksums: bool,
+    /// Adjacent pieces are coalesced into requests of at most this size
+    pub max_request_size: u64,
+}
+
+/// Number of redirects followed if not configured
+pub(crate) const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// Which redirects of the mirrors are followed
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RedirectOptions {
+    pub max: usize,
+    /// Follow redirects to other hosts than the mirror
+    pub cross_host: bool,
+}
+
+impl Default for RedirectOptions {
+    fn default() -> Self {
+        Self {
+            max: DEFAULT_MAX_REDIRECTS,
+            cross_host: true,
+        }
+    }
+}
+
+impl RedirectOptions {
+    fn policy(self) -> reqwest::redirect::Policy {
+        reqwest::redirect::Policy::custom(move |attempt| {
+            match self.refusal(attempt.previous(), attempt.url()) {
+                Some(reason) => attempt.error(reason),
+                None => attempt.follow(),
+            }
+        })
+    }
+
+    /// Why a redirect to `next` after requesting the `previous` urls is not
+    /// followed, None if it is
+    fn refusal(&self, previous: &[reqwest::Url], next: &reqwest::Url) -> Option<String> {
+        if previous.len() > self.max {
+            return Some(format!(
+                "{next}: too many redirects, at most {} are followed",
+                self.max
+            ));
+        }
+        let mirror = previous.first()?;
+        if !self.cross_host && mirror.host_str() != next.host_str() {
+            return Some(format!(
+                "{mirror} redirected to another host: {next}, cross host redirects are disabled"
+            ));
+        }
+        None
+    }
+}
+
+/// Time to connect to a mirror if not configured
+pub(crate) const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+/// Time without receiving any data before a request is aborted if not
+/// configured
+pub(crate) const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Limits on how long requests may take
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Timeouts {
+    pub connect: Duration,
+    /// Idle time between two reads of the response, a slow but steady
+    /// transfer is never aborted
+    pub read: Duration,
+    /// Deadline of each attempt to fetch a chunk, including its body
+    pub chunk: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            read: DEFAULT_READ_TIMEOUT,
+            chunk: None,
+        }
+    }
+}
+
+/// Reports requests sent again by the retry middleware as events, placed
+/// after the retry middleware so it sees every attempt
+struct RetryEventMiddleware;
+
+/// The previous attempt of a request, kept in the extensions of the request
+/// across attempts
+#[derive(Clone)]
+struct PreviousAttempt {
+    number: u32,
+    /// Why the attempt failed, None if it succeeded
+    failure: Option<String>,
+}
+
+#[async_trait::async_trait]
+impl Middleware for RetryEventMiddleware {
+    async fn handle(
+        &self,
+        req: reqwest::Request,
+        extensions: &mut http::Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest::Response> {
+        let previous = extensions.remove::<PreviousAttempt>();
+        let number = previous.as_ref().map_or(1, |previous| previous.number + 1);
+        if let Some(reason) = previous.and_then(|previous| previous.failure) {
+            events::emit(|| DownloadEvent::Retry {
+                url: req.url().clone(),
+                attempt: number,
+                reason,
+            });
+        }
+        let result = next.run(req, extensions).await;
+        let failure = match &result {
+            Ok(response) if response.status().is_server_error() => {
+                Some(format!("status {}", response.status()))
+            }
+            Ok(response) => matches!(
+                response.status(),
+                reqwest::StatusCode::TOO_MANY_REQUESTS | reqwest::StatusCode::REQUEST_TIMEOUT
+            )
+            .then(|| format!("status {}", response.status())),
+            Err(e) => Some(e.to_string()),
+        };
+        extensions.insert(PreviousAttempt { number, failure });
+        result
+    }
+}
+
+/// Sets the deadline of range requests, placed after the retry middleware
+/// so every attempt gets the full deadline
+struct ChunkDeadlineMiddleware {
+    deadline: Duration,
+}
+
+#[async_trait::async_trait]
+impl Middleware for ChunkDeadlineMiddleware {
+    async fn handle(
+        &self,
+        mut req: reqwest::Request,
+        extensions: &mut http::Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest::Response> {
+        if req.headers().contains_key(reqwest::header::RANGE) && req.timeout().is_none() {
+            *req.timeout_mut() = Some(self.deadline);
+        }
+        next.run(req, extensions).await
+    }
+}
+
+/// HTTP version spoken with the mirrors
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HttpVersion {
+    /// Only HTTP/1.1
+    #[value(name = "1")]
+    Http1,
+    /// Only HTTP/2, without negotiating it first
+    #[value(name = "2")]
+    Http2,
+    /// Negotiated with each mirror during the TLS handshake
+    #[default]
+    Auto,
+}
+
+impl std::fmt::Display for HttpVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpVersion::Http1 => write!(f, "HTTP/1.1"),
+            HttpVersion::Http2 => write!(f, "HTTP/2"),
+            HttpVersion::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Explains failed requests with a forced HTTP version, the mirror may not
+/// speak that version
+struct HttpVersionMiddleware {
+    version: HttpVersion,
+}
+
+#[async_trait::async_trait]
+impl Middleware for HttpVersionMiddleware {
+    async fn handle(
+        &self,
+        req: reqwest::Request,
+        extensions: &mut http::Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest::Response> {
+        let url = req.url().clone();
+        match next.run(req, extensions).await {
+            Err(reqwest_middleware::Error::Reqwest(e))
+                if (e.is_connect() || e.is_request()) && !e.is_timeout() =>
+            {
+                Err(reqwest_middleware::Error::Middleware(
+                    anyhow::Error::new(e).context(format!(
+                        "Request to {url} failed, the mirror may not support {}, \
+                         try --http-version auto",
+                        self.version
+                    )),
+                ))
+            }
+            result => result,
+        }
+    }
+}
+
+/// Certificates trusted and presented by the http client
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TlsOptions {
+    /// PEM files with CA certificates trusted in addition to the system ones
+    pub ca_certs: Vec<PathBuf>,
+    /// PEM files with the client certificate and its PKCS#8 private key
+    pub client_identity: Option<(PathBuf, PathBuf)>,
+    /// Accept invalid certificates
+    pub insecure: bool,
+}
+
+impl TlsOptions {
+    fn apply(&self, mut builder: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder> {
+        for path in &self.ca_certs {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read CA certificate {path:?}"))?;
+            for certificate in reqwest::Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Invalid CA certificate {path:?}"))?
+            {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if let Some((cert, key)) = &self.client_identity {
+            let cert_pem = std::fs::read(cert)
+                .with_context(|| format!("Failed to read client certificate {cert:?}"))?;
+            let key_pem =
+                std::fs::read(key).with_context(|| format!("Failed to read client key {key:?}"))?;
+            let identity = reqwest::Identity::from_pkcs8_pem(&cert_pem, &key_pem)
+                .with_context(|| format!("Invalid client certificate {cert:?} or key {key:?}"))?;
+            builder = builder.identity(identity);
+        }
+        if self.insecure {
+            tracing::warn!("TLS certificate validation is disabled");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder)
+    }
+}
+
 /// Creates a reqwest client to be used by the downloader tasks
-pub(crate) fn make_http_client(user_agent: String) -> Result<Client> {
+pub(crate) fn make_http_client(options: &HttpOptions) -> Result<Client> {
     let retry_policy = ExponentialBackoff::builder()
         .retry_bounds(Duration::from_secs(1), Duration::from_secs(60))
         .jitter(Jitter::Bounded)
         .base(2)
         .build_with_max_retries(5);
-    Ok(ClientBuilder::new(
-        reqwest::ClientBuilder::new()
-            .https_only(true)
-            .http2_prior_knowledge()
-            .gzip(true)
-            .zstd(true)
-            .timeout(Duration::from_secs(20))
-            .user_agent(user_agent)
-            .build()?,
-    )
-    .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-    .build())
+    let mut builder = reqwest::ClientBuilder::new()
+        .https_only(!options.allow_http)
+        .gzip(true)
+        .zstd(true)
+        .connect_timeout(options.timeouts.connect)
+        .read_timeout(options.timeouts.read)
+        .redirect(options.redirects.policy())
+        .user_agent(options.user_agent.clone());
+    builder = options.pool.apply(builder);
+    builder = options.dns.apply(builder);
+    let (cookie_jar, cookie_middleware) = options.cookies.store()?;
+    builder = options
+        .tls
+        .apply(builder)?
+        .default_headers(options.headers.clone())
+        .cookie_provider(cookie_jar);
+    if let Some(proxy) = &options.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
+    }
+    builder = match options.version {
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+        HttpVersion::Auto => builder,
+    };
+    let mut client = ClientBuilder::new(builder.build()?);
+    if options.version != HttpVersion::Auto {
+        // Added first so it sees the error after all retries
+        client = client.with(HttpVersionMiddleware {
+            version: options.version,
+        });
+    }
+    client = client.with(RetryTransientMiddleware::new_with_policy(retry_policy));
+    client = client.with(RetryEventMiddleware);
+    client = client.with(ConnectionStatsMiddleware);
+    if let Some(deadline) = options.timeouts.chunk {
+        client = client.with(ChunkDeadlineMiddleware { deadline });
+    }
+    if let Some(politeness) = options.politeness.middleware() {
+        client = client.with(politeness);
+    }
+    if let Some(auth) = options.auth.middleware()? {
+        client = client.with(auth);
+    }
+    if let Some(cookies) = cookie_middleware {
+        client = client.with(cookies);
+    }
+    Ok(client.build())
 }
 
-async fn request_range(
+/// Request the bytes `start..=end` of `url`. The response must not be
+/// compressed, the range refers to the bytes of the file on the server.
+pub(crate) async fn request_range(
     client: &Client,
     url: &reqwest::Url,
     start: u64,
@@ -49,235 +435,1665 @@ async fn request_range(
             reqwest::header::HeaderValue::from_str(&format!("bytes={start}-{end}"))
                 .expect("Failed to construct range header"),
         )
+        .header(
+            reqwest::header::ACCEPT_ENCODING,
+            reqwest::header::HeaderValue::from_static("identity"),
+        )
         .send()
         .await?)
 }
 
+/// Check that a range response carries exactly the bytes `start..=end`
+/// without a content encoding
+fn check_range_response(
+    headers: &reqwest::header::HeaderMap,
+    start: u64,
+    end: u64,
+) -> std::result::Result<(), String> {
+    if let Some(encoding) = headers.get(reqwest::header::CONTENT_ENCODING) {
+        if !encoding.as_bytes().eq_ignore_ascii_case(b"identity") {
+            return Err(format!(
+                "range is sent with Content-Encoding {encoding:?} although identity was requested"
+            ));
+        }
+    }
+    let content_range = headers
+        .get(reqwest::header::CONTENT_RANGE)
+        .ok_or("Content-Range header is missing")?;
+    match content_range.to_str().ok().and_then(content_range_bounds) {
+        Some((first, last)) if (first, last) == (start, end) => Ok(()),
+        _ => Err(format!(
+            "Content-Range {content_range:?} does not match the requested range {start}-{end}"
+        )),
+    }
+}
+
+/// The built-in transport fetching http and https urls with reqwest
+#[derive(Clone)]
+pub(crate) struct HttpTransport {
+    client: Client,
+}
+
+impl HttpTransport {
+    pub(crate) fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    /// Fetch a range, recording connection and transfer latency
+    async fn range(&self, url: &reqwest::Url, start: u64, end: u64) -> Result<Fetched> {
+        #[cfg(feature = "observability")]
+        let _connection = crate::observability::ActiveConnection::open();
+        let response = timed(
+            Stage::Connecting,
+            request_range(&self.client, url, start, end),
+        )
+        .await?;
+        let final_url = response.url().clone();
+        let invalid = |reason: String| MetalinkDownloadError::InvalidRangeResponse {
+            url: final_url.clone(),
+            reason,
+        };
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            response.error_for_status_ref()?;
+        }
+        if status != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Err(invalid(format!(
+                "status {status} instead of 206 Partial Content"
+            )));
+        }
+        check_range_response(response.headers(), start, end).map_err(invalid)?;
+        let bytes = timed(Stage::Transferring, response.bytes()).await?;
+        Ok(Fetched {
+            bytes,
+            url: final_url,
+        })
+    }
+
+    /// Stream the whole file to disk
+    async fn whole(&self, url: &reqwest::Url, target_file: &Path) -> Result<reqwest::Url> {
+        #[cfg(feature = "observability")]
+        let _connection = crate::observability::ActiveConnection::open();
+        let response = self
+            .client
+            .get(url.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        let final_url = response.url().clone();
+        let mut f = File::create(target_file)
+            .await
+            .with_context(|| format!("Failed to create file {:?}", target_file))?;
+        let mut stream = response.bytes_stream();
+        while let Some(bytes) = stream.next().await {
+            if shutdown::is_requested() {
+                f.flush()
+                    .await
+                    .with_context(|| format!("Failed to flush file {:?}", target_file))?;
+                return Err(MetalinkDownloadError::Cancelled {
+                    file: target_file.to_path_buf(),
+                });
+            }
+            let bytes = bytes?;
+            rate_limit::consume(bytes.len() as u64).await;
+            f.write_all(&bytes)
+                .await
+                .with_context(|| format!("Failed to write file {:?}", target_file))?;
+        }
+        f.flush()
+            .await
+            .with_context(|| format!("Failed to flush file {:?}", target_file))?;
+        Ok(final_url)
+    }
+}
+
+impl Transport for HttpTransport {
+    fn supports(&self, url: &url::Url) -> bool {
+        matches!(url.scheme(), "http" | "https")
+    }
+
+    fn probe<'a>(&'a self, url: &'a url::Url) -> BoxFuture<'a, Result<Probe>> {
+        Box::pin(probe(&self.client, url))
+    }
+
+    fn fetch_range<'a>(
+        &'a self,
+        url: &'a url::Url,
+        start: u64,
+        end: u64,
+    ) -> BoxFuture<'a, Result<Fetched>> {
+        Box::pin(self.range(url, start, end))
+    }
+
+    fn fetch_whole<'a>(
+        &'a self,
+        url: &'a url::Url,
+        target_file: &'a Path,
+    ) -> BoxFuture<'a, Result<url::Url>> {
+        Box::pin(self.whole(url, target_file))
+    }
+}
+
+/// Fetch the bytes of a chunk with `transport`. Returns the bytes and the
+/// url they were served from after redirects.
+pub(crate) async fn fetch_range(
+    transport: &dyn Transport,
+    url: &reqwest::Url,
+    chunk: &ChunkMetaData,
+) -> Result<(bytes::Bytes, reqwest::Url)> {
+    let Fetched {
+        bytes,
+        url: final_url,
+    } = transport.fetch_range(url, chunk.start, chunk.end).await?;
+    if &final_url != url {
+        tracing::debug!("{url} redirected to {final_url}");
+    }
+    rate_limit::consume(bytes.len() as u64).await;
+    // A compressed range is decoded transparently by the client, the decoded
+    // body does not have the length of the range
+    if bytes.len() as u64 != chunk.chunk_size() {
+        return Err(MetalinkDownloadError::InvalidRangeResponse {
+            url: final_url,
+            reason: format!(
+                "received {} bytes for range {}-{}",
+                bytes.len(),
+                chunk.start,
+                chunk.end
+            ),
+        });
+    }
+    Ok((bytes, final_url))
+}
+
+/// Measure the throughput of `url` by fetching its first segment and derive
+/// a segment size which takes about `AUTO_CHUNK_DURATION` to fetch. The
+/// probed bytes are discarded.
+pub(crate) async fn measure_chunk_size(
+    transport: &dyn Transport,
+    url: &reqwest::Url,
+    target_file: &Path,
+    size: u64,
+) -> Result<u64> {
+    let probe = ChunkMetaData::new(0, DEFAULT_CHUNK_SIZE.min(size) - 1, Arc::from(target_file));
+    let started = Instant::now();
+    fetch_range(transport, url, &probe).await?;
+    let chunk_size = chunk_size_for_throughput(probe.chunk_size(), started.elapsed());
+    tracing::info!("Measured {url}, downloading in segments of {chunk_size} bytes");
+    Ok(chunk_size)
+}
+
+/// The segment size fetching takes about `AUTO_CHUNK_DURATION` when `bytes`
+/// were fetched in `elapsed`, in whole MiB between the default and
+/// `MAX_AUTO_CHUNK_SIZE`
+fn chunk_size_for_throughput(bytes: u64, elapsed: Duration) -> u64 {
+    let per_second = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+    let target = (per_second * AUTO_CHUNK_DURATION.as_secs_f64()) as u64;
+    (target / DEFAULT_CHUNK_SIZE * DEFAULT_CHUNK_SIZE)
+        .clamp(DEFAULT_CHUNK_SIZE, MAX_AUTO_CHUNK_SIZE)
+}
+
+/// Attribute a checksum mismatch to the redirector if the mirror redirected
+/// the request, e.g. to an error page
+fn warn_redirected_mismatch(chunk: &ChunkMetaData, url: &reqwest::Url, final_url: &reqwest::Url) {
+    if url != final_url {
+        warnings::warn(Warning::RedirectedChecksumMismatch {
+            file: chunk.filename.to_path_buf(),
+            url: url.clone(),
+            final_url: final_url.clone(),
+        });
+    }
+}
+
+/// Download `url` in a single request. If the `known` validators of the file
+/// on disk are given the request is conditional and an unchanged file is not
+/// downloaded again. Returns the validators of the file on disk afterwards.
 pub(crate) async fn simple_download(
     client: &Client,
     url: reqwest::Url,
     target_file: PathBuf,
-) -> Result<()> {
+    known: Option<&Validators>,
+    progress: Option<(&ProgressSender, Arc<Path>)>,
+) -> Result<Option<Validators>> {
     info!("Simple Download: Target file={target_file:?}, Url: {url:?}");
-    let response = client.get(url).send().await?;
+    let mut request = client.get(url.clone());
+    if let Some(known) = known {
+        request = known.apply(request);
+    }
+    let response = request.send().await?;
+    if known.is_some() && response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        info!("{target_file:?} is unchanged on {url}, keeping it");
+        return Ok(known.cloned());
+    }
+    let expected_size = content_length(response.headers())?;
+    let headers = response.headers().clone();
+    let received = write_body(
+        response.bytes_stream(),
+        &target_file,
+        expected_size,
+        progress,
+    )
+    .await?;
+    Ok(Validators::from_headers(&headers, received))
+}
+
+/// Write the frames of a response `body` into `target_file` as they arrive,
+/// through the same [`WriteBuffer`] and [`FileSink`] as chunked downloads so
+/// only the write buffer is held in memory. A body longer than
+/// `expected_size` fails as soon as it exceeds it. Returns the number of
+/// bytes written.
+async fn write_body(
+    mut body: impl futures::Stream<Item = reqwest::Result<bytes::Bytes>> + Unpin,
+    target_file: &Path,
+    expected_size: Option<u64>,
+    progress: Option<(&ProgressSender, Arc<Path>)>,
+) -> Result<u64> {
     // Note proper error handling needed if parent is None
     std::fs::create_dir_all(target_file.parent().unwrap())?;
-    let mut output_file = std::fs::File::create(target_file.clone())
-        .with_context(|| format!("Failed to create file simple download: {target_file:#?}"))?;
-    output_file
-        .write_all(&response.bytes().await?)
-        .with_context(|| format!("Failed to write file simple download: {output_file:#?}"))?;
-    output_file
+    let mut file = FileSink::create(target_file, expected_size.unwrap_or(0))?;
+    let mut buffer = WriteBuffer::configured();
+    let mut received = 0;
+    while let Some(frame) = body.next().await {
+        if shutdown::is_requested() {
+            write_frames(&mut file, target_file, &mut buffer)?;
+            return Err(MetalinkDownloadError::Cancelled {
+                file: target_file.to_path_buf(),
+            });
+        }
+        let frame = frame?;
+        rate_limit::consume(frame.len() as u64).await;
+        if !buffer.continues(received) {
+            write_frames(&mut file, target_file, &mut buffer)?;
+        }
+        buffer.push(received, &frame);
+        received += frame.len() as u64;
+        if let Some(expected) = expected_size.filter(|expected| received > *expected) {
+            return Err(MetalinkDownloadError::SizeMismatch {
+                file: target_file.to_path_buf(),
+                expected,
+                actual: received,
+            });
+        }
+        if buffer.is_full()
+            || buffer
+                .deadline()
+                .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            write_frames(&mut file, target_file, &mut buffer)?;
+        }
+        if let Some((tx, file)) = &progress {
+            tx.send(ProgressUpdate::Progressed {
+                file: file.clone(),
+                bytes: frame.len() as u64,
+            })?;
+        }
+    }
+    write_frames(&mut file, target_file, &mut buffer)?;
+    if let Some(expected) = expected_size.filter(|expected| received != *expected) {
+        return Err(MetalinkDownloadError::SizeMismatch {
+            file: target_file.to_path_buf(),
+            expected,
+            actual: received,
+        });
+    }
+    Ok(received)
+}
+
+/// Write the frames in `buffer` into `file`
+fn write_frames(file: &mut FileSink, target_file: &Path, buffer: &mut WriteBuffer) -> Result<()> {
+    let Some(buffered) = buffer.take() else {
+        return Ok(());
+    };
+    let writing_started = Instant::now();
+    file.write_at(buffered.offset, &buffered.data)
+        .with_context(|| format!("Failed to write file simple download: {target_file:#?}"))?;
+    latency::record(Stage::Writing, writing_started.elapsed());
+    Ok(())
+}
+
+/// Verify that the file on disk has exactly the expected size
+pub(crate) fn verify_file_size(target_file: &Path, expected: u64) -> Result<()> {
+    let actual = std::fs::metadata(target_file)
+        .with_context(|| format!("Failed to read metadata of {target_file:?}"))?
+        .len();
+    if actual != expected {
+        return Err(MetalinkDownloadError::SizeMismatch {
+            file: target_file.to_path_buf(),
+            expected,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// Path of a `file://` url, None if the url is fetched over the network
+pub(crate) fn local_path(url: &reqwest::Url) -> Option<PathBuf> {
+    if url.scheme() != "file" {
+        return None;
+    }
+    url.to_file_path().ok()
+}
+
+/// Copy the `ranges` of the local file `source` into `target_file`. Only
+/// ranges which are valid against their checksum in `source` are copied,
+/// ranges without a checksum can not be checked and are never copied.
+/// Returns the ranges which were not copied.
+pub(crate) fn copy_local_ranges(
+    source: &Path,
+    target_file: &Path,
+    ranges: &[ChunkMetaData],
+    prog_tx: Option<&ProgressSender>,
+) -> Result<Vec<ChunkMetaData>> {
+    let source_file = std::fs::File::open(source)
+        .with_context(|| format!("Failed to open local source {source:?}"))?;
+    let mut reader = std::io::BufReader::with_capacity(hash_buffer_size(), source_file);
+    let parent = target_file.parent().ok_or_else(|| {
+        MetalinkDownloadError::Other(anyhow::anyhow!("{target_file:?} has no parent directory"))
+    })?;
+    std::fs::create_dir_all(parent)?;
+    // The file is not truncated, ranges which are not part of the plan
+    // already contain valid data
+    let mut writer = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(target_file)
+        .with_context(|| format!("Failed to open file {target_file:?}"))?;
+    let mut buffer = Vec::new();
+    let mut missing = Vec::new();
+    for chunk in ranges {
+        if shutdown::is_requested() {
+            return Err(MetalinkDownloadError::Cancelled {
+                file: target_file.to_path_buf(),
+            });
+        }
+        if !chunk.is_valid_in(&mut reader, &mut buffer)? {
+            missing.push(chunk.clone());
+            continue;
+        }
+        writer
+            .seek(std::io::SeekFrom::Start(chunk.start))
+            .with_context(|| format!("Failed to seek file {target_file:?}"))?;
+        writer
+            .write_all(&buffer)
+            .with_context(|| format!("Failed to write file {target_file:?}"))?;
+        record_write(chunk, chunk.chunk_size());
+        if let Some(tx) = prog_tx {
+            tx.send(ProgressUpdate::Progressed {
+                file: chunk.filename.clone(),
+                bytes: chunk.chunk_size(),
+            })?;
+        }
+    }
+    writer
         .flush()
-        .with_context(|| format!("Failed to flush file simple download: {output_file:#?}"))?;
+        .with_context(|| format!("Failed to flush file {target_file:?}"))?;
+    Ok(missing)
+}
+
+/// Copy the local file `source` to `target_file`, or hard link it if
+/// `hardlink` is set and both are on the same file system. Returns the size
+/// of the file.
+pub(crate) fn copy_local_file(source: &Path, target_file: &Path, hardlink: bool) -> Result<u64> {
+    std::fs::create_dir_all(target_file.parent().unwrap())?;
+    if target_file.exists() {
+        std::fs::remove_file(target_file)
+            .with_context(|| format!("Failed to remove {target_file:?}"))?;
+    }
+    if hardlink {
+        match std::fs::hard_link(source, target_file) {
+            Ok(()) => return Ok(std::fs::metadata(target_file)?.len()),
+            Err(e) => tracing::debug!("Failed to hard link {source:?}, copying it instead: {e}"),
+        }
+    }
+    Ok(std::fs::copy(source, target_file)
+        .with_context(|| format!("Failed to copy {source:?} to {target_file:?}"))?)
+}
+
+/// Determine the size of the file behind `url` and whether the server
+/// supports byte range requests for it. A HEAD request is tried first, if
+/// the server rejects it, omits the Content-Length or does not advertise
+/// range support a single byte range is probed and the total size is taken
+/// from the Content-Range header. The size is None if it can not be
+/// determined, e.g. for chunked responses.
+async fn probe(client: &Client, url: &reqwest::Url) -> Result<Probe> {
+    let mut size = None;
+    let mut advertised = None;
+    match client.head(url.clone()).send().await {
+        Ok(response) if response.status().is_success() => {
+            size = content_length(response.headers())?;
+            advertised = match response.headers().get(reqwest::header::ACCEPT_RANGES) {
+                Some(value) if value.as_bytes().eq_ignore_ascii_case(b"bytes") => Some(true),
+                Some(value) if value.as_bytes().eq_ignore_ascii_case(b"none") => Some(false),
+                _ => None,
+            };
+            if size.is_none() {
+                info!("HEAD response for {url} has no usable Content-Length");
+            }
+        }
+        Ok(response) => info!("HEAD request for {url} failed: {}", response.status()),
+        Err(e) => info!("HEAD request for {url} failed: {e}"),
+    }
+    if let (Some(_), Some(accepts_ranges)) = (size, advertised) {
+        return Ok(Probe {
+            size,
+            accepts_ranges,
+        });
+    }
+
+    let response = request_range(client, url, 0, 0).await?;
+    info!("Range probe for {url}: status={}", response.status());
+    let (probed_size, partial) = match response.status() {
+        reqwest::StatusCode::PARTIAL_CONTENT => (
+            response
+                .headers()
+                .get(reqwest::header::CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(content_range_total),
+            true,
+        ),
+        // The range header was ignored so the full body would be sent
+        status if status.is_success() => (content_length(response.headers())?, false),
+        _ => (None, false),
+    };
+    Ok(Probe {
+        size: size.or(probed_size),
+        accepts_ranges: advertised.unwrap_or(partial),
+    })
+}
+
+/// Fallback for servers without range support: fetch the whole file and
+/// verify the pieces of the plan afterwards. `reported` bytes of the ranges
+/// were already reported as progress by an aborted range download.
+async fn whole_file_download(
+    transport: &dyn Transport,
+    url: reqwest::Url,
+    target_file: &PathBuf,
+    ranges: &[ChunkMetaData],
+    prog_tx: Option<&ProgressSender>,
+    reported: u64,
+) -> Result<()> {
+    info!("Whole file download: Target file={target_file:?}, Url: {url:?}");
+    let final_url = transport.fetch_whole(&url, target_file).await?;
+
+    let invalid = hash_off_runtime({
+        let target_file = target_file.clone();
+        let ranges = ranges.to_vec();
+        move || -> Result<Option<(ChunkMetaData, Vec<u8>)>> {
+            let file_on_disk = std::fs::File::open(target_file)?;
+            for chunk in ranges {
+                if chunk.has_checksum() && !chunk.is_valid_on_disk(&file_on_disk)? {
+                    let mut reader = &file_on_disk;
+                    reader.seek(std::io::SeekFrom::Start(chunk.start))?;
+                    let mut data = Vec::new();
+                    reader.take(chunk.chunk_size()).read_to_end(&mut data)?;
+                    return Ok(Some((chunk, data)));
+                }
+            }
+            Ok(None)
+        }
+    })
+    .await??;
+    if let Some((chunk, data)) = invalid {
+        warn_redirected_mismatch(&chunk, &url, &final_url);
+        return Err(chunk.mismatch(&data));
+    }
+    if let (Some(tx), Some(first)) = (prog_tx, ranges.first()) {
+        let total: u64 = ranges.iter().map(ChunkMetaData::chunk_size).sum();
+        tx.send(ProgressUpdate::Progressed {
+            file: first.filename.clone(),
+            bytes: total.saturating_sub(reported),
+        })?;
+    }
 
     Ok(())
 }
 
-pub(crate) async fn get_file_size(client: &Client, url: reqwest::Url) -> Result<Option<u64>> {
-    let mut response = client.head(url).send().await?;
+/// Fall back to downloading the whole file after a mirror answered a range
+/// request with something else than the range
+async fn range_fallback(
+    transport: &dyn Transport,
+    url: reqwest::Url,
+    target_file: &PathBuf,
+    ranges: &[ChunkMetaData],
+    prog_tx: Option<&ProgressSender>,
+    reported: u64,
+    error: MetalinkDownloadError,
+) -> Result<()> {
+    tracing::warn!("{error}, downloading the whole file");
+    whole_file_download(transport, url, target_file, ranges, prog_tx, reported).await
+}
+
+/// The file name the server suggests for `url` in the Content-Disposition
+/// header of a HEAD response, None if it suggests none
+pub(crate) async fn attachment_file_name(client: &Client, url: &reqwest::Url) -> Option<String> {
+    let response = match client.head(url.clone()).send().await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(response) => {
+            info!("HEAD request for {url} failed: {}", response.status());
+            return None;
+        }
+        Err(e) => {
+            info!("HEAD request for {url} failed: {e}");
+            return None;
+        }
+    };
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_DISPOSITION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(content_disposition_file_name)
+}
+
+/// Extract the file name of a Content-Disposition header value, preferring
+/// the RFC 5987 encoded `filename*` over `filename`. Directories are removed
+/// so the name can not escape the target directory.
+fn content_disposition_file_name(value: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+    for parameter in value.split(';').skip(1) {
+        let Some((name, value)) = parameter.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "filename" => {
+                let unquoted = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value);
+                plain = Some(unquoted.replace("\\\"", "\""));
+            }
+            "filename*" => {
+                // charset'language'percent-encoded-name
+                let mut parts = value.splitn(3, '\'');
+                let (Some(charset), Some(_), Some(encoded)) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    continue;
+                };
+                if charset.eq_ignore_ascii_case("utf-8") {
+                    extended = percent_encoding::percent_decode_str(encoded)
+                        .decode_utf8()
+                        .ok()
+                        .map(|name| name.into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+    let name = extended.or(plain)?.replace('\\', "/");
+    let name = Path::new(&name).file_name()?.to_str()?.to_owned();
+    (!name.is_empty() && name != "..").then_some(name)
+}
 
-    match response
-        .headers_mut()
-        .entry(reqwest::header::CONTENT_LENGTH)
-    {
-        reqwest::header::Entry::Occupied(entry) => Ok(Some(
-            entry
-                .get()
+/// Returns the Content-Length of a response, None if it is missing or the
+/// body is sent with chunked transfer encoding
+pub(crate) fn content_length(headers: &reqwest::header::HeaderMap) -> Result<Option<u64>> {
+    let chunked = headers
+        .get(reqwest::header::TRANSFER_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+    if chunked {
+        return Ok(None);
+    }
+
+    match headers.get(reqwest::header::CONTENT_LENGTH) {
+        Some(value) => Ok(Some(
+            value
                 .to_str()
                 .with_context(|| "Failed convert header Content-Length header value to string")?
                 .parse()
                 .with_context(|| "Failed to parse Content-Length header")?,
         )),
-        reqwest::header::Entry::Vacant(_) => Ok(None),
+        None => Ok(None),
+    }
+}
+
+/// Extract the complete length from a Content-Range header value,
+/// e.g. `bytes 0-0/1234`. Returns None if the length is unknown (`*`).
+fn content_range_total(value: &str) -> Option<u64> {
+    let (unit, range) = value.trim().split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let (_, total) = range.split_once('/')?;
+    total.trim().parse().ok()
+}
+
+/// Extract the first and last byte from a Content-Range header value,
+/// e.g. `bytes 0-1023/1234`
+fn content_range_bounds(value: &str) -> Option<(u64, u64)> {
+    let (unit, range) = value.trim().split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
     }
+    let (range, _) = range.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    Some((first.trim().parse().ok()?, last.trim().parse().ok()?))
+}
+
+fn record_fetch(chunk: &ChunkMetaData, outcome: FetchOutcome) {
+    replay::record(ReplayEvent::ChunkFetched {
+        file: chunk.filename.to_path_buf(),
+        start: chunk.start,
+        end: chunk.end,
+        outcome,
+    });
+}
+
+fn record_write(chunk: &ChunkMetaData, bytes: u64) {
+    events::emit(|| DownloadEvent::ChunkCompleted {
+        file: chunk.filename.to_path_buf(),
+        offset: chunk.start,
+        bytes,
+    });
+    replay::record(ReplayEvent::ChunkWritten {
+        file: chunk.filename.to_path_buf(),
+        offset: chunk.start,
+        bytes,
+    });
 }
 
+#[tracing::instrument(name = "chunk", level = "debug", skip_all, fields(start = chunk.start, end = chunk.end))]
 async fn download_chunk(
     chunk: &ChunkMetaData,
-    client: &Client,
+    transport: &dyn Transport,
     url: &reqwest::Url,
-    tx: &tokio::sync::mpsc::UnboundedSender<Command>,
+    tx: &tokio::sync::mpsc::Sender<Command>,
 ) -> Result<()> {
-    if chunk.has_checksum() {
-        // retry at most three times
-        for _ in 0..3 {
-            let response = request_range(client, url, chunk.start, chunk.end).await?;
-            let bytes = response.bytes().await?;
-            log::debug!(
-                "Validating checksum of {:?} for chunk starting at {}",
-                chunk.filename,
-                chunk.start
-            );
-            if let Some(true) = chunk.validate_checksum(&bytes) {
-                log::debug!(
-                    "Checksum validation of {:?} for chunk starting at {} succeeded",
-                    chunk.filename,
-                    chunk.start
-                );
-                tx.send(Command::WriteFileChunk {
-                    offset: chunk.start,
-                    downloaded_bytes: bytes,
-                })
-                .with_context(|| {
-                    format!(
-                        "Failed to send downloaded chunk of {:?} starting at: {}",
-                        chunk.filename, chunk.start
-                    )
-                })?;
-                return Ok(());
-            }
-            log::warn!(
-                "Checksum validation for chunk of file {:?} starting at {} failed",
-                chunk.filename,
-                chunk.start
-            );
-        }
+    let bytes = if chunk.has_checksum() {
+        fetch_verified_chunk(transport, url, chunk).await?
     } else {
-        let response = request_range(client, url, chunk.start, chunk.end).await?;
-        tx.send(Command::WriteFileChunk {
-            offset: chunk.start,
-            downloaded_bytes: response.bytes().await?,
-        })
-        .with_context(|| {
-            format!(
-                "Failed to send unvalidated downloaded chunk of {:?}, starting at: {}",
-                chunk.filename, chunk.start
-            )
-        })?;
-        return Ok(());
-    }
+        let (bytes, _) = fetch_range(transport, url, chunk).await?;
+        record_fetch(chunk, FetchOutcome::Ok);
+        bytes
+    };
 
-    Err(anyhow!("Unable to download chunk").into())
+    let permit = match tx.try_reserve() {
+        Ok(permit) => Ok(permit),
+        Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+            // The writer can not keep up, wait for room in its queue
+            let started = Instant::now();
+            let permit = tx.reserve().await;
+            record_stall(started.elapsed());
+            permit.map_err(|_| ())
+        }
+        Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => Err(()),
+    }
+    .map_err(|_| {
+        MetalinkDownloadError::Other(anyhow::anyhow!(
+            "Failed to send downloaded chunk of {:?} starting at: {}, file writer stopped",
+            chunk.filename,
+            chunk.start
+        ))
+    })?;
+    permit.send(Command::WriteFileChunk {
+        offset: chunk.start,
+        downloaded_bytes: bytes,
+    });
+    Ok(())
 }
 
+/// Write the chunks received on `rx` into `target_file`. Adjacent chunks are
+/// collected in a [`WriteBuffer`] and written together.
 async fn file_writer_task(
-    target_file: &PathBuf,
+    target_file: &Path,
     size: u64,
-    mut rx: tokio::sync::mpsc::UnboundedReceiver<Command>,
-    prog_tx: Option<tokio::sync::mpsc::UnboundedSender<ProgressUpdate>>,
+    mut rx: tokio::sync::mpsc::Receiver<Command>,
+    prog_tx: Option<ProgressSender>,
 ) -> Result<()> {
     // Note proper error handling needed if parent is None
     std::fs::create_dir_all(target_file.parent().unwrap())?;
-    let mut file = std::fs::File::create(target_file.clone())
-        .with_context(|| format!("Failed to create file: {target_file:#?}"))?;
-    file.set_len(size)?;
+    let mut file = FileSink::create(target_file, size)?;
+    let mut buffer = WriteBuffer::configured();
     let mut bytes_written = 0;
-    while let Some(cmd) = rx.recv().await {
-        match cmd {
-            Command::FinishWriting => break,
-            Command::WriteFileChunk {
-                offset,
-                downloaded_bytes,
-            } => {
-                file.seek(std::io::SeekFrom::Start(offset))
-                    .with_context(|| format!("Failed to seek file: {file:#?}"))?;
-                let bytes = file
-                    .write(&downloaded_bytes)
-                    .with_context(|| format!("Failed to write file: {file:#?}"))?;
-                bytes_written += bytes;
-
-                info!(
-                    "Progress: {}%",
-                    (bytes_written as f64 / size as f64) * 100f64
-                );
-                file.flush()
-                    .with_context(|| format!("Failed to flush file: {file:#?}"))?;
-                if let Some(tx) = &prog_tx {
-                    tx.send(ProgressUpdate::Progressed(bytes as u64))
-                        .with_context(|| "Failed to send progress update")?;
+    loop {
+        let cmd = match buffer.deadline() {
+            Some(deadline) => {
+                match tokio::time::timeout_at(deadline.into(), rx.recv()).await {
+                    Ok(cmd) => cmd,
+                    // Nothing arrived while the buffered chunks waited
+                    Err(_) => {
+                        bytes_written +=
+                            write_buffered(&mut file, target_file, &mut buffer, &prog_tx)?;
+                        continue;
+                    }
                 }
             }
+            None => rx.recv().await,
+        };
+        let Some(Command::WriteFileChunk {
+            offset,
+            downloaded_bytes,
+        }) = cmd
+        else {
+            break;
+        };
+        if !buffer.continues(offset) {
+            bytes_written += write_buffered(&mut file, target_file, &mut buffer, &prog_tx)?;
+        }
+        buffer.push(offset, &downloaded_bytes);
+        if buffer.is_full() {
+            bytes_written += write_buffered(&mut file, target_file, &mut buffer, &prog_tx)?;
+            info!(
+                "Progress: {}%",
+                (bytes_written as f64 / size as f64) * 100f64
+            );
         }
     }
+    write_buffered(&mut file, target_file, &mut buffer, &prog_tx)?;
     Ok(())
 }
 
+/// Write the chunks in `buffer` into `file` and report them as written,
+/// returns the number of bytes written
+fn write_buffered(
+    file: &mut FileSink,
+    target_file: &Path,
+    buffer: &mut WriteBuffer,
+    prog_tx: &Option<ProgressSender>,
+) -> Result<u64> {
+    let Some(buffered) = buffer.take() else {
+        return Ok(0);
+    };
+    let writing_started = Instant::now();
+    file.write_at(buffered.offset, &buffered.data)
+        .with_context(|| format!("Failed to write file: {target_file:#?}"))?;
+    latency::record(Stage::Writing, writing_started.elapsed());
+    let file_key: Arc<Path> = Arc::from(target_file);
+    for (offset, bytes) in buffered.chunks {
+        events::emit(|| DownloadEvent::ChunkCompleted {
+            file: target_file.to_path_buf(),
+            offset,
+            bytes,
+        });
+        replay::record(ReplayEvent::ChunkWritten {
+            file: target_file.to_path_buf(),
+            offset,
+            bytes,
+        });
+        if let Some(tx) = prog_tx {
+            tx.send(ProgressUpdate::Progressed {
+                file: file_key.clone(),
+                bytes,
+            })?;
+            replay::record(ReplayEvent::ProgressReported {
+                file: target_file.to_path_buf(),
+                bytes,
+            });
+        }
+    }
+    Ok(buffered.data.len() as u64)
+}
+
+/// Download the `ranges` of a plain file into `target_file`. Up to
+/// `concurrency` ranges are fetched at the same time, a worker starts on the
+/// next range as soon as its previous one finished so a slow request does
+/// not hold up the others. Failed ranges are fetched once more after all
+/// other ranges, the download fails if they fail again.
 pub(crate) async fn segregrated_download(
-    client: &Client,
+    transport: &Arc<dyn Transport>,
     url: reqwest::Url,
     target_file: PathBuf,
     size: u64,
     ranges: &[ChunkMetaData],
-    prog_tx: Option<tokio::sync::mpsc::UnboundedSender<ProgressUpdate>>,
-    max_threads: u16,
+    prog_tx: Option<ProgressSender>,
+    concurrency: Concurrency,
 ) -> Result<()> {
-    let available_parallelism: usize = (max_threads - 1) as usize;
-    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Command>();
+    let mut parallelism = concurrency.parallelism(ranges.len());
+    let (tx, rx) = tokio::sync::mpsc::channel::<Command>(WRITE_QUEUE_CAPACITY);
+    let writer_target = target_file.clone();
+    let writer_prog_tx = prog_tx.clone();
     let file_writer: JoinHandle<Result<()>> =
-        tokio::spawn(async move { file_writer_task(&target_file, size, rx, prog_tx).await });
+        run::spawn(async move { file_writer_task(&writer_target, size, rx, writer_prog_tx).await });
 
-    for chunk in ranges.chunks(available_parallelism) {
-        let mut tasks: Vec<JoinHandle<Result<()>>> = Vec::new();
-        for chunk_meta_data in chunk {
-            let cloned_client = client.clone();
+    let download_started = Instant::now();
+    let mut slow_disk_reported = false;
+    let mut pending: VecDeque<ChunkMetaData> = ranges.iter().cloned().collect();
+    // Ranges which failed after the retries of the transport, they are
+    // fetched once more after all other ranges
+    let mut failed = Vec::new();
+    let mut retried = false;
+    let mut errors = Vec::new();
+    let mut written = 0;
+    let mut invalid_range = None;
+    let mut running = futures::stream::FuturesUnordered::new();
+    // The disk is checked after every `parallelism` finished ranges
+    let mut window_stalls = WriterStalls::snapshot();
+    let mut window_started = Instant::now();
+    let mut window_finished = 0;
+    loop {
+        while running.len() < parallelism && invalid_range.is_none() && !shutdown::is_requested() {
+            let Some(chunk_meta_data) = pending.pop_front() else {
+                break;
+            };
+            let cloned_transport = transport.clone();
             let cloned_url = url.clone();
             let cloned_tx = tx.clone();
-            let cloned_chunk_metadata = chunk_meta_data.clone();
-
-            tasks.push(tokio::spawn(async move {
-                download_chunk(
-                    &cloned_chunk_metadata,
-                    &cloned_client,
-                    &cloned_url,
-                    &cloned_tx,
-                )
-                .await
-            }));
+
+            replay::record(ReplayEvent::ChunkScheduled {
+                file: chunk_meta_data.filename.to_path_buf(),
+                start: chunk_meta_data.start,
+                end: chunk_meta_data.end,
+            });
+            latency::record(Stage::Queueing, download_started.elapsed());
+            running.push(run::spawn(
+                async move {
+                    let res = download_chunk(
+                        &chunk_meta_data,
+                        cloned_transport.as_ref(),
+                        &cloned_url,
+                        &cloned_tx,
+                    )
+                    .await;
+                    if res.is_err() {
+                        record_fetch(&chunk_meta_data, FetchOutcome::Failed);
+                    }
+                    (chunk_meta_data, res)
+                }
+                .in_current_span(),
+            ));
         }
-        // NOTE: the results need to be checked for failed requests and retried if it make sense
-        let _ = futures::future::join_all(tasks).await;
+        let Some(result) = running.next().await else {
+            if failed.is_empty() || retried || invalid_range.is_some() || shutdown::is_requested() {
+                break;
+            }
+            tracing::debug!("Retrying {} failed ranges of {target_file:?}", failed.len());
+            retried = true;
+            pending.extend(failed.drain(..).map(|(chunk, _)| chunk));
+            continue;
+        };
+        match result {
+            Ok((chunk, Ok(()))) => written += chunk.chunk_size(),
+            Ok((_, Err(e))) if e.is_invalid_range_response() => invalid_range = Some(e),
+            Ok((chunk, Err(e))) => {
+                tracing::warn!(
+                    "Range {}-{} of {target_file:?} failed: {e:#}",
+                    chunk.start,
+                    chunk.end
+                );
+                failed.push((chunk, e));
+            }
+            Err(e) => errors.push(MetalinkDownloadError::Other(
+                anyhow::Error::new(e).context("Range download task failed"),
+            )),
+        }
+
+        window_finished += 1;
+        if window_finished < parallelism {
+            continue;
+        }
+        let stalls = WriterStalls::snapshot().since(&window_stalls);
+        if stalls.is_disk_bound(parallelism, window_started.elapsed()) {
+            if !slow_disk_reported {
+                tracing::warn!("Writing {target_file:?} is slower than downloading: {stalls}");
+                eprintln!(
+                    "Warning: the disk is slower than the network, throughput is limited by writing {target_file:?}"
+                );
+                slow_disk_reported = true;
+            }
+            if concurrency.reduce_on_slow_disk && parallelism > 1 {
+                parallelism /= 2;
+                tracing::warn!("Reducing concurrent downloads of {target_file:?} to {parallelism}");
+            }
+        }
+        window_stalls = WriterStalls::snapshot();
+        window_started = Instant::now();
+        window_finished = 0;
     }
 
     tx.send(Command::FinishWriting)
+        .await
         .with_context(|| "Failed to send finished command to file writer")?;
     file_writer
         .await
         .with_context(|| "File writer task failed")??;
+    if let Some(e) = invalid_range {
+        return range_fallback(
+            transport.as_ref(),
+            url,
+            &target_file,
+            ranges,
+            prog_tx.as_ref(),
+            written,
+            e,
+        )
+        .await;
+    }
+    if !pending.is_empty() || (!failed.is_empty() && !retried) {
+        return Err(MetalinkDownloadError::Cancelled { file: target_file });
+    }
+    // The file is preallocated, a missing range leaves a hole its size
+    // check does not notice
+    errors.extend(failed.into_iter().map(|(_, e)| e));
+    if !errors.is_empty() {
+        return Err(MetalinkDownloadError::AllMirrorsFailed {
+            file: target_file,
+            errors,
+        });
+    }
 
     Ok(())
 }
 
+/// Download the pieces `ranges` of `target_file` one after the other, no
+/// request is issued while `pause` is set. Adjacent pieces are fetched with
+/// one request, see [`coalesce`].
 pub(crate) async fn download(
-    client: &Client,
+    transport: &dyn Transport,
     url: reqwest::Url,
     target_file: PathBuf,
     ranges: &[ChunkMetaData],
-    prog_tx: Option<tokio::sync::mpsc::UnboundedSender<ProgressUpdate>>,
-    verify_chunk_checksum: bool,
+    prog_tx: Option<ProgressSender>,
+    fetch: PieceFetch,
+    pause: &Pause,
 ) -> Result<()> {
     std::fs::create_dir_all(target_file.parent().unwrap())?;
-    let mut f = File::create(target_file.clone())
+    if !transport.probe(&url).await?.accepts_ranges {
+        tracing::warn!("{url} does not support range requests, downloading the whole file");
+        return whole_file_download(transport, url, &target_file, ranges, prog_tx.as_ref(), 0)
+            .await;
+    }
+
+    // The file is not truncated, ranges which are not part of the plan
+    // already contain valid data
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&target_file)
         .await
-        .with_context(|| format!("Failed to create file {:?}", target_file))?;
+        .with_context(|| format!("Failed to open file {:?}", target_file))?;
 
-    for chunk in ranges {
-        if chunk.has_checksum() && verify_chunk_checksum {
-            // retry at most three times
-            for _ in 0..3 {
-                let response = request_range(client, &url, chunk.start, chunk.end).await?;
-                let bytes = response.bytes().await?;
-                if let Some(true) = chunk.validate_checksum(&bytes) {
-                    log::debug!(
-                        "Checksum validation of {:?} for chunk starting at {} succeeded",
-                        chunk.filename,
-                        chunk.start
-                    );
-                    f.write_all(&bytes).await?;
-                    break;
-                }
-            }
-        } else {
-            let response = request_range(client, &url, chunk.start, chunk.end).await?;
-            let bytes = response.bytes().await?;
-            f.write_all(&bytes).await?;
+    let download_started = Instant::now();
+    let mut reported = 0;
+    for run in coalesce(ranges, fetch.max_request_size) {
+        if shutdown::is_requested() {
+            f.flush()
+                .await
+                .with_context(|| format!("Failed to flush file {:?}", target_file))?;
+            return Err(MetalinkDownloadError::Cancelled { file: target_file });
+        }
+        for chunk in run {
+            replay::record(ReplayEvent::ChunkScheduled {
+                file: chunk.filename.to_path_buf(),
+                start: chunk.start,
+                end: chunk.end,
+            });
         }
+        latency::record(Stage::Queueing, download_started.elapsed());
+        let (first, last) = (&run[0], &run[run.len() - 1]);
+        let fetched = pause
+            .run(|| {
+                fetch_run(transport, &url, run, fetch.verify_checksums).instrument(
+                    tracing::debug_span!("chunk", start = first.start, end = last.end),
+                )
+            })
+            .await;
+        let bytes = match fetched {
+            // Shut down while paused
+            None => {
+                f.flush()
+                    .await
+                    .with_context(|| format!("Failed to flush file {:?}", target_file))?;
+                return Err(MetalinkDownloadError::Cancelled { file: target_file });
+            }
+            Some(Ok(bytes)) => bytes,
+            Some(Err(e)) if e.is_invalid_range_response() => {
+                drop(f);
+                return range_fallback(
+                    transport,
+                    url,
+                    &target_file,
+                    ranges,
+                    prog_tx.as_ref(),
+                    reported,
+                    e,
+                )
+                .await;
+            }
+            Some(Err(e)) => return Err(e),
+        };
+
+        let writing_started = Instant::now();
+        f.seek(std::io::SeekFrom::Start(first.start))
+            .await
+            .with_context(|| format!("Failed to seek file {:?}", target_file))?;
+        f.write_all(&bytes)
+            .await
+            .with_context(|| format!("Failed to write file {:?}", target_file))?;
+        latency::record(Stage::Writing, writing_started.elapsed());
 
-        if let Some(tx) = &prog_tx {
-            tx.send(ProgressUpdate::Progressed(chunk.chunk_size()))
-                .with_context(|| "Failed to send progress update")?;
+        for chunk in run {
+            record_write(chunk, chunk.chunk_size());
+            if let Some(tx) = &prog_tx {
+                tx.send(ProgressUpdate::Progressed {
+                    file: chunk.filename.clone(),
+                    bytes: chunk.chunk_size(),
+                })?;
+                replay::record(ReplayEvent::ProgressReported {
+                    file: chunk.filename.to_path_buf(),
+                    bytes: chunk.chunk_size(),
+                });
+            }
+            reported += chunk.chunk_size();
         }
     }
+    f.flush()
+        .await
+        .with_context(|| format!("Failed to flush file {:?}", target_file))?;
 
     Ok(())
 }
+
+/// Split `ranges` into runs of adjacent chunks which are fetched with one
+/// request. A run covers at most `max_request_size` bytes unless it consists
+/// of a single larger chunk.
+pub(crate) fn coalesce(ranges: &[ChunkMetaData], max_request_size: u64) -> Vec<&[ChunkMetaData]> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+    for (i, chunk) in ranges.iter().enumerate() {
+        if i > start
+            && (ranges[i - 1].end + 1 != chunk.start
+                || size + chunk.chunk_size() > max_request_size)
+        {
+            runs.push(&ranges[start..i]);
+            start = i;
+            size = 0;
+        }
+        size += chunk.chunk_size();
+    }
+    if start < ranges.len() {
+        runs.push(&ranges[start..]);
+    }
+    runs
+}
+
+/// Fetch the adjacent chunks of `run` with a single request. With `verify`
+/// every piece is checked on the received data and only the pieces which do
+/// not match are fetched again on their own.
+async fn fetch_run(
+    transport: &dyn Transport,
+    url: &reqwest::Url,
+    run: &[ChunkMetaData],
+    verify: bool,
+) -> Result<bytes::Bytes> {
+    if let [chunk] = run {
+        if chunk.has_checksum() && verify {
+            return fetch_verified_chunk(transport, url, chunk).await;
+        }
+        let (bytes, _) = fetch_range(transport, url, chunk).await?;
+        record_fetch(chunk, FetchOutcome::Ok);
+        return Ok(bytes);
+    }
+
+    let (first, last) = (&run[0], &run[run.len() - 1]);
+    let span = ChunkMetaData::new(first.start, last.end, first.filename.clone());
+    let (bytes, final_url) = fetch_range(transport, url, &span).await?;
+    let run_start = first.start;
+    let piece = move |chunk: &ChunkMetaData| {
+        let offset = (chunk.start - run_start) as usize;
+        offset..offset + chunk.chunk_size() as usize
+    };
+    let invalid: Vec<ChunkMetaData> = if verify {
+        hash_off_runtime({
+            let run = run.to_vec();
+            let bytes = bytes.clone();
+            move || {
+                run.into_iter()
+                    .filter(|chunk| {
+                        chunk.has_checksum()
+                            && chunk.validate_checksum(&bytes.slice(piece(chunk))) != Some(true)
+                    })
+                    .collect()
+            }
+        })
+        .await?
+    } else {
+        Vec::new()
+    };
+    for chunk in run {
+        if !invalid.iter().any(|invalid| invalid.start == chunk.start) {
+            record_fetch(chunk, FetchOutcome::Ok);
+        }
+    }
+    if invalid.is_empty() {
+        return Ok(bytes);
+    }
+
+    let mut data = bytes::BytesMut::from(&bytes[..]);
+    for chunk in &invalid {
+        tracing::warn!(
+            "Checksum validation for chunk of file {:?} starting at {} failed, fetching it on its own",
+            chunk.filename,
+            chunk.start
+        );
+        record_fetch(chunk, FetchOutcome::ChecksumMismatch);
+        warn_redirected_mismatch(chunk, url, &final_url);
+        let refetched = fetch_verified_chunk(transport, url, chunk).await?;
+        data[piece(chunk)].copy_from_slice(&refetched);
+    }
+    Ok(data.freeze())
+}
+
+/// Chunks hashed at the same time, limited to `--hash-threads`
+static HASHING: std::sync::OnceLock<tokio::sync::Semaphore> = std::sync::OnceLock::new();
+
+/// Run the hashing `task` on the blocking thread pool, so hashing large
+/// chunks does not stall the network tasks of the runtime. At most
+/// `--hash-threads` tasks run at the same time.
+async fn hash_off_runtime<T: Send + 'static>(
+    task: impl FnOnce() -> T + Send + 'static,
+) -> Result<T> {
+    let _permit = HASHING
+        .get_or_init(|| tokio::sync::Semaphore::new(hash_threads()))
+        .acquire()
+        .await
+        .expect("the hashing semaphore is never closed");
+    let hashing_started = Instant::now();
+    let result = tokio::task::spawn_blocking(task)
+        .await
+        .with_context(|| "Hashing task failed")?;
+    latency::record(Stage::Hashing, hashing_started.elapsed());
+    Ok(result)
+}
+
+/// Fetch a chunk and validate it against its checksum, retrying at most three
+/// times before failing with a checksum mismatch
+async fn fetch_verified_chunk(
+    transport: &dyn Transport,
+    url: &reqwest::Url,
+    chunk: &ChunkMetaData,
+) -> Result<bytes::Bytes> {
+    let mut last = bytes::Bytes::new();
+    for attempt in 1..=3 {
+        if attempt > 1 {
+            events::emit(|| DownloadEvent::Retry {
+                url: url.clone(),
+                attempt,
+                reason: format!(
+                    "checksum mismatch of the chunk of {:?} starting at {}",
+                    chunk.filename, chunk.start
+                ),
+            });
+        }
+        let (bytes, final_url) = fetch_range(transport, url, chunk).await?;
+        let valid = hash_off_runtime({
+            let (chunk, bytes) = (chunk.clone(), bytes.clone());
+            move || chunk.validate_checksum(&bytes)
+        })
+        .await?;
+        if let Some(true) = valid {
+            tracing::debug!(
+                "Checksum validation of {:?} for chunk starting at {} succeeded",
+                chunk.filename,
+                chunk.start
+            );
+            record_fetch(chunk, FetchOutcome::Ok);
+            return Ok(bytes);
+        }
+        tracing::warn!(
+            "Checksum validation for chunk of file {:?} starting at {} failed",
+            chunk.filename,
+            chunk.start
+        );
+        record_fetch(chunk, FetchOutcome::ChecksumMismatch);
+        warn_redirected_mismatch(chunk, url, &final_url);
+        last = bytes;
+    }
+
+    Err(chunk.mismatch(&last))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::{MockTransport, Request};
+
+    #[test]
+    fn attachment_names_are_taken_from_content_disposition() {
+        assert_eq!(
+            content_disposition_file_name("attachment; filename=\"report 1.pdf\"").as_deref(),
+            Some("report 1.pdf")
+        );
+        assert_eq!(
+            content_disposition_file_name(
+                "attachment; filename=fallback.txt; filename*=UTF-8''na%C3%AFve.txt"
+            )
+            .as_deref(),
+            Some("naïve.txt")
+        );
+        assert_eq!(
+            content_disposition_file_name("attachment; filename=\"../../etc/passwd\"").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(
+            content_disposition_file_name("attachment; filename=\"..\\\\x.exe\"").as_deref(),
+            Some("x.exe")
+        );
+        assert_eq!(content_disposition_file_name("inline"), None);
+        assert_eq!(
+            content_disposition_file_name("attachment; filename=\"..\""),
+            None
+        );
+    }
+
+    #[test]
+    fn parallelism_is_limited_by_the_chunks() {
+        let concurrency = |max_threads| Concurrency {
+            max_threads,
+            reduce_on_slow_disk: false,
+        };
+        assert_eq!(concurrency(4).parallelism(10), 4);
+        assert_eq!(concurrency(4).parallelism(3), 3);
+        assert_eq!(concurrency(1).parallelism(10), 1);
+        assert_eq!(concurrency(4).parallelism(0), 1);
+        let auto = concurrency(0).parallelism(100);
+        assert!((2..=MAX_AUTO_THREADS).contains(&auto));
+        assert_eq!(concurrency(0).parallelism(1), 1);
+    }
+
+    #[test]
+    fn auto_chunk_size_follows_throughput() {
+        let mib = DEFAULT_CHUNK_SIZE;
+        // 1 MiB/s fetches 2 MiB in AUTO_CHUNK_DURATION
+        assert_eq!(
+            chunk_size_for_throughput(mib, Duration::from_secs(1)),
+            2 * mib
+        );
+        assert_eq!(chunk_size_for_throughput(mib, Duration::from_secs(10)), mib);
+        assert_eq!(
+            chunk_size_for_throughput(mib, Duration::from_millis(1)),
+            MAX_AUTO_CHUNK_SIZE
+        );
+        assert_eq!(
+            chunk_size_for_throughput(mib, Duration::from_millis(200)),
+            10 * mib
+        );
+    }
+
+    #[test]
+    fn content_range_total_parses_complete_length() {
+        assert_eq!(content_range_total("bytes 0-0/1234"), Some(1234));
+        assert_eq!(content_range_total("bytes */1234"), Some(1234));
+        assert_eq!(content_range_total("bytes 0-0/*"), None);
+        assert_eq!(content_range_total("items 0-0/5"), None);
+        assert_eq!(content_range_total("garbage"), None);
+    }
+
+    #[test]
+    fn range_responses_must_match_the_request() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        reqwest::header::HeaderName::from_static(name),
+                        reqwest::header::HeaderValue::from_static(value),
+                    )
+                })
+                .collect::<reqwest::header::HeaderMap>()
+        };
+        assert_eq!(content_range_bounds("bytes 0-1023/4096"), Some((0, 1023)));
+        assert_eq!(content_range_bounds("bytes */4096"), None);
+        assert!(
+            check_range_response(&headers(&[("content-range", "bytes 10-19/100")]), 10, 19).is_ok()
+        );
+        assert!(
+            check_range_response(&headers(&[("content-range", "bytes 0-99/100")]), 10, 19).is_err()
+        );
+        assert!(check_range_response(&headers(&[]), 10, 19).is_err());
+        assert!(check_range_response(
+            &headers(&[
+                ("content-range", "bytes 10-19/100"),
+                ("content-encoding", "gzip")
+            ]),
+            10,
+            19
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn content_length_ignores_chunked_responses() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::CONTENT_LENGTH,
+            reqwest::header::HeaderValue::from_static("42"),
+        );
+        assert_eq!(content_length(&headers).unwrap(), Some(42));
+
+        headers.insert(
+            reqwest::header::TRANSFER_ENCODING,
+            reqwest::header::HeaderValue::from_static("chunked"),
+        );
+        assert_eq!(content_length(&headers).unwrap(), None);
+    }
+
+    #[test]
+    fn redirects_are_limited() {
+        let url = |s: &str| reqwest::Url::parse(s).unwrap();
+        let mirror = url("https://mirror.example.com/file.iso");
+        let other_host = url("https://cdn.example.org/file.iso");
+        let options = RedirectOptions {
+            max: 2,
+            cross_host: false,
+        };
+        assert_eq!(
+            options.refusal(
+                std::slice::from_ref(&mirror),
+                &url("https://mirror.example.com/b")
+            ),
+            None
+        );
+        assert!(options
+            .refusal(std::slice::from_ref(&mirror), &other_host)
+            .is_some());
+        assert!(options
+            .refusal(&[mirror.clone(), mirror.clone(), mirror.clone()], &mirror)
+            .is_some());
+        assert_eq!(
+            RedirectOptions::default().refusal(&[mirror], &other_host),
+            None
+        );
+    }
+
+    /// Fetch and verify every piece with its own request
+    const PIECE_BY_PIECE: PieceFetch = PieceFetch {
+        verify_checksums: true,
+        max_request_size: 0,
+    };
+
+    /// The target file and the chunks of "abcdef" with pieces of three
+    /// bytes, in a temporary directory removed once it is dropped
+    fn pieces() -> (tempfile::TempDir, PathBuf, Vec<ChunkMetaData>) {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let metalink = dir.join("test.meta4");
+        std::fs::write(
+            &metalink,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="file.txt">
+    <size>6</size>
+    <pieces length="3" type="sha-256">
+      <hash>ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad</hash>
+      <hash>cb8379ac2098aa165029e3938a51da0bcecfc008fd6795f401178647f96c5b34</hash>
+    </pieces>
+    <url>https://example.com/file.txt</url>
+  </file>
+</metalink>"#,
+        )
+        .unwrap();
+        let plan = crate::types::Plan::new(
+            metalink,
+            dir,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+        )
+        .unwrap();
+        let file = &plan.files[0];
+        (
+            temp,
+            file.target_file.clone(),
+            file.chunks.as_ref().unwrap().to_vec(),
+        )
+    }
+
+    #[tokio::test]
+    async fn corrupt_chunks_are_fetched_again() {
+        let (_temp, target_file, chunks) = pieces();
+        let url = reqwest::Url::parse("mock://mirror/file.txt").unwrap();
+
+        let transport = MockTransport::new(b"abcdef").with_corrupt_range(3, 2);
+        download(
+            &transport,
+            url.clone(),
+            target_file.clone(),
+            &chunks,
+            None,
+            PIECE_BY_PIECE,
+            &Pause::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&target_file).unwrap(), b"abcdef");
+        assert_eq!(
+            transport.requests(),
+            [
+                Request::Range(0, 2),
+                Request::Range(3, 5),
+                Request::Range(3, 5),
+                Request::Range(3, 5)
+            ]
+        );
+
+        let transport = MockTransport::new(b"abcdef").with_corrupt_range(3, 3);
+        let err = download(
+            &transport,
+            url,
+            target_file.clone(),
+            &chunks,
+            None,
+            PIECE_BY_PIECE,
+            &Pause::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            MetalinkDownloadError::ChecksumMismatch { start: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn adjacent_chunks_are_coalesced() {
+        let filename: Arc<Path> = Arc::from(Path::new("file"));
+        let chunk = |start, end| ChunkMetaData::new(start, end, filename.clone());
+        let chunks = [
+            chunk(0, 9),
+            chunk(10, 19),
+            chunk(20, 29),
+            chunk(40, 49),
+            chunk(50, 79),
+        ];
+        let runs = |max_request_size| -> Vec<(u64, u64)> {
+            coalesce(&chunks, max_request_size)
+                .into_iter()
+                .map(|run| (run[0].start, run[run.len() - 1].end))
+                .collect()
+        };
+        assert_eq!(runs(100), [(0, 29), (40, 79)]);
+        assert_eq!(runs(20), [(0, 19), (20, 29), (40, 49), (50, 79)]);
+        assert_eq!(runs(0).len(), chunks.len());
+        assert!(coalesce(&[], 100).is_empty());
+    }
+
+    #[tokio::test]
+    async fn coalesced_chunks_are_verified_piece_by_piece() {
+        let (_temp, target_file, chunks) = pieces();
+        let url = reqwest::Url::parse("mock://mirror/file.txt").unwrap();
+        let fetch = PieceFetch {
+            verify_checksums: true,
+            max_request_size: 1024,
+        };
+
+        let transport = MockTransport::new(b"abcdef");
+        download(
+            &transport,
+            url.clone(),
+            target_file.clone(),
+            &chunks,
+            None,
+            fetch,
+            &Pause::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&target_file).unwrap(), b"abcdef");
+        assert_eq!(transport.requests(), [Request::Range(0, 5)]);
+
+        std::fs::write(&target_file, b"").unwrap();
+        let transport = MockTransport::new(b"abcdef").with_corrupt_range(0, 1);
+        download(
+            &transport,
+            url,
+            target_file.clone(),
+            &chunks,
+            None,
+            fetch,
+            &Pause::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&target_file).unwrap(), b"abcdef");
+        assert_eq!(
+            transport.requests(),
+            [
+                Request::Range(0, 5),
+                Request::Range(0, 2),
+                Request::Range(3, 5)
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn a_stalled_range_does_not_hold_up_the_others() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let target_file = dir.join("file.txt");
+        let url = reqwest::Url::parse("mock://mirror/file.txt").unwrap();
+        let ranges = ChunkMetaData::calculate_ranges(6, 1, &target_file);
+        // The first range is only served after all others were requested
+        let transport: Arc<dyn Transport> =
+            Arc::new(MockTransport::new(b"abcdef").with_stalled_range(0, 6));
+        let concurrency = Concurrency {
+            max_threads: 2,
+            reduce_on_slow_disk: false,
+        };
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            segregrated_download(
+                &transport,
+                url,
+                target_file.clone(),
+                6,
+                &ranges,
+                None,
+                concurrency,
+            ),
+        )
+        .await
+        .expect("the other ranges are fetched while the first one stalls")
+        .unwrap();
+        assert_eq!(std::fs::read(&target_file).unwrap(), b"abcdef");
+    }
+
+    #[tokio::test]
+    async fn failed_ranges_are_fetched_once_more() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let target_file = dir.join("file.txt");
+        let url = reqwest::Url::parse("mock://mirror/file.txt").unwrap();
+        let ranges = ChunkMetaData::calculate_ranges(6, 2, &target_file);
+        let concurrency = Concurrency {
+            max_threads: 2,
+            reduce_on_slow_disk: false,
+        };
+
+        let transport: Arc<dyn Transport> =
+            Arc::new(MockTransport::new(b"abcdef").with_failing_range(2, 1));
+        segregrated_download(
+            &transport,
+            url.clone(),
+            target_file.clone(),
+            6,
+            &ranges,
+            None,
+            concurrency,
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&target_file).unwrap(), b"abcdef");
+
+        // A range which keeps failing fails the download instead of leaving
+        // a hole in the preallocated file
+        let transport: Arc<dyn Transport> =
+            Arc::new(MockTransport::new(b"abcdef").with_failing_range(2, u32::MAX));
+        let err = segregrated_download(
+            &transport,
+            url,
+            target_file.clone(),
+            6,
+            &ranges,
+            None,
+            concurrency,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(&err, MetalinkDownloadError::AllMirrorsFailed { errors, .. } if errors.len() == 1),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn broken_ranges_fall_back_to_the_whole_file() {
+        let (_temp, target_file, chunks) = pieces();
+        let url = reqwest::Url::parse("mock://mirror/file.txt").unwrap();
+
+        let transport = MockTransport::new(b"abcdef").with_broken_ranges();
+        download(
+            &transport,
+            url,
+            target_file.clone(),
+            &chunks,
+            None,
+            PIECE_BY_PIECE,
+            &Pause::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&target_file).unwrap(), b"abcdef");
+        assert_eq!(transport.requests(), [Request::Range(0, 2), Request::Whole]);
+    }
+
+    #[tokio::test]
+    async fn response_bodies_are_written_as_they_arrive() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let target_file = dir.join("file.txt");
+        let frames = || {
+            futures::stream::iter(
+                [&b"abc"[..], b"de", b"f"].map(|frame| Ok(bytes::Bytes::from_static(frame))),
+            )
+        };
+
+        assert_eq!(
+            write_body(frames(), &target_file, Some(6), None)
+                .await
+                .unwrap(),
+            6
+        );
+        assert_eq!(std::fs::read(&target_file).unwrap(), b"abcdef");
+        assert_eq!(
+            write_body(frames(), &target_file, None, None)
+                .await
+                .unwrap(),
+            6
+        );
+        assert_eq!(std::fs::read(&target_file).unwrap(), b"abcdef");
+
+        // A body longer than announced is cut off, a shorter one is incomplete
+        assert!(matches!(
+            write_body(frames(), &target_file, Some(4), None).await,
+            Err(MetalinkDownloadError::SizeMismatch { actual: 5, .. })
+        ));
+        assert!(matches!(
+            write_body(frames(), &target_file, Some(8), None).await,
+            Err(MetalinkDownloadError::SizeMismatch { actual: 6, .. })
+        ));
+    }
+}
@@ -0,0 +1,429 @@
+//! Queue of download jobs run by the daemon.
+//!
+//! Every job downloads a metalink into a target directory with the library
+//! API. The queue is saved to a state file after every change, so jobs which
+//! were queued or running when the daemon stopped are started again by the
+//! next daemon. Pausing a running job stops its chunk requests and keeps the
+//! download waiting, resuming it continues the download. A paused job whose
+//! download is no longer running is queued again when it is resumed, the
+//! download keeps the complete files and valid pieces. Jobs downloading into
+//! the same target directory run one after the other. Every job is a run of
+//! its own, see [`crate::run`], while the rate limit applies to all jobs
+//! together.
+
+use crate::shutdown;
+use crate::{FileState, JobControl, MetalinkDownloadError, MetalinkDownloaderBuilder, Result};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// State of a download job
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum JobState {
+    Queued,
+    Running,
+    Paused,
+    Completed,
+    /// The download failed with the given error
+    Failed(String),
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Job {
+    id: u64,
+    /// Path or url of the metalink
+    metalink: String,
+    target_dir: PathBuf,
+    state: JobState,
+    /// Files of the running or last download of the job
+    #[serde(skip)]
+    control: JobControl,
+    /// A download of the job is running, it may still be stopping after the
+    /// job was paused or cancelled
+    #[serde(skip)]
+    active: bool,
+}
+
+/// What the daemon tells about a job
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct JobStatus {
+    pub id: u64,
+    pub metalink: String,
+    pub target_dir: PathBuf,
+    pub state: JobState,
+    /// Names of the files of the last download and their state
+    pub files: Vec<(String, String)>,
+}
+
+impl From<&Job> for JobStatus {
+    fn from(job: &Job) -> Self {
+        Self {
+            id: job.id,
+            metalink: job.metalink.clone(),
+            target_dir: job.target_dir.clone(),
+            state: job.state.clone(),
+            files: job
+                .control
+                .files()
+                .into_iter()
+                .map(|(name, state)| (name, file_state(&state)))
+                .collect(),
+        }
+    }
+}
+
+fn file_state(state: &FileState) -> String {
+    match state {
+        FileState::Queued => "queued".to_owned(),
+        FileState::Downloading => "downloading".to_owned(),
+        FileState::Downloaded => "downloaded".to_owned(),
+        FileState::Failed(e) => format!("failed: {e}"),
+        FileState::Cancelled => "cancelled".to_owned(),
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Queue {
+    next_id: u64,
+    jobs: BTreeMap<u64, Job>,
+}
+
+/// The jobs of the daemon, cloning it yields a handle to the same queue
+#[derive(Clone)]
+pub(crate) struct JobManager {
+    queue: Arc<Mutex<Queue>>,
+    state_file: PathBuf,
+    /// Settings of the downloads, the target directory is set per job
+    downloader: MetalinkDownloaderBuilder,
+    max_jobs: usize,
+    /// Wakes the scheduler when a job may be started
+    changed: Arc<Notify>,
+}
+
+impl JobManager {
+    /// Load the queue saved in `state_file`, jobs which were running are
+    /// queued again
+    pub(crate) fn load(
+        state_file: PathBuf,
+        downloader: MetalinkDownloaderBuilder,
+        max_jobs: usize,
+    ) -> Result<Self> {
+        let mut queue: Queue = match std::fs::read(&state_file) {
+            Ok(content) => serde_json::from_slice(&content)
+                .with_context(|| format!("Invalid job queue {state_file:?}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Queue::default(),
+            Err(e) => {
+                return Err(anyhow::Error::new(e)
+                    .context(format!("Failed to read job queue {state_file:?}"))
+                    .into())
+            }
+        };
+        for job in queue.jobs.values_mut() {
+            if job.state == JobState::Running {
+                job.state = JobState::Queued;
+            }
+        }
+        Ok(Self {
+            queue: Arc::new(Mutex::new(queue)),
+            state_file,
+            downloader,
+            max_jobs: max_jobs.max(1),
+            changed: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Queue the download of `metalink` into `target_dir`
+    pub(crate) fn add(&self, metalink: String, target_dir: PathBuf) -> Result<JobStatus> {
+        self.update(|queue| {
+            queue.next_id += 1;
+            let job = Job {
+                id: queue.next_id,
+                metalink,
+                target_dir,
+                state: JobState::Queued,
+                control: JobControl::default(),
+                active: false,
+            };
+            let status = JobStatus::from(&job);
+            queue.jobs.insert(job.id, job);
+            Ok(status)
+        })
+    }
+
+    /// All jobs, or only the job `id`
+    pub(crate) fn status(&self, id: Option<u64>) -> Result<Vec<JobStatus>> {
+        let queue = self.lock();
+        match id {
+            Some(id) => Ok(vec![JobStatus::from(job(&queue, id)?)]),
+            None => Ok(queue.jobs.values().map(JobStatus::from).collect()),
+        }
+    }
+
+    /// Keep the job `id` from being started, a running download stops
+    /// issuing requests until the job is resumed
+    pub(crate) fn pause(&self, id: u64) -> Result<JobStatus> {
+        self.transition(id, |job| match job.state {
+            JobState::Queued => Ok(JobState::Paused),
+            JobState::Running => {
+                job.control.pause();
+                Ok(JobState::Paused)
+            }
+            _ => Err(anyhow!("Only queued or running jobs can be paused")),
+        })
+    }
+
+    /// Continue the paused download of the job `id`, or queue the job again
+    /// if it is not running
+    pub(crate) fn resume(&self, id: u64) -> Result<JobStatus> {
+        self.transition(id, |job| match job.state {
+            JobState::Paused if job.active => {
+                job.control.resume();
+                Ok(JobState::Running)
+            }
+            JobState::Paused => Ok(JobState::Queued),
+            _ => Err(anyhow!("Only paused jobs can be resumed")),
+        })
+    }
+
+    /// Give up on the job `id`, a running download is stopped
+    pub(crate) fn cancel(&self, id: u64) -> Result<JobStatus> {
+        self.transition(id, |job| match job.state {
+            JobState::Queued | JobState::Paused if !job.active => Ok(JobState::Cancelled),
+            JobState::Running | JobState::Paused => {
+                job.control.cancel_all();
+                Ok(JobState::Cancelled)
+            }
+            _ => Err(anyhow!("The job has already finished")),
+        })
+    }
+
+    /// Start queued jobs in the order they were added, at most `max_jobs` at
+    /// the same time. Runs until a shutdown is requested, then waits for the
+    /// running jobs to stop and saves the queue.
+    pub(crate) async fn run(&self) {
+        let running = tokio_util::task::TaskTracker::new();
+        loop {
+            let changed = self.changed.notified();
+            while !shutdown::is_requested() {
+                let Some(job) = self.next_job() else {
+                    break;
+                };
+                let manager = self.clone();
+                running.spawn(async move { manager.run_job(job).await });
+            }
+            tokio::select! {
+                _ = changed => {}
+                _ = shutdown::requested() => break,
+            }
+        }
+        running.close();
+        tracing::info!("Waiting for {} running jobs to stop", running.len());
+        running.wait().await;
+        self.save(&self.lock());
+    }
+
+    /// Mark the next queued job as running if another job may run. Jobs
+    /// into a directory another job is downloading into have to wait, they
+    /// would only wait for its lock.
+    fn next_job(&self) -> Option<Job> {
+        let mut queue = self.lock();
+        let busy: Vec<PathBuf> = queue
+            .jobs
+            .values()
+            .filter(|job| job.active)
+            .map(|job| job.target_dir.clone())
+            .collect();
+        if busy.len() >= self.max_jobs {
+            return None;
+        }
+        let job = queue.jobs.values_mut().find(|job| {
+            job.state == JobState::Queued && !job.active && !busy.contains(&job.target_dir)
+        })?;
+        job.state = JobState::Running;
+        job.active = true;
+        job.control = JobControl::default();
+        let job = job.clone();
+        self.save(&queue);
+        Some(job)
+    }
+
+    async fn run_job(&self, job: Job) {
+        tracing::info!("Starting job {}: {}", job.id, job.metalink);
+        let result = match self.downloader.clone().target_dir(&job.target_dir).build() {
+            Ok(downloader) => downloader.download_job(&job.metalink, job.control).await,
+            Err(e) => Err(e),
+        };
+        let finished = match result {
+            Ok(report) => match report.failure() {
+                None => JobState::Completed,
+                Some(e) => JobState::Failed(format!("{e:#}")),
+            },
+            // The daemon is stopping, the next one runs the job again
+            Err(MetalinkDownloadError::Interrupted { .. }) => JobState::Queued,
+            Err(e) => JobState::Failed(format!("{e:#}")),
+        };
+        tracing::info!("Job {} finished: {finished:?}", job.id);
+        self.finish_job(job.id, finished);
+    }
+
+    /// Record the outcome of the download of the job `id`
+    fn finish_job(&self, id: u64, finished: JobState) {
+        let mut queue = self.lock();
+        if let Some(job) = queue.jobs.get_mut(&id) {
+            job.active = false;
+            match (&job.state, finished) {
+                (JobState::Running, finished) => job.state = finished,
+                // Files without pieces are downloaded while the job is paused
+                (JobState::Paused, JobState::Completed) => job.state = JobState::Completed,
+                // Other paused and cancelled jobs keep the state they were given
+                _ => {}
+            }
+        }
+        self.save(&queue);
+        drop(queue);
+        self.changed.notify_one();
+    }
+
+    fn transition(
+        &self,
+        id: u64,
+        next: impl FnOnce(&mut Job) -> anyhow::Result<JobState>,
+    ) -> Result<JobStatus> {
+        self.update(|queue| {
+            let job = queue
+                .jobs
+                .get_mut(&id)
+                .ok_or_else(|| anyhow!("There is no job {id}"))?;
+            job.state = next(job)?;
+            Ok(JobStatus::from(&*job))
+        })
+    }
+
+    /// Change the queue with `f` and save it if `f` succeeded
+    fn update<T>(&self, f: impl FnOnce(&mut Queue) -> Result<T>) -> Result<T> {
+        let mut queue = self.lock();
+        let result = f(&mut queue)?;
+        self.save(&queue);
+        drop(queue);
+        self.changed.notify_one();
+        Ok(result)
+    }
+
+    /// Failing to save the queue only loses it if the daemon stops
+    fn save(&self, queue: &Queue) {
+        if let Err(e) = write_queue(&self.state_file, queue) {
+            tracing::warn!("{e:#}");
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn job(queue: &Queue, id: u64) -> Result<&Job> {
+    Ok(queue
+        .jobs
+        .get(&id)
+        .ok_or_else(|| anyhow!("There is no job {id}"))?)
+}
+
+/// Replace the state file at once, a crash must not leave half a queue
+fn write_queue(state_file: &Path, queue: &Queue) -> anyhow::Result<()> {
+    if let Some(dir) = state_file.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory {dir:?}"))?;
+    }
+    let mut partial = state_file.as_os_str().to_owned();
+    partial.push(".tmp");
+    std::fs::write(&partial, serde_json::to_vec_pretty(queue)?)
+        .with_context(|| format!("Failed to save job queue to {partial:?}"))?;
+    std::fs::rename(&partial, state_file)
+        .with_context(|| format!("Failed to save job queue to {state_file:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobs_are_paused_resumed_and_cancelled() {
+        let temp = tempfile::tempdir().unwrap();
+        let state_file = temp.path().join("jobs.json");
+        let manager =
+            JobManager::load(state_file.clone(), MetalinkDownloaderBuilder::default(), 1).unwrap();
+        let first = manager
+            .add("release.meta4".to_owned(), PathBuf::from("/srv/mirror"))
+            .unwrap();
+        let second = manager
+            .add(
+                "https://example.com/other.meta4".to_owned(),
+                PathBuf::from("/srv/other"),
+            )
+            .unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+
+        assert_eq!(manager.pause(1).unwrap().state, JobState::Paused);
+        assert!(manager.pause(1).is_err());
+        assert_eq!(manager.next_job().unwrap().id, 2);
+        // The running download waits while paused
+        assert_eq!(manager.pause(2).unwrap().state, JobState::Paused);
+        assert!(manager.lock().jobs[&2].control.is_paused());
+        assert_eq!(manager.resume(2).unwrap().state, JobState::Running);
+        assert!(!manager.lock().jobs[&2].control.is_paused());
+        // Only one job runs at a time
+        assert_eq!(manager.resume(1).unwrap().state, JobState::Queued);
+        assert!(manager.next_job().is_none());
+        assert_eq!(manager.cancel(2).unwrap().state, JobState::Cancelled);
+        assert!(manager.cancel(2).is_err());
+        assert!(manager.resume(3).is_err());
+        // The cancelled download has to stop before the next job starts
+        assert!(manager.next_job().is_none());
+        manager.finish_job(2, JobState::Failed("cancelled".to_owned()));
+        assert_eq!(
+            manager.status(Some(2)).unwrap()[0].state,
+            JobState::Cancelled
+        );
+
+        // The running job is queued again by the next daemon
+        assert_eq!(manager.next_job().unwrap().id, 1);
+        let reloaded =
+            JobManager::load(state_file.clone(), MetalinkDownloaderBuilder::default(), 1).unwrap();
+        let states: Vec<_> = reloaded
+            .status(None)
+            .unwrap()
+            .into_iter()
+            .map(|job| job.state)
+            .collect();
+        assert_eq!(states, [JobState::Queued, JobState::Cancelled]);
+    }
+
+    #[test]
+    fn jobs_into_the_same_directory_run_one_after_the_other() {
+        let temp = tempfile::tempdir().unwrap();
+        let state_file = temp.path().join("jobs.json");
+        let manager =
+            JobManager::load(state_file.clone(), MetalinkDownloaderBuilder::default(), 3).unwrap();
+        for (metalink, target_dir) in [
+            ("a.meta4", "/srv/a"),
+            ("b.meta4", "/srv/a"),
+            ("c.meta4", "/srv/c"),
+        ] {
+            manager
+                .add(metalink.to_owned(), PathBuf::from(target_dir))
+                .unwrap();
+        }
+        assert_eq!(manager.next_job().unwrap().id, 1);
+        assert_eq!(manager.next_job().unwrap().id, 3);
+        assert!(manager.next_job().is_none());
+        manager.finish_job(1, JobState::Completed);
+        assert_eq!(manager.next_job().unwrap().id, 2);
+    }
+}
@@ -0,0 +1,117 @@
+//! Keybindings of the interactive progress display.
+//!
+//! While the progress bars are shown, pressing `p` pauses the download and
+//! pressing it again resumes it. The terminal passes single key presses
+//! without echoing them until the download ends, interrupting still works.
+
+use crate::JobControl;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// How long the key reader waits for a key before checking whether the
+/// download ended
+#[cfg(unix)]
+const KEY_POLL_INTERVAL_MS: i32 = 100;
+
+/// Reads the keys pressed during a download, dropping it restores the
+/// terminal
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) struct Keybindings {
+    stop: Arc<AtomicBool>,
+    reader: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Keybindings {
+    /// Pause and resume the downloads of `control` with the keys pressed on
+    /// stdin, None if stdin is not a terminal
+    #[cfg(unix)]
+    pub(crate) fn listen(control: JobControl) -> Option<Self> {
+        use rustix::termios::{tcgetattr, tcsetattr, LocalModes, OptionalActions};
+        use std::io::IsTerminal;
+
+        let stdin = std::io::stdin();
+        if !stdin.is_terminal() {
+            return None;
+        }
+        let original = tcgetattr(&stdin).ok()?;
+        let mut single_keys = original.clone();
+        single_keys
+            .local_modes
+            .remove(LocalModes::ICANON | LocalModes::ECHO);
+        tcsetattr(&stdin, OptionalActions::Now, &single_keys).ok()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                read_keys(&control, &stop);
+                if let Err(e) = tcsetattr(std::io::stdin(), OptionalActions::Now, &original) {
+                    tracing::warn!("Failed to restore the terminal: {e}");
+                }
+            }
+        });
+        Some(Self {
+            stop,
+            reader: Some(reader),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn listen(_: JobControl) -> Option<Self> {
+        None
+    }
+}
+
+impl Drop for Keybindings {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+#[cfg(unix)]
+fn read_keys(control: &JobControl, stop: &AtomicBool) {
+    use rustix::event::{poll, PollFd, PollFlags};
+    use rustix::io::Errno;
+
+    let stdin = std::io::stdin();
+    let mut key = [0u8; 1];
+    while !stop.load(Ordering::Relaxed) {
+        let mut fds = [PollFd::new(&stdin, PollFlags::IN)];
+        match poll(&mut fds, KEY_POLL_INTERVAL_MS) {
+            Ok(0) | Err(Errno::INTR) => continue,
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!("Failed to wait for keys: {e}");
+                return;
+            }
+        }
+        match rustix::io::read(&stdin, &mut key) {
+            // stdin was closed
+            Ok(0) => return,
+            Ok(_) => press(control, key[0]),
+            Err(Errno::INTR | Errno::AGAIN) => {}
+            Err(e) => {
+                tracing::warn!("Failed to read keys: {e}");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn press(control: &JobControl, key: u8) {
+    match key {
+        b'p' | b'P' if control.is_paused() => {
+            tracing::info!("Resuming the download");
+            control.resume();
+        }
+        b'p' | b'P' => {
+            tracing::info!("Pausing the download");
+            control.pause();
+        }
+        _ => {}
+    }
+}
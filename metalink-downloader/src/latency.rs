@@ -0,0 +1,289 @@
+use crate::run;
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Stages a chunk passes through in the download engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    /// Waiting for its turn after the download of the file started
+    Queueing,
+    /// Sending the request until the response headers arrived
+    Connecting,
+    /// Receiving the response body
+    Transferring,
+    /// Validating the chunk checksum
+    Hashing,
+    /// Writing the chunk to the target file
+    Writing,
+}
+
+const STAGES: [Stage; 5] = [
+    Stage::Queueing,
+    Stage::Connecting,
+    Stage::Transferring,
+    Stage::Hashing,
+    Stage::Writing,
+];
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Stage::Queueing => "queueing",
+            Stage::Connecting => "connecting",
+            Stage::Transferring => "transferring",
+            Stage::Hashing => "hashing",
+            Stage::Writing => "writing",
+        };
+        f.pad(name)
+    }
+}
+
+/// Sub-buckets per power of two of the histogram, bounds its relative error
+/// to 1/16
+const SUB_BUCKETS: u64 = 16;
+/// Durations of 2^40 microseconds (about 12 days) and longer share the last
+/// power of two
+const MAX_EXPONENT: u32 = 40;
+const BUCKETS: usize = ((MAX_EXPONENT - 3) as u64 * SUB_BUCKETS) as usize;
+
+/// Log-linear histogram of durations in microseconds, takes the same memory
+/// no matter how many samples are recorded
+#[derive(Debug, Clone)]
+struct Histogram {
+    counts: Box<[u64; BUCKETS]>,
+    samples: usize,
+    max: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: Box::new([0; BUCKETS]),
+            samples: 0,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl Histogram {
+    fn bucket(micros: u64) -> usize {
+        if micros < SUB_BUCKETS {
+            return micros as usize;
+        }
+        let exponent = (63 - micros.leading_zeros()).min(MAX_EXPONENT - 1);
+        let sub_bucket = (micros >> (exponent - 4)).min(2 * SUB_BUCKETS - 1) - SUB_BUCKETS;
+        ((exponent as u64 - 3) * SUB_BUCKETS + sub_bucket) as usize
+    }
+
+    /// The largest duration falling into `bucket`
+    fn upper_bound(bucket: usize) -> Duration {
+        let bucket = bucket as u64;
+        let micros = if bucket < SUB_BUCKETS {
+            bucket
+        } else {
+            let exponent = bucket / SUB_BUCKETS + 3;
+            ((bucket % SUB_BUCKETS + SUB_BUCKETS + 1) << (exponent - 4)) - 1
+        };
+        Duration::from_micros(micros)
+    }
+
+    fn record(&mut self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.counts[Self::bucket(micros)] += 1;
+        self.samples += 1;
+        self.max = self.max.max(duration);
+    }
+}
+
+/// Latency histograms of every stage of a run, see [`crate::run`]
+#[derive(Default)]
+pub(crate) struct LatencyRecorder {
+    stages: Mutex<[Histogram; STAGES.len()]>,
+}
+
+/// Record the time a chunk spent in `stage` in the current run
+pub(crate) fn record(stage: Stage, duration: Duration) {
+    run::with(|run| {
+        let mut stages = run.latency.stages.lock().unwrap_or_else(|e| e.into_inner());
+        stages[stage as usize].record(duration);
+    });
+}
+
+/// Run `f` and record its duration for `stage`
+pub(crate) async fn timed<T>(stage: Stage, f: impl std::future::Future<Output = T>) -> T {
+    let started = Instant::now();
+    let result = f.await;
+    record(stage, started.elapsed());
+    result
+}
+
+/// Latency percentiles of a single stage
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Percentiles {
+    pub samples: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Percentiles {
+    /// Nearest-rank percentiles, reported as the upper bound of the bucket
+    /// the ranked sample falls into
+    fn from_histogram(histogram: &Histogram) -> Option<Self> {
+        if histogram.samples == 0 {
+            return None;
+        }
+        let rank = |p: usize| {
+            let rank = (histogram.samples * p).div_ceil(100).max(1) as u64;
+            let mut seen = 0;
+            let bucket = histogram
+                .counts
+                .iter()
+                .position(|count| {
+                    seen += count;
+                    seen >= rank
+                })
+                .unwrap_or(BUCKETS - 1);
+            Histogram::upper_bound(bucket).min(histogram.max)
+        };
+        Some(Self {
+            samples: histogram.samples,
+            p50: rank(50),
+            p90: rank(90),
+            p99: rank(99),
+            max: histogram.max,
+        })
+    }
+}
+
+/// Per-stage latency percentiles of all chunks of a run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct LatencyBreakdown {
+    stages: Vec<(Stage, Percentiles)>,
+}
+
+impl LatencyBreakdown {
+    /// Aggregate the samples the current run recorded so far and start over
+    pub(crate) fn collect() -> Self {
+        let histograms = run::with(|run| {
+            std::mem::take(&mut *run.latency.stages.lock().unwrap_or_else(|e| e.into_inner()))
+        });
+        histograms.map_or_else(Self::default, |histograms| {
+            Self::from_histograms(&histograms)
+        })
+    }
+
+    fn from_histograms(histograms: &[Histogram; STAGES.len()]) -> Self {
+        Self {
+            stages: STAGES
+                .into_iter()
+                .zip(histograms)
+                .filter_map(|(stage, histogram)| {
+                    Percentiles::from_histogram(histogram).map(|percentiles| (stage, percentiles))
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+}
+
+impl std::fmt::Display for LatencyBreakdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Chunk latency (p50 / p90 / p99 / max):")?;
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        for (stage, p) in &self.stages {
+            writeln!(
+                f,
+                "  {stage:<12} {:>9.1}ms {:>9.1}ms {:>9.1}ms {:>9.1}ms ({} chunks)",
+                ms(p.p50),
+                ms(p.p90),
+                ms(p.p99),
+                ms(p.max),
+                p.samples
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(samples: impl IntoIterator<Item = Duration>) -> Histogram {
+        let mut histogram = Histogram::default();
+        for sample in samples {
+            histogram.record(sample);
+        }
+        histogram
+    }
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let p =
+            Percentiles::from_histogram(&histogram((1..=100).map(Duration::from_millis))).unwrap();
+        assert_eq!(p.samples, 100);
+        // Within the resolution of the histogram
+        let close_to = |actual: Duration, ms: u64| {
+            let expected = Duration::from_millis(ms);
+            actual >= expected && actual - expected <= expected / SUB_BUCKETS as u32
+        };
+        assert!(close_to(p.p50, 50), "{:?}", p.p50);
+        assert!(close_to(p.p90, 90), "{:?}", p.p90);
+        assert!(close_to(p.p99, 99), "{:?}", p.p99);
+        assert_eq!(p.max, Duration::from_millis(100));
+
+        let single = Percentiles::from_histogram(&histogram([Duration::from_millis(7)])).unwrap();
+        assert_eq!(single.p50, Duration::from_millis(7));
+        assert_eq!(Percentiles::from_histogram(&Histogram::default()), None);
+    }
+
+    #[test]
+    fn buckets_cover_all_durations() {
+        let mut previous = 0;
+        for micros in (0..100_000).chain([u64::MAX / 2, u64::MAX]) {
+            let bucket = Histogram::bucket(micros);
+            assert!(bucket >= previous && bucket < BUCKETS);
+            previous = bucket;
+            if micros < 1 << MAX_EXPONENT {
+                assert!(Histogram::upper_bound(bucket) >= Duration::from_micros(micros));
+            }
+        }
+    }
+
+    #[test]
+    fn breakdown_skips_stages_without_samples() {
+        let mut histograms: [Histogram; 5] = Default::default();
+        histograms[Stage::Writing as usize] = histogram([Duration::from_millis(2)]);
+        let breakdown = LatencyBreakdown::from_histograms(&histograms);
+        assert_eq!(
+            breakdown.to_string(),
+            "Chunk latency (p50 / p90 / p99 / max):\n  \
+             writing            2.0ms       2.0ms       2.0ms       2.0ms (1 chunks)\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_only_collect_their_own_samples() {
+        record(Stage::Writing, Duration::from_millis(1));
+        let collected = run::scope(async {
+            record(Stage::Hashing, Duration::from_millis(1));
+            run::spawn(async { record(Stage::Hashing, Duration::from_millis(1)) })
+                .await
+                .unwrap();
+            let collected = LatencyBreakdown::collect();
+            assert!(LatencyBreakdown::collect().is_empty());
+            collected
+        })
+        .await;
+        assert_eq!(collected.stages.len(), 1);
+        assert_eq!(collected.stages[0].0, Stage::Hashing);
+        assert_eq!(collected.stages[0].1.samples, 2);
+        assert!(LatencyBreakdown::collect().is_empty());
+    }
+}
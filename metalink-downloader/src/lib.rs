@@ -1,43 +1,482 @@
+//! Downloads the files of metalinks (RFC 5854) from their mirrors, verifying
+//! them against the checksums of the metalink.
+//!
+//! The command line is started with [`App::run`], other applications embed
+//! the downloader with [`MetalinkDownloader`] and follow running downloads
+//! through [`events::subscribe`].
+
+use anyhow::anyhow;
 use clap::Parser;
+use iana_registry_enums::HashFunctionTextualName;
 
+pub use auth::Credentials;
+pub use build_info::BuildInfo;
+#[cfg(feature = "observability")]
+pub use cli::MetricsArgs;
+pub use cli::{Cli, Commands, FilterArgs, HttpArgs, JobVerb, MirrorArgs};
+pub use commands::{HistoryFormat, LintDeny, PlanFormat};
+pub use control::{FileState, JobControl};
+pub use dns::ResolveOverride;
+pub use downloader::{MetalinkDownloader, MetalinkDownloaderBuilder};
 pub use error::{MetalinkDownloadError, Result};
+pub use http::{ChunkSize, HttpVersion};
+pub use lock::LockMode;
+pub use metaurl::MetaUrlHandler;
+pub use report::DownloadReport;
+pub use selection::{ConflictDecision, Dedupe, MirrorRewrite, OnConflict};
+pub use transport::Transport;
+pub use types::{CheckSum, ChunkMetaData, Chunks, Duplicate, FilePlan, Plan};
+pub use writeback::FsyncPolicy;
 
+mod auth;
+mod backpressure;
+mod build_info;
 mod cli;
 mod commands;
+mod config;
+mod connections;
+pub mod control;
+mod cookies;
+mod delta;
+#[cfg(all(feature = "direct-io", target_os = "linux"))]
+mod direct_io;
+mod disk;
+mod dns;
+mod downloader;
 mod error;
+pub mod events;
+mod history;
+mod hooks;
 mod http;
+mod jobs;
+mod keys;
+mod latency;
+mod local_source;
+mod lock;
+pub mod machine_log;
+pub mod metaurl;
+mod mirrorlist;
+#[cfg(feature = "observability")]
+mod observability;
+mod politeness;
+mod progress;
+mod quarantine;
+mod rate_limit;
+mod remote;
+mod replay;
+mod report;
+mod run;
+mod selection;
+mod shutdown;
+mod sums;
+mod sync;
+pub mod telemetry;
+pub mod transport;
 mod types;
+mod validators;
+mod verification_cache;
+mod warnings;
+mod watch;
+mod writeback;
 
-use cli::{Cli, Commands};
+use commands::{DownloadFileOptions, DownloadMetalinkOptions, PlanMode};
+use config::Config;
+use http::{Concurrency, Segmentation};
+use local_source::LocalSources;
+use metaurl::MetaUrlHandlers;
+use progress::ProgressMode;
+use remote::MetalinkSource;
+use selection::{Layout, RefreshSelection};
+use transport::Transports;
 
-pub struct App {}
+#[derive(Default)]
+pub struct App {
+    metaurl_handlers: MetaUrlHandlers,
+    transports: Transports,
+    dns_resolver: Option<dns::Resolver>,
+}
 
 impl App {
-    pub async fn run(self) -> Result<()> {
-        let cli = Cli::parse();
-        match cli.command {
+    /// Register a handler for files published through metaurls, e.g. BitTorrent
+    pub fn with_metaurl_handler(mut self, handler: impl MetaUrlHandler + 'static) -> Self {
+        self.metaurl_handlers.push(std::sync::Arc::new(handler));
+        self
+    }
+
+    /// Register a transport for urls the built-in http transport does not
+    /// fetch, e.g. FTP or S3. Registered transports are tried in order and
+    /// take precedence over the built-in one.
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transports.push(std::sync::Arc::new(transport));
+        self
+    }
+
+    /// Resolve the hosts of mirrors and metalinks with this resolver instead
+    /// of the system one, e.g. over DNS over HTTPS. Hosts given with
+    /// `--resolve` are not looked up.
+    pub fn with_dns_resolver(mut self, resolver: impl reqwest::dns::Resolve + 'static) -> Self {
+        self.dns_resolver = Some(dns::Resolver(std::sync::Arc::new(resolver)));
+        self
+    }
+
+    /// Parse command line arguments for [`App::run_cli`], the first argument
+    /// is the name of the binary
+    pub fn from_args<I, T>(args: I) -> std::result::Result<Cli, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        Cli::try_parse_from(args)
+    }
+
+    /// Run parsed command line arguments, including the global options
+    pub async fn run_cli(self, cli: Cli) -> Result<()> {
+        if cli.version {
+            println!("metalink-downloader {}", env!("CARGO_PKG_VERSION"));
+            if cli.build_info {
+                println!("{}", BuildInfo::current());
+            }
+            return Ok(());
+        }
+
+        let command = cli
+            .command
+            .ok_or_else(|| anyhow!("A subcommand is required"))?;
+        let config = Config::load(cli.config.as_deref())?;
+        types::set_hash_buffer_size(cli.hash_buffer as usize);
+        types::set_hash_threads(cli.hash_threads.map(std::num::NonZeroUsize::get));
+        writeback::set_write_buffering(cli.write_buffer, cli.flush_interval);
+        writeback::set_policy(cli.fsync);
+        #[cfg(feature = "direct-io")]
+        writeback::set_direct_io(cli.direct_io);
+        self.run_command(command, config).await
+    }
+
+    /// Run `command` with the defaults of the global options and the config
+    /// file at its default location
+    pub async fn run_with(self, command: Commands) -> Result<()> {
+        let config = Config::load(None)?;
+        self.run_command(command, config).await
+    }
+
+    async fn run_command(self, command: Commands, config: Config) -> Result<()> {
+        let http_options = |http| -> Result<http::HttpOptions> {
+            let mut options = config.http_options(http)?;
+            options.dns.resolver = self.dns_resolver.clone();
+            Ok(options)
+        };
+        match command {
             Commands::Plan {
                 metalink_file,
                 target_dir,
-            } => Ok(commands::plan(metalink_file, target_dir).await?),
+                check,
+                list,
+                format,
+                filter,
+            } => Ok(commands::plan(
+                metalink_file,
+                target_dir,
+                PlanMode::from_flags(check, list, format),
+                filter.into_filter()?,
+            )
+            .await?),
             Commands::DownloadFile {
                 url,
                 target_dir,
-                user_agent,
+                output,
+                http,
                 max_threads,
-            } => Ok(commands::download_file(url, target_dir, user_agent, max_threads).await?),
+                negotiate_metalink,
+                chunk_size,
+                min_split_size,
+                reduce_on_slow_disk,
+                quiet,
+                no_atomic,
+                replay_log,
+            } => {
+                if let Some(replay_log) = replay_log {
+                    replay::start_recording(&replay_log)?;
+                }
+                rate_limit::set_schedule(config.rate_schedule(http.limit_rate)?);
+                Ok(commands::download_file(
+                    url,
+                    target_dir,
+                    DownloadFileOptions {
+                        output,
+                        http: http_options(http)?,
+                        concurrency: Concurrency {
+                            max_threads: config.max_threads(max_threads),
+                            reduce_on_slow_disk,
+                        },
+                        segmentation: Segmentation {
+                            chunk_size,
+                            min_split_size,
+                        },
+                        negotiate: negotiate_metalink,
+                        transports: self.transports,
+                        progress: ProgressMode::detect(quiet),
+                        in_place: no_atomic,
+                    },
+                )
+                .await?)
+            }
             Commands::DownloadMetalink {
                 metalink_file,
+                metalink_url,
+                metalink_dir,
+                filter,
                 target_dir,
-                user_agent,
+                http,
                 verify_chunk_checksums,
-            } => Ok(commands::download_metalink(
+                no_verify_chunk_checksums,
+                verify,
+                no_verify,
+                mirrors,
+                quarantine_dir,
+                refresh,
+                force,
+                revalidate,
+                lock,
+                no_lock,
+                dry_run,
+                format,
+                keep_going,
+                quiet,
+                preserve_timestamps,
+                write_checksums,
+                on_conflict,
+                flatten,
+                strip_components,
+                dedupe,
+                no_atomic,
+                local_source,
+                hardlink_local,
+                warnings_log,
+                replay_log,
+                watch,
+                sync_interval,
+                #[cfg(feature = "observability")]
+                metrics,
+            } => {
+                if let Some(replay_log) = replay_log {
+                    replay::start_recording(&replay_log)?;
+                }
+                if let Some(warnings_log) = warnings_log {
+                    warnings::start_recording(&warnings_log)?;
+                }
+                rate_limit::set_schedule(config.rate_schedule(http.limit_rate)?);
+                #[cfg(feature = "observability")]
+                let metrics = observability::MetricsExporter::start(metrics).await?;
+                let hooks = config.hooks();
+                let file_hooks = hooks.watch_files();
+                let mirrors = mirrors.into_selection(&config, http.allow_http)?;
+                let sources: Vec<MetalinkSource> = metalink_file
+                    .into_iter()
+                    .map(MetalinkSource::File)
+                    .chain(metalink_dir.into_iter().map(MetalinkSource::Dir))
+                    .chain(metalink_url.map(MetalinkSource::Url))
+                    .collect();
+                let options = DownloadMetalinkOptions {
+                    http: http_options(http)?,
+                    verify_chunk_checksums: config.verify_chunk_checksums(cli::flag(
+                        verify_chunk_checksums,
+                        no_verify_chunk_checksums,
+                    )),
+                    verify_files: config.verify_files(cli::flag(verify, no_verify)),
+                    quarantine_dir,
+                    filter: filter.into_filter()?,
+                    mirrors,
+                    local_sources: LocalSources::new(local_source, hardlink_local),
+                    layout: Layout::new(flatten, strip_components),
+                    dedupe,
+                    selection: RefreshSelection::new(&refresh, &force)?
+                        .with_conflicts(on_conflict)
+                        .with_revalidate(revalidate),
+                    metaurl_handlers: self.metaurl_handlers,
+                    transports: self.transports,
+                    lock: LockMode::from_flags(lock, no_lock),
+                    control: JobControl::default(),
+                    dry_run: dry_run.then_some(format),
+                    keep_going,
+                    progress: ProgressMode::detect(quiet),
+                    preserve_timestamps,
+                    in_place: no_atomic,
+                    write_checksums,
+                    signing: config.signing(),
+                };
+                // Watching and syncing report every run to the hooks
+                let continuous = watch.is_some() || sync_interval.is_some();
+                let report = match (watch, sync_interval) {
+                    (Some(dir), _) => watch::watch(dir, target_dir, options, &hooks)
+                        .await
+                        .map(|()| DownloadReport::default()),
+                    (None, Some(interval)) => {
+                        sync::sync(sources, target_dir, options, interval, &hooks)
+                            .await
+                            .map(|()| DownloadReport::default())
+                    }
+                    (None, None) => commands::download_metalink(sources, target_dir, options).await,
+                };
+                #[cfg(feature = "observability")]
+                if let Some(metrics) = metrics {
+                    metrics.finish().await?;
+                }
+                if let Some(file_hooks) = file_hooks {
+                    file_hooks.finish().await;
+                }
+                if !dry_run && !continuous {
+                    hooks.finished(&report).await;
+                }
+                report?.failure().map_or(Ok(()), Err)
+            }
+            Commands::Repair {
                 metalink_file,
                 target_dir,
-                user_agent,
-                verify_chunk_checksums,
+                http,
+                dry_run,
+                lock,
+                no_lock,
+                keep_going,
+                mirrors,
+            } => {
+                rate_limit::set_schedule(config.rate_schedule(http.limit_rate)?);
+                let mirrors = mirrors.into_selection(&config, http.allow_http)?;
+                Ok(commands::repair(
+                    metalink_file,
+                    target_dir,
+                    http_options(http)?,
+                    mirrors,
+                    dry_run,
+                    LockMode::from_flags(lock, no_lock),
+                    keep_going,
+                )
+                .await?)
+            }
+            Commands::Generate {
+                dir,
+                from_sums,
+                base_url,
+                piece_length,
+                hash,
+                mirrors,
+                output,
+                http,
+            } => {
+                let mirrors = match mirrors {
+                    Some(mirrors) => mirrorlist::read(&mirrors)?,
+                    None => Vec::new(),
+                };
+                match (from_sums, dir) {
+                    (Some(sums), dir) => Ok(commands::generate_from_sums(
+                        sums,
+                        dir,
+                        base_url,
+                        mirrors,
+                        hash,
+                        http_options(http)?,
+                        output,
+                    )
+                    .await?),
+                    (None, Some(dir)) => Ok(commands::generate(
+                        dir,
+                        base_url,
+                        mirrors,
+                        piece_length,
+                        hash.unwrap_or(HashFunctionTextualName::Sha256),
+                        output,
+                    )
+                    .await?),
+                    (None, None) => Err(anyhow!("--dir is required without --from-sums").into()),
+                }
+            }
+            Commands::Sign {
+                metalink_file,
+                dir,
+                key,
+                gpg,
+                output,
+            } => Ok(commands::sign(metalink_file, dir, key, gpg, output).await?),
+            Commands::Lint {
+                metalink_file,
+                deny,
+            } => Ok(commands::lint(metalink_file, deny, &self.transports).await?),
+            Commands::Probe {
+                metalink_file,
+                max_requests,
+                filter,
+                http,
+            } => Ok(commands::probe(
+                metalink_file,
+                filter.into_filter()?,
+                http_options(http)?,
+                max_requests,
+                &self.transports,
+            )
+            .await?),
+            Commands::PlanDiff {
+                old,
+                new,
+                target_dir,
+                filter,
+            } => Ok(commands::plan_diff(old, new, target_dir, filter.into_filter()?).await?),
+            Commands::CrossCheck {
+                metalink_file,
+                against,
+                target_dir,
+                filter,
+            } => Ok(commands::cross_check(
+                metalink_file,
+                against,
+                target_dir,
+                filter.into_filter()?,
+            )
+            .await?),
+            Commands::Verify {
+                sums,
+                target_dir,
+                hash,
+                metalink_file,
+            } => Ok(commands::verify(sums, target_dir, hash, metalink_file).await?),
+            Commands::History {
+                target_dir,
+                files,
+                mirrors,
+                since,
+                by_mirror,
+                format,
+            } => Ok(commands::history(
+                target_dir,
+                commands::HistoryQuery {
+                    files,
+                    mirrors,
+                    since,
+                },
+                by_mirror,
+                format,
             )
             .await?),
+            Commands::Daemon {
+                listen,
+                state_file,
+                max_jobs,
+                http,
+            } => {
+                rate_limit::set_schedule(config.rate_schedule(http.limit_rate)?);
+                let downloader = MetalinkDownloader::builder()
+                    .http_options(http_options(http)?)
+                    .verify_chunk_checksums(config.verify_chunk_checksums(None))
+                    .verify_files(config.verify_files(None))
+                    // Jobs wait for command line runs into the same directory
+                    .lock(LockMode::Wait)
+                    .extensions(self.metaurl_handlers, self.transports);
+                let state_file = match state_file {
+                    Some(state_file) => state_file,
+                    None => commands::default_state_file()?,
+                };
+                Ok(commands::daemon(listen, state_file, max_jobs, downloader).await?)
+            }
+            Commands::Job { socket, verb } => Ok(commands::job(socket, verb).await?),
+            Commands::Replay { log } => Ok(commands::replay(log).await?),
         }
     }
 }
@@ -0,0 +1,196 @@
+//! Local sources of the files of a metalink.
+//!
+//! Files already present in a local mirror, a mounted share or a previous
+//! download are copied or hard linked instead of fetched over the network.
+//! Files with pieces are copied piece by piece, only valid pieces are taken
+//! and the remaining ones are downloaded from the mirrors.
+
+use crate::http::{copy_local_file, copy_local_ranges};
+use crate::progress::{ProgressSender, ProgressUpdate};
+use crate::types::{Chunks, FilePlan};
+use crate::Result;
+
+use anyhow::{anyhow, Context};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// What a local source provided of a file
+#[derive(Debug)]
+pub(crate) enum LocalCopy {
+    /// The whole file was copied
+    Complete,
+    /// These chunks still have to be downloaded
+    Missing(Chunks),
+    /// The source does not have the file
+    Unavailable,
+}
+
+/// Directories searched for the files of a metalink before downloading them
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LocalSources {
+    dirs: Vec<PathBuf>,
+    /// Hard link whole files instead of copying them
+    hardlink: bool,
+}
+
+impl LocalSources {
+    /// Files are looked up by their name in the metalink, earlier
+    /// directories are searched first
+    pub(crate) fn new(dirs: Vec<PathBuf>, hardlink: bool) -> Self {
+        Self { dirs, hardlink }
+    }
+
+    /// Copy what the first directory containing `file` has of it to `path`
+    pub(crate) async fn copy(
+        &self,
+        file: &FilePlan,
+        path: &Path,
+        tx: &ProgressSender,
+    ) -> Result<LocalCopy> {
+        let Some(source) = self
+            .dirs
+            .iter()
+            .map(|dir| dir.join(&file.name))
+            .find(|source| source.is_file())
+        else {
+            return Ok(LocalCopy::Unavailable);
+        };
+        tracing::info!("Found {} in local source {source:?}", file.name);
+        copy_from(source, file, path, tx, self.hardlink).await
+    }
+
+    /// Copy `file` from the `file://` mirror `source` to `path`. Unlike a
+    /// local source directory the mirror must provide the whole file.
+    pub(crate) async fn copy_mirror(
+        &self,
+        source: PathBuf,
+        file: &FilePlan,
+        path: &Path,
+        tx: &ProgressSender,
+    ) -> Result<()> {
+        match copy_from(source.clone(), file, path, tx, self.hardlink).await? {
+            LocalCopy::Complete => Ok(()),
+            LocalCopy::Missing(chunks) => {
+                Err(anyhow!("{} of the pieces of {source:?} are invalid", chunks.len()).into())
+            }
+            LocalCopy::Unavailable => Err(anyhow!("{source:?} does not match the metalink").into()),
+        }
+    }
+}
+
+/// Hashing the source must not block the runtime
+async fn copy_from(
+    source: PathBuf,
+    file: &FilePlan,
+    path: &Path,
+    tx: &ProgressSender,
+    hardlink: bool,
+) -> Result<LocalCopy> {
+    let file = file.clone();
+    let path = path.to_path_buf();
+    let tx = tx.clone();
+    tokio::task::spawn_blocking(move || copy_blocking(&source, &file, &path, &tx, hardlink))
+        .await
+        .with_context(|| "Local copy task failed")?
+}
+
+fn copy_blocking(
+    source: &Path,
+    file: &FilePlan,
+    path: &Path,
+    tx: &ProgressSender,
+    hardlink: bool,
+) -> Result<LocalCopy> {
+    if let Some(chunks) = &file.chunks {
+        let missing: HashSet<u64> = copy_local_ranges(source, path, &chunks.to_vec(), Some(tx))?
+            .into_iter()
+            .map(|chunk| chunk.start)
+            .collect();
+        tracing::info!(
+            "Copied {} of {} pieces of {:?} from {source:?}",
+            chunks.len() - missing.len(),
+            chunks.len(),
+            file.target_file
+        );
+        if missing.is_empty() {
+            return Ok(LocalCopy::Complete);
+        }
+        return Ok(LocalCopy::Missing(
+            chunks.filter(|chunk| missing.contains(&chunk.start)),
+        ));
+    }
+    let size = std::fs::metadata(source)
+        .with_context(|| format!("Failed to read metadata of {source:?}"))?
+        .len();
+    if file.file_size.is_some_and(|expected| expected != size) {
+        tracing::info!("{source:?} has a different size than {}", file.name);
+        return Ok(LocalCopy::Unavailable);
+    }
+    if let Some(checksum) = &file.file_checksums {
+        if !checksum.validate_file_checksum(source) {
+            tracing::info!("{source:?} does not match the checksum of {}", file.name);
+            return Ok(LocalCopy::Unavailable);
+        }
+    }
+    let bytes = copy_local_file(source, path, hardlink)?;
+    tx.send(ProgressUpdate::Progressed {
+        file: Arc::from(file.target_file.as_path()),
+        bytes,
+    })?;
+    Ok(LocalCopy::Complete)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::progress_channel;
+    use crate::selection::{Layout, MirrorSelection};
+    use crate::types::Plan;
+
+    #[tokio::test]
+    async fn valid_pieces_are_copied_from_local_sources() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source_dir = dir.join("source");
+        let target_dir = dir.join("target");
+        std::fs::create_dir_all(&source_dir).unwrap();
+        // "abc" and "def" as pieces of three bytes, the second piece of the
+        // local copy is corrupt
+        std::fs::write(source_dir.join("file.txt"), b"abcxyz").unwrap();
+        let metalink = dir.join("test.meta4");
+        std::fs::write(
+            &metalink,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="file.txt">
+    <size>6</size>
+    <pieces length="3" type="sha-256">
+      <hash>ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad</hash>
+      <hash>cb8379ac2098aa165029e3938a51da0bcecfc008fd6795f401178647f96c5b34</hash>
+    </pieces>
+    <url>https://example.com/file.txt</url>
+  </file>
+</metalink>"#,
+        )
+        .unwrap();
+        let plan = Plan::new(
+            metalink,
+            &target_dir,
+            &Default::default(),
+            &MirrorSelection::default(),
+            &Layout::default(),
+        )
+        .unwrap();
+        let file = &plan.files[0];
+        let (tx, _rx) = progress_channel(16);
+
+        let sources = LocalSources::new(vec![dir.join("missing"), source_dir], false);
+        let copy = sources.copy(file, &file.target_file, &tx).await.unwrap();
+        let LocalCopy::Missing(missing) = copy else {
+            panic!("expected missing pieces, got {copy:?}");
+        };
+        assert_eq!(missing.iter().map(|c| c.start).collect::<Vec<_>>(), [3]);
+        assert_eq!(&std::fs::read(&file.target_file).unwrap()[..3], b"abc");
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{MetalinkDownloadError, Result};
+
+use anyhow::Context;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the lock file inside the target directory
+const LOCK_FILE: &str = ".metalink-downloader.lock";
+
+/// How to treat the target directory lock
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LockMode {
+    /// Fail if another run holds the lock
+    #[default]
+    Fail,
+    /// Wait until the other run released the lock
+    Wait,
+    /// Do not lock the target directory
+    #[value(skip)]
+    Disabled,
+}
+
+impl LockMode {
+    /// `--lock` unless the lock is disabled with `--no-lock`
+    pub(crate) fn from_flags(lock: LockMode, no_lock: bool) -> Self {
+        if no_lock {
+            LockMode::Disabled
+        } else {
+            lock
+        }
+    }
+}
+
+/// Advisory lock on a target directory, released when dropped
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+/// Lock `target_dir` so concurrent runs do not interleave their writes.
+/// The lock file records the process holding the lock for diagnostics.
+pub(crate) async fn lock_target_dir(target_dir: &Path, mode: LockMode) -> Result<Option<DirLock>> {
+    if mode == LockMode::Disabled {
+        return Ok(None);
+    }
+    std::fs::create_dir_all(target_dir)?;
+    let path = target_dir.join(LOCK_FILE);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Failed to open lock file: {path:?}"))?;
+
+    let file = match file.try_lock() {
+        Ok(()) => file,
+        Err(TryLockError::WouldBlock) => {
+            let holder = std::fs::read_to_string(&path).unwrap_or_default();
+            let holder = holder.trim().to_owned();
+            if mode == LockMode::Fail {
+                return Err(MetalinkDownloadError::Locked {
+                    dir: target_dir.to_path_buf(),
+                    holder,
+                });
+            }
+            eprintln!("Waiting for the lock on {target_dir:?} held by {holder}");
+            tokio::task::spawn_blocking(move || file.lock().map(|_| file))
+                .await
+                .with_context(|| "Waiting for the lock failed")??
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(anyhow::Error::new(e)
+                .context(format!("Failed to lock {path:?}"))
+                .into())
+        }
+    };
+    write_holder(&file, &path)?;
+    Ok(Some(DirLock { _file: file }))
+}
+
+fn write_holder(mut file: &File, path: &Path) -> Result<()> {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let command: Vec<String> = std::env::args().collect();
+    file.set_len(0)?;
+    writeln!(
+        file,
+        "pid {} since {since} (unix time): {}",
+        std::process::id(),
+        command.join(" ")
+    )
+    .with_context(|| format!("Failed to write lock file: {path:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn second_lock_reports_holder() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let lock = lock_target_dir(dir, LockMode::Fail).await.unwrap();
+        assert!(lock.is_some());
+
+        match lock_target_dir(dir, LockMode::Fail).await {
+            Err(MetalinkDownloadError::Locked { holder, .. }) => {
+                assert!(holder.starts_with(&format!("pid {}", std::process::id())))
+            }
+            other => panic!("expected lock error, got {other:?}"),
+        }
+        assert!(lock_target_dir(dir, LockMode::Disabled)
+            .await
+            .unwrap()
+            .is_none());
+
+        drop(lock);
+        assert!(lock_target_dir(dir, LockMode::Fail)
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
@@ -0,0 +1,306 @@
+//! Machine readable log formats written by the downloader.
+//!
+//! Every line of a machine log is a JSON object carrying a `schema_version`
+//! next to the record itself. Field names and event tags are stable snake_case
+//! identifiers and never contain localized text.
+//!
+//! # Compatibility policy
+//!
+//! * Adding new event types or new optional fields is not a breaking change and
+//!   does not bump [`SCHEMA_VERSION`]. Parsers should ignore unknown fields, unknown
+//!   event types deserialize to the `Unknown` variant of the event enums.
+//! * Removing or renaming fields or events, or changing their meaning, bumps
+//!   [`SCHEMA_VERSION`]. Records with a newer schema version than the parser
+//!   understands are rejected by [`Record::check_compatible`].
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Current version of the machine log schema
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A single line of a machine log
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Record<E> {
+    /// Schema version the record was written with
+    pub schema_version: u32,
+    /// Sequence number of the record within the log
+    pub seq: u64,
+    /// The recorded event
+    #[serde(flatten)]
+    pub event: E,
+}
+
+impl<E> Record<E> {
+    /// Create a record for the current schema version
+    pub fn new(seq: u64, event: E) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            seq,
+            event,
+        }
+    }
+
+    /// Returns whether this version of the library understands the record
+    pub fn check_compatible(&self) -> Result<(), IncompatibleSchema> {
+        if self.schema_version > SCHEMA_VERSION {
+            return Err(IncompatibleSchema {
+                found: self.schema_version,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A record was written with a newer, incompatible schema version
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[error("Unsupported machine log schema version {found}, supported up to {SCHEMA_VERSION}")]
+pub struct IncompatibleSchema {
+    /// The schema version found in the record
+    pub found: u32,
+}
+
+/// Outcome of a single range request as seen by the scheduler
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchOutcome {
+    /// The range was fetched (and verified if a checksum is known)
+    Ok,
+    /// The range was fetched but failed checksum validation
+    ChecksumMismatch,
+    /// The request failed
+    Failed,
+    /// Outcome written by a newer version of the downloader
+    #[serde(other)]
+    Unknown,
+}
+
+/// Scheduler decisions and transport outcomes recorded to the replay log
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ReplayEvent {
+    /// A chunk was handed to a download task
+    ChunkScheduled {
+        /// The target file of the chunk
+        file: PathBuf,
+        /// First byte of the chunk
+        start: u64,
+        /// Last byte of the chunk
+        end: u64,
+    },
+    /// A request for a chunk finished
+    ChunkFetched {
+        /// The target file of the chunk
+        file: PathBuf,
+        /// First byte of the chunk
+        start: u64,
+        /// Last byte of the chunk
+        end: u64,
+        /// Outcome of the request
+        outcome: FetchOutcome,
+    },
+    /// Downloaded bytes were written to the target file
+    ChunkWritten {
+        /// The target file
+        file: PathBuf,
+        /// Offset the bytes were written at
+        offset: u64,
+        /// Number of bytes written
+        bytes: u64,
+    },
+    /// Progress was reported to the progress display
+    ProgressReported {
+        /// The target file
+        file: PathBuf,
+        /// Number of bytes reported
+        bytes: u64,
+    },
+    /// Event written by a newer version of the downloader
+    #[serde(other)]
+    Unknown,
+}
+
+/// Something noteworthy that did not fail the run, collected during parsing,
+/// planning and downloading and written to the warnings log
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(tag = "warning", rename_all = "snake_case")]
+pub enum Warning {
+    /// The metalink only parsed with lenient parsing
+    LenientParse {
+        /// The metalink
+        metalink: PathBuf,
+        /// Why strict parsing failed
+        reason: String,
+    },
+    /// The strongest checksum of a file uses a hash function that is no
+    /// longer collision resistant
+    WeakHash {
+        /// Name of the file in the metalink
+        file: String,
+        /// The hash function
+        hash_type: String,
+    },
+    /// A checksum was ignored because its length does not match its hash function
+    InvalidHash {
+        /// Name of the file in the metalink
+        file: String,
+        /// The hash function
+        hash_type: String,
+        /// The ignored checksum
+        value: String,
+    },
+    /// A mirror of a file was given up on in favour of another source
+    SkippedMirror {
+        /// The target file
+        file: PathBuf,
+        /// The mirror given up on
+        url: url::Url,
+        /// Why the mirror was skipped
+        reason: String,
+    },
+    /// The clock of a server differs from the local clock
+    ClockSkew {
+        /// The server
+        host: String,
+        /// Seconds the server clock is ahead of the local clock, negative if it
+        /// is behind
+        skew_secs: i64,
+    },
+    /// Mirrors of the plan serve files over plain http
+    InsecureMirrors {
+        /// The insecure mirrors
+        urls: Vec<url::Url>,
+        /// Whether the mirrors are used, they are skipped unless plain http
+        /// is allowed
+        allowed: bool,
+    },
+    /// A chunk served after a redirect of the mirror failed checksum
+    /// validation, the redirect target may serve something else than the file
+    RedirectedChecksumMismatch {
+        /// The target file
+        file: PathBuf,
+        /// The mirror
+        url: url::Url,
+        /// The url the mirror redirected to
+        final_url: url::Url,
+    },
+    /// Downloading a file was limited by writing it to disk
+    SlowDisk {
+        /// The target file
+        file: PathBuf,
+        /// How often the download tasks found the writer queue full
+        stalls: u64,
+        /// Milliseconds the download tasks waited for the disk
+        waited_ms: u64,
+    },
+    /// Warning written by a newer version of the downloader
+    #[serde(other)]
+    Unknown,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::LenientParse { metalink, reason } => {
+                write!(
+                    f,
+                    "{metalink:?} is not a valid metalink, parsed it leniently: {reason}"
+                )
+            }
+            Warning::WeakHash { file, hash_type } => {
+                write!(f, "{file} is only protected by the weak hash {hash_type}")
+            }
+            Warning::InvalidHash {
+                file,
+                hash_type,
+                value,
+            } => write!(
+                f,
+                "{file}: ignoring {hash_type} hash with invalid length: {value}"
+            ),
+            Warning::SkippedMirror { file, url, reason } => {
+                write!(f, "{file:?}: skipped mirror {url}: {reason}")
+            }
+            Warning::ClockSkew { host, skew_secs } => {
+                write!(
+                    f,
+                    "The clock of {host} differs from the local clock by {skew_secs}s"
+                )
+            }
+            Warning::InsecureMirrors { urls, allowed } => {
+                let urls: Vec<String> = urls.iter().map(url::Url::to_string).collect();
+                if *allowed {
+                    write!(f, "Using insecure http mirrors: {}", urls.join(", "))
+                } else {
+                    write!(
+                        f,
+                        "Skipped insecure http mirrors, use --allow-http to use them: {}",
+                        urls.join(", ")
+                    )
+                }
+            }
+            Warning::RedirectedChecksumMismatch {
+                file,
+                url,
+                final_url,
+            } => write!(
+                f,
+                "{file:?}: checksum mismatch of data from {final_url}, redirected from {url}"
+            ),
+            Warning::SlowDisk {
+                file,
+                stalls,
+                waited_ms,
+            } => write!(
+                f,
+                "{file:?}: the disk is slower than the network, the writer queue was full {stalls} times and downloads waited {:.1}s for the disk",
+                *waited_ms as f64 / 1000.0
+            ),
+            Warning::Unknown => write!(f, "unknown warning"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_round_trips_through_json() {
+        let record = Record::new(
+            3,
+            ReplayEvent::ChunkFetched {
+                file: "/x".into(),
+                start: 10,
+                end: 19,
+                outcome: FetchOutcome::ChecksumMismatch,
+            },
+        );
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            line,
+            r#"{"schema_version":1,"seq":3,"event":"chunk_fetched","file":"/x","start":10,"end":19,"outcome":"checksum_mismatch"}"#
+        );
+        let parsed: Record<ReplayEvent> = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed, record);
+    }
+
+    #[test]
+    fn unknown_events_and_fields_are_tolerated() {
+        let line = r#"{"schema_version":1,"seq":0,"event":"mirror_switched","mirror":"x"}"#;
+        let parsed: Record<ReplayEvent> = serde_json::from_str(line).unwrap();
+        assert_eq!(parsed.event, ReplayEvent::Unknown);
+        assert_eq!(parsed.check_compatible(), Ok(()));
+    }
+
+    #[test]
+    fn newer_schema_versions_are_rejected() {
+        let line =
+            r#"{"schema_version":2,"seq":0,"event":"progress_reported","file":"/x","bytes":1}"#;
+        let parsed: Record<ReplayEvent> = serde_json::from_str(line).unwrap();
+        assert_eq!(
+            parsed.check_compatible(),
+            Err(IncompatibleSchema { found: 2 })
+        );
+    }
+}
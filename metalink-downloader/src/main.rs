@@ -1,27 +1,36 @@
 use anyhow::{anyhow, Context};
-use log::LevelFilter;
-use log4rs::append::file::FileAppender;
-use log4rs::config::{Appender, Config, Root};
 
-use metalink_downloader::{App, MetalinkDownloadError, Result};
+use clap::{CommandFactory, Parser};
+use metalink_downloader::{telemetry, App, Cli, MetalinkDownloadError, Result};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let logfile = FileAppender::builder().build("log/output.log")?;
-
-    let config = Config::builder()
-        .appender(Appender::builder().build("logfile", Box::new(logfile)))
-        .build(
-            Root::builder()
-                .appender("logfile")
-                .build(LevelFilter::Debug),
-        )
-        .context(MetalinkDownloadError::Other(anyhow!("")))?;
-
-    log4rs::init_config(config).context(MetalinkDownloadError::Other(anyhow!(
-        "Failed to init logging"
+    // Colors, unicode and links are picked from what the terminal supports
+    miette::set_hook(Box::new(|_| {
+        Box::new(miette::MietteHandlerOpts::new().context_lines(2).build())
+    }))
+    .context(MetalinkDownloadError::Other(anyhow!(
+        "Failed to install error report handler"
     )))?;
 
-    let app = App {};
-    app.run().await
+    let cli = Cli::parse();
+    if cli.command.is_none() && !cli.version {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "a subcommand is required",
+            )
+            .exit()
+    }
+    let telemetry = telemetry::init(&cli)?;
+    let app = App::default();
+    let result = app.run_cli(cli).await;
+    // Exiting skips destructors, spans not exported yet are flushed first
+    drop(telemetry);
+    if let Err(e) = result {
+        let exit_code = e.exit_code();
+        eprintln!("{:?}", miette::Report::new(e));
+        std::process::exit(exit_code);
+    }
+    Ok(())
 }
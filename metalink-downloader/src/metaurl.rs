@@ -0,0 +1,95 @@
+//! Extension point for downloading files published through metalink:metaurl
+//! elements, e.g. BitTorrent, instead of plain urls.
+
+use crate::Result;
+
+use futures::future::BoxFuture;
+use metalink::{MetaUrl, TorrentOrMime};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Fetches files described by a metalink:metaurl element.
+///
+/// Handlers are used for files without a url and as a fallback when
+/// downloading a file from its url fails.
+pub trait MetaUrlHandler: Send + Sync {
+    /// Returns whether the handler can fetch metaurls of the given media type
+    fn supports(&self, media_type: &TorrentOrMime) -> bool;
+
+    /// Fetch the file described by `metaurl` and store it at `target_file`
+    fn fetch<'a>(
+        &'a self,
+        metaurl: &'a MetaUrl,
+        target_file: &'a Path,
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+/// The registered metaurl handlers
+#[derive(Clone, Default)]
+pub(crate) struct MetaUrlHandlers(Vec<Arc<dyn MetaUrlHandler>>);
+
+impl MetaUrlHandlers {
+    pub(crate) fn push(&mut self, handler: Arc<dyn MetaUrlHandler>) {
+        self.0.push(handler);
+    }
+
+    /// Find the metaurl with the highest priority (lowest value) a handler
+    /// is registered for, together with that handler
+    pub(crate) fn find<'a>(
+        &self,
+        metaurls: &'a [MetaUrl],
+    ) -> Option<(Arc<dyn MetaUrlHandler>, &'a MetaUrl)> {
+        let mut metaurls: Vec<&MetaUrl> = metaurls.iter().collect();
+        metaurls.sort_by_key(|metaurl| metaurl.priority().unwrap_or(u32::MAX));
+        metaurls.into_iter().find_map(|metaurl| {
+            self.0
+                .iter()
+                .find(|handler| handler.supports(metaurl.mediatype()))
+                .map(|handler| (handler.clone(), metaurl))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TorrentHandler;
+
+    impl MetaUrlHandler for TorrentHandler {
+        fn supports(&self, media_type: &TorrentOrMime) -> bool {
+            *media_type == TorrentOrMime::Torrent
+        }
+
+        fn fetch<'a>(&'a self, _: &'a MetaUrl, _: &'a Path) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn metaurl(priority: u32, media_type: TorrentOrMime) -> MetaUrl {
+        MetaUrl::new(
+            url::Url::parse(&format!("https://example.com/{priority}")).unwrap(),
+            media_type,
+            Some(priority),
+            None,
+        )
+    }
+
+    #[test]
+    fn find_prefers_supported_metaurl_with_highest_priority() {
+        let mut handlers = MetaUrlHandlers::default();
+        let metaurls = vec![
+            metaurl(3, TorrentOrMime::Torrent),
+            metaurl(
+                1,
+                TorrentOrMime::Mime("application/x-ipfs".parse().unwrap()),
+            ),
+            metaurl(2, TorrentOrMime::Torrent),
+        ];
+        assert!(handlers.find(&metaurls).is_none());
+
+        handlers.push(Arc::new(TorrentHandler));
+        let (_, found) = handlers.find(&metaurls).unwrap();
+        assert_eq!(found.priority(), Some(2));
+    }
+}
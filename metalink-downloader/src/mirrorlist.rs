@@ -0,0 +1,182 @@
+//! Mirror lists naming the base urls a tree is published under.
+//!
+//! Every line holds the base url of a mirror, optionally with the ISO 3166-1
+//! alpha-2 code of the country the mirror is located in and its priority,
+//! lower is preferred, e.g. `https://mirror.example/pub/ de 10`. The columns
+//! may come in any order and further columns like the contact address of
+//! mirmon lists (`de https://mirror.example/pub/ admin@mirror.example`) are
+//! ignored. Empty lines and lines starting with `#` are skipped.
+
+use crate::Result;
+
+use anyhow::{anyhow, Context};
+use isocountry::CountryCode;
+use metalink::FileUrl;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::path::Path;
+
+/// Characters which are not taken literally in a path segment of a url
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b']')
+    .add(b'\\')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// A mirror of a mirror list
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Mirror {
+    /// The url the tree is published under, ending in a `/`
+    pub base_url: url::Url,
+    pub location: Option<CountryCode>,
+    pub priority: Option<u32>,
+}
+
+impl Mirror {
+    pub(crate) fn new(mut base_url: url::Url) -> Self {
+        // File names are joined onto the base url, which replaces the last
+        // path segment unless the url denotes a directory
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        Self {
+            base_url,
+            location: None,
+            priority: None,
+        }
+    }
+
+    /// The url of the file `name` on the mirror, the segments of `name` are
+    /// percent-encoded so they are not read as query or fragment
+    pub(crate) fn file_url(&self, name: &str) -> Result<FileUrl> {
+        let path: Vec<String> = name
+            .split('/')
+            .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+            .collect();
+        Ok(FileUrl::new(
+            self.base_url.join(&path.join("/"))?,
+            self.priority,
+            self.location,
+        ))
+    }
+}
+
+/// The urls of the file `name` on all `mirrors`
+pub(crate) fn file_urls(mirrors: &[Mirror], name: &str) -> Result<Vec<FileUrl>> {
+    mirrors.iter().map(|mirror| mirror.file_url(name)).collect()
+}
+
+/// Read the mirror list in `path`
+pub(crate) fn read(path: &Path) -> Result<Vec<Mirror>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read mirror list {path:?}"))?;
+    Ok(parse(&content).with_context(|| format!("Invalid mirror list {path:?}"))?)
+}
+
+fn parse(content: &str) -> anyhow::Result<Vec<Mirror>> {
+    let mut mirrors = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut base_url = None;
+        let mut location = None;
+        let mut priority = None;
+        for column in line.split_whitespace() {
+            if column.contains("://") {
+                base_url = Some(
+                    url::Url::parse(column)
+                        .with_context(|| format!("Invalid url {column:?} in line {}", i + 1))?,
+                );
+            } else if let Ok(value) = column.parse::<u32>() {
+                if !(1..=999_999).contains(&value) {
+                    return Err(anyhow!(
+                        "Priority {value} in line {} is not between 1 and 999999",
+                        i + 1
+                    ));
+                }
+                priority = Some(value);
+            } else if column.len() == 2 {
+                location =
+                    Some(CountryCode::for_alpha2_caseless(column).with_context(|| {
+                        format!("Unknown country {column:?} in line {}", i + 1)
+                    })?);
+            }
+        }
+        let base_url = base_url.ok_or_else(|| anyhow!("No url in line {}", i + 1))?;
+        mirrors.push(Mirror {
+            location,
+            priority,
+            ..Mirror::new(base_url)
+        });
+    }
+    Ok(mirrors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirror_lists_are_parsed() {
+        let mirrors = parse(
+            "# mirrors\n\
+             https://a.example/pub\n\
+             \n\
+             https://b.example/pub/ de 10\n\
+             us http://c.example/ admin@c.example\n",
+        )
+        .unwrap();
+        assert_eq!(
+            mirrors,
+            [
+                Mirror {
+                    base_url: url::Url::parse("https://a.example/pub/").unwrap(),
+                    location: None,
+                    priority: None,
+                },
+                Mirror {
+                    base_url: url::Url::parse("https://b.example/pub/").unwrap(),
+                    location: Some(CountryCode::DEU),
+                    priority: Some(10),
+                },
+                Mirror {
+                    base_url: url::Url::parse("http://c.example/").unwrap(),
+                    location: Some(CountryCode::USA),
+                    priority: None,
+                },
+            ]
+        );
+        assert_eq!(
+            mirrors[1].file_url("dir/file.iso").unwrap().url().as_str(),
+            "https://b.example/pub/dir/file.iso"
+        );
+
+        assert!(parse("de 10\n").is_err());
+        assert!(parse("https://a.example/ xx\n").is_err());
+        assert!(parse("https://a.example/ 0\n").is_err());
+    }
+
+    #[test]
+    fn file_names_are_percent_encoded() {
+        let mirror = Mirror::new(url::Url::parse("https://a.example/pub/").unwrap());
+        let url = |name| mirror.file_url(name).unwrap().url().to_string();
+        assert_eq!(url("sub/a.iso"), "https://a.example/pub/sub/a.iso");
+        assert_eq!(url("a#1.iso"), "https://a.example/pub/a%231.iso");
+        assert_eq!(
+            url("dir?/100% b.iso"),
+            "https://a.example/pub/dir%3F/100%25%20b.iso"
+        );
+    }
+}
@@ -0,0 +1,402 @@
+//! Prometheus metrics of running downloads for unattended mirroring.
+//!
+//! The metrics are aggregated from the [download events](crate::events) and
+//! exported in the Prometheus text format, served over http with
+//! `--metrics-listen` or written to a file for the textfile collector of the
+//! node exporter with `--metrics-textfile`.
+
+use crate::cli::MetricsArgs;
+use crate::events::{self, DownloadEvent};
+use crate::Result;
+
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// How often `--metrics-textfile` is rewritten
+const TEXTFILE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Number of http requests of the built-in transport transferring data
+static ACTIVE_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a request of the built-in transport as active while alive
+pub(crate) struct ActiveConnection(());
+
+impl ActiveConnection {
+    pub(crate) fn open() -> Self {
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters aggregated from the download events
+#[derive(Debug, Default)]
+struct Metrics {
+    /// When the first download was planned, the ETA is based on the
+    /// throughput since then
+    started: Option<Instant>,
+    planned_bytes: u64,
+    downloaded_bytes: u64,
+    files_downloaded: u64,
+    files_failed: u64,
+    files_cancelled: u64,
+    verification_failures: u64,
+    retries: u64,
+    /// Failed requests and abandoned mirrors by host
+    mirror_errors: BTreeMap<String, u64>,
+}
+
+impl Metrics {
+    fn record(&mut self, event: &DownloadEvent, now: Instant) {
+        match event {
+            DownloadEvent::DownloadPlanned { bytes, .. } => {
+                self.started.get_or_insert(now);
+                self.planned_bytes += bytes;
+            }
+            DownloadEvent::Progressed { bytes, .. } => self.downloaded_bytes += bytes,
+            DownloadEvent::FileDownloaded { .. } => self.files_downloaded += 1,
+            DownloadEvent::VerificationFailed { .. } => self.verification_failures += 1,
+            DownloadEvent::FileFailed { .. } => self.files_failed += 1,
+            DownloadEvent::FileCancelled { .. } => self.files_cancelled += 1,
+            DownloadEvent::Retry { url, .. } => {
+                self.retries += 1;
+                self.mirror_error(url);
+            }
+            DownloadEvent::MirrorSwitched { from, .. } => self.mirror_error(from),
+            _ => {}
+        }
+    }
+
+    fn mirror_error(&mut self, url: &url::Url) {
+        let host = url.host_str().unwrap_or_else(|| url.scheme());
+        *self.mirror_errors.entry(host.to_owned()).or_default() += 1;
+    }
+
+    /// Seconds until the planned bytes are downloaded at the throughput so
+    /// far, None before anything was downloaded
+    fn eta(&self, now: Instant) -> Option<f64> {
+        let remaining = self.planned_bytes.saturating_sub(self.downloaded_bytes);
+        if remaining == 0 {
+            return Some(0.0);
+        }
+        let elapsed = now.duration_since(self.started?).as_secs_f64();
+        if self.downloaded_bytes == 0 || elapsed == 0.0 {
+            return None;
+        }
+        Some(remaining as f64 * elapsed / self.downloaded_bytes as f64)
+    }
+
+    /// The metrics in the Prometheus text exposition format
+    fn render(&self, now: Instant, active_connections: u64) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            let _ = writeln!(out, "# HELP metalink_downloader_{name} {help}");
+            let _ = writeln!(out, "# TYPE metalink_downloader_{name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "metalink_downloader_{name}{labels} {value}");
+            }
+        };
+        let plain = |value: u64| vec![(String::new(), value.to_string())];
+        metric(
+            "planned_bytes_total",
+            "counter",
+            "Bytes planned to be downloaded.",
+            &plain(self.planned_bytes),
+        );
+        metric(
+            "downloaded_bytes_total",
+            "counter",
+            "Bytes downloaded or copied.",
+            &plain(self.downloaded_bytes),
+        );
+        metric(
+            "files_total",
+            "counter",
+            "Files which finished downloading by outcome.",
+            &[
+                ("downloaded", self.files_downloaded),
+                ("failed", self.files_failed),
+                ("cancelled", self.files_cancelled),
+            ]
+            .map(|(outcome, value)| (format!("{{outcome=\"{outcome}\"}}"), value.to_string())),
+        );
+        metric(
+            "verification_failures_total",
+            "counter",
+            "Downloaded files not matching their checksum.",
+            &plain(self.verification_failures),
+        );
+        metric(
+            "retries_total",
+            "counter",
+            "Requests sent again after a failed attempt.",
+            &plain(self.retries),
+        );
+        metric(
+            "mirror_errors_total",
+            "counter",
+            "Failed requests and abandoned mirrors by host.",
+            &self
+                .mirror_errors
+                .iter()
+                .map(|(host, value)| {
+                    (
+                        format!("{{host=\"{}\"}}", escape_label(host)),
+                        value.to_string(),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        );
+        metric(
+            "active_connections",
+            "gauge",
+            "Http requests transferring data.",
+            &plain(active_connections),
+        );
+        metric(
+            "eta_seconds",
+            "gauge",
+            "Estimated seconds until the planned bytes are downloaded.",
+            &self
+                .eta(now)
+                .map(|eta| (String::new(), format!("{eta:.0}")))
+                .into_iter()
+                .collect::<Vec<_>>(),
+        );
+        out
+    }
+}
+
+/// Escape a label value of the text exposition format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Exports the metrics of the downloads of the process until finished
+pub(crate) struct MetricsExporter {
+    metrics: Arc<Mutex<Metrics>>,
+    textfile: Option<PathBuf>,
+    stop: CancellationToken,
+    aggregator: JoinHandle<()>,
+}
+
+impl MetricsExporter {
+    /// Start aggregating events and exporting them as configured by `args`,
+    /// None if no export was requested
+    pub(crate) async fn start(args: MetricsArgs) -> Result<Option<Self>> {
+        if args.metrics_listen.is_none() && args.metrics_textfile.is_none() {
+            return Ok(None);
+        }
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        let stop = CancellationToken::new();
+        // Subscribed before returning, events of the download must not be
+        // sent before the aggregator listens
+        let aggregator = tokio::spawn(aggregate(
+            events::subscribe(),
+            metrics.clone(),
+            stop.clone(),
+        ));
+        if let Some(addr) = args.metrics_listen {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to listen for metrics requests on {addr}"))?;
+            tracing::info!("Serving metrics on http://{addr}/metrics");
+            tokio::spawn(serve(listener, metrics.clone(), stop.clone()));
+        }
+        if let Some(path) = &args.metrics_textfile {
+            write_textfile(path, &metrics)?;
+            tokio::spawn(rewrite_textfile(
+                path.clone(),
+                metrics.clone(),
+                stop.clone(),
+            ));
+        }
+        Ok(Some(Self {
+            metrics,
+            textfile: args.metrics_textfile,
+            stop,
+            aggregator,
+        }))
+    }
+
+    /// Stop exporting after recording the events sent so far, the textfile
+    /// keeps the final metrics
+    pub(crate) async fn finish(self) -> Result<()> {
+        self.stop.cancel();
+        self.aggregator
+            .await
+            .with_context(|| "Metrics aggregation task failed")?;
+        if let Some(path) = &self.textfile {
+            write_textfile(path, &self.metrics)?;
+        }
+        Ok(())
+    }
+}
+
+async fn aggregate(
+    mut events: broadcast::Receiver<DownloadEvent>,
+    metrics: Arc<Mutex<Metrics>>,
+    stop: CancellationToken,
+) {
+    let record = |event: &DownloadEvent| {
+        let mut metrics = metrics.lock().unwrap_or_else(|e| e.into_inner());
+        metrics.record(event, Instant::now());
+    };
+    loop {
+        tokio::select! {
+            biased;
+            event = events.recv() => match event {
+                Ok(event) => record(&event),
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Metrics missed {missed} download events");
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = stop.cancelled() => break,
+        }
+    }
+    loop {
+        match events.try_recv() {
+            Ok(event) => record(&event),
+            Err(TryRecvError::Lagged(_)) => {}
+            Err(TryRecvError::Empty | TryRecvError::Closed) => return,
+        }
+    }
+}
+
+fn render(metrics: &Mutex<Metrics>) -> String {
+    metrics
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .render(Instant::now(), ACTIVE_CONNECTIONS.load(Ordering::Relaxed))
+}
+
+/// Answer every request with the metrics, whatever its path
+async fn serve(
+    listener: tokio::net::TcpListener,
+    metrics: Arc<Mutex<Metrics>>,
+    stop: CancellationToken,
+) {
+    loop {
+        let (mut stream, _) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Failed to accept metrics request: {e}");
+                    continue;
+                }
+            },
+            _ = stop.cancelled() => return,
+        };
+        let body = render(&metrics);
+        tokio::spawn(async move {
+            // Only the request line and headers are expected, the request
+            // itself does not matter
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request).await;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{body}",
+                body.len()
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                tracing::debug!("Failed to send metrics: {e}");
+            }
+        });
+    }
+}
+
+async fn rewrite_textfile(path: PathBuf, metrics: Arc<Mutex<Metrics>>, stop: CancellationToken) {
+    let mut interval = tokio::time::interval(TEXTFILE_INTERVAL);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stop.cancelled() => return,
+        }
+        if let Err(e) = write_textfile(&path, &metrics) {
+            tracing::warn!("{e:#}");
+        }
+    }
+}
+
+/// Replace `path` at once, the collector must not read a partial file
+fn write_textfile(path: &Path, metrics: &Mutex<Metrics>) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    std::fs::write(&partial, render(metrics))
+        .with_context(|| format!("Failed to write metrics to {partial:?}"))?;
+    std::fs::rename(&partial, path)
+        .with_context(|| format!("Failed to write metrics to {path:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_rendered_as_prometheus_metrics() {
+        let started = Instant::now();
+        let file = PathBuf::from("file.iso");
+        let mirror = url::Url::parse("https://mirror.example.com/file.iso").unwrap();
+        let mut metrics = Metrics::default();
+        for event in [
+            DownloadEvent::DownloadPlanned {
+                files: 1,
+                bytes: 300,
+            },
+            DownloadEvent::Progressed {
+                file: file.clone(),
+                bytes: 100,
+            },
+            DownloadEvent::Retry {
+                url: mirror.clone(),
+                attempt: 2,
+                reason: "status 503 Service Unavailable".to_owned(),
+            },
+            DownloadEvent::VerificationFailed {
+                file,
+                reason: "checksum mismatch".to_owned(),
+            },
+        ] {
+            metrics.record(&event, started);
+        }
+
+        let text = metrics.render(started + Duration::from_secs(10), 3);
+        for line in [
+            "metalink_downloader_downloaded_bytes_total 100",
+            "metalink_downloader_files_total{outcome=\"downloaded\"} 0",
+            "metalink_downloader_verification_failures_total 1",
+            "metalink_downloader_mirror_errors_total{host=\"mirror.example.com\"} 1",
+            "metalink_downloader_active_connections 3",
+            // 200 bytes left at 10 bytes per second
+            "metalink_downloader_eta_seconds 20",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} missing in\n{text}");
+        }
+        assert_eq!(escape_label("a\"b\\"), r#"a\"b\\"#);
+    }
+}
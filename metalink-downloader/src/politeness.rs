@@ -0,0 +1,106 @@
+//! Spacing of the requests sent to the same host, so mirrors are not
+//! hammered with the many small range requests of a file with small pieces.
+
+use reqwest_middleware::{Middleware, Next};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How requests to the same host are spaced, nothing is spaced by default
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct PolitenessOptions {
+    /// Most requests started per second and host
+    pub max_per_second: Option<u32>,
+    /// Least time between the starts of two requests to the same host
+    pub delay: Option<Duration>,
+}
+
+impl PolitenessOptions {
+    /// Least time between two requests to the same host, None if requests
+    /// are not spaced
+    fn interval(&self) -> Option<Duration> {
+        let rate = self
+            .max_per_second
+            .map(|per_second| Duration::from_secs(1) / per_second);
+        rate.max(self.delay)
+    }
+
+    /// Middleware spacing the requests, None if requests are not spaced
+    pub(crate) fn middleware(&self) -> Option<PolitenessMiddleware> {
+        self.interval().map(|interval| PolitenessMiddleware {
+            interval,
+            next_start: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+/// Delays requests so that requests to the same host start at least
+/// `interval` apart, placed after the retry middleware so retries are spaced
+/// as well
+pub(crate) struct PolitenessMiddleware {
+    interval: Duration,
+    /// When the next request to a host may start
+    next_start: Mutex<HashMap<String, Instant>>,
+}
+
+impl PolitenessMiddleware {
+    /// Reserve the next start of a request to `host` at or after `now`,
+    /// returns how long the request has to wait for it
+    fn reserve(&self, host: &str, now: Instant) -> Duration {
+        let mut next_start = self.next_start.lock().unwrap_or_else(|e| e.into_inner());
+        let start = next_start.get(host).map_or(now, |next| (*next).max(now));
+        next_start.insert(host.to_owned(), start + self.interval);
+        start - now
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for PolitenessMiddleware {
+    async fn handle(
+        &self,
+        req: reqwest::Request,
+        extensions: &mut http::Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest::Response> {
+        if let Some(host) = req.url().host_str() {
+            let wait = self.reserve(host, Instant::now());
+            if !wait.is_zero() {
+                tracing::debug!("Waiting {wait:?} before requesting {}", req.url());
+                tokio::time::sleep(wait).await;
+            }
+        }
+        next.run(req, extensions).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_to_a_host_are_spaced() {
+        assert!(PolitenessOptions::default().middleware().is_none());
+        let options = PolitenessOptions {
+            max_per_second: Some(4),
+            delay: Some(Duration::from_millis(100)),
+        };
+        assert_eq!(options.interval(), Some(Duration::from_millis(250)));
+
+        let middleware = options.middleware().unwrap();
+        let now = Instant::now();
+        assert_eq!(middleware.reserve("a.example.com", now), Duration::ZERO);
+        assert_eq!(
+            middleware.reserve("a.example.com", now),
+            Duration::from_millis(250)
+        );
+        assert_eq!(
+            middleware.reserve("a.example.com", now + Duration::from_millis(100)),
+            Duration::from_millis(400)
+        );
+        assert_eq!(middleware.reserve("b.example.com", now), Duration::ZERO);
+        assert_eq!(
+            middleware.reserve("a.example.com", now + Duration::from_secs(5)),
+            Duration::ZERO
+        );
+    }
+}
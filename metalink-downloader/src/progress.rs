@@ -0,0 +1,236 @@
+use crate::events::{self, DownloadEvent};
+use crate::{MetalinkDownloadError, Result};
+
+use anyhow::anyhow;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Maximum number of `Progressed` updates buffered for the progress reporter
+pub(crate) const PROGRESS_CHANNEL_CAPACITY: usize = 1024;
+
+/// Interval of the progress lines printed without a terminal
+pub(crate) const PROGRESS_LINE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How the progress of a download is displayed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ProgressMode {
+    /// Progress bars, for interactive terminals
+    #[default]
+    Bars,
+    /// Plain progress lines printed periodically, e.g. for CI logs
+    Lines,
+    /// No progress and no summary, only errors are printed
+    Quiet,
+}
+
+impl ProgressMode {
+    /// `--quiet` disables all output besides errors, if stdout is not a
+    /// terminal progress is printed as plain lines
+    pub(crate) fn detect(quiet: bool) -> Self {
+        if quiet {
+            Self::Quiet
+        } else if std::io::stdout().is_terminal() {
+            Self::Bars
+        } else {
+            Self::Lines
+        }
+    }
+}
+
+/// Progress of the files of a download, files are identified by their target path.
+///
+/// Dropped `Progressed` updates are only accounted for the overall progress,
+/// the progress of the individual file falls behind.
+#[derive(Debug)]
+pub(crate) enum ProgressUpdate {
+    /// The download of a file of `size` bytes started
+    Started { file: Arc<Path>, size: u64 },
+    /// The download of a file progressed by n bytes
+    Progressed { file: Arc<Path>, bytes: u64 },
+    /// The file was downloaded
+    Finished { file: Arc<Path> },
+    /// The file was verified against its checksum
+    Verified { file: Arc<Path> },
+    /// Downloading or verifying the file failed
+    Failed { file: Arc<Path> },
+    /// The download of the file was cancelled
+    Cancelled { file: Arc<Path> },
+}
+
+/// Sending half of the progress channel.
+///
+/// At most `capacity` `Progressed` updates are buffered, further ones are
+/// dropped so slow progress displays never block or grow memory without
+/// bounds. Bytes of dropped updates are accounted separately so the
+/// reported total stays exact. The other updates happen a few times per
+/// file and are never dropped.
+#[derive(Debug, Clone)]
+pub(crate) struct ProgressSender {
+    tx: async_channel::Sender<ProgressUpdate>,
+    shared: Arc<Shared>,
+}
+
+/// Receiving half of the progress channel
+#[derive(Debug)]
+pub(crate) struct ProgressReceiver {
+    rx: async_channel::Receiver<ProgressUpdate>,
+    shared: Arc<Shared>,
+}
+
+/// State of the progress channel shared by its halves
+#[derive(Debug)]
+struct Shared {
+    capacity: usize,
+    /// Number of `Progressed` updates in the channel
+    queued_progress: AtomicUsize,
+    dropped_bytes: AtomicU64,
+}
+
+/// Create a progress channel buffering up to `capacity` `Progressed` updates
+pub(crate) fn progress_channel(capacity: usize) -> (ProgressSender, ProgressReceiver) {
+    let (tx, rx) = async_channel::unbounded();
+    let shared = Arc::new(Shared {
+        capacity,
+        queued_progress: AtomicUsize::new(0),
+        dropped_bytes: AtomicU64::new(0),
+    });
+    (
+        ProgressSender {
+            tx,
+            shared: shared.clone(),
+        },
+        ProgressReceiver { rx, shared },
+    )
+}
+
+impl ProgressSender {
+    /// Send a progress update, never blocks
+    pub(crate) fn send(&self, update: ProgressUpdate) -> Result<()> {
+        if let ProgressUpdate::Progressed { file, bytes } = &update {
+            events::emit(|| DownloadEvent::Progressed {
+                file: file.to_path_buf(),
+                bytes: *bytes,
+            });
+            let queued = self.shared.queued_progress.fetch_add(1, Ordering::Relaxed);
+            if queued >= self.shared.capacity {
+                self.shared.queued_progress.fetch_sub(1, Ordering::Relaxed);
+                self.shared
+                    .dropped_bytes
+                    .fetch_add(*bytes, Ordering::Relaxed);
+                return Ok(());
+            }
+        }
+        self.tx.try_send(update).map_err(|_| {
+            MetalinkDownloadError::Other(anyhow!("Progress reporter is no longer running"))
+        })
+    }
+}
+
+impl ProgressReceiver {
+    /// Receive the next update, returns None once all senders are dropped
+    /// and the channel is drained
+    pub(crate) async fn recv(&self) -> Option<ProgressUpdate> {
+        let update = self.rx.recv().await.ok()?;
+        if matches!(update, ProgressUpdate::Progressed { .. }) {
+            self.shared.queued_progress.fetch_sub(1, Ordering::Relaxed);
+        }
+        Some(update)
+    }
+
+    /// Returns the bytes of updates dropped since the last call
+    pub(crate) fn take_dropped_bytes(&self) -> u64 {
+        self.shared.dropped_bytes.swap(0, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn dropped_updates_are_accounted() {
+        let file: Arc<Path> = Arc::from(Path::new("a"));
+        let (tx, rx) = progress_channel(2);
+        for bytes in 1..=5 {
+            tx.send(ProgressUpdate::Progressed {
+                file: file.clone(),
+                bytes,
+            })
+            .unwrap();
+        }
+        drop(tx);
+
+        let mut total = 0;
+        while let Some(ProgressUpdate::Progressed { bytes, .. }) = rx.recv().await {
+            total += bytes;
+        }
+        total += rx.take_dropped_bytes();
+        assert_eq!(total, 15);
+    }
+
+    #[tokio::test]
+    async fn receiver_drains_after_senders_are_dropped() {
+        let file: Arc<Path> = Arc::from(Path::new("a"));
+        let (tx, rx) = progress_channel(4);
+        let cloned_tx = tx.clone();
+        tx.send(ProgressUpdate::Started {
+            file: file.clone(),
+            size: 1,
+        })
+        .unwrap();
+        drop(tx);
+        cloned_tx
+            .send(ProgressUpdate::Finished { file: file.clone() })
+            .unwrap();
+        drop(cloned_tx);
+
+        assert!(matches!(
+            rx.recv().await,
+            Some(ProgressUpdate::Started { size: 1, .. })
+        ));
+        assert!(matches!(
+            rx.recv().await,
+            Some(ProgressUpdate::Finished { .. })
+        ));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn only_progressed_updates_are_dropped() {
+        let file: Arc<Path> = Arc::from(Path::new("a"));
+        let (tx, rx) = progress_channel(1);
+        tx.send(ProgressUpdate::Started {
+            file: file.clone(),
+            size: 6,
+        })
+        .unwrap();
+        for bytes in 1..=3 {
+            tx.send(ProgressUpdate::Progressed {
+                file: file.clone(),
+                bytes,
+            })
+            .unwrap();
+        }
+        tx.send(ProgressUpdate::Finished { file: file.clone() })
+            .unwrap();
+        drop(tx);
+
+        assert!(matches!(
+            rx.recv().await,
+            Some(ProgressUpdate::Started { size: 6, .. })
+        ));
+        assert!(matches!(
+            rx.recv().await,
+            Some(ProgressUpdate::Progressed { bytes: 1, .. })
+        ));
+        assert!(matches!(
+            rx.recv().await,
+            Some(ProgressUpdate::Finished { .. })
+        ));
+        assert!(rx.recv().await.is_none());
+        assert_eq!(rx.take_dropped_bytes(), 5);
+    }
+}
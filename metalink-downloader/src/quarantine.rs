@@ -0,0 +1,89 @@
+use crate::Result;
+
+use anyhow::Context;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Metadata written next to a quarantined file
+#[derive(Debug, Serialize)]
+pub(crate) struct QuarantineRecord {
+    /// Where the file was downloaded to
+    pub file: PathBuf,
+    /// The url the file was downloaded from if it has one
+    pub url: Option<url::Url>,
+    /// Hash function of the file checksum
+    pub hash_type: String,
+    /// Checksum listed in the metalink
+    pub expected: String,
+    /// Checksum of the downloaded file
+    pub actual: String,
+    /// Seconds since the unix epoch when the file was quarantined
+    pub quarantined_at: u64,
+}
+
+/// Move a corrupt file into `quarantine_dir` and write a `.json` sidecar
+/// describing the expected and actual checksums. `relative` is the path the
+/// file is stored under inside the quarantine directory.
+///
+/// Returns the path of the quarantined file.
+pub(crate) fn quarantine(
+    quarantine_dir: &Path,
+    relative: &Path,
+    record: &QuarantineRecord,
+) -> Result<PathBuf> {
+    let mut destination = quarantine_dir.join(relative);
+    // Keep earlier quarantined copies of the same file around
+    destination
+        .as_mut_os_string()
+        .push(format!(".{}", record.quarantined_at));
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create quarantine directory: {parent:?}"))?;
+    }
+
+    if std::fs::rename(&record.file, &destination).is_err() {
+        // Renaming fails across file systems, fall back to copy and delete
+        std::fs::copy(&record.file, &destination)
+            .with_context(|| format!("Failed to quarantine {:?}", record.file))?;
+        std::fs::remove_file(&record.file)?;
+    }
+
+    let mut sidecar = destination.clone();
+    sidecar.as_mut_os_string().push(".json");
+    let metadata = serde_json::to_string_pretty(record)
+        .with_context(|| "Failed to serialize quarantine metadata")?;
+    std::fs::write(&sidecar, metadata)
+        .with_context(|| format!("Failed to write quarantine metadata: {sidecar:?}"))?;
+    Ok(destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarantine_moves_file_and_writes_sidecar() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let target = dir.join("download/sub/file.bin");
+        std::fs::create_dir_all(target.parent().unwrap()).unwrap();
+        std::fs::write(&target, b"corrupt").unwrap();
+
+        let record = QuarantineRecord {
+            file: target.clone(),
+            url: Some(url::Url::parse("https://example.com/sub/file.bin").unwrap()),
+            hash_type: "sha-256".into(),
+            expected: "ab".into(),
+            actual: "cd".into(),
+            quarantined_at: 42,
+        };
+        let quarantined =
+            quarantine(&dir.join("quarantine"), Path::new("sub/file.bin"), &record).unwrap();
+
+        assert_eq!(quarantined, dir.join("quarantine/sub/file.bin.42"));
+        assert!(!target.exists());
+        assert_eq!(std::fs::read(&quarantined).unwrap(), b"corrupt");
+        let sidecar = std::fs::read_to_string(dir.join("quarantine/sub/file.bin.42.json")).unwrap();
+        assert!(sidecar.contains(r#""expected": "ab""#));
+    }
+}
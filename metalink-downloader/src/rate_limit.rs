@@ -0,0 +1,186 @@
+//! Process wide limit of the download rate.
+//!
+//! Received bytes are paid for after the fact: a download which received
+//! more than the limit allows sleeps until the debt is paid off, so the
+//! average rate over all concurrent downloads stays below the limit.
+//!
+//! The limit can follow a [`Schedule`] of time windows which is
+//! re-evaluated while the download runs.
+
+use chrono::NaiveTime;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often a schedule is re-evaluated
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A rate limit which applies during a window of the local time of day
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RateWindow {
+    pub start: NaiveTime,
+    /// The window ends before `end`, it wraps around midnight if `end` is
+    /// before `start`
+    pub end: NaiveTime,
+    /// Bytes per second, None is unlimited
+    pub limit: Option<u64>,
+}
+
+impl RateWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// Rate limits by time of day, the first window containing the current
+/// time applies and the default limit outside of all windows
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Schedule {
+    pub default: Option<u64>,
+    pub windows: Vec<RateWindow>,
+}
+
+impl Schedule {
+    /// A fixed limit which applies all day
+    pub(crate) fn fixed(limit: Option<u64>) -> Self {
+        Self {
+            default: limit,
+            windows: Vec::new(),
+        }
+    }
+
+    fn limit_at(&self, time: NaiveTime) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|window| window.contains(time))
+            .map_or(self.default, |window| window.limit)
+    }
+}
+
+/// Limit the download rate according to `schedule`, the limit changes when
+/// a window of the schedule starts or ends
+pub(crate) fn set_schedule(schedule: Schedule) {
+    let now = || chrono::Local::now().time();
+    let mut current = schedule.limit_at(now());
+    set_limit(current);
+    if schedule.windows.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(SCHEDULE_INTERVAL).await;
+            let limit = schedule.limit_at(now());
+            if limit != current {
+                tracing::info!("Changing the rate limit to {limit:?} bytes per second");
+                set_limit(limit);
+                current = limit;
+            }
+        }
+    });
+}
+
+#[derive(Debug)]
+struct Bucket {
+    bytes_per_sec: u64,
+    /// Bytes which can be received without waiting, negative while in debt
+    available: f64,
+    refilled: Instant,
+}
+
+static LIMIT: Mutex<Option<Bucket>> = Mutex::new(None);
+
+/// Limit the download rate to `bytes_per_sec`, None removes the limit
+fn set_limit(bytes_per_sec: Option<u64>) {
+    let mut limit = LIMIT.lock().unwrap_or_else(|e| e.into_inner());
+    *limit = bytes_per_sec
+        .filter(|bytes_per_sec| *bytes_per_sec > 0)
+        .map(|bytes_per_sec| Bucket {
+            bytes_per_sec,
+            available: 0.0,
+            refilled: Instant::now(),
+        });
+}
+
+/// Account for `bytes` received, waits while the rate is above the limit
+pub(crate) async fn consume(bytes: u64) {
+    let wait = {
+        let mut limit = LIMIT.lock().unwrap_or_else(|e| e.into_inner());
+        match limit.as_mut() {
+            Some(bucket) => bucket.consume(bytes, Instant::now()),
+            None => Duration::ZERO,
+        }
+    };
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+impl Bucket {
+    /// Take `bytes` out of the bucket, returns how long to wait for the debt
+    fn consume(&mut self, bytes: u64, now: Instant) -> Duration {
+        let rate = self.bytes_per_sec as f64;
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        // At most one second worth of bytes can be saved up for bursts
+        self.available = (self.available + elapsed * rate).min(rate);
+        self.refilled = now;
+        self.available -= bytes as f64;
+        if self.available < 0.0 {
+            Duration::from_secs_f64(-self.available / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debt_is_paid_off_over_time() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            bytes_per_sec: 100,
+            available: 0.0,
+            refilled: start,
+        };
+        assert_eq!(bucket.consume(50, start), Duration::from_millis(500));
+        assert_eq!(
+            bucket.consume(50, start + Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            bucket.consume(100, start + Duration::from_secs(10)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn schedule_applies_windows_by_time_of_day() {
+        let time = |s: &str| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+        let schedule = Schedule {
+            default: Some(1024),
+            windows: vec![
+                RateWindow {
+                    start: time("01:00"),
+                    end: time("07:00"),
+                    limit: None,
+                },
+                RateWindow {
+                    start: time("22:00"),
+                    end: time("01:00"),
+                    limit: Some(4096),
+                },
+            ],
+        };
+        assert_eq!(schedule.limit_at(time("03:30")), None);
+        assert_eq!(schedule.limit_at(time("07:00")), Some(1024));
+        assert_eq!(schedule.limit_at(time("23:00")), Some(4096));
+        assert_eq!(schedule.limit_at(time("00:59")), Some(4096));
+        assert_eq!(schedule.limit_at(time("01:00")), None);
+        assert_eq!(Schedule::fixed(Some(1)).limit_at(time("12:00")), Some(1));
+    }
+}
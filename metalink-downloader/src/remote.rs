@@ -0,0 +1,211 @@
+use crate::http::Client;
+use crate::warnings::{self, Warning};
+use crate::Result;
+
+use anyhow::{anyhow, Context};
+use digest::Digest;
+use metalink::Metalink;
+use reqwest::header::{ACCEPT, CONTENT_TYPE, DATE, ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Directory inside the target directory caching metalinks fetched from urls
+pub(crate) const CACHE_DIR: &str = ".metalink-cache";
+
+/// Media type of metalink documents, see [RFC5854 Section 7](https://www.rfc-editor.org/rfc/rfc5854#section-7)
+const METALINK_MEDIA_TYPE: &str = "application/metalink4+xml";
+
+/// Where the metalink of a download comes from
+#[derive(Debug, Clone)]
+pub enum MetalinkSource {
+    /// A metalink on the local file system
+    File(PathBuf),
+    /// A metalink published at a url
+    Url(url::Url),
+    /// All `*.meta4` and `*.metalink` files of a local directory
+    Dir(PathBuf),
+}
+
+impl MetalinkSource {
+    /// Returns the paths of the metalinks on disk, fetching them first if
+    /// needed
+    pub(crate) async fn resolve(&self, client: &Client, target_dir: &Path) -> Result<Vec<PathBuf>> {
+        match self {
+            MetalinkSource::File(path) => Ok(vec![path.clone()]),
+            MetalinkSource::Url(url) => Ok(vec![
+                fetch_metalink(client, url, &target_dir.join(CACHE_DIR)).await?,
+            ]),
+            MetalinkSource::Dir(dir) => metalinks_in(dir),
+        }
+    }
+}
+
+/// The metalinks directly inside `dir` sorted by name
+fn metalinks_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut metalinks = Vec::new();
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read metalink directory {dir:?}"))?
+    {
+        let path = entry?.path();
+        if is_metalink(&path) && path.is_file() {
+            metalinks.push(path);
+        }
+    }
+    if metalinks.is_empty() {
+        return Err(anyhow!("No *.meta4 or *.metalink files in {dir:?}").into());
+    }
+    metalinks.sort();
+    Ok(metalinks)
+}
+
+/// Whether `path` is named like a metalink, `*.meta4` or `*.metalink`
+pub(crate) fn is_metalink(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "meta4" || extension == "metalink")
+}
+
+/// Fetch the metalink at `url` into `cache_dir`.
+///
+/// The ETag of the response is stored next to the metalink so unchanged
+/// metalinks are not transferred again. If the metalink declares a dynamic
+/// origin at a different url, the metalink is refreshed from there.
+pub(crate) async fn fetch_metalink(
+    client: &Client,
+    url: &url::Url,
+    cache_dir: &Path,
+) -> Result<PathBuf> {
+    let path = fetch_cached(client, url, cache_dir).await?;
+    let metalink = Metalink::load_from_file_lenient(&path)?;
+    match metalink.origin() {
+        Some(origin) if origin.is_dynamic() && origin.url() != url => {
+            tracing::info!(
+                "Refreshing metalink from its dynamic origin {}",
+                origin.url()
+            );
+            fetch_cached(client, origin.url(), cache_dir).await
+        }
+        _ => Ok(path),
+    }
+}
+
+async fn fetch_cached(client: &Client, url: &url::Url, cache_dir: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(cache_dir)
+        .with_context(|| format!("Failed to create metalink cache: {cache_dir:?}"))?;
+    let (path, etag_path) = cache_paths(cache_dir, url);
+
+    let mut request = client.get(url.clone());
+    if path.exists() {
+        if let Ok(etag) = std::fs::read_to_string(&etag_path) {
+            request = request.header(IF_NONE_MATCH, etag.trim());
+        }
+    }
+    let response = request.send().await?;
+    check_clock_skew(url, response.headers());
+    if response.status() == StatusCode::NOT_MODIFIED {
+        tracing::info!("Metalink {url} is unchanged, using cached copy");
+        return Ok(path);
+    }
+    if !response.status().is_success() {
+        return Err(anyhow!("Fetching metalink {url} failed: {}", response.status()).into());
+    }
+
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(ToOwned::to_owned);
+    let body = response.bytes().await?;
+    std::fs::write(&path, &body).with_context(|| format!("Failed to write metalink: {path:?}"))?;
+    match etag {
+        Some(etag) => std::fs::write(&etag_path, etag)?,
+        None if etag_path.exists() => std::fs::remove_file(&etag_path)?,
+        None => {}
+    }
+    Ok(path)
+}
+
+/// Servers whose clock differs by more than this from the local clock are
+/// reported, as skew breaks the freshness checks of dynamic metalinks
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+/// Warn if the Date header of a response is far off the local clock
+fn check_clock_skew(url: &url::Url, headers: &reqwest::header::HeaderMap) {
+    let Some(date) = headers
+        .get(DATE)
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| httpdate::parse_http_date(date).ok())
+    else {
+        return;
+    };
+    let now = SystemTime::now();
+    let skew_secs = match date.duration_since(now) {
+        Ok(ahead) => ahead.as_secs() as i64,
+        Err(behind) => -(behind.duration().as_secs() as i64),
+    };
+    if skew_secs.unsigned_abs() > MAX_CLOCK_SKEW.as_secs() {
+        warnings::warn(Warning::ClockSkew {
+            host: url.host_str().unwrap_or_default().to_owned(),
+            skew_secs,
+        });
+    }
+}
+
+/// Request `url` preferring a metalink describing it over the resource itself.
+///
+/// Returns the path of the stored metalink if the server answered with one,
+/// or None if it sent the resource.
+pub(crate) async fn negotiate_metalink(
+    client: &Client,
+    url: &url::Url,
+    cache_dir: &Path,
+) -> Result<Option<PathBuf>> {
+    let response = client
+        .get(url.clone())
+        .header(ACCEPT, format!("{METALINK_MEDIA_TYPE}, */*;q=0.1"))
+        .send()
+        .await?;
+    let is_metalink = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|media_type| media_type.trim() == METALINK_MEDIA_TYPE);
+    if !response.status().is_success() || !is_metalink {
+        // Dropping the response aborts the transfer of the resource
+        return Ok(None);
+    }
+
+    std::fs::create_dir_all(cache_dir)
+        .with_context(|| format!("Failed to create metalink cache: {cache_dir:?}"))?;
+    let (path, _) = cache_paths(cache_dir, url);
+    let body = response.bytes().await?;
+    std::fs::write(&path, &body).with_context(|| format!("Failed to write metalink: {path:?}"))?;
+    Ok(Some(path))
+}
+
+/// Paths of the cached metalink and its ETag for `url`
+fn cache_paths(cache_dir: &Path, url: &url::Url) -> (PathBuf, PathBuf) {
+    let key = format!("{:x}", sha2::Sha256::digest(url.as_str().as_bytes()));
+    let key = &key[..16];
+    (
+        cache_dir.join(format!("{key}.meta4")),
+        cache_dir.join(format!("{key}.etag")),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_paths_are_stable_per_url() {
+        let url = url::Url::parse("https://example.com/project.meta4").unwrap();
+        let other = url::Url::parse("https://example.com/other.meta4").unwrap();
+        let (path, etag) = cache_paths(Path::new("/cache"), &url);
+        assert_eq!(path.extension().unwrap(), "meta4");
+        assert_eq!(etag.with_extension("meta4"), path);
+        assert_eq!(cache_paths(Path::new("/cache"), &url).0, path);
+        assert_ne!(cache_paths(Path::new("/cache"), &other).0, path);
+    }
+}
@@ -0,0 +1,621 @@
+use crate::control::Pause;
+use crate::http::{self, Concurrency, PieceFetch};
+use crate::machine_log::Record;
+pub(crate) use crate::machine_log::{FetchOutcome, ReplayEvent};
+use crate::run::{self, RunStats};
+use crate::transport::mock::MockTransport;
+use crate::transport::Transport;
+use crate::types::{CheckSum, ChunkMetaData, Chunks};
+use crate::{MetalinkDownloadError, Result};
+
+use anyhow::{anyhow, Context};
+use iana_registry_enums::HashFunctionTextualName;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// Largest file reproduced from a replay log, the file is served from memory
+const MAX_REPRODUCED_SIZE: u64 = 256 * 1024 * 1024;
+
+/// How long reproducing a single file may take before it counts as stalled
+const REPRODUCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Recorder {
+    seq: u64,
+    writer: LineWriter<std::fs::File>,
+}
+
+static RECORDER: OnceLock<Mutex<Recorder>> = OnceLock::new();
+
+/// Start recording replay events into the file at `path`.
+/// Recording is process wide and can only be started once.
+pub(crate) fn start_recording(path: &Path) -> Result<()> {
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Failed to create replay log: {path:?}"))?;
+    RECORDER
+        .set(Mutex::new(Recorder {
+            seq: 0,
+            writer: LineWriter::new(file),
+        }))
+        .map_err(|_| MetalinkDownloadError::Other(anyhow!("Replay recording already started")))
+}
+
+/// Append an event to the replay log, a no-op if recording is disabled.
+/// A run reproducing a log keeps its events instead.
+pub(crate) fn record(event: ReplayEvent) {
+    let captured = run::with(|run| {
+        run.replay.as_ref().map(|events| {
+            events
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(event.clone())
+        })
+    });
+    if captured.flatten().is_some() {
+        return;
+    }
+    let Some(recorder) = RECORDER.get() else {
+        return;
+    };
+    let mut recorder = recorder.lock().unwrap_or_else(|e| e.into_inner());
+    let record = Record::new(recorder.seq, event);
+    recorder.seq += 1;
+    let res = serde_json::to_writer(&mut recorder.writer, &record)
+        .map_err(std::io::Error::from)
+        .and_then(|_| recorder.writer.write_all(b"\n"));
+    if let Err(e) = res {
+        tracing::warn!("Failed to write replay event: {e}");
+    }
+}
+
+/// Load the events of a replay log in recording order
+pub(crate) fn load(path: &Path) -> Result<Vec<ReplayEvent>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open replay log: {path:?}"))?;
+    let mut records: Vec<Record<ReplayEvent>> = Vec::new();
+    for (number, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record<ReplayEvent> = serde_json::from_str(&line)
+            .with_context(|| format!("Malformed replay event on line {}", number + 1))?;
+        record
+            .check_compatible()
+            .with_context(|| format!("Incompatible replay event on line {}", number + 1))?;
+        records.push(record);
+    }
+    records.sort_by_key(|record| record.seq);
+    Ok(records.into_iter().map(|record| record.event).collect())
+}
+
+/// Anomaly detected while replaying a log
+#[derive(Debug, PartialEq)]
+pub(crate) enum Anomaly {
+    /// A chunk was scheduled again although no failed fetch preceded it
+    DoubleScheduled { file: PathBuf, start: u64 },
+    /// A chunk was scheduled but no transport outcome was ever recorded
+    NeverCompleted { file: PathBuf, start: u64 },
+    /// The same offset was written more than once
+    DoubleWritten { file: PathBuf, offset: u64 },
+    /// Progress reported for a file does not match the bytes written
+    ProgressMismatch {
+        file: PathBuf,
+        written: u64,
+        reported: u64,
+    },
+    /// Reproducing the log fetched a range with other outcomes than recorded
+    Diverged {
+        file: PathBuf,
+        start: u64,
+        recorded: Vec<FetchOutcome>,
+        reproduced: Vec<FetchOutcome>,
+    },
+    /// Reproducing the downloads of a file did not finish
+    Stalled { file: PathBuf },
+}
+
+impl std::fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Anomaly::DoubleScheduled { file, start } => {
+                write!(f, "{file:?}: chunk at {start} scheduled twice")
+            }
+            Anomaly::NeverCompleted { file, start } => write!(
+                f,
+                "{file:?}: chunk at {start} never completed (possible deadlock)"
+            ),
+            Anomaly::DoubleWritten { file, offset } => {
+                write!(f, "{file:?}: offset {offset} written twice")
+            }
+            Anomaly::ProgressMismatch {
+                file,
+                written,
+                reported,
+            } => write!(
+                f,
+                "{file:?}: {written} bytes written but {reported} bytes reported as progress"
+            ),
+            Anomaly::Diverged {
+                file,
+                start,
+                recorded,
+                reproduced,
+            } => write!(
+                f,
+                "{file:?}: chunk at {start} was fetched {recorded:?} but reproduced as {reproduced:?}"
+            ),
+            Anomaly::Stalled { file } => {
+                write!(f, "{file:?}: reproduction did not finish (possible deadlock)")
+            }
+        }
+    }
+}
+
+/// Replay the recorded events deterministically in sequence order and
+/// check the invariants of the download engine
+pub(crate) fn replay(events: &[ReplayEvent]) -> Vec<Anomaly> {
+    let mut anomalies = Vec::new();
+    let mut in_flight: BTreeSet<(PathBuf, u64)> = BTreeSet::new();
+    let mut written: BTreeSet<(PathBuf, u64)> = BTreeSet::new();
+    let mut written_bytes: BTreeMap<PathBuf, u64> = BTreeMap::new();
+    let mut reported_bytes: BTreeMap<PathBuf, u64> = BTreeMap::new();
+
+    for event in events {
+        match event {
+            ReplayEvent::ChunkScheduled { file, start, .. } => {
+                if !in_flight.insert((file.clone(), *start)) {
+                    anomalies.push(Anomaly::DoubleScheduled {
+                        file: file.clone(),
+                        start: *start,
+                    });
+                }
+            }
+            ReplayEvent::ChunkFetched { file, start, .. } => {
+                in_flight.remove(&(file.clone(), *start));
+            }
+            ReplayEvent::ChunkWritten {
+                file,
+                offset,
+                bytes,
+            } => {
+                if !written.insert((file.clone(), *offset)) {
+                    anomalies.push(Anomaly::DoubleWritten {
+                        file: file.clone(),
+                        offset: *offset,
+                    });
+                }
+                *written_bytes.entry(file.clone()).or_default() += bytes;
+            }
+            ReplayEvent::ProgressReported { file, bytes } => {
+                *reported_bytes.entry(file.clone()).or_default() += bytes;
+            }
+            ReplayEvent::Unknown => {}
+        }
+    }
+
+    for (file, start) in in_flight {
+        anomalies.push(Anomaly::NeverCompleted { file, start });
+    }
+
+    for (file, reported) in reported_bytes {
+        let written = written_bytes.get(&file).copied().unwrap_or(0);
+        if written != reported {
+            anomalies.push(Anomaly::ProgressMismatch {
+                file,
+                written,
+                reported,
+            });
+        }
+    }
+
+    anomalies
+}
+
+/// The ranges of a file and the recorded outcomes of their requests
+#[derive(Default)]
+struct FileLog {
+    /// Last byte of the range starting at the key
+    ranges: BTreeMap<u64, u64>,
+    outcomes: BTreeMap<u64, Vec<FetchOutcome>>,
+}
+
+impl FileLog {
+    fn size(&self) -> u64 {
+        self.ranges.values().max().map_or(0, |end| end + 1)
+    }
+
+    fn has_checksum_mismatch(&self) -> bool {
+        self.outcomes
+            .values()
+            .flatten()
+            .any(|outcome| *outcome == FetchOutcome::ChecksumMismatch)
+    }
+}
+
+fn outcomes_by_range(events: &[ReplayEvent]) -> BTreeMap<(PathBuf, u64), Vec<FetchOutcome>> {
+    let mut outcomes: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for event in events {
+        if let ReplayEvent::ChunkFetched {
+            file,
+            start,
+            outcome,
+            ..
+        } = event
+        {
+            outcomes
+                .entry((file.clone(), *start))
+                .or_default()
+                .push(*outcome);
+        }
+    }
+    outcomes
+}
+
+/// Reproduce the recorded downloads through the download engine. Every file
+/// is downloaded again from a [`MockTransport`] answering the requests with
+/// the recorded outcomes, the outcomes the engine ends up with are compared
+/// with the recorded ones and the events of the reproduction are checked
+/// like a recorded log, see [`replay`].
+pub(crate) async fn reproduce(events: &[ReplayEvent]) -> Result<Vec<Anomaly>> {
+    let mut files: BTreeMap<PathBuf, FileLog> = BTreeMap::new();
+    for event in events {
+        match event {
+            ReplayEvent::ChunkScheduled { file, start, end } => {
+                files
+                    .entry(file.clone())
+                    .or_default()
+                    .ranges
+                    .insert(*start, *end);
+            }
+            ReplayEvent::ChunkFetched {
+                file,
+                start,
+                outcome,
+                ..
+            } => {
+                files
+                    .entry(file.clone())
+                    .or_default()
+                    .outcomes
+                    .entry(*start)
+                    .or_default()
+                    .push(*outcome);
+            }
+            _ => {}
+        }
+    }
+
+    // Removed when dropped, also if reproducing fails early
+    let dir = tempfile::Builder::new()
+        .prefix("metalink-downloader-replay-")
+        .tempdir()?;
+    let mut anomalies = Vec::new();
+    for (index, (file, log)) in files.iter().enumerate() {
+        if log.size() > MAX_REPRODUCED_SIZE {
+            tracing::warn!("{file:?} is too large to be reproduced, skipping it");
+            continue;
+        }
+        let target_file = dir.path().join(index.to_string());
+        match reproduce_file(log, &target_file).await {
+            Ok(reproduced) => {
+                let reproduced: Vec<_> = reproduced
+                    .into_iter()
+                    .map(|event| restore_file(event, &target_file, file))
+                    .collect();
+                let recorded = &log.outcomes;
+                let outcomes = outcomes_by_range(&reproduced);
+                for &start in log.ranges.keys() {
+                    let recorded = recorded.get(&start).cloned().unwrap_or_default();
+                    if recorded.contains(&FetchOutcome::Unknown) {
+                        continue;
+                    }
+                    let reproduced = outcomes
+                        .get(&(file.clone(), start))
+                        .cloned()
+                        .unwrap_or_default();
+                    if recorded != reproduced {
+                        anomalies.push(Anomaly::Diverged {
+                            file: file.clone(),
+                            start,
+                            recorded,
+                            reproduced,
+                        });
+                    }
+                }
+                anomalies.extend(replay(&reproduced));
+            }
+            Err(_) => anomalies.push(Anomaly::Stalled { file: file.clone() }),
+        }
+    }
+    dir.close()?;
+    Ok(anomalies)
+}
+
+/// Download the ranges of `log` into `target_file` from a transport replaying
+/// the recorded outcomes, returns the events of the download or an error if
+/// it did not finish in time
+async fn reproduce_file(
+    log: &FileLog,
+    target_file: &Path,
+) -> std::result::Result<Vec<ReplayEvent>, tokio::time::error::Elapsed> {
+    let size = log.size();
+    // Every byte differs from the zeros a corrupt range is served as
+    let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8 + 1).collect();
+    let mut transport = MockTransport::from_bytes(data.clone().into());
+    for (start, outcomes) in &log.outcomes {
+        transport = transport.with_script(*start, outcomes.clone());
+    }
+    let url = reqwest::Url::parse("mock://replay/file").expect("valid url");
+    let stats = Arc::new(RunStats {
+        replay: Some(Mutex::new(Vec::new())),
+        ..Default::default()
+    });
+
+    let download = async {
+        if log.has_checksum_mismatch() {
+            // Only ranges with pieces are verified, the recorded ranges are
+            // the pieces of the file
+            let chunks = reproduced_pieces(log, &data, target_file)?;
+            let fetch = PieceFetch {
+                verify_checksums: true,
+                max_request_size: 0,
+            };
+            http::download(
+                &transport,
+                url,
+                target_file.to_path_buf(),
+                &chunks,
+                None,
+                fetch,
+                &Pause::default(),
+            )
+            .await
+        } else {
+            let ranges: Vec<_> = log
+                .ranges
+                .iter()
+                .map(|(start, end)| ChunkMetaData::new(*start, *end, Arc::from(target_file)))
+                .collect();
+            let concurrency = Concurrency {
+                max_threads: 1,
+                reduce_on_slow_disk: false,
+            };
+            let transport: Arc<dyn Transport> = Arc::new(transport);
+            http::segregrated_download(
+                &transport,
+                url,
+                target_file.to_path_buf(),
+                size,
+                &ranges,
+                None,
+                concurrency,
+            )
+            .await
+        }
+    };
+    let res =
+        tokio::time::timeout(REPRODUCE_TIMEOUT, run::scope_with(stats.clone(), download)).await?;
+    // A failed download is compared like a successful one, the recorded
+    // download may have failed as well
+    if let Err(e) = res {
+        tracing::debug!("Reproduced download of {target_file:?} failed: {e}");
+    }
+    let events = stats
+        .replay
+        .as_ref()
+        .map(|events| std::mem::take(&mut *events.lock().unwrap_or_else(|e| e.into_inner())))
+        .unwrap_or_default();
+    Ok(events)
+}
+
+/// The recorded ranges as pieces of `data`, the piece length is the length
+/// of the first range
+fn reproduced_pieces(log: &FileLog, data: &[u8], target_file: &Path) -> Result<Vec<ChunkMetaData>> {
+    let length = log
+        .ranges
+        .iter()
+        .next()
+        .map_or(1, |(start, end)| end - start + 1);
+    let hash_type = HashFunctionTextualName::Sha256;
+    let hashes = data
+        .chunks(length as usize)
+        .map(|piece| {
+            metalink::Hash::new(
+                Some(hash_type),
+                &hex::encode(CheckSum::calculate_digest(hash_type, piece)),
+            )
+        })
+        .collect();
+    let pieces = metalink::Pieces::new(hash_type, length, hashes);
+    let chunks = Chunks::from_pieces(&pieces, target_file, data.len() as u64)?;
+    Ok(chunks
+        .iter()
+        .filter(|chunk| log.ranges.contains_key(&chunk.start))
+        .collect())
+}
+
+/// `event` with the reproduced target file replaced by the recorded one
+fn restore_file(event: ReplayEvent, reproduced: &Path, recorded: &Path) -> ReplayEvent {
+    let restore = |file: PathBuf| {
+        if file == reproduced {
+            recorded.to_path_buf()
+        } else {
+            file
+        }
+    };
+    match event {
+        ReplayEvent::ChunkScheduled { file, start, end } => ReplayEvent::ChunkScheduled {
+            file: restore(file),
+            start,
+            end,
+        },
+        ReplayEvent::ChunkFetched {
+            file,
+            start,
+            end,
+            outcome,
+        } => ReplayEvent::ChunkFetched {
+            file: restore(file),
+            start,
+            end,
+            outcome,
+        },
+        ReplayEvent::ChunkWritten {
+            file,
+            offset,
+            bytes,
+        } => ReplayEvent::ChunkWritten {
+            file: restore(file),
+            offset,
+            bytes,
+        },
+        ReplayEvent::ProgressReported { file, bytes } => ReplayEvent::ProgressReported {
+            file: restore(file),
+            bytes,
+        },
+        ReplayEvent::Unknown => ReplayEvent::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduled(start: u64) -> ReplayEvent {
+        ReplayEvent::ChunkScheduled {
+            file: "/x".into(),
+            start,
+            end: start + 9,
+        }
+    }
+
+    fn fetched(start: u64, outcome: FetchOutcome) -> ReplayEvent {
+        ReplayEvent::ChunkFetched {
+            file: "/x".into(),
+            start,
+            end: start + 9,
+            outcome,
+        }
+    }
+
+    fn written(offset: u64) -> ReplayEvent {
+        ReplayEvent::ChunkWritten {
+            file: "/x".into(),
+            offset,
+            bytes: 10,
+        }
+    }
+
+    fn progressed(bytes: u64) -> ReplayEvent {
+        ReplayEvent::ProgressReported {
+            file: "/x".into(),
+            bytes,
+        }
+    }
+
+    #[test]
+    fn replay_of_clean_log_has_no_anomalies() {
+        let events = vec![
+            scheduled(0),
+            scheduled(10),
+            fetched(10, FetchOutcome::Ok),
+            fetched(0, FetchOutcome::ChecksumMismatch),
+            scheduled(0),
+            fetched(0, FetchOutcome::Ok),
+            written(10),
+            progressed(10),
+            written(0),
+            progressed(10),
+        ];
+        assert_eq!(replay(&events), vec![]);
+    }
+
+    #[test]
+    fn replay_detects_double_counting_and_stalls() {
+        let events = vec![
+            scheduled(0),
+            scheduled(0),
+            scheduled(10),
+            fetched(0, FetchOutcome::Ok),
+            written(0),
+            written(0),
+            progressed(10),
+        ];
+        assert_eq!(
+            replay(&events),
+            vec![
+                Anomaly::DoubleScheduled {
+                    file: "/x".into(),
+                    start: 0
+                },
+                Anomaly::DoubleWritten {
+                    file: "/x".into(),
+                    offset: 0
+                },
+                Anomaly::NeverCompleted {
+                    file: "/x".into(),
+                    start: 10
+                },
+                Anomaly::ProgressMismatch {
+                    file: "/x".into(),
+                    written: 20,
+                    reported: 10
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reproduction_follows_the_recorded_outcomes() {
+        // A failed range is fetched once more after the others
+        let events = vec![
+            scheduled(0),
+            fetched(0, FetchOutcome::Failed),
+            scheduled(10),
+            fetched(10, FetchOutcome::Ok),
+            written(10),
+            progressed(10),
+            scheduled(0),
+            fetched(0, FetchOutcome::Ok),
+            written(0),
+            progressed(10),
+        ];
+        assert_eq!(reproduce(&events).await.unwrap(), vec![]);
+
+        // A corrupt piece is fetched again
+        let events = vec![
+            scheduled(0),
+            fetched(0, FetchOutcome::ChecksumMismatch),
+            fetched(0, FetchOutcome::Ok),
+            written(0),
+            progressed(10),
+            scheduled(10),
+            fetched(10, FetchOutcome::Ok),
+            written(10),
+            progressed(10),
+        ];
+        assert_eq!(reproduce(&events).await.unwrap(), vec![]);
+
+        // The engine gives up after the second failure
+        let events = vec![
+            scheduled(0),
+            fetched(0, FetchOutcome::Failed),
+            scheduled(0),
+            fetched(0, FetchOutcome::Failed),
+            scheduled(0),
+            fetched(0, FetchOutcome::Failed),
+        ];
+        assert_eq!(
+            reproduce(&events).await.unwrap(),
+            vec![Anomaly::Diverged {
+                file: "/x".into(),
+                start: 0,
+                recorded: vec![FetchOutcome::Failed; 3],
+                reproduced: vec![FetchOutcome::Failed; 2],
+            }]
+        );
+    }
+}
@@ -0,0 +1,431 @@
+use crate::connections::ConnectionReuse;
+use crate::events::{self, DownloadEvent};
+use crate::latency::LatencyBreakdown;
+use crate::selection::ConflictDecision;
+use crate::warnings::Warning;
+use crate::MetalinkDownloadError;
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Result of the post-download verification of a single file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verification {
+    /// The file matched its file-level checksum
+    Verified,
+    /// The metalink does not provide a file-level checksum
+    NoChecksum,
+    /// Verification was disabled with `--no-verify`
+    Disabled,
+}
+
+/// Outcome of a single file of a metalink download
+#[derive(Debug)]
+pub(crate) enum FileOutcome {
+    Downloaded(Verification),
+    Failed(MetalinkDownloadError),
+    /// The download was cancelled, e.g. because another file failed and
+    /// `--keep-going` was not given
+    Cancelled,
+}
+
+/// The files of a metalink download by outcome
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+    /// Files downloaded or created from a file with the same content,
+    /// verified unless verification was disabled
+    pub succeeded: Vec<PathBuf>,
+    /// Files already complete in the target directory or kept because of
+    /// `--on-conflict`
+    pub skipped: Vec<PathBuf>,
+    /// Files which failed or were cancelled, with the reason
+    pub failed: Vec<(PathBuf, MetalinkDownloadError)>,
+}
+
+impl DownloadReport {
+    /// The error to fail the run with if not all files were downloaded
+    pub fn failure(&self) -> Option<MetalinkDownloadError> {
+        if self.failed.is_empty() {
+            return None;
+        }
+        let errors: Vec<&MetalinkDownloadError> = self
+            .failed
+            .iter()
+            .map(|(_, e)| e)
+            .filter(|e| !matches!(e, MetalinkDownloadError::Cancelled { .. }))
+            .collect();
+        Some(MetalinkDownloadError::FilesFailed {
+            failed: self.failed.len(),
+            total: self.succeeded.len() + self.failed.len(),
+            verification_failed: errors.iter().any(|e| e.is_verification_failure()),
+            network_failed: !errors.is_empty() && errors.iter().all(|e| e.is_network_failure()),
+        })
+    }
+}
+
+/// Summary printed at the end of a metalink download
+#[derive(Debug, Default)]
+pub(crate) struct DownloadSummary {
+    files: Vec<(PathBuf, FileOutcome)>,
+    /// Files of the metalink which did not have to be downloaded
+    skipped: Vec<PathBuf>,
+    download_time: Option<Duration>,
+    verification_time: Option<Duration>,
+    latency: LatencyBreakdown,
+    connections: ConnectionReuse,
+    warnings: Vec<Warning>,
+    conflicts: Vec<(PathBuf, ConflictDecision)>,
+}
+
+impl DownloadSummary {
+    pub(crate) fn add(&mut self, file: PathBuf, outcome: FileOutcome) {
+        // Every file ends up here, whether it was verified, copied from a
+        // duplicate or not verified at all
+        if let FileOutcome::Downloaded(_) = outcome {
+            events::emit(|| DownloadEvent::FileCompleted { file: file.clone() });
+        }
+        self.files.push((file, outcome));
+    }
+
+    /// The outcome of `file`, the last one if it was added several times
+    pub(crate) fn outcome(&self, file: &Path) -> Option<&FileOutcome> {
+        self.files
+            .iter()
+            .rev()
+            .find(|(path, _)| path == file)
+            .map(|(_, outcome)| outcome)
+    }
+
+    pub(crate) fn set_skipped(&mut self, skipped: Vec<PathBuf>) {
+        self.skipped = skipped;
+    }
+
+    pub(crate) fn set_download_time(&mut self, time: Duration) {
+        self.download_time = Some(time);
+    }
+
+    pub(crate) fn set_verification_time(&mut self, time: Duration) {
+        self.verification_time = Some(time);
+    }
+
+    pub(crate) fn set_latency(&mut self, latency: LatencyBreakdown) {
+        self.latency = latency;
+    }
+
+    pub(crate) fn set_connections(&mut self, connections: ConnectionReuse) {
+        self.connections = connections;
+    }
+
+    pub(crate) fn set_warnings(&mut self, warnings: Vec<Warning>) {
+        self.warnings = warnings;
+    }
+
+    pub(crate) fn set_conflicts(&mut self, conflicts: Vec<(PathBuf, ConflictDecision)>) {
+        self.conflicts = conflicts;
+    }
+
+    pub(crate) fn failed_count(&self) -> usize {
+        self.files
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, FileOutcome::Failed(_)))
+            .count()
+    }
+
+    fn cancelled_count(&self) -> usize {
+        self.files
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, FileOutcome::Cancelled))
+            .count()
+    }
+
+    /// The outcome of every file, consuming the summary once it is printed
+    pub(crate) fn into_report(self) -> DownloadReport {
+        let mut report = DownloadReport {
+            skipped: self.skipped,
+            ..Default::default()
+        };
+        for (file, outcome) in self.files {
+            match outcome {
+                FileOutcome::Downloaded(_) => report.succeeded.push(file),
+                FileOutcome::Failed(e) => report.failed.push((file, e)),
+                FileOutcome::Cancelled => {
+                    let e = MetalinkDownloadError::Cancelled { file: file.clone() };
+                    report.failed.push((file, e));
+                }
+            }
+        }
+        report
+    }
+
+    fn verification_count(&self, verification: Verification) -> usize {
+        self.files
+            .iter()
+            .filter(
+                |(_, outcome)| matches!(outcome, FileOutcome::Downloaded(v) if *v == verification),
+            )
+            .count()
+    }
+}
+
+impl std::fmt::Display for DownloadSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Downloaded {} files: {} verified, {} without checksum, {} not verified, {} failed",
+            self.files.len(),
+            self.verification_count(Verification::Verified),
+            self.verification_count(Verification::NoChecksum),
+            self.verification_count(Verification::Disabled),
+            self.failed_count()
+        )?;
+        if self.cancelled_count() > 0 {
+            writeln!(f, "{} files were cancelled", self.cancelled_count())?;
+        }
+        if !self.skipped.is_empty() {
+            writeln!(
+                f,
+                "{} files were already complete or kept",
+                self.skipped.len()
+            )?;
+        }
+        if !self.conflicts.is_empty() {
+            writeln!(
+                f,
+                "{} files existed without checksums:",
+                self.conflicts.len()
+            )?;
+            for (file, decision) in &self.conflicts {
+                writeln!(f, "  {file:?}: {decision}")?;
+            }
+        }
+        if let Some(time) = self.download_time {
+            write!(f, "Download took {:.1}s", time.as_secs_f64())?;
+            match self.verification_time {
+                Some(time) => writeln!(f, ", verification took {:.1}s", time.as_secs_f64())?,
+                None => writeln!(f)?,
+            }
+        }
+        if !self.latency.is_empty() {
+            write!(f, "{}", self.latency)?;
+        }
+        if !self.connections.is_empty() {
+            write!(f, "{}", self.connections)?;
+        }
+        if !self.warnings.is_empty() {
+            writeln!(f, "{} warnings:", self.warnings.len())?;
+            for warning in &self.warnings {
+                writeln!(f, "  WARNING {warning}")?;
+            }
+        }
+
+        // Failures sharing a root cause are reported once instead of per file
+        let mut signatures: Vec<(String, Vec<(&PathBuf, &MetalinkDownloadError)>)> = Vec::new();
+        for (file, outcome) in &self.files {
+            if let FileOutcome::Failed(e) = outcome {
+                let signature = error_signature(e);
+                match signatures.iter_mut().find(|(s, _)| *s == signature) {
+                    Some((_, failures)) => failures.push((file, e)),
+                    None => signatures.push((signature, vec![(file, e)])),
+                }
+            }
+        }
+        for (signature, failures) in signatures {
+            match failures.as_slice() {
+                [(file, e)] => writeln!(f, "  FAILED {file:?}: {e:#}")?,
+                _ => writeln!(
+                    f,
+                    "  FAILED {signature} affected {} files, e.g. {:?}",
+                    failures.len(),
+                    failures[0].0
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Root cause of an error together with the host of the failed request if
+/// known, errors with the same signature are assumed to have the same cause
+fn error_signature(error: &MetalinkDownloadError) -> String {
+    let mut host = match error {
+        MetalinkDownloadError::RequestError(e) => request_host(e),
+        MetalinkDownloadError::RequestMiddlewareError(reqwest_middleware::Error::Reqwest(e)) => {
+            request_host(e)
+        }
+        _ => None,
+    };
+    let mut root_cause: &dyn std::error::Error = error;
+    while let Some(source) = root_cause.source() {
+        if host.is_none() {
+            host = source
+                .downcast_ref::<reqwest::Error>()
+                .and_then(request_host);
+        }
+        root_cause = source;
+    }
+    match host {
+        Some(host) => format!("{root_cause} ({host})"),
+        None => root_cause.to_string(),
+    }
+}
+
+fn request_host(error: &reqwest::Error) -> Option<String> {
+    error
+        .url()
+        .and_then(|url| url.host_str())
+        .map(ToOwned::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_counts_outcomes() {
+        let mut summary = DownloadSummary::default();
+        summary.add("/a".into(), FileOutcome::Downloaded(Verification::Verified));
+        summary.add(
+            "/b".into(),
+            FileOutcome::Downloaded(Verification::NoChecksum),
+        );
+        summary.add(
+            "/c".into(),
+            FileOutcome::Failed(MetalinkDownloadError::FileChecksumMismatch {
+                file: "/c".into(),
+                expected: "ab".into(),
+                actual: "cd".into(),
+            }),
+        );
+
+        assert_eq!(summary.failed_count(), 1);
+        assert_eq!(
+            summary.to_string(),
+            "Downloaded 3 files: 1 verified, 1 without checksum, 0 not verified, 1 failed\n  \
+             FAILED \"/c\": Checksum mismatch for \"/c\": expected ab, found cd\n"
+        );
+    }
+
+    #[test]
+    fn summary_deduplicates_identical_failures() {
+        let mut summary = DownloadSummary::default();
+        for file in ["/a", "/b", "/c"] {
+            summary.add(
+                file.into(),
+                FileOutcome::Failed(MetalinkDownloadError::Other(
+                    anyhow::anyhow!("connection refused")
+                        .context(format!("Parallel download of {file:?} failed")),
+                )),
+            );
+        }
+        summary.add(
+            "/d".into(),
+            FileOutcome::Failed(MetalinkDownloadError::Other(anyhow::anyhow!("disk full"))),
+        );
+
+        assert_eq!(
+            summary.to_string(),
+            "Downloaded 4 files: 0 verified, 0 without checksum, 0 not verified, 4 failed\n  \
+             FAILED connection refused affected 3 files, e.g. \"/a\"\n  \
+             FAILED \"/d\": disk full\n"
+        );
+    }
+
+    #[test]
+    fn failure_classifies_errors() {
+        let report = DownloadReport {
+            succeeded: vec!["/a".into()],
+            ..Default::default()
+        };
+        assert!(report.failure().is_none());
+
+        let mut summary = DownloadSummary::default();
+        summary.add("/a".into(), FileOutcome::Downloaded(Verification::Verified));
+        summary.set_skipped(vec!["/s".into()]);
+        summary.add(
+            "/b".into(),
+            FileOutcome::Failed(MetalinkDownloadError::Other(
+                anyhow::Error::from(MetalinkDownloadError::ChecksumMismatch {
+                    file: "/b".into(),
+                    start: 0,
+                    end: 9,
+                    expected: "00".to_owned(),
+                    actual: "ff".to_owned(),
+                })
+                .context("Parallel download of \"/b\" failed"),
+            )),
+        );
+        summary.add("/c".into(), FileOutcome::Cancelled);
+        let report = summary.into_report();
+        assert_eq!(report.succeeded, [PathBuf::from("/a")]);
+        assert_eq!(report.skipped, [PathBuf::from("/s")]);
+        assert_eq!(report.failed.len(), 2);
+        let failure = report.failure().unwrap();
+        assert_eq!(failure.to_string(), "2 of 3 files failed");
+        assert_eq!(failure.exit_code(), crate::error::EXIT_VERIFICATION_FAILED);
+
+        let report = DownloadReport {
+            succeeded: vec!["/a".into()],
+            failed: vec![(
+                "/b".into(),
+                MetalinkDownloadError::Other(anyhow::anyhow!("disk full")),
+            )],
+            ..Default::default()
+        };
+        assert_eq!(
+            report.failure().unwrap().exit_code(),
+            crate::error::EXIT_PARTIAL_SUCCESS
+        );
+    }
+
+    #[test]
+    fn summary_lists_warnings() {
+        let mut summary = DownloadSummary::default();
+        summary.add("/a".into(), FileOutcome::Downloaded(Verification::Verified));
+        summary.set_warnings(vec![Warning::WeakHash {
+            file: "a".into(),
+            hash_type: "md5".into(),
+        }]);
+
+        assert_eq!(
+            summary.to_string(),
+            "Downloaded 1 files: 1 verified, 0 without checksum, 0 not verified, 0 failed\n\
+             1 warnings:\n  \
+             WARNING a is only protected by the weak hash md5\n"
+        );
+    }
+
+    #[test]
+    fn summary_lists_conflict_decisions() {
+        let mut summary = DownloadSummary::default();
+        summary.add(
+            "/b.1".into(),
+            FileOutcome::Downloaded(Verification::NoChecksum),
+        );
+        summary.set_conflicts(vec![
+            ("/a".into(), ConflictDecision::Kept),
+            ("/b".into(), ConflictDecision::Renamed("/b.1".into())),
+        ]);
+
+        assert_eq!(
+            summary.to_string(),
+            "Downloaded 1 files: 0 verified, 1 without checksum, 0 not verified, 0 failed\n\
+             2 files existed without checksums:\n  \
+             \"/a\": kept\n  \
+             \"/b\": downloaded to \"/b.1\"\n"
+        );
+    }
+
+    #[test]
+    fn summary_prints_stage_timings() {
+        let mut summary = DownloadSummary::default();
+        summary.add("/a".into(), FileOutcome::Downloaded(Verification::Verified));
+        summary.set_download_time(Duration::from_millis(2500));
+        summary.set_verification_time(Duration::from_millis(400));
+
+        assert_eq!(
+            summary.to_string(),
+            "Downloaded 1 files: 1 verified, 0 without checksum, 0 not verified, 0 failed\n\
+             Download took 2.5s, verification took 0.4s\n"
+        );
+    }
+}
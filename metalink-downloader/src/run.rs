@@ -0,0 +1,97 @@
+//! Statistics collected by a single download run. A process may run several
+//! downloads, one after the other or at the same time, so what a run records
+//! is kept in a context of the run instead of process wide.
+
+use crate::connections::Connections;
+use crate::latency::LatencyRecorder;
+use crate::replay::ReplayEvent;
+use crate::warnings::Warning;
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+/// What the tasks of a run record for its summary
+#[derive(Default)]
+pub(crate) struct RunStats {
+    pub latency: LatencyRecorder,
+    pub warnings: Mutex<Vec<Warning>>,
+    pub connections: Mutex<Connections>,
+    /// Replay events of the run, only kept while reproducing a replay log
+    pub replay: Option<Mutex<Vec<ReplayEvent>>>,
+}
+
+tokio::task_local! {
+    static CURRENT: Arc<RunStats>;
+}
+
+/// Run `f` as a new run, everything recorded while it runs is kept apart
+/// from other runs
+pub(crate) async fn scope<F: Future>(f: F) -> F::Output {
+    scope_with(Arc::default(), f).await
+}
+
+/// Run `f` as a new run recording into `stats`
+pub(crate) async fn scope_with<F: Future>(stats: Arc<RunStats>, f: F) -> F::Output {
+    CURRENT.scope(stats, f).await
+}
+
+/// Run `f` with the statistics of the current run, does nothing outside of
+/// a run
+pub(crate) fn with<T>(f: impl FnOnce(&RunStats) -> T) -> Option<T> {
+    CURRENT.try_with(|run| f(run)).ok()
+}
+
+/// Bind `f` to the current run, so it records into it when polled on
+/// another task
+pub(crate) fn bind<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    let current = CURRENT.try_with(Arc::clone).ok();
+    async move {
+        match current {
+            Some(current) => CURRENT.scope(current, f).await,
+            None => f.await,
+        }
+    }
+}
+
+/// Spawn `f` as a task of the current run
+pub(crate) fn spawn<F>(f: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(bind(f))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::warnings;
+
+    fn weak_hash(file: &str) -> Warning {
+        Warning::WeakHash {
+            file: file.to_owned(),
+            hash_type: "md5".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_runs_keep_their_warnings_apart() {
+        let run = |file: &'static str| {
+            scope(async move {
+                warnings::warn(weak_hash(file));
+                spawn(async move { warnings::warn(weak_hash(file)) })
+                    .await
+                    .unwrap();
+                tokio::task::yield_now().await;
+                warnings::take()
+            })
+        };
+        let (a, b) = tokio::join!(run("a"), run("b"));
+        assert_eq!(a, [weak_hash("a"), weak_hash("a")]);
+        assert_eq!(b, [weak_hash("b"), weak_hash("b")]);
+        // Outside of a run nothing is collected
+        warnings::warn(weak_hash("c"));
+        assert!(warnings::take().is_empty());
+    }
+}
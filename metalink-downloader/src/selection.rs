@@ -0,0 +1,584 @@
+use crate::{MetalinkDownloadError, Result};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use iana_registry_enums::OperatingSystemName;
+use std::path::PathBuf;
+
+/// Selects files of a metalink by glob patterns on their names
+#[derive(Debug, Clone)]
+pub(crate) struct RefreshSelection {
+    refresh: GlobSet,
+    force: GlobSet,
+    on_conflict: OnConflict,
+    revalidate: bool,
+}
+
+/// What to do with an existing target file which has no checksums to
+/// validate it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnConflict {
+    /// Keep the existing file and do not download it
+    Skip,
+    /// Download the file again and replace the existing one
+    #[default]
+    Overwrite,
+    /// Keep the existing file and download to `<name>.1`, `<name>.2`, ...
+    Rename,
+    /// Fail the file without downloading it
+    Fail,
+}
+
+/// How a file with the same content as another file of the plan is created
+/// from that file instead of downloading it again
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Dedupe {
+    /// Hard link it to the downloaded file, copy it across file systems
+    Hardlink,
+    /// Copy the downloaded file
+    #[default]
+    Copy,
+    /// Download every file
+    Off,
+}
+
+/// What was done with an existing target file without checksums
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConflictDecision {
+    Kept,
+    Overwritten,
+    Renamed(PathBuf),
+    Failed,
+}
+
+impl std::fmt::Display for ConflictDecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConflictDecision::Kept => write!(f, "kept"),
+            ConflictDecision::Overwritten => write!(f, "overwritten"),
+            ConflictDecision::Renamed(path) => write!(f, "downloaded to {path:?}"),
+            ConflictDecision::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).map_err(|e| MetalinkDownloadError::Other(e.into()))?);
+    }
+    builder
+        .build()
+        .map_err(|e| MetalinkDownloadError::Other(e.into()))
+}
+
+impl RefreshSelection {
+    /// `refresh` selects files which are fully re-hashed even if all their
+    /// pieces are valid, `force` selects files which are re-downloaded
+    /// regardless of their state on disk
+    pub(crate) fn new(refresh: &[String], force: &[String]) -> Result<Self> {
+        Ok(Self {
+            refresh: build_glob_set(refresh)?,
+            force: build_glob_set(force)?,
+            on_conflict: OnConflict::default(),
+            revalidate: false,
+        })
+    }
+
+    /// Treat existing files without checksums according to `on_conflict`
+    pub(crate) fn with_conflicts(mut self, on_conflict: OnConflict) -> Self {
+        self.on_conflict = on_conflict;
+        self
+    }
+
+    pub(crate) fn on_conflict(&self) -> OnConflict {
+        self.on_conflict
+    }
+
+    /// Hash all existing files even if they did not change since their last
+    /// verification
+    pub(crate) fn with_revalidate(mut self, revalidate: bool) -> Self {
+        self.revalidate = revalidate;
+        self
+    }
+
+    /// Whether the file can be trusted if it did not change since its last
+    /// verification
+    pub(crate) fn trusts_cache(&self, name: &str) -> bool {
+        !self.revalidate && !self.is_refreshed(name)
+    }
+
+    pub(crate) fn is_refreshed(&self, name: &str) -> bool {
+        self.refresh.is_match(name)
+    }
+
+    pub(crate) fn is_forced(&self, name: &str) -> bool {
+        self.force.is_match(name)
+    }
+}
+
+impl Default for RefreshSelection {
+    fn default() -> Self {
+        Self {
+            refresh: GlobSet::empty(),
+            force: GlobSet::empty(),
+            on_conflict: OnConflict::default(),
+            revalidate: false,
+        }
+    }
+}
+
+/// Restricts a metalink download to a subset of its files
+#[derive(Debug, Clone)]
+pub(crate) struct FileFilter {
+    include: GlobSet,
+    names: Vec<String>,
+    exclude: GlobSet,
+    oses: Vec<OperatingSystemName>,
+    languages: Vec<String>,
+}
+
+impl FileFilter {
+    /// Files are selected if they match one of the `include` globs or are
+    /// listed in `names`, all files are selected if both are empty. Files
+    /// matching one of the `exclude` globs are never selected.
+    pub(crate) fn new(include: &[String], exclude: &[String], names: &[String]) -> Result<Self> {
+        Ok(Self {
+            include: build_glob_set(include)?,
+            names: names.to_vec(),
+            exclude: build_glob_set(exclude)?,
+            oses: Vec::new(),
+            languages: Vec::new(),
+        })
+    }
+
+    /// Only select files for one of `oses`, files without an os are
+    /// independent of the os and always selected
+    pub(crate) fn with_oses(mut self, oses: Vec<OperatingSystemName>) -> Self {
+        self.oses = oses;
+        self
+    }
+
+    /// Only select files in one of `languages`, files without a language are
+    /// always selected. `en` selects `en-US` as well.
+    pub(crate) fn with_languages(mut self, languages: Vec<String>) -> Self {
+        self.languages = languages;
+        self
+    }
+
+    /// Whether `file` is selected by its name, os and language
+    pub(crate) fn matches_file(&self, file: &metalink::File) -> bool {
+        let os_matches = match file.oses() {
+            Some(oses) if !self.oses.is_empty() && !oses.is_empty() => {
+                oses.iter().any(|os| self.oses.contains(&os.name()))
+            }
+            _ => true,
+        };
+        let language_matches = match file.languages() {
+            Some(languages) if !self.languages.is_empty() && !languages.is_empty() => {
+                languages.iter().any(|language| {
+                    self.languages
+                        .iter()
+                        .any(|wanted| language_matches(wanted, language.language()))
+                })
+            }
+            _ => true,
+        };
+        os_matches && language_matches && self.matches(file.name())
+    }
+
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        let included = (self.include.is_empty() && self.names.is_empty())
+            || self.include.is_match(name)
+            || self.names.iter().any(|n| n == name);
+        included && !self.exclude.is_match(name)
+    }
+
+    /// Names requested explicitly by the user
+    pub(crate) fn names(&self) -> &[String] {
+        &self.names
+    }
+}
+
+/// Whether the language tag `tag` is `wanted` or a sub tag of it
+fn language_matches(wanted: &str, tag: &str) -> bool {
+    tag.eq_ignore_ascii_case(wanted)
+        || (tag
+            .get(..wanted.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(wanted))
+            && tag[wanted.len()..].starts_with('-'))
+}
+
+impl Default for FileFilter {
+    fn default() -> Self {
+        Self {
+            include: GlobSet::empty(),
+            names: Vec::new(),
+            exclude: GlobSet::empty(),
+            oses: Vec::new(),
+            languages: Vec::new(),
+        }
+    }
+}
+
+/// Selects and orders the urls of a file. Urls are rewritten first, then
+/// excluded mirrors and plain http mirrors are skipped unless allowed.
+/// `file://` urls are tried first, then urls of mirrors in one of the
+/// preferred locations.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MirrorSelection {
+    locations: Vec<String>,
+    allow_http: bool,
+    /// Matched against the url and the host of a mirror
+    exclude: GlobSet,
+    /// All mirrors are used if empty
+    only: GlobSet,
+    rewrites: Vec<MirrorRewrite>,
+}
+
+/// Replaces the url prefix `from` of a mirror with `to`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorRewrite {
+    from: String,
+    to: String,
+}
+
+impl MirrorRewrite {
+    /// Parse `from=>to`
+    pub(crate) fn parse(s: &str) -> std::result::Result<Self, String> {
+        match s.split_once("=>") {
+            Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => Ok(Self {
+                from: from.trim().to_owned(),
+                to: to.trim().to_owned(),
+            }),
+            _ => Err(format!("expected 'from=>to', got {s:?}")),
+        }
+    }
+
+    fn apply(&self, url: &url::Url) -> Option<url::Url> {
+        let rest = url.as_str().strip_prefix(&self.from)?;
+        match url::Url::parse(&format!("{}{rest}", self.to)) {
+            Ok(rewritten) => Some(rewritten),
+            Err(e) => {
+                tracing::warn!("Rewriting {url} with {} gives an invalid url: {e}", self.to);
+                None
+            }
+        }
+    }
+}
+
+impl MirrorSelection {
+    /// `locations` are ISO3166-1 alpha-2 country codes, earlier ones are
+    /// preferred over later ones
+    pub(crate) fn new(locations: Vec<String>) -> Self {
+        Self {
+            locations,
+            ..Self::default()
+        }
+    }
+
+    /// Also use mirrors serving the file over plain http
+    pub(crate) fn with_http(mut self, allow_http: bool) -> Self {
+        self.allow_http = allow_http;
+        self
+    }
+
+    /// Skip mirrors matching one of the `exclude` globs and, unless `only` is
+    /// empty, mirrors matching none of the `only` globs
+    pub(crate) fn with_filters(mut self, exclude: &[String], only: &[String]) -> Result<Self> {
+        self.exclude = build_glob_set(exclude)?;
+        self.only = build_glob_set(only)?;
+        Ok(self)
+    }
+
+    /// Rewrite mirror urls with the first matching rewrite
+    pub(crate) fn with_rewrites(mut self, rewrites: Vec<MirrorRewrite>) -> Self {
+        self.rewrites = rewrites;
+        self
+    }
+
+    pub(crate) fn allows_http(&self) -> bool {
+        self.allow_http
+    }
+
+    fn matches(globs: &GlobSet, url: &url::Url) -> bool {
+        globs.is_match(url.as_str()) || url.host_str().is_some_and(|host| globs.is_match(host))
+    }
+
+    /// The rewritten urls which are not excluded, regardless of their scheme
+    pub(crate) fn candidates<'a>(
+        &self,
+        urls: &'a [metalink::FileUrl],
+    ) -> Vec<(url::Url, &'a metalink::FileUrl)> {
+        urls.iter()
+            .map(|file_url| {
+                let url = file_url.url();
+                let url = self
+                    .rewrites
+                    .iter()
+                    .find_map(|rewrite| rewrite.apply(&url))
+                    .unwrap_or(url);
+                (url, file_url)
+            })
+            .filter(|(url, _)| !Self::matches(&self.exclude, url))
+            .filter(|(url, _)| self.only.is_empty() || Self::matches(&self.only, url))
+            .collect()
+    }
+
+    /// The usable urls ordered by preference, urls of the same preference
+    /// keep their order in the metalink
+    pub(crate) fn select(&self, urls: &[metalink::FileUrl]) -> Vec<url::Url> {
+        let mut urls: Vec<(url::Url, &metalink::FileUrl)> = self
+            .candidates(urls)
+            .into_iter()
+            .filter(|(url, _)| self.allow_http || !is_insecure(url))
+            .collect();
+        urls.sort_by_key(|(url, file_url)| {
+            let location = file_url
+                .location()
+                .and_then(|location| {
+                    self.locations
+                        .iter()
+                        .position(|preferred| preferred.eq_ignore_ascii_case(location.alpha2()))
+                })
+                .unwrap_or(self.locations.len());
+            (url.scheme() != "file", location)
+        });
+        urls.into_iter().map(|(url, _)| url).collect()
+    }
+
+    /// Why none of `urls` is selected
+    pub(crate) fn rejection(&self, urls: &[metalink::FileUrl]) -> &'static str {
+        if self.candidates(urls).is_empty() {
+            "all mirrors are excluded by --exclude-mirror or --only-mirror"
+        } else {
+            "all mirrors use plain http, use --allow-http to download from them"
+        }
+    }
+}
+
+/// Where the files of a metalink are placed inside the target directory. By
+/// default the directory structure of the file names is reproduced.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Layout {
+    /// Place all files directly in the target directory
+    flatten: bool,
+    /// Number of leading directories removed from the file names
+    strip_components: usize,
+}
+
+impl Layout {
+    pub(crate) fn new(flatten: bool, strip_components: usize) -> Self {
+        Self {
+            flatten,
+            strip_components,
+        }
+    }
+
+    /// The path of the file `name` relative to the target directory. Names
+    /// which are absolute or contain "." or ".." are rejected, they could
+    /// place the file outside of the target directory.
+    pub(crate) fn relative_path(&self, name: &str) -> Result<PathBuf> {
+        let components: Vec<_> = std::path::Path::new(name).components().collect();
+        if !components
+            .iter()
+            .all(|component| matches!(component, std::path::Component::Normal(_)))
+        {
+            return Err(MetalinkDownloadError::UnsafeFileName {
+                name: name.to_owned(),
+            });
+        }
+        let path: PathBuf = if self.flatten {
+            components.last().into_iter().collect()
+        } else {
+            components.into_iter().skip(self.strip_components).collect()
+        };
+        if path.as_os_str().is_empty() {
+            return Err(MetalinkDownloadError::Other(anyhow::anyhow!(
+                "{name} has no path left after stripping {} components",
+                self.strip_components
+            )));
+        }
+        Ok(path)
+    }
+}
+
+/// Whether `url` is transferred without encryption
+pub(crate) fn is_insecure(url: &url::Url) -> bool {
+    url.scheme() == "http"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_flattens_and_strips_directories() {
+        let name = "project-1.0/images/netinst.iso";
+        assert_eq!(
+            Layout::default().relative_path(name).unwrap(),
+            PathBuf::from(name)
+        );
+        assert_eq!(
+            Layout::new(false, 1).relative_path(name).unwrap(),
+            PathBuf::from("images/netinst.iso")
+        );
+        assert_eq!(
+            Layout::new(true, 0).relative_path(name).unwrap(),
+            PathBuf::from("netinst.iso")
+        );
+        assert!(Layout::new(false, 3).relative_path(name).is_err());
+    }
+
+    #[test]
+    fn layout_rejects_names_leaving_the_target_directory() {
+        for name in ["../../.bashrc", "/etc/cron.d/x", "images/../../x", "./x"] {
+            for layout in [
+                Layout::default(),
+                Layout::new(true, 0),
+                Layout::new(false, 1),
+            ] {
+                assert!(matches!(
+                    layout.relative_path(name),
+                    Err(MetalinkDownloadError::UnsafeFileName { .. })
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn selection_matches_globs() {
+        let selection =
+            RefreshSelection::new(&["docs/**".to_owned()], &["*.iso".to_owned()]).unwrap();
+        assert!(selection.is_refreshed("docs/a/b.txt"));
+        assert!(!selection.is_refreshed("src/a.txt"));
+        assert!(selection.is_forced("image.iso"));
+        assert!(!selection.is_forced("docs/a/b.txt"));
+        assert!(!RefreshSelection::default().is_forced("image.iso"));
+    }
+
+    #[test]
+    fn filter_applies_includes_names_and_excludes() {
+        assert!(FileFilter::default().matches("any/file"));
+
+        let filter = FileFilter::new(
+            &["*.iso".to_owned()],
+            &["*debug*".to_owned()],
+            &["README".to_owned()],
+        )
+        .unwrap();
+        assert!(filter.matches("images/netinst.iso"));
+        assert!(filter.matches("README"));
+        assert!(!filter.matches("images/netinst-debug.iso"));
+        assert!(!filter.matches("images/netinst.img"));
+
+        let exclude_only = FileFilter::new(&[], &["*.debug".to_owned()], &[]).unwrap();
+        assert!(exclude_only.matches("bin/tool"));
+        assert!(!exclude_only.matches("bin/tool.debug"));
+    }
+
+    #[test]
+    fn filter_selects_by_os_and_language() {
+        use metalink::{FileBuilder, FileUrl, Language, OS};
+
+        let file = |oses: Vec<OS>, languages: Vec<Language>| {
+            FileBuilder::new()
+                .with_name("setup")
+                .with_oses(oses)
+                .with_languages(languages)
+                .with_urls(vec![FileUrl::new(
+                    url::Url::parse("https://example.com/setup").unwrap(),
+                    None,
+                    None,
+                )])
+                .build()
+                .unwrap()
+        };
+        let filter = FileFilter::default()
+            .with_oses(vec![OperatingSystemName::Linux])
+            .with_languages(vec!["en".to_owned()]);
+
+        assert!(filter.matches_file(&file(vec![], vec![])));
+        assert!(filter.matches_file(&file(
+            vec![OS::new(OperatingSystemName::Linux)],
+            vec![Language::new("en-US")]
+        )));
+        assert!(!filter.matches_file(&file(
+            vec![OS::new(OperatingSystemName::Win32)],
+            vec![Language::new("en")]
+        )));
+        assert!(!filter.matches_file(&file(vec![], vec![Language::new("eng")])));
+    }
+
+    #[test]
+    fn invalid_globs_are_rejected() {
+        assert!(RefreshSelection::new(&["a[".to_owned()], &[]).is_err());
+    }
+
+    #[test]
+    fn mirrors_are_rewritten_and_filtered() {
+        use metalink::FileUrl;
+
+        let url = |xml: &str| xml.parse::<FileUrl>().unwrap();
+        let urls = vec![
+            url("<url>https://old.example.com/pub/file</url>"),
+            url("<url>https://cdn.example.org/file</url>"),
+            url("<url>http://plain.example.net/file</url>"),
+        ];
+        let rewrite =
+            MirrorRewrite::parse("https://old.example.com/pub=>https://mirror.local/pub").unwrap();
+        let selection = MirrorSelection::default()
+            .with_rewrites(vec![rewrite])
+            .with_filters(&["cdn.example.org".to_owned()], &[])
+            .unwrap();
+        assert_eq!(
+            selection.select(&urls),
+            [url::Url::parse("https://mirror.local/pub/file").unwrap()]
+        );
+
+        let only = MirrorSelection::default()
+            .with_filters(&[], &["*.example.net".to_owned()])
+            .unwrap();
+        assert!(only.select(&urls).is_empty());
+        assert!(only.rejection(&urls).contains("--allow-http"));
+        let none = MirrorSelection::default()
+            .with_filters(&[], &["https://internal/*".to_owned()])
+            .unwrap();
+        assert!(none.rejection(&urls).contains("--only-mirror"));
+        assert!(MirrorRewrite::parse("https://a=>").is_err());
+    }
+
+    #[test]
+    fn mirrors_in_preferred_locations_come_first() {
+        use metalink::FileUrl;
+
+        let url = |xml: &str| xml.parse::<FileUrl>().unwrap();
+        let urls = vec![
+            url(r#"<url location="us">https://us.example.com/file</url>"#),
+            url("<url>https://any.example.com/file</url>"),
+            url(r#"<url location="fr">http://plain.example.com/file</url>"#),
+            url(r#"<url location="de">https://de.example.com/file</url>"#),
+            url(r#"<url location="fr">https://fr.example.com/file</url>"#),
+        ];
+        let hosts = |preference: MirrorSelection| -> Vec<String> {
+            preference
+                .select(&urls)
+                .iter()
+                .map(|url| url.host_str().unwrap().to_owned())
+                .collect()
+        };
+        assert_eq!(
+            hosts(MirrorSelection::new(vec!["fr".to_owned(), "DE".to_owned()])),
+            [
+                "fr.example.com",
+                "de.example.com",
+                "us.example.com",
+                "any.example.com"
+            ]
+        );
+        assert_eq!(hosts(MirrorSelection::default())[0], "us.example.com");
+        assert_eq!(
+            hosts(MirrorSelection::new(vec!["fr".to_owned()]).with_http(true))[..2],
+            ["plain.example.com", "fr.example.com"]
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Graceful shutdown on SIGINT and SIGTERM.
+//!
+//! The first signal stops scheduling new chunks, the chunks in flight are
+//! still written and the files flushed. The data on disk is the resume state:
+//! the next run re-validates the pieces and only downloads what is missing.
+//! A second signal exits immediately.
+
+use std::sync::{Once, OnceLock};
+use tokio_util::sync::CancellationToken;
+
+/// Exit code of a run stopped by a second signal
+const EXIT_ABORTED: i32 = 130;
+
+static SHUTDOWN: OnceLock<CancellationToken> = OnceLock::new();
+static HANDLER: Once = Once::new();
+
+fn token() -> &'static CancellationToken {
+    SHUTDOWN.get_or_init(CancellationToken::new)
+}
+
+/// Install the signal handler, only the first call has an effect
+pub(crate) fn install_handler() {
+    HANDLER.call_once(|| {
+        tokio::spawn(async {
+            if let Err(e) = signal().await {
+                tracing::warn!("Failed to install the signal handler: {e}");
+                return;
+            }
+            tracing::info!("Shutdown requested");
+            eprintln!("Stopping after the chunks in flight, interrupt again to abort immediately");
+            token().cancel();
+            if signal().await.is_ok() {
+                std::process::exit(EXIT_ABORTED);
+            }
+        });
+    });
+}
+
+/// Whether a shutdown was requested, no new chunks must be scheduled then
+pub(crate) fn is_requested() -> bool {
+    SHUTDOWN.get().is_some_and(CancellationToken::is_cancelled)
+}
+
+/// Wait until a shutdown is requested
+pub(crate) async fn requested() {
+    token().cancelled().await
+}
+
+/// The command line of this run, to be shown as a hint how to resume it
+pub(crate) fn resume_command() -> String {
+    std::env::args()
+        .map(|arg| {
+            if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"') {
+                format!("{arg:?}")
+            } else {
+                arg
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(unix)]
+async fn signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
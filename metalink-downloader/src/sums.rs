@@ -0,0 +1,301 @@
+//! Checksum files like `SHA256SUMS` covering the files of a download.
+//!
+//! Every line holds the checksum, two spaces and the path relative to the
+//! target directory, the format of `sha256sum`, so consumers check the files
+//! with `sha256sum -c` without the metalink. If a signing key is configured
+//! an ASCII armored detached signature is written next to it, e.g.
+//! `SHA256SUMS.asc`.
+//!
+//! Checksum files shipped by upstream are read in the same format or the
+//! tagged format of `sha256sum --tag`, `SHA256 (path) = checksum`.
+
+use crate::commands::detached_signature;
+use crate::types::{hash_threads, CheckSum, SUPPORTED_HASH_TYPES};
+use crate::{MetalinkDownloadError, Result};
+
+use anyhow::{anyhow, Context};
+use futures::StreamExt;
+use iana_registry_enums::HashFunctionTextualName;
+use std::path::{Path, PathBuf};
+
+/// The key checksum files are signed with
+#[derive(Debug, Clone)]
+pub(crate) struct Signing {
+    /// Key id or user id of the private key in the gpg keyring
+    pub key: String,
+    pub gpg: PathBuf,
+}
+
+/// Name of the checksum file of `hash_type`, e.g. `SHA256SUMS` for
+/// `sha-256`. Fails for hash types which can not be calculated.
+pub(crate) fn file_name(hash_type: HashFunctionTextualName) -> Result<String> {
+    if !SUPPORTED_HASH_TYPES.contains(&hash_type) {
+        return Err(anyhow!("Unsupported checksum file hash type: {hash_type}").into());
+    }
+    Ok(format!("{}SUMS", label(hash_type)))
+}
+
+/// Hash type of a checksum file named like `SHA256SUMS` or `sha1sums.txt`
+pub(crate) fn hash_type_of(path: &Path) -> Option<HashFunctionTextualName> {
+    let name = path.file_name()?.to_string_lossy().to_uppercase();
+    let (prefix, _) = name.split_once("SUM")?;
+    from_label(prefix.trim_end_matches(['.', '-', '_']))
+}
+
+/// A file listed in a checksum file
+#[derive(Debug, PartialEq)]
+pub(crate) struct Sum {
+    /// Path of the file relative to the directory of the checksum file
+    pub name: String,
+    pub checksum: CheckSum,
+}
+
+/// Parse the lines of a checksum file. Untagged lines are of `hash_type`, or
+/// guessed from the length of their checksum if it is None.
+pub(crate) fn parse(content: &str, hash_type: Option<HashFunctionTextualName>) -> Result<Vec<Sum>> {
+    let mut sums = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || anyhow!("Invalid line {} of checksum file: {line:?}", i + 1);
+        // Names containing backslashes or newlines are escaped and the line
+        // is prefixed with a backslash
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let (hash_type, checksum, name) = match tagged(line) {
+            Some((hash_type, name, checksum)) => (hash_type, checksum, name),
+            None => {
+                let (checksum, name) = line.split_once(' ').ok_or_else(invalid)?;
+                let name = name
+                    .strip_prefix(' ')
+                    .or_else(|| name.strip_prefix('*'))
+                    .ok_or_else(invalid)?;
+                let hash_type =
+                    hash_type
+                        .or_else(|| guess_hash_type(checksum))
+                        .ok_or_else(|| {
+                            anyhow!("Unknown hash type of line {} of checksum file", i + 1)
+                        })?;
+                (hash_type, checksum, name)
+            }
+        };
+        if hash_type.digest_length() != Some(checksum.len() / 2)
+            || !checksum.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return Err(anyhow!("Invalid {hash_type} checksum in line {}", i + 1).into());
+        }
+        let name = if escaped {
+            unescape(name).ok_or_else(invalid)?
+        } else {
+            name.to_owned()
+        };
+        sums.push(Sum {
+            name,
+            checksum: CheckSum::new(hash_type, checksum.to_ascii_lowercase()),
+        });
+    }
+    Ok(sums)
+}
+
+/// Split a line like `SHA256 (path) = checksum`
+fn tagged(line: &str) -> Option<(HashFunctionTextualName, &str, &str)> {
+    let (tag, rest) = line.split_once(" (")?;
+    let (name, checksum) = rest.rsplit_once(") = ")?;
+    Some((from_label(tag)?, name, checksum))
+}
+
+/// The hash type with the most common checksum of `checksum`'s length
+fn guess_hash_type(checksum: &str) -> Option<HashFunctionTextualName> {
+    [
+        HashFunctionTextualName::Md5,
+        HashFunctionTextualName::Sha1,
+        HashFunctionTextualName::Sha224,
+        HashFunctionTextualName::Sha256,
+        HashFunctionTextualName::Sha384,
+        HashFunctionTextualName::Sha512,
+    ]
+    .into_iter()
+    .find(|hash_type| hash_type.digest_length() == Some(checksum.len() / 2))
+}
+
+fn unescape(name: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => unescaped.push('\n'),
+                '\\' => unescaped.push('\\'),
+                _ => return None,
+            },
+            c => unescaped.push(c),
+        }
+    }
+    Some(unescaped)
+}
+
+/// How coreutils name `hash_type`, e.g. `SHA256` or `SHA3-512`
+fn label(hash_type: HashFunctionTextualName) -> String {
+    hash_type
+        .to_string()
+        .to_uppercase()
+        .replacen("SHA-", "SHA", 1)
+}
+
+fn from_label(label: &str) -> Option<HashFunctionTextualName> {
+    SUPPORTED_HASH_TYPES
+        .iter()
+        .copied()
+        .find(|hash_type| self::label(*hash_type).eq_ignore_ascii_case(label))
+}
+
+/// Write the checksum file of `hash_type` covering `files` into `target_dir`
+/// and sign it with `signing`. Returns the path of the checksum file.
+pub(crate) async fn write(
+    target_dir: &Path,
+    hash_type: HashFunctionTextualName,
+    mut files: Vec<PathBuf>,
+    signing: Option<&Signing>,
+) -> Result<PathBuf> {
+    files.sort();
+    files.dedup();
+    let lines: Vec<String> = futures::stream::iter(files)
+        .map(|file| {
+            let target_dir = target_dir.to_path_buf();
+            tokio::task::spawn_blocking(move || line(&target_dir, &file, hash_type))
+        })
+        .buffered(hash_threads())
+        .map(|line| line.with_context(|| "Hashing task failed")?)
+        .collect::<Vec<Result<String>>>()
+        .await
+        .into_iter()
+        .collect::<Result<_>>()?;
+
+    let path = target_dir.join(file_name(hash_type)?);
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    std::fs::write(&partial, lines.concat())
+        .with_context(|| format!("Failed to write checksum file {partial:?}"))?;
+    std::fs::rename(&partial, &path)
+        .with_context(|| format!("Failed to write checksum file {path:?}"))?;
+
+    if let Some(Signing { key, gpg }) = signing {
+        let signature = tokio::task::spawn_blocking({
+            let (gpg, key, path) = (gpg.clone(), key.clone(), path.clone());
+            move || detached_signature(&gpg, &key, &path)
+        })
+        .await
+        .with_context(|| "Signing task failed")??;
+        let mut signature_file = path.as_os_str().to_owned();
+        signature_file.push(".asc");
+        std::fs::write(&signature_file, signature)
+            .with_context(|| format!("Failed to write signature {signature_file:?}"))?;
+    }
+    Ok(path)
+}
+
+/// The line of `file` in a checksum file in `target_dir`
+fn line(target_dir: &Path, file: &Path, hash_type: HashFunctionTextualName) -> Result<String> {
+    let relative = file.strip_prefix(target_dir).unwrap_or(file);
+    let name = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    // The format has no way to list a name spanning several lines
+    if name.contains('\n') {
+        return Err(MetalinkDownloadError::Other(anyhow!(
+            "{file:?} can not be listed in a checksum file"
+        )));
+    }
+    let checksum = CheckSum::new(hash_type, String::new()).calculate_file_checksum(file)?;
+    Ok(format!("{checksum}  {name}\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn checksum_files_list_relative_paths() {
+        assert_eq!(
+            file_name(HashFunctionTextualName::Sha256).unwrap(),
+            "SHA256SUMS"
+        );
+        assert_eq!(file_name(HashFunctionTextualName::Md5).unwrap(), "MD5SUMS");
+        assert_eq!(
+            file_name(HashFunctionTextualName::Sha3_512).unwrap(),
+            "SHA3-512SUMS"
+        );
+
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub").join("b.txt"), b"abc").unwrap();
+        std::fs::write(dir.join("a.txt"), b"").unwrap();
+
+        let path = write(
+            dir,
+            HashFunctionTextualName::Sha256,
+            vec![dir.join("sub").join("b.txt"), dir.join("a.txt")],
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(path, dir.join("SHA256SUMS"));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  a.txt\n\
+             ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  sub/b.txt\n"
+        );
+    }
+
+    #[test]
+    fn checksum_files_are_parsed() {
+        assert_eq!(
+            hash_type_of(Path::new("/srv/SHA256SUMS")),
+            Some(HashFunctionTextualName::Sha256)
+        );
+        assert_eq!(
+            hash_type_of(Path::new("sha3-512sums.txt")),
+            Some(HashFunctionTextualName::Sha3_512)
+        );
+        assert_eq!(hash_type_of(Path::new("CHECKSUMS")), None);
+
+        let sha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let md5 = "d41d8cd98f00b204e9800998ecf8427e";
+        let content = format!(
+            "# comment\n\
+             {sha256}  a b.txt\n\
+             {} *sub/c.iso\n\
+             \\{sha256}  new\\nline\n\
+             MD5 (d (1).txt) = {md5}\n",
+            sha256.to_uppercase()
+        );
+        let sums = parse(&content, None).unwrap();
+        let names: Vec<&str> = sums.iter().map(|sum| sum.name.as_str()).collect();
+        assert_eq!(names, ["a b.txt", "sub/c.iso", "new\nline", "d (1).txt"]);
+        assert_eq!(
+            sums[0].checksum,
+            CheckSum::new(HashFunctionTextualName::Sha256, sha256.to_owned())
+        );
+        assert_eq!(sums[1].checksum.expected(), sha256);
+        assert_eq!(sums[3].checksum.hash_type(), HashFunctionTextualName::Md5);
+
+        // The hash type of the file name takes precedence over the length
+        assert!(parse(
+            &format!("{sha256}  a\n"),
+            Some(HashFunctionTextualName::Sha3_256)
+        )
+        .is_ok());
+        assert!(parse(
+            &format!("{md5}  a\n"),
+            Some(HashFunctionTextualName::Sha256)
+        )
+        .is_err());
+        assert!(parse("xyz  a\n", None).is_err());
+    }
+}
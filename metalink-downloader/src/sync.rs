@@ -0,0 +1,79 @@
+//! Mirroring mode re-syncing a metalink published at a url.
+//!
+//! Every sync fetches the metalink again, from its dynamic origin if it
+//! declares one, plans the download against the target directory and only
+//! downloads the files which are missing or changed. A failed sync is
+//! reported to the hooks and retried with the next sync.
+
+use crate::commands::{self, DownloadMetalinkOptions};
+use crate::hooks::Hooks;
+use crate::remote::MetalinkSource;
+use crate::{shutdown, JobControl, MetalinkDownloadError, Result};
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Sync `sources` into `target_dir` every `interval` until interrupted
+pub(crate) async fn sync(
+    sources: Vec<MetalinkSource>,
+    target_dir: PathBuf,
+    options: DownloadMetalinkOptions,
+    interval: Duration,
+    hooks: &Hooks,
+) -> Result<()> {
+    shutdown::install_handler();
+    loop {
+        tracing::info!("Syncing {sources:?} into {target_dir:?}");
+        let report = commands::download_metalink(
+            sources.clone(),
+            target_dir.clone(),
+            DownloadMetalinkOptions {
+                control: JobControl::default(),
+                ..options.clone()
+            },
+        )
+        .await;
+        if let Err(e @ MetalinkDownloadError::Interrupted { .. }) = report {
+            return Err(e);
+        }
+        hooks.finished(&report).await;
+        match report {
+            Ok(report) => {
+                if let Some(e) = report.failure() {
+                    eprintln!("Sync failed: {e}");
+                }
+            }
+            Err(e) => eprintln!("Sync failed: {e}"),
+        }
+
+        eprintln!("Syncing again in {}", format_interval(interval));
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = shutdown::requested() => return Ok(()),
+        }
+    }
+}
+
+/// `interval` in the largest unit `--sync-interval` accepts which divides it
+fn format_interval(interval: Duration) -> String {
+    let secs = interval.as_secs();
+    match secs {
+        _ if interval.subsec_nanos() != 0 => format!("{}ms", interval.as_millis()),
+        _ if secs.is_multiple_of(3600) => format!("{}h", secs / 3600),
+        _ if secs.is_multiple_of(60) => format!("{}m", secs / 60),
+        _ => format!("{secs}s"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals_are_formatted_like_they_are_given() {
+        assert_eq!(format_interval(Duration::from_secs(6 * 3600)), "6h");
+        assert_eq!(format_interval(Duration::from_secs(90 * 60)), "90m");
+        assert_eq!(format_interval(Duration::from_secs(45)), "45s");
+        assert_eq!(format_interval(Duration::from_millis(1500)), "1500ms");
+    }
+}
@@ -0,0 +1,105 @@
+//! Tracing of the command line into `log/output.log`.
+//!
+//! Files are traced in `download_file` spans, their chunks in `chunk` spans
+//! and their verification in `verify` spans. Closed spans are logged with
+//! their busy and idle time, so slow stages of long downloads show up in the
+//! log. `RUST_LOG` overrides the default `debug` level.
+
+use crate::{Cli, Result};
+
+use anyhow::Context;
+use std::sync::Mutex;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// File the command line traces into
+const LOG_FILE: &str = "log/output.log";
+
+/// Format of the lines of the log file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TraceFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// A JSON object per line including the fields of the enclosing spans
+    Json,
+}
+
+/// Keeps exporting spans until dropped, dropping it flushes the spans not
+/// exported yet
+pub struct TelemetryGuard {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to export the remaining spans: {e}");
+            }
+        }
+    }
+}
+
+/// Install the global subscriber configured by the arguments of `cli`.
+/// Records of the `log` crate, e.g. of reqwest, are traced as well.
+pub fn init(cli: &Cli) -> Result<TelemetryGuard> {
+    std::fs::create_dir_all("log").with_context(|| "Failed to create the log directory")?;
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(LOG_FILE)
+        .with_context(|| format!("Failed to open {LOG_FILE}"))?;
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(Mutex::new(file))
+        .with_ansi(false)
+        .with_span_events(FmtSpan::CLOSE);
+    let fmt = match cli.trace_format {
+        TraceFormat::Text => fmt.boxed(),
+        TraceFormat::Json => fmt.json().with_span_list(true).boxed(),
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("debug"));
+    let registry = tracing_subscriber::registry().with(filter).with(fmt);
+
+    #[cfg(feature = "otlp")]
+    let (registry, provider) = {
+        let provider = cli.otlp_endpoint.as_ref().map(otlp_provider).transpose()?;
+        let otlp = provider.as_ref().map(|provider| {
+            use opentelemetry::trace::TracerProvider as _;
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("metalink-downloader"))
+        });
+        (registry.with(otlp), provider)
+    };
+
+    registry
+        .try_init()
+        .with_context(|| "Failed to init tracing")?;
+    Ok(TelemetryGuard {
+        #[cfg(feature = "otlp")]
+        provider,
+    })
+}
+
+/// Export spans in batches to the OTLP/HTTP collector at `endpoint`
+#[cfg(feature = "otlp")]
+fn otlp_provider(endpoint: &url::Url) -> Result<opentelemetry_sdk::trace::SdkTracerProvider> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint.as_str())
+        .build()
+        .with_context(|| format!("Failed to export spans to {endpoint}"))?;
+    Ok(opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            opentelemetry_sdk::Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build())
+}
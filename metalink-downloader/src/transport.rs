@@ -0,0 +1,318 @@
+//! Extension point for fetching files over other protocols than http, e.g.
+//! FTP, S3 or BitTorrent, without changing the download engine.
+//!
+//! The engine splits files into pieces, verifies them and writes them to
+//! disk; a transport only fetches bytes. Urls no registered transport
+//! supports are fetched with the built-in reqwest transport.
+
+use crate::Result;
+
+use futures::future::BoxFuture;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What a transport knows about a file before fetching it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Probe {
+    /// Size of the file, None if the transport can not tell
+    pub size: Option<u64>,
+    /// Whether ranges of the file can be fetched with `fetch_range`
+    pub accepts_ranges: bool,
+}
+
+/// Bytes fetched by a transport
+#[derive(Debug, Clone)]
+pub struct Fetched {
+    pub bytes: bytes::Bytes,
+    /// The url the bytes were served from, differs from the requested url
+    /// after redirects
+    pub url: url::Url,
+}
+
+/// Fetches the bytes of the files of a metalink.
+///
+/// A transport which can not serve a requested range fails with
+/// [`MetalinkDownloadError::InvalidRangeResponse`](crate::MetalinkDownloadError::InvalidRangeResponse),
+/// the engine then falls back to fetching the whole file.
+pub trait Transport: Send + Sync {
+    /// Returns whether the transport can fetch `url`, usually decided by its
+    /// scheme
+    fn supports(&self, url: &url::Url) -> bool;
+
+    /// Determine the size of the file behind `url` and whether ranges of it
+    /// can be fetched
+    fn probe<'a>(&'a self, url: &'a url::Url) -> BoxFuture<'a, Result<Probe>>;
+
+    /// Fetch the bytes `start..=end` of the file behind `url`
+    fn fetch_range<'a>(
+        &'a self,
+        url: &'a url::Url,
+        start: u64,
+        end: u64,
+    ) -> BoxFuture<'a, Result<Fetched>>;
+
+    /// Fetch the whole file behind `url` and store it at `target_file`.
+    /// Returns the url the file was served from.
+    fn fetch_whole<'a>(
+        &'a self,
+        url: &'a url::Url,
+        target_file: &'a Path,
+    ) -> BoxFuture<'a, Result<url::Url>>;
+}
+
+/// The registered transports
+#[derive(Clone, Default)]
+pub(crate) struct Transports(Vec<Arc<dyn Transport>>);
+
+impl Transports {
+    pub(crate) fn push(&mut self, transport: Arc<dyn Transport>) {
+        self.0.push(transport);
+    }
+
+    /// The first registered transport supporting `url`, None if `url` is
+    /// fetched with the built-in transport
+    pub(crate) fn find(&self, url: &url::Url) -> Option<Arc<dyn Transport>> {
+        self.0
+            .iter()
+            .find(|transport| transport.supports(url))
+            .cloned()
+    }
+}
+
+/// A transport serving a file from memory, so the download engine is tested
+/// without a server and recorded replay logs are reproduced, see
+/// [`crate::replay::reproduce`]
+pub(crate) mod mock {
+    use super::*;
+    use crate::replay::FetchOutcome;
+    use crate::MetalinkDownloadError;
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::Mutex;
+
+    /// A request received by [`MockTransport`]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) enum Request {
+        Range(u64, u64),
+        Whole,
+    }
+
+    pub(crate) struct MockTransport {
+        data: bytes::Bytes,
+        accepts_ranges: bool,
+        /// Answer range requests with something else than the range
+        broken_ranges: bool,
+        /// How often the range starting at the key is served corrupted
+        corrupt: Mutex<HashMap<u64, u32>>,
+        /// How often requests for the range starting at the key fail
+        failing: Mutex<HashMap<u64, u32>>,
+        /// Outcomes of the next requests for the range starting at the key
+        script: Mutex<HashMap<u64, VecDeque<FetchOutcome>>>,
+        /// The range starting at the first value is only served once this
+        /// many requests were received
+        stalled: Option<(u64, usize)>,
+        requests: Mutex<Vec<Request>>,
+    }
+
+    impl MockTransport {
+        #[cfg(test)]
+        pub(crate) fn new(data: &'static [u8]) -> Self {
+            Self::from_bytes(bytes::Bytes::from_static(data))
+        }
+
+        pub(crate) fn from_bytes(data: bytes::Bytes) -> Self {
+            Self {
+                data,
+                accepts_ranges: true,
+                broken_ranges: false,
+                corrupt: Mutex::new(HashMap::new()),
+                failing: Mutex::new(HashMap::new()),
+                script: Mutex::new(HashMap::new()),
+                stalled: None,
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+
+        #[cfg(test)]
+        /// Claim range support but answer range requests wrongly
+        pub(crate) fn with_broken_ranges(mut self) -> Self {
+            self.broken_ranges = true;
+            self
+        }
+
+        #[cfg(test)]
+        /// Serve the range starting at `start` corrupted `times` times
+        pub(crate) fn with_corrupt_range(self, start: u64, times: u32) -> Self {
+            self.corrupt.lock().unwrap().insert(start, times);
+            self
+        }
+
+        #[cfg(test)]
+        /// Fail requests for the range starting at `start` `times` times
+        pub(crate) fn with_failing_range(self, start: u64, times: u32) -> Self {
+            self.failing.lock().unwrap().insert(start, times);
+            self
+        }
+
+        #[cfg(test)]
+        /// Hold back the range starting at `start` until `requests` requests
+        /// were received
+        pub(crate) fn with_stalled_range(mut self, start: u64, requests: usize) -> Self {
+            self.stalled = Some((start, requests));
+            self
+        }
+
+        /// Answer the next requests for the range starting at `start` with
+        /// `outcomes` in order, a checksum mismatch is served as corrupt data
+        pub(crate) fn with_script(self, start: u64, outcomes: Vec<FetchOutcome>) -> Self {
+            self.script.lock().unwrap().insert(start, outcomes.into());
+            self
+        }
+
+        #[cfg(test)]
+        pub(crate) fn requests(&self) -> Vec<Request> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn supports(&self, url: &url::Url) -> bool {
+            url.scheme() == "mock"
+        }
+
+        fn probe<'a>(&'a self, _: &'a url::Url) -> BoxFuture<'a, Result<Probe>> {
+            Box::pin(async {
+                Ok(Probe {
+                    size: Some(self.data.len() as u64),
+                    accepts_ranges: self.accepts_ranges,
+                })
+            })
+        }
+
+        fn fetch_range<'a>(
+            &'a self,
+            url: &'a url::Url,
+            start: u64,
+            end: u64,
+        ) -> BoxFuture<'a, Result<Fetched>> {
+            Box::pin(async move {
+                self.requests
+                    .lock()
+                    .unwrap()
+                    .push(Request::Range(start, end));
+                if let Some((_, requests)) = self.stalled.filter(|(stalled, _)| *stalled == start) {
+                    while self.requests.lock().unwrap().len() < requests {
+                        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                    }
+                }
+                if self.broken_ranges {
+                    return Err(MetalinkDownloadError::InvalidRangeResponse {
+                        url: url.clone(),
+                        reason: "status 200 OK instead of 206 Partial Content".to_owned(),
+                    });
+                }
+                if let Some(times) = self.failing.lock().unwrap().get_mut(&start) {
+                    if *times > 0 {
+                        *times -= 1;
+                        return Err(MetalinkDownloadError::Other(anyhow::anyhow!(
+                            "connection reset by {url}"
+                        )));
+                    }
+                }
+                let scripted = self
+                    .script
+                    .lock()
+                    .unwrap()
+                    .get_mut(&start)
+                    .and_then(VecDeque::pop_front);
+                if scripted == Some(FetchOutcome::Failed) {
+                    return Err(MetalinkDownloadError::Other(anyhow::anyhow!(
+                        "scripted failure of {url}"
+                    )));
+                }
+                let mut bytes = self.data.slice(start as usize..=end as usize);
+                let mut corrupt = scripted == Some(FetchOutcome::ChecksumMismatch);
+                if let Some(times) = self.corrupt.lock().unwrap().get_mut(&start) {
+                    if *times > 0 {
+                        *times -= 1;
+                        corrupt = true;
+                    }
+                }
+                if corrupt {
+                    bytes = bytes::Bytes::from(vec![0; bytes.len()]);
+                }
+                Ok(Fetched {
+                    bytes,
+                    url: url.clone(),
+                })
+            })
+        }
+
+        fn fetch_whole<'a>(
+            &'a self,
+            url: &'a url::Url,
+            target_file: &'a Path,
+        ) -> BoxFuture<'a, Result<url::Url>> {
+            Box::pin(async move {
+                self.requests.lock().unwrap().push(Request::Whole);
+                std::fs::write(target_file, &self.data)?;
+                Ok(url.clone())
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FtpTransport;
+
+    impl Transport for FtpTransport {
+        fn supports(&self, url: &url::Url) -> bool {
+            url.scheme() == "ftp"
+        }
+
+        fn probe<'a>(&'a self, _: &'a url::Url) -> BoxFuture<'a, Result<Probe>> {
+            Box::pin(async {
+                Ok(Probe {
+                    size: None,
+                    accepts_ranges: false,
+                })
+            })
+        }
+
+        fn fetch_range<'a>(
+            &'a self,
+            url: &'a url::Url,
+            _: u64,
+            _: u64,
+        ) -> BoxFuture<'a, Result<Fetched>> {
+            Box::pin(async {
+                Ok(Fetched {
+                    bytes: bytes::Bytes::new(),
+                    url: url.clone(),
+                })
+            })
+        }
+
+        fn fetch_whole<'a>(
+            &'a self,
+            url: &'a url::Url,
+            _: &'a Path,
+        ) -> BoxFuture<'a, Result<url::Url>> {
+            Box::pin(async { Ok(url.clone()) })
+        }
+    }
+
+    #[test]
+    fn find_returns_transport_supporting_the_url() {
+        let mut transports = Transports::default();
+        let ftp = url::Url::parse("ftp://example.com/file").unwrap();
+        let https = url::Url::parse("https://example.com/file").unwrap();
+        assert!(transports.find(&ftp).is_none());
+
+        transports.push(Arc::new(FtpTransport));
+        assert!(transports.find(&ftp).is_some());
+        assert!(transports.find(&https).is_none());
+    }
+}
@@ -1,11 +1,27 @@
-use anyhow::anyhow;
+//! The download plan and the pieces it is made of, shared by all commands.
+//!
+//! [`Plan`], [`FilePlan`], [`ChunkMetaData`] and [`CheckSum`] only exist
+//! here; commands, transports and the library interface build on these
+//! types instead of keeping their own copies.
+
+use anyhow::{anyhow, Context};
 use digest::{generic_array::ArrayLength, Digest, OutputSizeUser};
 use iana_registry_enums::HashFunctionTextualName;
-use log::info;
+use indicatif::{ProgressBar, ProgressStyle};
 use metalink::Metalink;
 use std::io::{Read, Seek};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::info;
 
+use crate::selection::{
+    is_insecure, ConflictDecision, FileFilter, Layout, MirrorSelection, OnConflict,
+    RefreshSelection,
+};
+use crate::verification_cache::VerificationCache;
+use crate::warnings::{self, Warning};
+use crate::writeback;
 use crate::{MetalinkDownloadError, Result};
 
 #[derive(Debug)]
@@ -17,117 +33,571 @@ pub(crate) enum Command {
     FinishWriting,
 }
 
-#[derive(Debug)]
-pub(crate) enum ProgressUpdate {
-    // Download progressed by n bytes
-    Progressed(u64),
-    Finished,
-}
-
 #[derive(Debug, Default)]
 pub struct Plan {
     pub files: Vec<FilePlan>,
     pub total_size: u64,
+    /// Decisions about existing target files without checksums
+    pub conflicts: Vec<(PathBuf, ConflictDecision)>,
+    /// Files created from another file of the plan with the same content
+    pub duplicates: Vec<Duplicate>,
+}
+
+/// A file with the same content as another file of the plan, it is created
+/// from that file once it is downloaded
+#[derive(Debug, Clone)]
+pub struct Duplicate {
+    pub file: FilePlan,
+    /// Target file of the file which is downloaded
+    pub original: PathBuf,
 }
 
 impl Plan {
-    pub fn new(metalink_file: PathBuf, target_dir: &Path) -> Result<Self> {
+    pub(crate) fn new(
+        metalink_file: PathBuf,
+        target_dir: &Path,
+        filter: &FileFilter,
+        mirrors: &MirrorSelection,
+        layout: &Layout,
+    ) -> Result<Self> {
+        Self::from_metalinks(
+            std::slice::from_ref(&metalink_file),
+            target_dir,
+            filter,
+            mirrors,
+            layout,
+        )
+    }
+
+    /// Plan the download of the files of all `metalink_files` into one
+    /// target directory. A file described by several metalinks is downloaded
+    /// once from the urls of all of them.
+    pub(crate) fn from_metalinks(
+        metalink_files: &[PathBuf],
+        target_dir: &Path,
+        filter: &FileFilter,
+        mirrors: &MirrorSelection,
+        layout: &Layout,
+    ) -> Result<Self> {
         let mut files: Vec<FilePlan> = Vec::new();
-        let loaded_metalink = Metalink::load_from_file(metalink_file)?;
-        for file in loaded_metalink.files() {
-            files.push(FilePlan::new(file, target_dir)?);
+        let loaded_metalinks = metalink_files
+            .iter()
+            .map(|metalink_file| load_metalink(metalink_file))
+            .collect::<Result<Vec<_>>>()?;
+        for name in filter.names() {
+            if !loaded_metalinks
+                .iter()
+                .flat_map(Metalink::files)
+                .any(|file| file.name() == name)
+            {
+                return Err(MetalinkDownloadError::Other(anyhow!(
+                    "{name} is not part of the metalink"
+                )));
+            }
+        }
+        let mut insecure: Vec<url::Url> = Vec::new();
+        for loaded_metalink in &loaded_metalinks {
+            let modified = loaded_metalink
+                .updated()
+                .or(loaded_metalink.published())
+                .copied();
+            for file in loaded_metalink.files() {
+                if filter.matches_file(file) {
+                    let all_urls = file.urls().map_or(&[][..], Vec::as_slice);
+                    for (url, _) in mirrors.candidates(all_urls) {
+                        if is_insecure(&url) && !insecure.contains(&url) {
+                            insecure.push(url);
+                        }
+                    }
+                    let file = FilePlan {
+                        modified,
+                        ..FilePlan::new(file, target_dir, mirrors, layout)?
+                    };
+                    match files
+                        .iter_mut()
+                        .find(|planned| planned.target_file == file.target_file)
+                    {
+                        Some(planned) => planned.merge(file)?,
+                        None => files.push(file),
+                    }
+                }
+            }
+        }
+        if !insecure.is_empty() {
+            warnings::warn(Warning::InsecureMirrors {
+                urls: insecure,
+                allowed: mirrors.allows_http(),
+            });
         }
 
         let total_size = files
             .iter()
             .fold(0, |acc, file| acc + file.file_size.unwrap_or(0));
 
-        Ok(Self { files, total_size })
+        Ok(Self {
+            files,
+            total_size,
+            conflicts: Vec::new(),
+            duplicates: Vec::new(),
+        })
     }
 
     /// Shrink the plan so the only files and chunks that need to
-    /// be downloaded are left
-    pub fn minimize_plan(self) -> Result<Plan> {
+    /// be downloaded are left. Files selected by `selection` are
+    /// re-hashed or re-downloaded regardless of their state on disk.
+    ///
+    /// Existing files are validated on `--hash-threads` threads, with
+    /// `show_progress` the share of validated files is shown on stderr.
+    /// Files in `cache` which did not change since their verification are
+    /// not hashed again.
+    pub(crate) fn minimize_plan(
+        self,
+        selection: &RefreshSelection,
+        cache: &VerificationCache,
+        show_progress: bool,
+    ) -> Result<Plan> {
         let mut minimized_plan = Plan::default();
 
-        for file in self.files {
-            if !file.target_file.exists() {
-                minimized_plan.files.push(file);
-            } else if let Some(chunks) = file.chunks {
-                let file_on_disk = std::fs::File::open(&file.target_file)?;
-                let mut minimized_chunks: Vec<ChunkMetaData> = Vec::new();
-                for chunk in chunks {
-                    if !chunk.is_valid_on_disk(&file_on_disk)? {
-                        minimized_chunks.push(chunk);
-                    }
-                }
+        let bar = if show_progress {
+            ProgressBar::new(self.files.len() as u64)
+        } else {
+            ProgressBar::hidden()
+        };
+        bar.set_style(
+            ProgressStyle::with_template("Validating existing files… {percent}% ({pos}/{len})")
+                .expect("valid progress template"),
+        );
+        let workers = hash_threads().min(self.files.len().max(1));
+        let next = AtomicUsize::new(0);
+        let results: Vec<Mutex<Option<Result<MinimizedFile>>>> =
+            self.files.iter().map(|_| Mutex::new(None)).collect();
+        std::thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(file) = self.files.get(index) else {
+                        break;
+                    };
+                    let result = minimize_file(file.clone(), selection, cache);
+                    *results[index].lock().unwrap_or_else(|e| e.into_inner()) = Some(result);
+                    bar.inc(1);
+                });
+            }
+        });
+        bar.finish_and_clear();
 
-                if !minimized_chunks.is_empty() {
-                    minimized_plan.files.push(FilePlan {
-                        target_file: file.target_file,
-                        url: file.url,
-                        file_checksums: file.file_checksums,
-                        chunks: Some(minimized_chunks),
-                        file_size: file.file_size,
-                    });
-                }
-            } else if let Some(checksum) = file.file_checksums.as_ref() {
-                if !checksum.validate_file_checksum(&file.target_file) {
-                    minimized_plan.files.push(file);
-                }
-            } else {
-                // no checksums to validate need to assume that the file is broken an
-                // redownload it
-                minimized_plan.files.push(file);
+        for result in results {
+            let minimized = result
+                .into_inner()
+                .unwrap_or_else(|e| e.into_inner())
+                .expect("every file is minimized")?;
+            minimized_plan.files.extend(minimized.download);
+            minimized_plan.conflicts.extend(minimized.conflict);
+        }
+
+        minimized_plan.total_size = minimized_plan
+            .files
+            .iter()
+            .map(FilePlan::bytes_to_fetch)
+            .sum();
+
+        Ok(minimized_plan)
+    }
+
+    /// Download files sharing their file checksum only once, the others are
+    /// moved to the duplicates. Of each group the file with the least bytes
+    /// left to fetch is downloaded. Weak hashes are not trusted to identify
+    /// files.
+    pub(crate) fn dedupe(mut self) -> Self {
+        let mut groups: Vec<(String, Vec<FilePlan>)> = Vec::new();
+        let mut files = Vec::new();
+        for file in self.files {
+            let key = file
+                .file_checksums
+                .as_ref()
+                .filter(|checksum| !WEAK_HASH_TYPES.contains(&checksum.hash_type()))
+                .map(|checksum| {
+                    format!(
+                        "{}:{}",
+                        checksum.hash_type(),
+                        checksum.expected().to_ascii_lowercase()
+                    )
+                });
+            match key {
+                Some(key) => match groups.iter_mut().find(|(k, _)| *k == key) {
+                    Some((_, group)) => group.push(file),
+                    None => groups.push((key, vec![file])),
+                },
+                None => files.push(file),
             }
         }
+        for (_, mut group) in groups {
+            let index = group
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, file)| file.bytes_to_fetch())
+                .map_or(0, |(index, _)| index);
+            let original = group.remove(index);
+            for file in group {
+                tracing::info!(
+                    "{:?} has the same content as {:?}, downloading it once",
+                    file.target_file,
+                    original.target_file
+                );
+                self.duplicates.push(Duplicate {
+                    file,
+                    original: original.target_file.clone(),
+                });
+            }
+            files.push(original);
+        }
+        self.total_size = files.iter().map(FilePlan::bytes_to_fetch).sum();
+        self.files = files;
+        self
+    }
+}
 
-        // After minimizing the plan total size calculation gets a bit complicated
-        // If we have a file without chunks then we take the file size if the file
-        // has chunks we need to sum up the size of the chunks. As only those parts
-        // will be downloaded
-        let mut total_size: u64 = 0;
+/// What minimizing a single file of a plan resulted in
+struct MinimizedFile {
+    /// The file if anything of it needs to be downloaded
+    download: Option<FilePlan>,
+    /// The decision about an existing file without checksums
+    conflict: Option<(PathBuf, ConflictDecision)>,
+}
+
+impl MinimizedFile {
+    fn download(file: FilePlan) -> Self {
+        Self {
+            download: Some(file),
+            conflict: None,
+        }
+    }
+
+    fn valid() -> Self {
+        Self {
+            download: None,
+            conflict: None,
+        }
+    }
+}
+
+/// Validate the existing data of `file` and decide what of it needs to be
+/// downloaded
+fn minimize_file(
+    file: FilePlan,
+    selection: &RefreshSelection,
+    cache: &VerificationCache,
+) -> Result<MinimizedFile> {
+    if selection.is_forced(&file.name) {
+        info!("Forcing re-download of {}", file.name);
+        return Ok(MinimizedFile::download(file));
+    }
+    if !file.target_file.exists() && !file.part_file().exists() {
+        return Ok(MinimizedFile::download(file));
+    }
+    if let Some(checksum) = file.file_checksums.as_ref() {
+        if selection.trusts_cache(&file.name)
+            && !file.part_file().exists()
+            && cache.is_verified(&file.target_file, checksum)
+        {
+            info!("{:?} is unchanged since its verification", file.target_file);
+            return Ok(MinimizedFile::valid());
+        }
+    }
+    let validate = |checksum: &CheckSum| {
+        let valid = checksum.validate_file_checksum(&file.target_file);
+        if valid {
+            cache.record(&file.target_file, checksum);
+        } else {
+            cache.forget(&file.target_file);
+        }
+        valid
+    };
+    if let Some(chunks) = file.chunks.clone() {
+        // An interrupted download is resumed from its part file
+        let minimized_chunks = invalid_chunks_on_disk(chunks, &file.downloaded_file())?;
 
-        for file in &minimized_plan.files {
-            if let Some(chunks) = file.chunks.as_ref() {
-                for chunk in chunks {
-                    total_size += chunk.chunk_size();
+        return Ok(
+            if minimized_chunks.is_empty() && file.part_file().exists() {
+                // The part file is complete, it only needs to be verified and
+                // renamed to the target
+                MinimizedFile::download(FilePlan {
+                    chunks: Some(minimized_chunks),
+                    ..file
+                })
+            } else if minimized_chunks.is_empty() {
+                // All pieces are valid, which is usually trusted without hashing
+                // the whole file unless a refresh was requested
+                match file.file_checksums.as_ref() {
+                    Some(checksum) if selection.is_refreshed(&file.name) => {
+                        info!("Re-hashing {}", file.name);
+                        if validate(checksum) {
+                            MinimizedFile::valid()
+                        } else {
+                            MinimizedFile::download(file)
+                        }
+                    }
+                    _ => MinimizedFile::valid(),
                 }
             } else {
-                // NOTE: if a file element in the metalink does not have a file size this
-                // might fail
-                total_size += file.file_size.unwrap();
-            }
+                MinimizedFile::download(FilePlan {
+                    chunks: Some(minimized_chunks),
+                    ..file
+                })
+            },
+        );
+    }
+    if !file.target_file.exists() {
+        return Ok(MinimizedFile::download(file));
+    }
+    if let Some(checksum) = file.file_checksums.as_ref() {
+        return Ok(if validate(checksum) {
+            MinimizedFile::valid()
+        } else {
+            MinimizedFile::download(file)
+        });
+    }
+
+    // No checksums to validate the existing file
+    let (download, decision) = match selection.on_conflict() {
+        OnConflict::Skip => (None, ConflictDecision::Kept),
+        // Requested again and replaced unconditionally
+        OnConflict::Overwrite => (Some(file.clone()), ConflictDecision::Overwritten),
+        OnConflict::Rename => {
+            let renamed = free_file_name(&file.target_file);
+            (
+                Some(FilePlan {
+                    target_file: renamed.clone(),
+                    ..file.clone()
+                }),
+                ConflictDecision::Renamed(renamed),
+            )
         }
+        OnConflict::Fail => (None, ConflictDecision::Failed),
+    };
+    info!(
+        "{:?} exists without checksums: {decision}",
+        file.target_file
+    );
+    Ok(MinimizedFile {
+        download,
+        conflict: Some((file.target_file, decision)),
+    })
+}
 
-        minimized_plan.total_size = total_size;
+/// Load a metalink, falling back to lenient parsing with a warning if it is
+/// not valid
+fn load_metalink(metalink_file: &Path) -> Result<Metalink> {
+    match Metalink::load_from_file(metalink_file) {
+        Ok(metalink) => Ok(metalink),
+        Err(strict_error) => {
+            let metalink = Metalink::load_from_file_lenient(metalink_file)?;
+            warnings::warn(Warning::LenientParse {
+                metalink: metalink_file.to_path_buf(),
+                reason: format!("{:#}", anyhow::Error::from(strict_error)),
+            });
+            Ok(metalink)
+        }
+    }
+}
 
-        Ok(minimized_plan)
+/// Default size of the blocks existing files are read in when hashing them
+pub(crate) const DEFAULT_HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+static HASH_BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_HASH_BUFFER_SIZE);
+
+/// Read existing files in blocks of `size` bytes when hashing them
+pub(crate) fn set_hash_buffer_size(size: usize) {
+    HASH_BUFFER_SIZE.store(size.max(1), Ordering::Relaxed);
+}
+
+pub(crate) fn hash_buffer_size() -> usize {
+    HASH_BUFFER_SIZE.load(Ordering::Relaxed)
+}
+
+/// Number of files hashed at the same time, 0 for one per core
+static HASH_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Hash up to `threads` files at the same time, None for one per core
+pub(crate) fn set_hash_threads(threads: Option<usize>) {
+    HASH_THREADS.store(threads.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Number of files hashed at the same time
+pub(crate) fn hash_threads() -> usize {
+    match HASH_THREADS.load(Ordering::Relaxed) {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        threads => threads,
     }
 }
 
+/// Returns the chunks which are not valid in the file on disk
+pub(crate) fn invalid_chunks_on_disk(chunks: Chunks, target_file: &Path) -> Result<Chunks> {
+    let file_on_disk = std::fs::File::open(target_file)?;
+    // The chunks are in ascending order, so the file is read sequentially
+    // through one handle and one buffer
+    let mut reader = std::io::BufReader::with_capacity(hash_buffer_size(), file_on_disk);
+    let mut buffer = Vec::new();
+    let mut invalid: Vec<usize> = Vec::new();
+    for (index, chunk) in chunks.indexed() {
+        if !chunk.is_valid_in(&mut reader, &mut buffer)? {
+            invalid.push(index);
+        }
+    }
+    Ok(chunks.select(invalid))
+}
+
+/// The first of `<name>.1`, `<name>.2`, ... next to `target_file` which
+/// does not exist yet
+fn free_file_name(target_file: &Path) -> PathBuf {
+    (1..)
+        .map(|n| {
+            let mut name = target_file.as_os_str().to_owned();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        })
+        .find(|candidate| !candidate.exists() && !part_file(candidate).exists())
+        .expect("an unused file name exists")
+}
+
+/// `<name>.part` next to `target_file`
+pub(crate) fn part_file(target_file: &Path) -> PathBuf {
+    let mut part = target_file.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
 #[derive(Debug, Clone)]
 pub struct FilePlan {
+    pub name: String,
     pub target_file: PathBuf,
-    pub url: url::Url,
+    /// Url to download the file from first, None if the file is only
+    /// published through metaurls
+    pub url: Option<url::Url>,
+    /// All urls of the file, urls in preferred locations first and
+    /// otherwise in the order of the metalink. They are tried in this order
+    /// when a mirror fails.
+    pub urls: Vec<url::Url>,
+    pub metaurls: Vec<metalink::MetaUrl>,
     pub file_checksums: Option<CheckSum>,
-    pub chunks: Option<Vec<ChunkMetaData>>,
+    pub chunks: Option<Chunks>,
     pub file_size: Option<u64>,
+    /// When the metalink was last updated or published
+    pub modified: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 impl FilePlan {
-    pub fn new(file: &metalink::File, base_download_dir: &Path) -> Result<Self> {
-        let target_file = base_download_dir.join(file.name());
+    /// Merge `other` describing the same target file into this plan, its
+    /// urls are tried after the ones of this plan. Fails if the two describe
+    /// different content.
+    fn merge(&mut self, other: FilePlan) -> Result<()> {
+        let conflicting = matches!((self.file_size, other.file_size), (Some(a), Some(b)) if a != b)
+            || matches!(
+                (&self.file_checksums, &other.file_checksums),
+                (Some(a), Some(b)) if a.hash_type() == b.hash_type()
+                    && !a.expected().eq_ignore_ascii_case(b.expected())
+            );
+        if conflicting {
+            return Err(MetalinkDownloadError::Other(anyhow!(
+                "{} and {} would both be downloaded to {:?}",
+                self.name,
+                other.name,
+                self.target_file
+            )));
+        }
+        tracing::info!(
+            "{} is described by several metalinks, downloading it once",
+            self.target_file.display()
+        );
+        for url in other.urls {
+            if !self.urls.contains(&url) {
+                self.urls.push(url);
+            }
+        }
+        if self.url.is_none() {
+            self.url = self.urls.first().cloned();
+        }
+        self.metaurls.extend(other.metaurls);
+        if self.file_checksums.is_none() {
+            self.file_checksums = other.file_checksums;
+        }
+        if self.chunks.is_none() {
+            self.chunks = other.chunks;
+        }
+        self.file_size = self.file_size.or(other.file_size);
+        self.modified = self.modified.max(other.modified);
+        Ok(())
+    }
+
+    /// The file a download is written to before it is verified and renamed
+    /// to the target file, `<name>.part` next to the target
+    /// Bytes downloaded for the file, only the selected chunks of a file
+    /// with pieces
+    pub fn bytes_to_fetch(&self) -> u64 {
+        match &self.chunks {
+            Some(chunks) => chunks.total_bytes(),
+            None => self.file_size.unwrap_or(0),
+        }
+    }
+
+    pub fn part_file(&self) -> PathBuf {
+        part_file(&self.target_file)
+    }
+
+    /// Where the downloaded data is, the part file while a download is in
+    /// progress or not yet verified and the target file otherwise
+    pub fn downloaded_file(&self) -> PathBuf {
+        let part = self.part_file();
+        if part.exists() {
+            part
+        } else {
+            self.target_file.clone()
+        }
+    }
+
+    /// Sync the downloaded file as configured with `--fsync` and rename a
+    /// verified part file to the target file
+    pub(crate) fn finish(&self) -> Result<()> {
+        let downloaded = self.downloaded_file();
+        if downloaded.exists() {
+            writeback::file_complete(&downloaded)?;
+        }
+        let part = self.part_file();
+        if part.exists() {
+            std::fs::rename(&part, &self.target_file)
+                .with_context(|| format!("Failed to rename {part:?} to {:?}", self.target_file))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn new(
+        file: &metalink::File,
+        base_download_dir: &Path,
+        mirrors: &MirrorSelection,
+        layout: &Layout,
+    ) -> Result<Self> {
+        let target_file = base_download_dir.join(layout.relative_path(file.name())?);
         let file_size: Option<u64> = file.size().map(metalink::Size::size);
 
-        let chunks: Option<Vec<ChunkMetaData>> = match file.pieces() {
+        let chunks: Option<Chunks> = match file.pieces() {
             Some(pieces) => {
+                if let Some(index) = pieces
+                    .hashes()
+                    .iter()
+                    .position(|hash| !hash.matches_digest_length(pieces.hash_type()))
+                {
+                    return Err(MetalinkDownloadError::Other(anyhow!(
+                        "{}: {} hash of piece {index} has an invalid length",
+                        file.name(),
+                        pieces.hash_type()
+                    )));
+                }
                 if file_size.is_none() {
                     return Err(MetalinkDownloadError::Other(anyhow!(
                         "File size is required when having pieces"
                     )));
                 }
-                Some(ChunkMetaData::to_chunk_metadata(
+                Some(Chunks::from_pieces(
                     pieces,
                     &target_file,
                     file_size.unwrap(),
@@ -140,107 +610,218 @@ impl FilePlan {
         let file_checksums: Option<CheckSum> = match file.hashes() {
             Some(hashes) => hashes
                 .iter()
-                .filter(|hash| hash.hash_type().is_some())
-                .max_by_key(|hash| hash.hash_type().unwrap())
+                .filter(|hash| match hash.hash_type() {
+                    Some(hash_type) if !SUPPORTED_HASH_TYPES.contains(&hash_type) => {
+                        tracing::debug!("{}: ignoring unsupported {hash_type} hash", file.name());
+                        false
+                    }
+                    Some(hash_type) if hash.matches_digest_length(hash_type) => true,
+                    Some(hash_type) => {
+                        warnings::warn(Warning::InvalidHash {
+                            file: file.name().to_owned(),
+                            hash_type: hash_type.to_string(),
+                            value: hash.value().to_owned(),
+                        });
+                        false
+                    }
+                    None => false,
+                })
+                // Equally strong hashes are ranked by declaration order
+                .max_by_key(|hash| {
+                    let hash_type = hash.hash_type().unwrap();
+                    (hash_type.strength(), hash_type)
+                })
                 .map(|hash| CheckSum::new(hash.hash_type().unwrap(), hash.value().to_owned())),
             None => None,
         };
+        let strongest_hash = file_checksums
+            .as_ref()
+            .map(CheckSum::hash_type)
+            .or_else(|| file.pieces().map(metalink::Pieces::hash_type));
+        if let Some(hash_type) = strongest_hash.filter(|t| WEAK_HASH_TYPES.contains(t)) {
+            warnings::warn(Warning::WeakHash {
+                file: file.name().to_owned(),
+                hash_type: hash_type.to_string(),
+            });
+        }
 
-        let url: url::Url = match file.urls() {
-            Some(urls) if !urls.is_empty() => urls.first().unwrap().url(),
-            Some(_) => {
-                return Err(MetalinkDownloadError::Other(anyhow!(
-                    "File urls should not be empty"
-                )))
-            }
-            None => {
-                return Err(MetalinkDownloadError::Other(anyhow!(
-                    "Non-url based file defintions are not supported"
-                )))
-            }
-        };
+        let all_urls = file.urls().map_or(&[][..], Vec::as_slice);
+        let urls: Vec<url::Url> = mirrors.select(all_urls);
+        let url: Option<url::Url> = urls.first().cloned();
+        let metaurls: Vec<metalink::MetaUrl> = file.meta_urls().cloned().unwrap_or_default();
+        if url.is_none() && metaurls.is_empty() && !all_urls.is_empty() {
+            return Err(MetalinkDownloadError::Other(anyhow!(
+                "{}: {}",
+                file.name(),
+                mirrors.rejection(all_urls)
+            )));
+        }
+        if url.is_none() && metaurls.is_empty() {
+            return Err(MetalinkDownloadError::Other(anyhow!(
+                "{}: file has neither urls nor metaurls",
+                file.name()
+            )));
+        }
 
         Ok(Self {
+            name: file.name().to_owned(),
             target_file,
             url,
+            urls,
+            metaurls,
             file_checksums,
             chunks,
             file_size,
+            modified: None,
         })
     }
 }
 
+/// Digests of all pieces of a file stored back to back as raw bytes, a
+/// piece is looked up by its offset in the file
+#[derive(Debug, PartialEq)]
+pub struct PieceDigests {
+    hash_type: HashFunctionTextualName,
+    piece_length: u64,
+    digest_length: usize,
+    digests: Box<[u8]>,
+}
+
+impl PieceDigests {
+    pub fn from_pieces(pieces: &metalink::Pieces) -> Result<Self> {
+        let hash_type = pieces.hash_type();
+        if !SUPPORTED_HASH_TYPES.contains(&hash_type) {
+            return Err(MetalinkDownloadError::Other(anyhow!(
+                "Unsupported piece hash type {hash_type}"
+            )));
+        }
+        let digest_length = hash_type
+            .digest_length()
+            .ok_or_else(|| anyhow!("Piece hash type {hash_type} has no fixed digest length"))?;
+        let mut digests = Vec::with_capacity(digest_length * pieces.hashes().len());
+        for hash in pieces.hashes() {
+            let digest = hex::decode(hash.value())
+                .with_context(|| format!("Invalid {hash_type} piece hash {}", hash.value()))?;
+            if digest.len() != digest_length {
+                return Err(MetalinkDownloadError::Other(anyhow!(
+                    "Invalid {hash_type} piece hash {}",
+                    hash.value()
+                )));
+            }
+            digests.extend_from_slice(&digest);
+        }
+        Ok(Self {
+            hash_type,
+            piece_length: pieces.length(),
+            digest_length,
+            digests: digests.into_boxed_slice(),
+        })
+    }
+
+    /// Number of pieces
+    pub(crate) fn len(&self) -> usize {
+        self.digests.len() / self.digest_length
+    }
+
+    /// Digest of the piece starting at `offset`
+    pub fn digest_at(&self, offset: u64) -> Option<&[u8]> {
+        let index = usize::try_from(offset / self.piece_length).ok()?;
+        self.digests
+            .get(index * self.digest_length..(index + 1) * self.digest_length)
+    }
+
+    /// Whether `data` is the piece starting at `offset`, None if there is no
+    /// such piece
+    pub fn validate(&self, offset: u64, data: &[u8]) -> Option<bool> {
+        self.digest_at(offset)
+            .map(|digest| CheckSum::calculate_digest(self.hash_type, data) == digest)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct ChunkMetaData {
     pub start: u64,
     pub end: u64,
-    pub checksum: Option<CheckSum>,
-    pub filename: PathBuf,
+    /// Shared by all chunks of a file
+    pub filename: Arc<Path>,
+    /// Shared by all chunks of a file, None if the file has no pieces
+    digests: Option<Arc<PieceDigests>>,
 }
 
 impl ChunkMetaData {
-    pub fn new(start: u64, end: u64, filename: PathBuf) -> Self {
+    pub fn new(start: u64, end: u64, filename: Arc<Path>) -> Self {
         Self {
             start,
             end,
             filename,
-            checksum: None,
+            digests: None,
         }
     }
 
     pub fn has_checksum(&self) -> bool {
-        self.checksum.is_some()
+        self.digests.is_some()
     }
 
     pub fn validate_checksum(&self, bytes: &bytes::Bytes) -> Option<bool> {
-        self.checksum
+        self.digests
             .as_ref()
-            .map(|checksum| checksum.validate_checksum(bytes))
+            .and_then(|digests| digests.validate(self.start, bytes))
     }
 
-    pub fn is_valid_on_disk(&self, mut file: &std::fs::File) -> Result<bool> {
-        if let Some(checksum) = self.checksum.as_ref() {
-            file.seek(std::io::SeekFrom::Start(self.start))?;
-            info!("Chunk size: {}", self.chunk_size());
-            let buffer_size: usize = self.chunk_size() as usize;
-            let mut buffer: Vec<u8> = vec![0; buffer_size];
-            let count = file.read(buffer.as_mut_slice())?;
-            if count != buffer_size {
-                return Ok(false);
-            }
-
-            return Ok(checksum.validate_checksum(&bytes::Bytes::from(buffer)));
+    /// The error of `data` not matching the piece of the chunk
+    pub(crate) fn mismatch(&self, data: &[u8]) -> MetalinkDownloadError {
+        let (expected, actual) = match &self.digests {
+            Some(digests) => (
+                digests
+                    .digest_at(self.start)
+                    .map(hex::encode)
+                    .unwrap_or_default(),
+                hex::encode(CheckSum::calculate_digest(digests.hash_type, data)),
+            ),
+            None => Default::default(),
+        };
+        MetalinkDownloadError::ChecksumMismatch {
+            file: self.filename.to_path_buf(),
+            start: self.start,
+            end: self.end,
+            expected,
+            actual,
         }
-
-        Ok(false)
     }
 
-    pub fn chunk_size(&self) -> u64 {
-        self.end - self.start + 1
+    pub fn is_valid_on_disk(&self, mut file: &std::fs::File) -> Result<bool> {
+        self.is_valid_in(&mut file, &mut Vec::new())
     }
 
-    pub fn to_chunk_metadata(
-        pieces: &metalink::Pieces,
-        filename: &Path,
-        total_size: u64,
-    ) -> Result<Vec<ChunkMetaData>> {
-        let mut ranges = Self::calculate_ranges(total_size, pieces.length(), filename);
-
-        let hash_type = pieces.hash_type();
-
-        if ranges.len() != pieces.hashes().len() {
-            return Err(MetalinkDownloadError::Other(anyhow!(
-                "Mismatch between chunk count({}) and pieces count({})",
-                ranges.len(),
-                pieces.hashes().len()
-            )));
+    /// Whether the chunk is valid in `reader`, reading it into `buffer` which
+    /// is reused across chunks. Seeks only if `reader` is not at the start of
+    /// the chunk already.
+    pub(crate) fn is_valid_in<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        buffer: &mut Vec<u8>,
+    ) -> Result<bool> {
+        if !self.has_checksum() {
+            return Ok(false);
         }
-
-        for (chunk, hash) in ranges.iter_mut().zip(pieces.hashes().iter()) {
-            chunk.checksum = Some(CheckSum::new(hash_type, hash.value().to_owned()));
+        if reader.stream_position()? != self.start {
+            reader.seek(std::io::SeekFrom::Start(self.start))?;
+        }
+        buffer.resize(self.chunk_size() as usize, 0);
+        match reader.read_exact(buffer) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e.into()),
         }
+        Ok(self
+            .digests
+            .as_ref()
+            .and_then(|digests| digests.validate(self.start, buffer))
+            == Some(true))
+    }
 
-        Ok(ranges)
+    pub fn chunk_size(&self) -> u64 {
+        self.end - self.start + 1
     }
 
     pub fn calculate_ranges(
@@ -248,6 +829,7 @@ impl ChunkMetaData {
         block_size: u64,
         filename: &Path,
     ) -> Vec<ChunkMetaData> {
+        let filename: Arc<Path> = Arc::from(filename);
         let mut remaining_size = total_size;
         let mut current_pos = 0;
 
@@ -256,7 +838,7 @@ impl ChunkMetaData {
             ranges.push(ChunkMetaData::new(
                 current_pos,
                 current_pos + block_size - 1,
-                filename.to_path_buf(),
+                filename.clone(),
             ));
             current_pos += block_size;
             remaining_size -= block_size;
@@ -264,40 +846,168 @@ impl ChunkMetaData {
         ranges.push(ChunkMetaData::new(
             current_pos,
             current_pos + remaining_size - 1,
-            filename.to_path_buf(),
+            filename,
         ));
 
         ranges
     }
 }
 
+/// The chunks of a file described by its pieces. Chunk descriptors are
+/// generated on demand from the pieces instead of being stored, so even
+/// files with millions of pieces are planned quickly.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Chunks {
+    filename: Arc<Path>,
+    total_size: u64,
+    digests: Arc<PieceDigests>,
+    /// Indices of the selected pieces in ascending order, all pieces if None
+    selected: Option<Arc<[usize]>>,
+}
+
+impl Chunks {
+    pub fn from_pieces(
+        pieces: &metalink::Pieces,
+        filename: &Path,
+        total_size: u64,
+    ) -> Result<Self> {
+        if pieces.length() == 0 {
+            return Err(MetalinkDownloadError::Other(anyhow!(
+                "Piece length must not be zero"
+            )));
+        }
+        let digests = PieceDigests::from_pieces(pieces)?;
+        let chunk_count = total_size.div_ceil(pieces.length());
+        if chunk_count != digests.len() as u64 {
+            return Err(MetalinkDownloadError::Other(anyhow!(
+                "Mismatch between chunk count({}) and pieces count({})",
+                chunk_count,
+                digests.len()
+            )));
+        }
+        Ok(Self {
+            filename: Arc::from(filename),
+            total_size,
+            digests: Arc::new(digests),
+            selected: None,
+        })
+    }
+
+    /// Number of selected chunks
+    pub fn len(&self) -> usize {
+        match &self.selected {
+            Some(selected) => selected.len(),
+            None => self.digests.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Descriptor of the piece with the given index
+    fn chunk(&self, index: usize) -> ChunkMetaData {
+        let start = index as u64 * self.digests.piece_length;
+        let end = (start + self.digests.piece_length).min(self.total_size) - 1;
+        ChunkMetaData {
+            start,
+            end,
+            filename: self.filename.clone(),
+            digests: Some(self.digests.clone()),
+        }
+    }
+
+    /// The selected chunks together with the index of their piece
+    fn indexed(&self) -> impl Iterator<Item = (usize, ChunkMetaData)> + '_ {
+        let indices: Box<dyn Iterator<Item = usize> + '_> = match &self.selected {
+            Some(selected) => Box::new(selected.iter().copied()),
+            None => Box::new(0..self.digests.len()),
+        };
+        indices.map(|index| (index, self.chunk(index)))
+    }
+
+    /// The selected chunks in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = ChunkMetaData> + '_ {
+        self.indexed().map(|(_, chunk)| chunk)
+    }
+
+    /// Materialize the selected chunks, used when the file is scheduled for download
+    pub fn to_vec(&self) -> Vec<ChunkMetaData> {
+        self.iter().collect()
+    }
+
+    /// Number of bytes covered by the selected chunks
+    pub fn total_bytes(&self) -> u64 {
+        self.iter().map(|chunk| chunk.chunk_size()).sum()
+    }
+
+    /// Only keep the selected chunks for which `keep` returns true
+    pub(crate) fn filter(&self, mut keep: impl FnMut(&ChunkMetaData) -> bool) -> Self {
+        let indices = self
+            .indexed()
+            .filter(|(_, chunk)| keep(chunk))
+            .map(|(index, _)| index)
+            .collect();
+        self.select(indices)
+    }
+
+    /// Only keep the pieces with the given indices
+    fn select(&self, indices: Vec<usize>) -> Self {
+        Self {
+            selected: Some(indices.into()),
+            ..self.clone()
+        }
+    }
+}
+
+/// Hash algorithms which are no longer collision resistant
+pub(crate) const WEAK_HASH_TYPES: &[HashFunctionTextualName] = &[
+    HashFunctionTextualName::Md2,
+    HashFunctionTextualName::Md5,
+    HashFunctionTextualName::Sha1,
+];
+
+/// Hash algorithms supported for checksum validation
+pub(crate) const SUPPORTED_HASH_TYPES: &[HashFunctionTextualName] = &[
+    HashFunctionTextualName::Md2,
+    HashFunctionTextualName::Md5,
+    HashFunctionTextualName::Sha1,
+    HashFunctionTextualName::Sha224,
+    HashFunctionTextualName::Sha256,
+    HashFunctionTextualName::Sha384,
+    HashFunctionTextualName::Sha512,
+    HashFunctionTextualName::Sha3_224,
+    HashFunctionTextualName::Sha3_256,
+    HashFunctionTextualName::Sha3_384,
+    HashFunctionTextualName::Sha3_512,
+];
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct CheckSum {
     hash_type: HashFunctionTextualName,
     checksum: String,
 }
 
-fn calculate_checksum<D: Digest>(data: &bytes::Bytes) -> String
-where
-    <D as OutputSizeUser>::OutputSize: std::ops::Add,
-    <<D as OutputSizeUser>::OutputSize as std::ops::Add>::Output: ArrayLength<u8>,
-{
-    format!("{:x}", D::digest(data))
+fn calculate_digest<D: Digest>(data: &[u8]) -> Vec<u8> {
+    D::digest(data).to_vec()
 }
 
-fn calculate_file_checksum<D: Digest>(path: &std::path::Path) -> Result<String>
+fn calculate_file_checksum<D: Digest>(path: &std::path::Path, buffer_size: usize) -> Result<String>
 where
     <D as OutputSizeUser>::OutputSize: std::ops::Add,
     <<D as OutputSizeUser>::OutputSize as std::ops::Add>::Output: ArrayLength<u8>,
 {
-    let input_file = std::fs::File::open(path)?;
-    let mut reader = std::io::BufReader::new(input_file);
+    let mut input_file = std::fs::File::open(path)?;
 
     let digest = {
         let mut hasher = D::new();
-        let mut buffer = [0; 1024];
+        let mut buffer = vec![0; buffer_size];
         loop {
-            let count = reader.read(&mut buffer)?;
+            let count = match input_file.read(&mut buffer) {
+                Ok(count) => count,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
             if count == 0 {
                 break;
             }
@@ -309,47 +1019,92 @@ where
 }
 
 impl CheckSum {
+    /// The hex encoded `checksum` is trimmed and lowercased, so it compares
+    /// equal to calculated checksums
     pub fn new(hash_type: HashFunctionTextualName, checksum: String) -> Self {
         Self {
             hash_type,
-            checksum,
+            checksum: checksum.trim().to_ascii_lowercase(),
         }
     }
 
-    fn calculate_checksum(&self, data: &bytes::Bytes) -> String {
-        match self.hash_type {
-            HashFunctionTextualName::Md2 => calculate_checksum::<md2::Md2>(data),
-            HashFunctionTextualName::Md5 => calculate_checksum::<md5::Md5>(data),
-            HashFunctionTextualName::Sha1 => calculate_checksum::<sha1_checked::Sha1>(data),
-            HashFunctionTextualName::Sha224 => calculate_checksum::<sha2::Sha224>(data),
-            HashFunctionTextualName::Sha256 => calculate_checksum::<sha2::Sha256>(data),
-            HashFunctionTextualName::Sha384 => calculate_checksum::<sha2::Sha384>(data),
-            HashFunctionTextualName::Sha512 => calculate_checksum::<sha2::Sha512>(data),
-            _ => unimplemented!(),
-        }
+    /// Calculate the checksum of `data` with the given hash type
+    pub(crate) fn calculate(hash_type: HashFunctionTextualName, data: &bytes::Bytes) -> String {
+        hex::encode(Self::calculate_digest(hash_type, data))
     }
 
-    fn calculate_file_checksum(&self, path: &std::path::Path) -> Result<String> {
-        match self.hash_type {
-            HashFunctionTextualName::Md2 => calculate_file_checksum::<md2::Md2>(path),
-            HashFunctionTextualName::Md5 => calculate_file_checksum::<md5::Md5>(path),
-            HashFunctionTextualName::Sha1 => calculate_file_checksum::<sha1_checked::Sha1>(path),
-            HashFunctionTextualName::Sha224 => calculate_file_checksum::<sha2::Sha224>(path),
-            HashFunctionTextualName::Sha256 => calculate_file_checksum::<sha2::Sha256>(path),
-            HashFunctionTextualName::Sha384 => calculate_file_checksum::<sha2::Sha384>(path),
-            HashFunctionTextualName::Sha512 => calculate_file_checksum::<sha2::Sha512>(path),
+    /// Calculate the raw digest of `data` with the given hash type
+    pub(crate) fn calculate_digest(hash_type: HashFunctionTextualName, data: &[u8]) -> Vec<u8> {
+        match hash_type {
+            HashFunctionTextualName::Md2 => calculate_digest::<md2::Md2>(data),
+            HashFunctionTextualName::Md5 => calculate_digest::<md5::Md5>(data),
+            HashFunctionTextualName::Sha1 => calculate_digest::<sha1_checked::Sha1>(data),
+            HashFunctionTextualName::Sha224 => calculate_digest::<sha2::Sha224>(data),
+            HashFunctionTextualName::Sha256 => calculate_digest::<sha2::Sha256>(data),
+            HashFunctionTextualName::Sha384 => calculate_digest::<sha2::Sha384>(data),
+            HashFunctionTextualName::Sha512 => calculate_digest::<sha2::Sha512>(data),
+            HashFunctionTextualName::Sha3_224 => calculate_digest::<sha3::Sha3_224>(data),
+            HashFunctionTextualName::Sha3_256 => calculate_digest::<sha3::Sha3_256>(data),
+            HashFunctionTextualName::Sha3_384 => calculate_digest::<sha3::Sha3_384>(data),
+            HashFunctionTextualName::Sha3_512 => calculate_digest::<sha3::Sha3_512>(data),
             _ => unimplemented!(),
         }
     }
 
-    pub fn validate_checksum(&self, data: &bytes::Bytes) -> bool {
-        let res = self.calculate_checksum(data) == self.checksum;
-        if res {
-            log::info!("Checksum validation succeeded");
-        } else {
-            log::info!("Checksum validation failed");
+    /// Returns the hash function of the checksum
+    pub fn hash_type(&self) -> HashFunctionTextualName {
+        self.hash_type
+    }
+
+    /// Returns the expected checksum
+    pub fn expected(&self) -> &str {
+        &self.checksum
+    }
+
+    /// Calculate the checksum of the file at `path` with the hash type of this checksum
+    pub fn calculate_file_checksum(&self, path: &std::path::Path) -> Result<String> {
+        self.calculate_file_checksum_with(path, hash_buffer_size())
+    }
+
+    /// Calculate the checksum of the file at `path`, reading it in blocks of
+    /// `buffer_size` bytes
+    fn calculate_file_checksum_with(
+        &self,
+        path: &std::path::Path,
+        buffer_size: usize,
+    ) -> Result<String> {
+        match self.hash_type {
+            HashFunctionTextualName::Md2 => calculate_file_checksum::<md2::Md2>(path, buffer_size),
+            HashFunctionTextualName::Md5 => calculate_file_checksum::<md5::Md5>(path, buffer_size),
+            HashFunctionTextualName::Sha1 => {
+                calculate_file_checksum::<sha1_checked::Sha1>(path, buffer_size)
+            }
+            HashFunctionTextualName::Sha224 => {
+                calculate_file_checksum::<sha2::Sha224>(path, buffer_size)
+            }
+            HashFunctionTextualName::Sha256 => {
+                calculate_file_checksum::<sha2::Sha256>(path, buffer_size)
+            }
+            HashFunctionTextualName::Sha384 => {
+                calculate_file_checksum::<sha2::Sha384>(path, buffer_size)
+            }
+            HashFunctionTextualName::Sha512 => {
+                calculate_file_checksum::<sha2::Sha512>(path, buffer_size)
+            }
+            HashFunctionTextualName::Sha3_224 => {
+                calculate_file_checksum::<sha3::Sha3_224>(path, buffer_size)
+            }
+            HashFunctionTextualName::Sha3_256 => {
+                calculate_file_checksum::<sha3::Sha3_256>(path, buffer_size)
+            }
+            HashFunctionTextualName::Sha3_384 => {
+                calculate_file_checksum::<sha3::Sha3_384>(path, buffer_size)
+            }
+            HashFunctionTextualName::Sha3_512 => {
+                calculate_file_checksum::<sha3::Sha3_512>(path, buffer_size)
+            }
+            _ => unimplemented!(),
         }
-        res
     }
 
     pub fn validate_file_checksum(&self, file_on_disk: &std::path::Path) -> bool {
@@ -371,7 +1126,7 @@ mod tests {
         assert_eq!(chunks.len(), 1);
         assert_eq!(
             chunks.first(),
-            Some(ChunkMetaData::new(0, 4, "/x".into())).as_ref()
+            Some(ChunkMetaData::new(0, 4, Arc::from(file.as_path()))).as_ref()
         );
 
         assert_eq!(Some(5), chunks.first().map(|chunk| chunk.chunk_size()));
@@ -384,7 +1139,7 @@ mod tests {
         assert_eq!(chunks.len(), 1);
         assert_eq!(
             chunks.first(),
-            Some(ChunkMetaData::new(0, 9, "/x".into())).as_ref()
+            Some(ChunkMetaData::new(0, 9, Arc::from(file.as_path()))).as_ref()
         );
         assert_eq!(Some(10), chunks.first().map(|chunk| chunk.chunk_size()));
     }
@@ -397,8 +1152,8 @@ mod tests {
         assert_eq!(
             chunks,
             vec![
-                ChunkMetaData::new(0, 9, "/x".into()),
-                ChunkMetaData::new(10, 14, "/x".into())
+                ChunkMetaData::new(0, 9, Arc::from(file.as_path())),
+                ChunkMetaData::new(10, 14, Arc::from(file.as_path()))
             ]
         );
         assert_eq!(10, chunks[0].chunk_size());
@@ -407,12 +1162,228 @@ mod tests {
 
     #[test]
     fn validate_checksum() {
+        let bytes = bytes::Bytes::from(&b"abc"[..]);
+        assert_eq!(
+            CheckSum::calculate(HashFunctionTextualName::Sha256, &bytes),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            CheckSum::calculate(HashFunctionTextualName::Sha3_256, &bytes),
+            "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532"
+        );
+    }
+
+    #[test]
+    fn the_strongest_file_hash_is_used() {
+        let file = metalink::File::try_from(
+            format!(
+                r#"<file name="file.txt">
+                    <hash type="sha3-224">{}</hash>
+                    <hash type="sha-512">{}</hash>
+                    <hash type="sha-256">{}</hash>
+                    <url>https://example.com/file.txt</url>
+                </file>"#,
+                "a".repeat(56),
+                "b".repeat(128),
+                "c".repeat(64)
+            )
+            .as_str(),
+        )
+        .unwrap();
+        let plan = FilePlan::new(
+            &file,
+            Path::new("/x"),
+            &Default::default(),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            plan.file_checksums.map(|checksum| checksum.hash_type()),
+            Some(HashFunctionTextualName::Sha512)
+        );
+    }
+
+    #[test]
+    fn uppercase_checksums_match_calculated_ones() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("file.txt");
+        std::fs::write(&path, b"abc").unwrap();
         let checksum = CheckSum::new(
             HashFunctionTextualName::Sha256,
-            String::from("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            " BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD\n".to_owned(),
+        );
+        assert_eq!(
+            checksum.expected(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(checksum.validate_file_checksum(&path));
+        assert_eq!(
+            checksum.calculate_file_checksum(&path).unwrap(),
+            checksum.expected()
+        );
+    }
+
+    #[test]
+    fn piece_digests_are_looked_up_by_offset() {
+        let pieces = metalink::Pieces::new(
+            HashFunctionTextualName::Sha256,
+            3,
+            vec![
+                metalink::Hash::new(
+                    None,
+                    "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD",
+                ),
+                metalink::Hash::new(
+                    None,
+                    "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+                ),
+            ],
         );
+        let chunks = Chunks::from_pieces(&pieces, Path::new("/x"), 5).unwrap();
+        assert_eq!(chunks.total_bytes(), 5);
+        let chunks = chunks.to_vec();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!((chunks[1].start, chunks[1].end), (3, 4));
+        assert!(Arc::ptr_eq(&chunks[0].filename, &chunks[1].filename));
 
-        let bytes = bytes::Bytes::from(&b"abc"[..]);
-        assert_eq!(checksum.validate_checksum(&bytes), true);
+        let abc = bytes::Bytes::from(&b"abc"[..]);
+        assert_eq!(chunks[0].validate_checksum(&abc), Some(true));
+        assert_eq!(chunks[1].validate_checksum(&abc), Some(true));
+        assert_eq!(
+            chunks[0].validate_checksum(&bytes::Bytes::from(&b"abd"[..])),
+            Some(false)
+        );
+
+        let digests = PieceDigests::from_pieces(&pieces).unwrap();
+        assert_eq!(digests.digest_at(5).map(<[u8]>::len), Some(32));
+        assert_eq!(digests.digest_at(6), None);
+    }
+
+    #[test]
+    fn files_of_several_metalinks_are_merged() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let metalink = |name: &str, size: u64, url: &str| {
+            let path = dir.join(name);
+            std::fs::write(
+                &path,
+                format!(
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="shared.bin">
+    <size>{size}</size>
+    <url>{url}</url>
+  </file>
+</metalink>"#
+                ),
+            )
+            .unwrap();
+            path
+        };
+        let a = metalink("a.meta4", 10, "https://a.example.com/shared.bin");
+        let b = metalink("b.meta4", 10, "https://b.example.com/shared.bin");
+        let c = metalink("c.meta4", 11, "https://c.example.com/shared.bin");
+        let plan = |metalinks: &[PathBuf]| {
+            Plan::from_metalinks(
+                metalinks,
+                &dir.join("target"),
+                &FileFilter::default(),
+                &MirrorSelection::default(),
+                &Layout::default(),
+            )
+        };
+
+        let merged = plan(&[a.clone(), b]).unwrap();
+        assert_eq!(merged.files.len(), 1);
+        assert_eq!(merged.files[0].urls.len(), 2);
+        assert_eq!(merged.total_size, 10);
+        assert!(plan(&[a, c]).is_err());
+    }
+
+    #[test]
+    fn identical_files_are_downloaded_once() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let file = |name: &str, hash_type: &str, hash: &str| {
+            format!(
+                r#"<file name="{name}"><size>3</size><hash type="{hash_type}">{hash}</hash><url>https://example.com/{name}</url></file>"#
+            )
+        };
+        let sha = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let md5 = "900150983cd24fb0d6963f7d28e17f72";
+        let metalink = dir.join("test.meta4");
+        std::fs::write(
+            &metalink,
+            format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">{}{}{}{}</metalink>"#,
+                file("a.bin", "sha-256", sha),
+                file("b.bin", "sha-256", &sha.to_uppercase()),
+                file("c.bin", "md5", md5),
+                file("d.bin", "md5", md5),
+            ),
+        )
+        .unwrap();
+        let plan = Plan::new(
+            metalink,
+            &dir.join("target"),
+            &FileFilter::default(),
+            &MirrorSelection::default(),
+            &Layout::default(),
+        )
+        .unwrap()
+        .minimize_plan(
+            &RefreshSelection::default(),
+            &VerificationCache::in_memory(),
+            false,
+        )
+        .unwrap()
+        .dedupe();
+
+        let names: Vec<&str> = plan.files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["c.bin", "d.bin", "a.bin"]);
+        assert_eq!(plan.total_size, 9);
+        assert_eq!(plan.duplicates.len(), 1);
+        assert_eq!(plan.duplicates[0].file.name, "b.bin");
+        assert_eq!(
+            plan.duplicates[0].original,
+            dir.join("target").join("a.bin")
+        );
+    }
+
+    #[test]
+    fn existing_files_are_hashed_in_blocks() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let path = dir.join("file.bin");
+        std::fs::write(&path, b"abcabX").unwrap();
+
+        let checksum = CheckSum::new(HashFunctionTextualName::Sha256, String::new());
+        let whole = CheckSum::calculate(
+            HashFunctionTextualName::Sha256,
+            &bytes::Bytes::from(&b"abcabX"[..]),
+        );
+        for buffer_size in [1, 4, 1024] {
+            assert_eq!(
+                checksum
+                    .calculate_file_checksum_with(&path, buffer_size)
+                    .unwrap(),
+                whole
+            );
+        }
+
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let pieces = metalink::Pieces::new(
+            HashFunctionTextualName::Sha256,
+            3,
+            vec![
+                metalink::Hash::new(None, abc),
+                metalink::Hash::new(None, abc),
+            ],
+        );
+        let chunks = Chunks::from_pieces(&pieces, &path, 6).unwrap();
+        let invalid = invalid_chunks_on_disk(chunks, &path).unwrap().to_vec();
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].start, 3);
     }
 }
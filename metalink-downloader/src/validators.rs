@@ -0,0 +1,154 @@
+//! HTTP validators of downloaded files without checksums.
+//!
+//! Without a checksum a file on disk can not be checked, so it is requested
+//! again with the `ETag` and `Last-Modified` of the previous download. A
+//! `304 Not Modified` response keeps the file on disk instead of downloading
+//! it again.
+
+use crate::Result;
+
+use anyhow::Context;
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Name of the file inside the target directory the validators are kept in
+const VALIDATORS_FILE: &str = ".metalink-downloader.validators.json";
+
+/// Validators a server sent with a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Size of the file when it was downloaded, a file with a different
+    /// size on disk was changed locally and is downloaded again
+    pub size: u64,
+}
+
+impl Validators {
+    /// The validators of a response, None if it has neither an ETag nor a
+    /// Last-Modified header
+    pub(crate) fn from_headers(headers: &HeaderMap, size: u64) -> Option<Self> {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        (etag.is_some() || last_modified.is_some()).then_some(Self {
+            etag,
+            last_modified,
+            size,
+        })
+    }
+
+    /// Make `request` conditional on the file having changed
+    pub(crate) fn apply(
+        &self,
+        mut request: reqwest_middleware::RequestBuilder,
+    ) -> reqwest_middleware::RequestBuilder {
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        request
+    }
+}
+
+/// The validators of the files in a target directory, keyed by their path
+/// relative to the directory
+#[derive(Debug)]
+pub(crate) struct ValidatorStore {
+    target_dir: PathBuf,
+    entries: Mutex<BTreeMap<PathBuf, Validators>>,
+}
+
+impl ValidatorStore {
+    /// Load the validators kept in `target_dir`, a missing or unreadable
+    /// file is treated as empty as the files are then downloaded again
+    pub(crate) fn load(target_dir: &Path) -> Self {
+        let path = target_dir.join(VALIDATORS_FILE);
+        let entries = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring invalid validators file {path:?}: {e}");
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Self {
+            target_dir: target_dir.to_path_buf(),
+            entries: Mutex::new(entries),
+        }
+    }
+
+    fn key(&self, target_file: &Path) -> PathBuf {
+        target_file
+            .strip_prefix(&self.target_dir)
+            .unwrap_or(target_file)
+            .to_path_buf()
+    }
+
+    /// The validators of `target_file` if it is still on disk as downloaded
+    pub(crate) fn get(&self, target_file: &Path) -> Option<Validators> {
+        let size = std::fs::metadata(target_file).ok()?.len();
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(&self.key(target_file))
+            .filter(|validators| validators.size == size)
+            .cloned()
+    }
+
+    /// Remember the validators of `target_file`, None forgets them
+    pub(crate) fn set(&self, target_file: &Path, validators: Option<Validators>) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let key = self.key(target_file);
+        let changed = match validators {
+            Some(validators) => entries.insert(key, validators.clone()) != Some(validators),
+            None => entries.remove(&key).is_some(),
+        };
+        if !changed {
+            return Ok(());
+        }
+        let path = self.target_dir.join(VALIDATORS_FILE);
+        let temp = self.target_dir.join(format!("{VALIDATORS_FILE}.tmp"));
+        let content = serde_json::to_vec_pretty(&*entries)
+            .with_context(|| "Failed to serialize validators")?;
+        std::fs::write(&temp, content)
+            .with_context(|| format!("Failed to write validators file {temp:?}"))?;
+        std::fs::rename(&temp, &path)
+            .with_context(|| format!("Failed to replace validators file {path:?}"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validators_are_kept_while_the_file_is_unchanged() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let file = dir.join("file.txt");
+        std::fs::write(&file, b"hello").unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, "\"abc\"".parse().unwrap());
+        let validators = Validators::from_headers(&headers, 5).unwrap();
+        assert_eq!(validators.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(Validators::from_headers(&HeaderMap::new(), 5), None);
+
+        let store = ValidatorStore::load(dir);
+        store.set(&file, Some(validators.clone())).unwrap();
+        assert_eq!(ValidatorStore::load(dir).get(&file), Some(validators));
+
+        std::fs::write(&file, b"changed").unwrap();
+        assert_eq!(store.get(&file), None);
+    }
+}
@@ -0,0 +1,175 @@
+//! Cache of the files in a target directory which were verified against
+//! their file checksum.
+//!
+//! Hashing a large tree takes long, so a file whose size and modification
+//! time did not change since it was verified is trusted without hashing it
+//! again. `--revalidate` ignores the cache.
+
+use crate::types::CheckSum;
+use crate::Result;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Name of the file inside the target directory the cache is kept in
+const CACHE_FILE: &str = ".metalink-downloader.verified.json";
+
+/// A file as it was on disk when it was verified
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct VerifiedFile {
+    size: u64,
+    modified: SystemTime,
+    hash_type: String,
+    checksum: String,
+    verified_at: SystemTime,
+}
+
+/// The verified files of a target directory, keyed by their path relative to
+/// the directory
+#[derive(Debug)]
+pub(crate) struct VerificationCache {
+    /// None if the cache is only kept in memory
+    target_dir: Option<PathBuf>,
+    entries: Mutex<BTreeMap<PathBuf, VerifiedFile>>,
+    changed: AtomicBool,
+}
+
+impl VerificationCache {
+    /// Load the cache kept in `target_dir`, a missing or unreadable file is
+    /// treated as empty as the files are then hashed again
+    pub(crate) fn load(target_dir: &Path) -> Self {
+        let path = target_dir.join(CACHE_FILE);
+        let entries = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring invalid verification cache {path:?}: {e}");
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Self {
+            target_dir: Some(target_dir.to_path_buf()),
+            entries: Mutex::new(entries),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    /// An empty cache which is never saved, for checks which must hash every
+    /// file
+    pub(crate) fn in_memory() -> Self {
+        Self {
+            target_dir: None,
+            entries: Mutex::new(BTreeMap::new()),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    fn key(&self, target_file: &Path) -> PathBuf {
+        self.target_dir
+            .as_deref()
+            .and_then(|dir| target_file.strip_prefix(dir).ok())
+            .unwrap_or(target_file)
+            .to_path_buf()
+    }
+
+    /// Whether `target_file` was verified against `checksum` and did not
+    /// change on disk since
+    pub(crate) fn is_verified(&self, target_file: &Path, checksum: &CheckSum) -> bool {
+        let Ok(metadata) = std::fs::metadata(target_file) else {
+            return false;
+        };
+        let Ok(modified) = metadata.modified() else {
+            return false;
+        };
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.get(&self.key(target_file)).is_some_and(|entry| {
+            entry.size == metadata.len()
+                && entry.modified == modified
+                && entry.hash_type == checksum.hash_type().to_string()
+                && entry.checksum.eq_ignore_ascii_case(checksum.expected())
+        })
+    }
+
+    /// Remember that `target_file` as it is on disk matches `checksum`
+    pub(crate) fn record(&self, target_file: &Path, checksum: &CheckSum) {
+        let Some((size, modified)) = std::fs::metadata(target_file)
+            .ok()
+            .and_then(|metadata| Some((metadata.len(), metadata.modified().ok()?)))
+        else {
+            return;
+        };
+        let entry = VerifiedFile {
+            size,
+            modified,
+            hash_type: checksum.hash_type().to_string(),
+            checksum: checksum.expected().to_owned(),
+            verified_at: SystemTime::now(),
+        };
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.insert(self.key(target_file), entry);
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Forget `target_file`, e.g. after it failed verification
+    pub(crate) fn forget(&self, target_file: &Path) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.remove(&self.key(target_file)).is_some() {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Write the cache into the target directory if it changed
+    pub(crate) fn save(&self) -> Result<()> {
+        let Some(target_dir) = &self.target_dir else {
+            return Ok(());
+        };
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let path = target_dir.join(CACHE_FILE);
+        let temp = target_dir.join(format!("{CACHE_FILE}.tmp"));
+        let content = serde_json::to_vec_pretty(&*entries)
+            .with_context(|| "Failed to serialize verification cache")?;
+        std::fs::write(&temp, content)
+            .with_context(|| format!("Failed to write verification cache {temp:?}"))?;
+        std::fs::rename(&temp, &path)
+            .with_context(|| format!("Failed to replace verification cache {path:?}"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iana_registry_enums::HashFunctionTextualName;
+
+    #[test]
+    fn verified_files_are_trusted_until_they_change() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let file = dir.join("file.txt");
+        std::fs::write(&file, b"abc").unwrap();
+        let checksum = CheckSum::new(
+            HashFunctionTextualName::Sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_owned(),
+        );
+        let other = CheckSum::new(HashFunctionTextualName::Sha256, "00".to_owned());
+
+        let cache = VerificationCache::load(dir);
+        assert!(!cache.is_verified(&file, &checksum));
+        cache.record(&file, &checksum);
+        cache.save().unwrap();
+
+        let cache = VerificationCache::load(dir);
+        assert!(cache.is_verified(&file, &checksum));
+        assert!(!cache.is_verified(&file, &other));
+
+        std::fs::write(&file, b"abcd").unwrap();
+        assert!(!cache.is_verified(&file, &checksum));
+    }
+}
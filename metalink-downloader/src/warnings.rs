@@ -0,0 +1,57 @@
+use crate::machine_log::Record;
+pub(crate) use crate::machine_log::Warning;
+use crate::run;
+use crate::{MetalinkDownloadError, Result};
+
+use anyhow::{anyhow, Context};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+struct Recorder {
+    seq: u64,
+    writer: LineWriter<std::fs::File>,
+}
+
+static RECORDER: OnceLock<Mutex<Recorder>> = OnceLock::new();
+
+/// Start writing warnings as JSON events into the file at `path`.
+/// Recording is process wide and can only be started once.
+pub(crate) fn start_recording(path: &Path) -> Result<()> {
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Failed to create warnings log: {path:?}"))?;
+    RECORDER
+        .set(Mutex::new(Recorder {
+            seq: 0,
+            writer: LineWriter::new(file),
+        }))
+        .map_err(|_| MetalinkDownloadError::Other(anyhow!("Warnings log already started")))
+}
+
+/// Record a warning for the report of the current run and the warnings log
+pub(crate) fn warn(warning: Warning) {
+    tracing::warn!("{warning}");
+    if let Some(recorder) = RECORDER.get() {
+        let mut recorder = recorder.lock().unwrap_or_else(|e| e.into_inner());
+        let record = Record::new(recorder.seq, &warning);
+        recorder.seq += 1;
+        let res = serde_json::to_writer(&mut recorder.writer, &record)
+            .map_err(std::io::Error::from)
+            .and_then(|_| recorder.writer.write_all(b"\n"));
+        if let Err(e) = res {
+            tracing::warn!("Failed to write warning event: {e}");
+        }
+    }
+    run::with(|run| {
+        run.warnings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(warning)
+    });
+}
+
+/// Take all warnings the current run recorded so far
+pub(crate) fn take() -> Vec<Warning> {
+    run::with(|run| std::mem::take(&mut *run.warnings.lock().unwrap_or_else(|e| e.into_inner())))
+        .unwrap_or_default()
+}
@@ -0,0 +1,173 @@
+//! Watch mode downloading the metalinks dropped into a directory.
+//!
+//! The metalinks in the directory when the watch starts and the ones added
+//! later are downloaded into the target directory one after another. A
+//! processed metalink is moved into the `done` or `failed` subdirectory of
+//! the watched directory. An interrupted download leaves its metalink in
+//! place, the next watch resumes it.
+
+use crate::commands::{self, DownloadMetalinkOptions};
+use crate::hooks::Hooks;
+use crate::remote::{is_metalink, MetalinkSource};
+use crate::{shutdown, JobControl, MetalinkDownloadError, Result};
+
+use anyhow::Context;
+use notify::{RecursiveMode, Watcher};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Subdirectory of the metalinks which were downloaded
+const DONE_DIR: &str = "done";
+/// Subdirectory of the metalinks which failed to download
+const FAILED_DIR: &str = "failed";
+
+/// A metalink is planned once it was not changed for this long, so a
+/// metalink still being copied into the directory is not read half written
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// How often the changed metalinks are checked for being settled
+const SETTLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Metalinks which changed and when they changed last
+#[derive(Debug, Default)]
+struct Pending(BTreeMap<PathBuf, Instant>);
+
+impl Pending {
+    fn changed(&mut self, path: PathBuf, now: Instant) {
+        if is_metalink(&path) {
+            self.0.insert(path, now);
+        }
+    }
+
+    /// Take the metalinks which did not change for `SETTLE_TIME`
+    fn settled(&mut self, now: Instant) -> Vec<PathBuf> {
+        let (settled, pending) = std::mem::take(&mut self.0)
+            .into_iter()
+            .partition(|(_, changed)| now.duration_since(*changed) >= SETTLE_TIME);
+        self.0 = pending;
+        settled.into_keys().collect()
+    }
+}
+
+/// Download the metalinks dropped into `dir` into `target_dir` until
+/// interrupted
+pub(crate) async fn watch(
+    dir: PathBuf,
+    target_dir: PathBuf,
+    options: DownloadMetalinkOptions,
+    hooks: &Hooks,
+) -> Result<()> {
+    for subdir in [DONE_DIR, FAILED_DIR] {
+        let subdir = dir.join(subdir);
+        std::fs::create_dir_all(&subdir)
+            .with_context(|| format!("Failed to create directory {subdir:?}"))?;
+    }
+    shutdown::install_handler();
+
+    let (sender, mut changes) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => event.paths.into_iter().for_each(|path| {
+                let _ = sender.send(path);
+            }),
+            Err(e) => tracing::warn!("Failed to watch for metalinks: {e}"),
+        })
+        .with_context(|| "Failed to watch for metalinks")?;
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {dir:?}"))?;
+
+    let mut pending = Pending::default();
+    let now = Instant::now();
+    for entry in std::fs::read_dir(&dir).with_context(|| format!("Failed to read {dir:?}"))? {
+        let entry = entry.with_context(|| format!("Failed to read {dir:?}"))?;
+        pending.changed(entry.path(), now);
+    }
+    eprintln!("Watching {dir:?} for metalinks");
+
+    let mut settle = tokio::time::interval(SETTLE_INTERVAL);
+    loop {
+        tokio::select! {
+            Some(path) = changes.recv() => pending.changed(path, Instant::now()),
+            _ = settle.tick() => {
+                for metalink in pending.settled(Instant::now()) {
+                    // Moved away, e.g. into `done` after being processed
+                    if metalink.is_file() {
+                        process(&metalink, &dir, &target_dir, &options, hooks).await?;
+                    }
+                }
+            }
+            _ = shutdown::requested() => return Ok(()),
+        }
+    }
+}
+
+/// Download `metalink` and move it into `done` or `failed`
+async fn process(
+    metalink: &Path,
+    dir: &Path,
+    target_dir: &Path,
+    options: &DownloadMetalinkOptions,
+    hooks: &Hooks,
+) -> Result<()> {
+    tracing::info!("Downloading dropped metalink {metalink:?}");
+    eprintln!("Downloading {metalink:?}");
+    let report = commands::download_metalink(
+        vec![MetalinkSource::File(metalink.to_owned())],
+        target_dir.to_owned(),
+        DownloadMetalinkOptions {
+            control: JobControl::default(),
+            ..options.clone()
+        },
+    )
+    .await;
+    if let Err(e @ MetalinkDownloadError::Interrupted { .. }) = report {
+        return Err(e);
+    }
+    hooks.finished(&report).await;
+    let failure = match report {
+        Ok(report) => report.failure(),
+        Err(e) => Some(e),
+    };
+    let subdir = match &failure {
+        None => DONE_DIR,
+        Some(e) => {
+            eprintln!("Failed to download {metalink:?}: {e}");
+            FAILED_DIR
+        }
+    };
+    let processed = dir
+        .join(subdir)
+        .join(metalink.file_name().unwrap_or_default());
+    std::fs::rename(metalink, &processed)
+        .with_context(|| format!("Failed to move {metalink:?} to {processed:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metalinks_are_taken_once_settled() {
+        let start = Instant::now();
+        let mut pending = Pending::default();
+        pending.changed(PathBuf::from("drop/release.meta4"), start);
+        pending.changed(PathBuf::from("drop/notes.txt"), start);
+        pending.changed(PathBuf::from("drop/other.metalink"), start);
+        assert!(pending.settled(start + SETTLE_TIME / 2).is_empty());
+
+        // Still being written
+        pending.changed(PathBuf::from("drop/other.metalink"), start + SETTLE_TIME);
+        assert_eq!(
+            pending.settled(start + SETTLE_TIME),
+            [PathBuf::from("drop/release.meta4")]
+        );
+        assert_eq!(
+            pending.settled(start + SETTLE_TIME * 2),
+            [PathBuf::from("drop/other.metalink")]
+        );
+        assert!(pending.settled(start + SETTLE_TIME * 3).is_empty());
+    }
+}
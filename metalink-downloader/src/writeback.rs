@@ -0,0 +1,252 @@
+//! How downloaded data reaches the disk. Adjacent chunks are collected in a
+//! buffer and written together, and files are synced according to the
+//! `--fsync` policy before they are renamed to their target.
+
+#[cfg(all(feature = "direct-io", target_os = "linux"))]
+use crate::direct_io::DirectWriter;
+use crate::Result;
+
+use anyhow::Context;
+use std::io::{Seek, Write};
+use std::path::Path;
+#[cfg(feature = "direct-io")]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+
+/// When downloaded files are synced to disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FsyncPolicy {
+    /// Sync the file system of the target directory once the download
+    /// completed, files are renamed to their target without syncing them
+    OnComplete,
+    /// Sync every file before it is renamed to its target
+    #[default]
+    PerFile,
+    /// Leave writing the files back to the operating system
+    Never,
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(FsyncPolicy::PerFile as u8);
+
+pub(crate) fn set_policy(policy: FsyncPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+fn policy() -> FsyncPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        0 => FsyncPolicy::OnComplete,
+        1 => FsyncPolicy::PerFile,
+        _ => FsyncPolicy::Never,
+    }
+}
+
+/// Size of the write buffer if not configured
+pub(crate) const DEFAULT_WRITE_BUFFER: u64 = 4 * 1024 * 1024;
+
+/// How long buffered data is held if not configured
+pub(crate) const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+static WRITE_BUFFER: AtomicU64 = AtomicU64::new(DEFAULT_WRITE_BUFFER);
+static FLUSH_INTERVAL_MILLIS: AtomicU64 = AtomicU64::new(DEFAULT_FLUSH_INTERVAL.as_millis() as u64);
+
+/// Write buffered chunks once `bytes` are buffered or the oldest was
+/// buffered for `interval`, a buffer of 0 bytes writes every chunk at once
+pub(crate) fn set_write_buffering(bytes: u64, interval: Duration) {
+    WRITE_BUFFER.store(bytes, Ordering::Relaxed);
+    FLUSH_INTERVAL_MILLIS.store(interval.as_millis() as u64, Ordering::Relaxed);
+}
+
+#[cfg(feature = "direct-io")]
+static DIRECT_IO: AtomicBool = AtomicBool::new(false);
+
+/// Write plain files with `O_DIRECT`, which is only supported on Linux
+#[cfg(feature = "direct-io")]
+pub(crate) fn set_direct_io(enabled: bool) {
+    if enabled && !cfg!(target_os = "linux") {
+        tracing::warn!("Direct I/O is only supported on Linux, writing through the page cache");
+    }
+    DIRECT_IO.store(enabled, Ordering::Relaxed);
+}
+
+/// A file the downloaded chunks are written into, with `O_DIRECT` if
+/// enabled with `--direct-io`
+pub(crate) struct FileSink {
+    file: std::fs::File,
+    #[cfg(all(feature = "direct-io", target_os = "linux"))]
+    direct: Option<DirectWriter>,
+}
+
+impl FileSink {
+    /// Create `path` with a size of `size` bytes
+    pub(crate) fn create(path: &Path, size: u64) -> Result<Self> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create file: {path:#?}"))?;
+        file.set_len(size)?;
+        Ok(Self {
+            #[cfg(all(feature = "direct-io", target_os = "linux"))]
+            direct: DIRECT_IO
+                .load(Ordering::Relaxed)
+                .then(|| DirectWriter::open(path))
+                .flatten(),
+            file,
+        })
+    }
+
+    pub(crate) fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        #[cfg(all(feature = "direct-io", target_os = "linux"))]
+        if let Some(direct) = &mut self.direct {
+            return direct.write_at(&self.file, offset, data);
+        }
+        self.file.seek(std::io::SeekFrom::Start(offset))?;
+        self.file.write_all(data)
+    }
+}
+
+/// Called once `path` is completely written, before it is renamed to its
+/// target
+pub(crate) fn file_complete(path: &Path) -> Result<()> {
+    if policy() != FsyncPolicy::PerFile {
+        return Ok(());
+    }
+    std::fs::File::open(path)
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("Failed to sync {path:?}"))?;
+    Ok(())
+}
+
+/// Called once all files of a download into `dir` are complete
+pub(crate) fn download_complete(dir: &Path) -> Result<()> {
+    if policy() != FsyncPolicy::OnComplete || !dir.exists() {
+        return Ok(());
+    }
+    sync_file_system(dir).with_context(|| format!("Failed to sync the file system of {dir:?}"))?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn sync_file_system(dir: &Path) -> std::io::Result<()> {
+    Ok(rustix::fs::syncfs(std::fs::File::open(dir)?)?)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn sync_file_system(_: &Path) -> std::io::Result<()> {
+    rustix::fs::sync();
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_file_system(_: &Path) -> std::io::Result<()> {
+    tracing::debug!("Syncing a file system is not supported on this platform");
+    Ok(())
+}
+
+/// Adjacent chunks waiting to be written
+pub(crate) struct WriteBuffer {
+    capacity: u64,
+    interval: Duration,
+    /// Offset of the first buffered byte in the file
+    offset: u64,
+    data: Vec<u8>,
+    /// Offset and length of the buffered chunks
+    chunks: Vec<(u64, u64)>,
+    /// When the oldest chunk was buffered
+    since: Option<Instant>,
+}
+
+/// Chunks taken from a [`WriteBuffer`] to be written at `offset`
+pub(crate) struct Buffered {
+    pub offset: u64,
+    pub data: Vec<u8>,
+    pub chunks: Vec<(u64, u64)>,
+}
+
+impl WriteBuffer {
+    /// A buffer with the configured size and flush interval
+    pub(crate) fn configured() -> Self {
+        Self::new(
+            WRITE_BUFFER.load(Ordering::Relaxed),
+            Duration::from_millis(FLUSH_INTERVAL_MILLIS.load(Ordering::Relaxed)),
+        )
+    }
+
+    fn new(capacity: u64, interval: Duration) -> Self {
+        Self {
+            capacity,
+            interval,
+            offset: 0,
+            data: Vec::new(),
+            chunks: Vec::new(),
+            since: None,
+        }
+    }
+
+    /// Whether a chunk at `offset` continues the buffered chunks
+    pub(crate) fn continues(&self, offset: u64) -> bool {
+        self.chunks.is_empty() || self.offset + self.data.len() as u64 == offset
+    }
+
+    /// Buffer a chunk which continues the buffered chunks
+    pub(crate) fn push(&mut self, offset: u64, bytes: &[u8]) {
+        debug_assert!(self.continues(offset));
+        if self.chunks.is_empty() {
+            self.offset = offset;
+            self.since = Some(Instant::now());
+        }
+        self.data.extend_from_slice(bytes);
+        self.chunks.push((offset, bytes.len() as u64));
+    }
+
+    /// Whether the buffered chunks need to be written
+    pub(crate) fn is_full(&self) -> bool {
+        self.data.len() as u64 >= self.capacity
+    }
+
+    /// When the buffered chunks need to be written at the latest
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.since.map(|since| since + self.interval)
+    }
+
+    /// Take the buffered chunks, None if there are none
+    pub(crate) fn take(&mut self) -> Option<Buffered> {
+        if self.chunks.is_empty() {
+            return None;
+        }
+        self.since = None;
+        Some(Buffered {
+            offset: self.offset,
+            data: std::mem::take(&mut self.data),
+            chunks: std::mem::take(&mut self.chunks),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adjacent_chunks_are_buffered_until_full() {
+        let mut buffer = WriteBuffer::new(8, Duration::from_secs(1));
+        assert!(buffer.take().is_none());
+        assert!(buffer.deadline().is_none());
+        buffer.push(4, b"abc");
+        assert!(!buffer.is_full());
+        assert!(buffer.continues(7));
+        assert!(!buffer.continues(8));
+        buffer.push(7, b"defgh");
+        assert!(buffer.is_full());
+        assert!(buffer.deadline().is_some());
+
+        let buffered = buffer.take().unwrap();
+        assert_eq!(buffered.offset, 4);
+        assert_eq!(buffered.data, b"abcdefgh");
+        assert_eq!(buffered.chunks, [(4, 3), (7, 5)]);
+        assert!(buffer.take().is_none());
+        assert!(buffer.continues(0));
+
+        let mut unbuffered = WriteBuffer::new(0, Duration::from_secs(1));
+        unbuffered.push(0, b"a");
+        assert!(unbuffered.is_full());
+    }
+}
@@ -4,14 +4,14 @@ use clap::Parser;
 /// Simple example for loading a metalink file
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
-struct CLI {
+struct Cli {
     /// The path to the metalink file
     #[arg(short, long)]
     file_path: String,
 }
 
 fn main() -> Result<()> {
-    let args = CLI::parse();
+    let args = Cli::parse();
 
     let metalink = metalink::Metalink::load_from_file(args.file_path)?;
     println!("{:#?}", metalink);
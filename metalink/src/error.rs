@@ -16,10 +16,18 @@ pub enum MetalinkError {
     #[error("Error constructing the Metalink: {0}")]
     MetalinkConstructionError(String),
 
+    /// The metalink is well-formed but violates the specification
+    #[error("Invalid metalink: {0}")]
+    ValidationError(String),
+
     /// An error while parsing the metalink xml occured
     #[error("Error while parsing metalink")]
     MetalinkParseError(#[from] quick_xml::de::DeError),
 
+    /// An error while serializing a metalink to xml occured
+    #[error("Error while serializing metalink")]
+    MetalinkSerializeError(#[source] quick_xml::de::DeError),
+
     /// An unexpected error occured
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
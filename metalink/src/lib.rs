@@ -5,7 +5,8 @@
 pub use crate::error::MetalinkError;
 pub use crate::models::{
     Copyright, Description, File, FileBuilder, FileUrl, Hash, Identity, Language, Logo, MetaUrl,
-    Metalink, Origin, Pieces, Publisher, Signature, Size, TorrentOrMime, Version, OS,
+    Metalink, MetalinkBuilder, Origin, Pieces, Publisher, Signature, Size, TorrentOrMime, Version,
+    METALINK_NAMESPACE, OS,
 };
 
 mod error;
@@ -1,8 +1,8 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Representation of the metalink:description element
 /// according to [RFC5854 Section 4.2.2](https://www.rfc-editor.org/rfc/rfc5854#section-4.2.2)
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Description {
     #[serde(rename = "$text")]
     description: String,
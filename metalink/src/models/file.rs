@@ -2,27 +2,41 @@ use crate::{
     Copyright, Description, FileUrl, Hash, Identity, Language, Logo, MetaUrl, MetalinkError,
     Pieces, Publisher, Signature, Size, Version, OS,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Representation of the metalink:file element according to
 /// [RFC5854 Section 4.1.2](https://www.rfc-editor.org/rfc/rfc5854#section-4.1.2)
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct File {
     #[serde(rename = "@name")]
     name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     copyright: Option<Copyright>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     description: Option<Description>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     hash: Option<Vec<Hash>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     identity: Option<Identity>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     language: Option<Vec<Language>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     logo: Option<Logo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     metaurl: Option<Vec<MetaUrl>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     os: Option<Vec<OS>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pieces: Option<Pieces>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     publisher: Option<Publisher>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     signature: Option<Signature>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     size: Option<Size>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     url: Option<Vec<FileUrl>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     version: Option<Version>,
 }
 
@@ -32,6 +46,15 @@ impl File {
         &self.name
     }
 
+    /// Returns whether the name is a relative path without "." or ".."
+    /// components, so the file stays inside the directory it is downloaded
+    /// to. See [RFC5854 Section 4.1.2.1](https://www.rfc-editor.org/rfc/rfc5854#section-4.1.2.1)
+    pub fn has_safe_name(&self) -> bool {
+        std::path::Path::new(&self.name)
+            .components()
+            .all(|component| matches!(component, std::path::Component::Normal(_)))
+    }
+
     /// Returns the copyright of the file element if set
     pub fn copyright(&self) -> Option<&Copyright> {
         self.copyright.as_ref()
@@ -87,6 +110,11 @@ impl File {
         self.signature.as_ref()
     }
 
+    /// Set the value of the metalink:signature element, replacing an existing signature
+    pub fn set_signature(&mut self, signature: Signature) {
+        self.signature = Some(signature);
+    }
+
     /// Returns the size of the file referenced by the file element if set
     pub fn size(&self) -> Option<&Size> {
         self.size.as_ref()
@@ -1,18 +1,18 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use validator::Validate;
 
 /// Representation of the metalink:url element according to
 /// [RFC5854 Section 4.2.16](https://www.rfc-editor.org/rfc/rfc5854#section-4.2.16)
-#[derive(Debug, Deserialize, Validate, PartialEq, Clone)]
+#[derive(Debug, Deserialize, Serialize, Validate, PartialEq, Clone)]
 pub struct FileUrl {
     #[validate(range(
         min = 1,
         max = 999999,
         message = "priority needs to be between 1 and 999999"
     ))]
-    #[serde(rename = "@priority")]
+    #[serde(rename = "@priority", skip_serializing_if = "Option::is_none")]
     priority: Option<u32>,
-    #[serde(rename = "@location")]
+    #[serde(rename = "@location", skip_serializing_if = "Option::is_none")]
     location: Option<isocountry::CountryCode>,
     #[serde(rename = "$text")]
     url: url::Url,
@@ -50,6 +50,12 @@ impl FileUrl {
     pub fn url(&self) -> url::Url {
         self.url.clone()
     }
+
+    /// Returns true if the url is a `file://` url of a local or mounted
+    /// file system
+    pub fn is_local(&self) -> bool {
+        self.url.scheme() == "file"
+    }
 }
 
 impl std::str::FromStr for FileUrl {
@@ -104,4 +110,19 @@ mod tests {
         );
         url.validate().unwrap();
     }
+
+    #[test]
+    fn read_local_file_url() {
+        const URL: &str = r#"
+            <url>file:///srv/mirror/rfc5854.txt</url>
+        "#;
+
+        let url = FileUrl::try_from(URL).unwrap();
+        assert!(url.is_local());
+        assert_eq!(
+            url.url().to_file_path().unwrap(),
+            std::path::Path::new("/srv/mirror/rfc5854.txt")
+        );
+        url.validate().unwrap();
+    }
 }
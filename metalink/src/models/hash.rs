@@ -1,9 +1,9 @@
 use iana_registry_enums::HashFunctionTextualName;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Representation of the metalink:hash element according to
 /// [RFC5854 Section 4.2.4](https://www.rfc-editor.org/rfc/rfc5854#section-4.2.4)
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Hash {
     #[serde(rename = "@type", skip_serializing_if = "Option::is_none")]
     r#type: Option<HashFunctionTextualName>,
@@ -30,6 +30,20 @@ impl Hash {
     pub fn value(&self) -> &str {
         self.value.as_ref()
     }
+
+    /// Returns whether the hex encoded hash value has the digest length of
+    /// `hash_type`. Values of algorithms without a fixed digest length are
+    /// only checked for being hex encoded.
+    pub fn matches_digest_length(&self, hash_type: HashFunctionTextualName) -> bool {
+        let value = self.value.trim();
+        if !value.chars().all(|c| c.is_ascii_hexdigit()) || !value.len().is_multiple_of(2) {
+            return false;
+        }
+        match hash_type.digest_length() {
+            Some(length) => value.len() == 2 * length,
+            None => !value.is_empty(),
+        }
+    }
 }
 
 impl std::str::FromStr for Hash {
@@ -64,6 +78,22 @@ mod tests {
         assert_eq!(hash.value(), String::from("abc"));
     }
 
+    #[test]
+    fn matches_digest_length() {
+        let sha1 = Hash::new(
+            Some(HashFunctionTextualName::Sha1),
+            "a9993e364706816aba3e25717850c26c9cd0d89d",
+        );
+        assert!(sha1.matches_digest_length(HashFunctionTextualName::Sha1));
+        assert!(!sha1.matches_digest_length(HashFunctionTextualName::Sha256));
+
+        let truncated = Hash::new(Some(HashFunctionTextualName::Sha1), "a9993e3647");
+        assert!(!truncated.matches_digest_length(HashFunctionTextualName::Sha1));
+
+        let not_hex = Hash::new(None, "zz");
+        assert!(!not_hex.matches_digest_length(HashFunctionTextualName::Shake128));
+    }
+
     #[test]
     fn read_hash_without_type() {
         const HASH: &str = r#"
@@ -1,4 +1,4 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use validator::Validate;
 
@@ -7,20 +7,20 @@ use crate::TorrentOrMime;
 /// Representation of the metalink:metaurl element according to
 /// [RFC5854 Section 4.2.8](https://www.rfc-editor.org/rfc/rfc5854#section-4.2.8)
 #[serde_as]
-#[derive(Debug, Deserialize, Validate, PartialEq, Clone)]
+#[derive(Debug, Deserialize, Serialize, Validate, PartialEq, Clone)]
 pub struct MetaUrl {
     #[validate(range(
         min = 1,
         max = 999999,
         message = "priority needs to be between 1 and 999999"
     ))]
-    #[serde(rename = "@priority")]
+    #[serde(rename = "@priority", skip_serializing_if = "Option::is_none")]
     priority: Option<u32>,
     // TODO needs validation for valid MIME type or the string torrent of bittorrent urls
     #[serde_as(as = "DisplayFromStr")]
     #[serde(rename = "@mediatype")]
     media_type: TorrentOrMime,
-    #[serde(rename = "@name")]
+    #[serde(rename = "@name", skip_serializing_if = "Option::is_none")]
     name: Option<String>,
     #[serde(rename = "$text")]
     url: url::Url,
@@ -2,26 +2,44 @@ use std::str::FromStr;
 
 use anyhow::Context;
 use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{utils::rfc3339_to_datetime_utc, MetalinkError};
 use crate::{File, Origin};
 
 /// Representation of the metalink:metalink element according to
 /// [RFC5854 Section 4.1.1](https://www.rfc-editor.org/rfc/rfc5854#section-4.1.1)
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[serde(rename = "metalink")]
 pub struct Metalink {
+    #[serde(
+        rename = "@xmlns",
+        skip_deserializing,
+        serialize_with = "metalink_namespace"
+    )]
+    namespace: (),
+    #[serde(skip_serializing_if = "Option::is_none")]
     generator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     origin: Option<Origin>,
     #[serde(default)]
     #[serde(with = "rfc3339_to_datetime_utc")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     published: Option<chrono::DateTime<chrono::Utc>>,
     #[serde(default)]
     #[serde(with = "rfc3339_to_datetime_utc")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     updated: Option<chrono::DateTime<chrono::Utc>>,
     file: Vec<File>,
 }
 
+/// XML namespace of metalink documents, see [RFC5854 Section 4](https://www.rfc-editor.org/rfc/rfc5854#section-4)
+pub const METALINK_NAMESPACE: &str = "urn:ietf:params:xml:ns:metalink";
+
+fn metalink_namespace<S: serde::Serializer>(_: &(), serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(METALINK_NAMESPACE)
+}
+
 impl Metalink {
     /// Load a metalink from the file specified by file_path
     pub fn load_from_file<P: AsRef<std::path::Path>>(
@@ -32,6 +50,39 @@ impl Metalink {
         ))?)
     }
 
+    /// Load a metalink from the file specified by file_path in lenient mode.
+    /// Lenient mode accepts common real-world spellings of values that the
+    /// RFC restricts to registry names, e.g. `GNU/Linux` in metalink:os.
+    pub fn load_from_file_lenient<P: AsRef<std::path::Path>>(
+        file_path: P,
+    ) -> Result<Metalink, MetalinkError> {
+        crate::utils::with_lenient(|| Self::load_from_file(file_path))
+    }
+
+    /// Parse a metalink from a string in lenient mode, see [Metalink::load_from_file_lenient]
+    pub fn from_str_lenient(s: &str) -> Result<Metalink, MetalinkError> {
+        crate::utils::with_lenient(|| s.parse())
+    }
+
+    /// Serialize the metalink into an RFC5854 XML document
+    pub fn to_xml_string(&self) -> Result<String, MetalinkError> {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        // No indentation, quick-xml would otherwise pad text content with whitespace
+        self.serialize(quick_xml::se::Serializer::new(&mut xml))
+            .map_err(MetalinkError::MetalinkSerializeError)?;
+        xml.push('\n');
+        Ok(xml)
+    }
+
+    /// Write the metalink as an RFC5854 XML document to the file specified by file_path
+    pub fn save_to_file<P: AsRef<std::path::Path>>(
+        &self,
+        file_path: P,
+    ) -> Result<(), MetalinkError> {
+        std::fs::write(file_path, self.to_xml_string()?).context("Failed to write file")?;
+        Ok(())
+    }
+
     /// Returns the value of the metalink:generator element
     /// if the field exists. See [RFC5854 Section 4.2.3](https://www.rfc-editor.org/rfc/rfc5854#section-4.2.3)
     pub fn generator(&self) -> Option<&String> {
@@ -56,11 +107,64 @@ impl Metalink {
         self.updated.as_ref()
     }
 
+    /// Validate the metalink beyond what is enforced by parsing.
+    /// Currently checks that every file name is a relative path inside the
+    /// download directory and that every hash value has the digest length of
+    /// its declared hash algorithm, catching truncated or corrupted hash values.
+    pub fn validate(&self) -> Result<(), MetalinkError> {
+        match self.violations().into_iter().next() {
+            Some(violation) => Err(MetalinkError::ValidationError(violation)),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns every violation of the specification [Metalink::validate]
+    /// checks for, in the order of the files
+    pub fn violations(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+        for file in &self.file {
+            if !file.has_safe_name() {
+                violations.push(format!(
+                    "file {}: name is not a relative path inside the download directory",
+                    file.name()
+                ));
+            }
+            for hash in file.hashes().into_iter().flatten() {
+                if let Some(hash_type) = hash.hash_type() {
+                    if !hash.matches_digest_length(hash_type) {
+                        violations.push(format!(
+                            "file {}: {hash_type} hash {} has an invalid length",
+                            file.name(),
+                            hash.value()
+                        ));
+                    }
+                }
+            }
+            if let Some(pieces) = file.pieces() {
+                for (index, hash) in pieces.hashes().iter().enumerate() {
+                    if !hash.matches_digest_length(pieces.hash_type()) {
+                        violations.push(format!(
+                            "file {}: {} hash of piece {index} has an invalid length",
+                            file.name(),
+                            pieces.hash_type()
+                        ));
+                    }
+                }
+            }
+        }
+        violations
+    }
+
     /// Returns the list of metalink:file elements.
     /// See [RFC5854 Section 4.1.2](https://www.rfc-editor.org/rfc/rfc5854#section-4.1.2)
     pub fn files(&self) -> &Vec<File> {
         &self.file
     }
+
+    /// Returns mutable access to the metalink:file elements
+    pub fn files_mut(&mut self) -> &mut [File] {
+        &mut self.file
+    }
 }
 
 impl FromStr for Metalink {
@@ -78,9 +182,75 @@ impl std::convert::TryFrom<&str> for Metalink {
         value.parse()
     }
 }
+
+/// Helper type for constructing Metalink elements
+#[derive(Debug, Default)]
+pub struct MetalinkBuilder {
+    generator: Option<String>,
+    origin: Option<Origin>,
+    published: Option<DateTime<Utc>>,
+    updated: Option<DateTime<Utc>>,
+    file: Vec<File>,
+}
+
+impl MetalinkBuilder {
+    /// Create a new MetalinkBuilder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Construct a Metalink object based on stored parameters.
+    pub fn build(self) -> Result<Metalink, MetalinkError> {
+        if self.file.is_empty() {
+            return Err(MetalinkError::MetalinkConstructionError(
+                "Metalink elements require at least one file element".to_owned(),
+            ));
+        }
+
+        Ok(Metalink {
+            namespace: (),
+            generator: self.generator,
+            origin: self.origin,
+            published: self.published,
+            updated: self.updated,
+            file: self.file,
+        })
+    }
+
+    /// Set the generator of the metalink
+    pub fn with_generator(mut self, generator: &str) -> Self {
+        self.generator = Some(generator.to_owned());
+        self
+    }
+
+    /// Set the origin of the metalink
+    pub fn with_origin(mut self, origin: Origin) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    /// Set the publishing date of the metalink
+    pub fn with_published(mut self, published: DateTime<Utc>) -> Self {
+        self.published = Some(published);
+        self
+    }
+
+    /// Set the date of the last update of the metalink
+    pub fn with_updated(mut self, updated: DateTime<Utc>) -> Self {
+        self.updated = Some(updated);
+        self
+    }
+
+    /// Set the files of the metalink
+    pub fn with_files(mut self, files: Vec<File>) -> Self {
+        self.file = files;
+        self
+    }
+}
 // ----------------------------------------------------------------------------
 
 #[cfg(test)]
+#[allow(clippy::clone_on_copy)]
 mod tests {
     // use std::str::FromStr;
     //
@@ -181,6 +351,7 @@ mod tests {
         ];
 
         let expected = Metalink {
+            namespace: (),
             generator: expected_generator.clone(),
             published: expected_published.clone(),
             updated: expected_updated.clone(),
@@ -196,6 +367,60 @@ mod tests {
         assert_eq!(*metalink.files(), expected_files);
     }
 
+    #[test]
+    fn validate_rejects_hashes_with_wrong_length() {
+        const METALINK: &str = r#"
+            <metalink>
+                <file name="abc/def">
+                    <hash type="sha-1">a9993e364706816aba3e25717850c26c9cd0d89d</hash>
+                    <pieces type="sha-1" length="50">
+                        <hash>a9993e364706816aba3e25717850c26c9cd0d89d</hash>
+                        <hash>a9993e36</hash>
+                    </pieces>
+                    <url>https://www.google.de</url>
+                </file>
+            </metalink>
+        "#;
+        let metalink = Metalink::try_from(METALINK).unwrap();
+        assert!(matches!(
+            metalink.validate(),
+            Err(MetalinkError::ValidationError(_))
+        ));
+        assert_eq!(
+            metalink.violations(),
+            ["file abc/def: sha-1 hash of piece 1 has an invalid length"]
+        );
+
+        const VALID: &str = r#"
+            <metalink>
+                <file name="abc/def">
+                    <hash type="sha-1">a9993e364706816aba3e25717850c26c9cd0d89d</hash>
+                    <url>https://www.google.de</url>
+                </file>
+            </metalink>
+        "#;
+        assert!(Metalink::try_from(VALID).unwrap().validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_names_leaving_the_directory() {
+        for name in ["../../.bashrc", "/etc/cron.d/x", "abc/../def", "./abc"] {
+            let metalink = Metalink::try_from(
+                format!(
+                    r#"<metalink><file name="{name}"><url>https://www.google.de</url></file></metalink>"#
+                )
+                .as_str(),
+            )
+            .unwrap();
+            assert_eq!(
+                metalink.violations(),
+                [format!(
+                    "file {name}: name is not a relative path inside the download directory"
+                )]
+            );
+        }
+    }
+
     #[test]
     fn parse_minimal_metalink() {
         const METALINK: &str = r#"
@@ -233,6 +458,7 @@ mod tests {
             .build()
             .unwrap()];
         let expected = Metalink {
+            namespace: (),
             generator: None,
             published: None,
             updated: None,
@@ -247,4 +473,41 @@ mod tests {
         assert_eq!(metalink.origin(), None);
         assert_eq!(*metalink.files(), expected_files);
     }
+
+    #[test]
+    fn serialized_metalink_round_trips() {
+        let metalink = MetalinkBuilder::new()
+            .with_generator("TestGenerator")
+            .with_published(
+                DateTime::parse_from_rfc3339("2010-05-01T12:15:02Z")
+                    .unwrap()
+                    .with_timezone(&Utc),
+            )
+            .with_files(vec![FileBuilder::new()
+                .with_name("abc/def")
+                .with_hashes(vec![Hash::new(Some(HashFunctionTextualName::Sha1), "abc")])
+                .with_pieces(Pieces::new(
+                    HashFunctionTextualName::Sha1,
+                    50,
+                    vec![Hash::new(None, "abc"), Hash::new(None, "def")],
+                ))
+                .with_urls(vec![FileUrl::new(
+                    url::Url::parse("https://www.google.de").unwrap(),
+                    Some(1),
+                    Some(isocountry::CountryCode::DEU),
+                )])
+                .build()
+                .unwrap()])
+            .build()
+            .unwrap();
+
+        let xml = metalink.to_xml_string().unwrap();
+        assert!(xml.contains(r#"<metalink xmlns="urn:ietf:params:xml:ns:metalink">"#));
+        assert_eq!(Metalink::try_from(xml.as_str()).unwrap(), metalink);
+    }
+
+    #[test]
+    fn metalink_requires_files() {
+        assert!(MetalinkBuilder::new().build().is_err());
+    }
 }
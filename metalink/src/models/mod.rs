@@ -26,7 +26,7 @@ pub use identity::Identity;
 pub use language::Language;
 pub use logo::Logo;
 pub use meta_url::MetaUrl;
-pub use metalink::Metalink;
+pub use metalink::{Metalink, MetalinkBuilder, METALINK_NAMESPACE};
 pub use origin::Origin;
 pub use os::OS;
 pub use pieces::Pieces;
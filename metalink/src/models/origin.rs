@@ -1,10 +1,10 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Representation of the metalink:origin element according to
 /// [RFC5854 Section 4.2.9](https://www.rfc-editor.org/rfc/rfc5854#section-4.2.9)
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Origin {
-    #[serde(rename = "@dynamic")]
+    #[serde(rename = "@dynamic", skip_serializing_if = "Option::is_none")]
     dynamic: Option<bool>,
     #[serde(rename = "$text")]
     url: url::Url,
@@ -44,6 +44,7 @@ impl std::convert::TryFrom<&str> for Origin {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use std::str::FromStr;
 
@@ -1,11 +1,11 @@
 use iana_registry_enums::OperatingSystemName;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Representation of the metalink:os element according to
 /// [RFC5854 Section 4.2.10](https://www.rfc-editor.org/rfc/rfc5854#section-4.2.10)
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct OS {
-    #[serde(rename = "$text")]
+    #[serde(rename = "$text", with = "crate::utils::lenient_os_name")]
     name: OperatingSystemName,
 }
 
@@ -46,6 +46,19 @@ mod tests {
         assert_eq!(os.name(), OperatingSystemName::MacOS);
     }
 
+    #[test]
+    fn read_common_spelling_works() {
+        let os = OS::try_from("<os>GNU/Linux</os>").unwrap();
+        assert_eq!(os.name(), OperatingSystemName::Linux);
+    }
+
+    #[test]
+    fn read_unusual_spelling_requires_lenient_parsing() {
+        assert!(OS::try_from("<os>windows_10</os>").is_err());
+        let os = crate::utils::with_lenient(|| OS::try_from("<os>windows_10</os>")).unwrap();
+        assert_eq!(os.name(), OperatingSystemName::Win32);
+    }
+
     #[test]
     fn read_works() {
         const OS: &str = r#"<OS>MACOS</OS>"#;
@@ -1,17 +1,17 @@
 use iana_registry_enums::HashFunctionTextualName;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::Hash;
 
 /// Representation of the metalink:pieces element according to
 /// [RFC5854 Section 4.1.3](https://www.rfc-editor.org/rfc/rfc5854#section-4.1.3)
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Pieces {
     #[serde(rename = "@type")]
     r#type: HashFunctionTextualName,
     #[serde(rename = "@length")]
     length: u64,
-    #[serde(default, rename = "$value")]
+    #[serde(default, rename(serialize = "hash", deserialize = "$value"))]
     hashes: Vec<Hash>,
 }
 
@@ -1,11 +1,11 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 /// Representation of the metalink:publisher field according to
 /// [RFC5854 Section 4.2.12](https://www.rfc-editor.org/rfc/rfc5854#section-4.2.12)
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Publisher {
     #[serde(rename = "@name")]
     name: String,
-    #[serde(rename = "@url")]
+    #[serde(rename = "@url", skip_serializing_if = "Option::is_none")]
     url: Option<url::Url>,
 }
 
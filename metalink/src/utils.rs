@@ -1,8 +1,67 @@
+use std::cell::Cell;
+
 pub(crate) use quick_xml::de::from_str;
 
+thread_local! {
+    static LENIENT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f` with lenient parsing enabled on the current thread
+pub(crate) fn with_lenient<T>(f: impl FnOnce() -> T) -> T {
+    let previous = LENIENT.with(|lenient| lenient.replace(true));
+    let result = f();
+    LENIENT.with(|lenient| lenient.set(previous));
+    result
+}
+
+/// Returns whether lenient parsing is enabled on the current thread
+pub(crate) fn is_lenient() -> bool {
+    LENIENT.with(Cell::get)
+}
+
+pub mod lenient_os_name {
+    use iana_registry_enums::OperatingSystemName;
+    use serde::{self, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S>(name: &OperatingSystemName, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        name.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<OperatingSystemName, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let parsed = if super::is_lenient() {
+            OperatingSystemName::parse_lenient(&s)
+        } else {
+            OperatingSystemName::deserialize(serde::de::value::StrDeserializer::<
+                serde::de::value::Error,
+            >::new(&s))
+            .map_err(|_| iana_registry_enums::IANARegistryError::OsNameParseError)
+        };
+        parsed.map_err(|e| serde::de::Error::custom(format!("{e}: {s}")))
+    }
+}
+
 pub mod rfc3339_to_datetime_utc {
-    use chrono::{DateTime, Utc};
-    use serde::{self, Deserialize, Deserializer};
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use serde::{self, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(date: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match date {
+            Some(date) => {
+                serializer.serialize_str(&date.to_rfc3339_opts(SecondsFormat::Secs, true))
+            }
+            None => serializer.serialize_none(),
+        }
+    }
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
     where